@@ -0,0 +1,148 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::span;
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+
+fn get_terms(spec: &serde_yaml::Value) -> Option<&Vec<serde_yaml::Value>> {
+    spec["affinity"]["nodeAffinity"]["requiredDuringSchedulingIgnoredDuringExecution"]
+        ["nodeSelectorTerms"]
+        .as_sequence()
+}
+
+// Whether the pod restricts the nodes it is scheduled on, i.e., has a nodeSelector or a
+// required node affinity. The preferred node affinity does not decide the node.
+pub fn has_node_constraints(spec: &serde_yaml::Value) -> bool {
+    spec["nodeSelector"]
+        .as_mapping()
+        .is_some_and(|node_selector| !node_selector.is_empty())
+        || get_terms(spec).is_some()
+}
+
+fn get_values(expression: &serde_yaml::Value) -> Result<Vec<&str>> {
+    expression["values"]
+        .as_sequence()
+        .into_iter()
+        .flatten()
+        .map(|value| {
+            value
+                .as_str()
+                .ok_or_else(|| span::error(value, "failed to parse values into str"))
+        })
+        .collect()
+}
+
+fn get_number(value: &str, expression: &serde_yaml::Value) -> Result<i64> {
+    value.parse().map_err(|_| {
+        span::error(
+            expression,
+            format!("{} is not an integer, expected by Gt and Lt", value),
+        )
+    })
+}
+
+// Follow the NodeSelectorRequirement operators, e.g., NotIn and DoesNotExist match the nodes
+// without the label
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/staging/src/k8s.io/component-helpers/scheduling/corev1/nodeaffinity/nodeaffinity.go#L235
+fn matches_expression(
+    expression: &serde_yaml::Value,
+    labels: &BTreeMap<String, String>,
+) -> Result<bool> {
+    let key = expression["key"]
+        .as_str()
+        .ok_or_else(|| span::error(&expression["key"], "failed to parse key into str"))?;
+    let operator = expression["operator"]
+        .as_str()
+        .ok_or_else(|| span::error(&expression["operator"], "failed to parse operator into str"))?;
+    let values = get_values(expression)?;
+    let label = labels.get(key).map(|label| label.as_str());
+
+    Ok(match operator {
+        "In" => label.is_some_and(|label| values.contains(&label)),
+        "NotIn" => !label.is_some_and(|label| values.contains(&label)),
+        "Exists" => label.is_some(),
+        "DoesNotExist" => label.is_none(),
+        "Gt" | "Lt" => {
+            let value = match values.as_slice() {
+                [value] => get_number(value, expression)?,
+                _ => {
+                    return Err(span::error(
+                        expression,
+                        format!("{} expects a single value", operator),
+                    ))
+                }
+            };
+
+            match label.map(|label| label.parse::<i64>()) {
+                Some(Ok(label)) if operator == "Gt" => label > value,
+                Some(Ok(label)) => label < value,
+                _ => false,
+            }
+        }
+        _ => {
+            return Err(span::error(
+                &expression["operator"],
+                format!("unsupported operator {} of node affinity", operator),
+            ))
+        }
+    })
+}
+
+// Whether the scheduler may place the pod on the nodes with the given labels, i.e., the
+// nodeSelector matches them and any of the nodeSelectorTerms of the required node affinity
+// matches them, where a term matches if all its matchExpressions do. The matchFields refer to
+// the node name, which is not known, so they are ignored with a warning.
+// Reference: https://kubernetes.io/docs/concepts/scheduling-eviction/assign-pod-node/#node-affinity
+pub fn matches_node_labels(
+    spec: &serde_yaml::Value,
+    labels: &BTreeMap<String, String>,
+) -> Result<bool> {
+    if let Some(node_selector) = spec["nodeSelector"].as_mapping() {
+        for (key, value) in node_selector {
+            let key = key
+                .as_str()
+                .ok_or_else(|| span::error(key, "failed to parse nodeSelector key into str"))?;
+            let value = value
+                .as_str()
+                .ok_or_else(|| span::error(value, "failed to parse nodeSelector value into str"))?;
+
+            if labels.get(key).map(|label| label.as_str()) != Some(value) {
+                return Ok(false);
+            }
+        }
+    }
+
+    let terms = match get_terms(spec) {
+        Some(terms) => terms,
+        None => return Ok(true),
+    };
+
+    for term in terms {
+        if term.get("matchFields").is_some() {
+            warn!("the matchFields of the node affinity refer to the node name and are ignored");
+        }
+
+        let expressions = term["matchExpressions"].as_sequence();
+
+        // A term without requirements matches no node
+        if expressions.is_none_or(|expressions| expressions.is_empty()) {
+            continue;
+        }
+
+        let mut matched = true;
+        for expression in expressions.into_iter().flatten() {
+            if !matches_expression(expression, labels)? {
+                matched = false;
+                break;
+            }
+        }
+
+        if matched {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
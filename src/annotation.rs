@@ -0,0 +1,137 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::span::NodeError;
+
+use anyhow::{Error, Result};
+use clap::ArgEnum;
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// Format of the warnings and errors, set by --diagnostics
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+    Text,
+    Github,
+}
+
+static GITHUB: AtomicBool = AtomicBool::new(false);
+
+// Location of the first failing document, reported with the error returned by main
+static ERROR_LOCATION: Mutex<Option<Location>> = Mutex::new(None);
+
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+thread_local! {
+    // Document being processed by the thread, if any
+    static LOCATION: RefCell<Option<Location>> = const { RefCell::new(None) };
+}
+
+pub fn set_format(format: &DiagnosticsFormat) {
+    GITHUB.store(*format == DiagnosticsFormat::Github, Ordering::Relaxed);
+}
+
+// Run f with the warnings located at the line of the file
+pub fn with_location<T, F>(file: &Path, line: Option<usize>, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let location = Location {
+        file: file.display().to_string(),
+        line,
+        column: None,
+    };
+
+    let previous = LOCATION.with(|current| current.replace(Some(location.clone())));
+    let result = f();
+    LOCATION.with(|current| *current.borrow_mut() = previous);
+
+    if let Err(e) = &result {
+        let mut location = location;
+        if let Some((line, column)) = get_position(e) {
+            location.line = Some(line);
+            location.column = Some(column);
+        }
+
+        ERROR_LOCATION.lock().unwrap().get_or_insert(location);
+    }
+
+    result
+}
+
+// Position of the offending node or of the syntax error, if known
+fn get_position(error: &Error) -> Option<(usize, usize)> {
+    if let Some(node) = error.downcast_ref::<NodeError>() {
+        return node.position();
+    }
+
+    error
+        .downcast_ref::<serde_yaml::Error>()
+        .and_then(|e| e.location())
+        .map(|location| (location.line(), location.column()))
+}
+
+// Workflow command escaping
+// Reference: https://github.com/actions/toolkit/blob/main/packages/core/src/command.ts
+fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(property: &str) -> String {
+    escape_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+// Reference: https://docs.github.com/en/actions/using-workflow-commands-for-github-actions
+fn command(name: &str, location: Option<Location>, message: &str) -> String {
+    let properties = match location {
+        Some(Location {
+            file,
+            line: Some(line),
+            column: Some(column),
+        }) => format!(
+            " file={},line={},col={}",
+            escape_property(&file),
+            line,
+            column
+        ),
+        Some(Location {
+            file,
+            line: Some(line),
+            column: None,
+        }) => format!(" file={},line={}", escape_property(&file), line),
+        Some(Location {
+            file, line: None, ..
+        }) => format!(" file={}", escape_property(&file)),
+        None => String::new(),
+    };
+
+    format!("::{}{}::{}", name, properties, escape_data(message))
+}
+
+pub fn warning(message: &str) {
+    if GITHUB.load(Ordering::Relaxed) {
+        let location = LOCATION.with(|current| current.borrow().clone());
+        eprintln!("{}", command("warning", location, message));
+    } else {
+        eprintln!("warning: {}", message);
+    }
+}
+
+// Report the error returned by main, which is printed by the standard library afterwards
+pub fn error(message: &str) {
+    if GITHUB.load(Ordering::Relaxed) {
+        let location = ERROR_LOCATION.lock().unwrap().take();
+        eprintln!("{}", command("error", location, message));
+    }
+}
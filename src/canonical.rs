@@ -0,0 +1,93 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use serde_json::Value;
+
+// JSON Canonicalization Scheme, i.e., no whitespace, the keys of the objects sorted by their
+// UTF-16 code units, and the numbers and strings serialized like ECMAScript JSON.stringify.
+// The policies encoded with --canonical-json are canonicalized so that their digests do not
+// depend on the formatting.
+// Reference: https://www.rfc-editor.org/rfc/rfc8785
+pub fn to_canonical_json(value: &Value) -> String {
+    let mut result = String::new();
+    write_value(value, &mut result);
+    result
+}
+
+fn write_value(value: &Value, result: &mut String) {
+    match value {
+        Value::Null => result.push_str("null"),
+        Value::Bool(value) => result.push_str(if *value { "true" } else { "false" }),
+        Value::Number(number) => result.push_str(&format_number(number)),
+        // serde_json escapes the same characters as JSON.stringify, with lowercase hex digits
+        Value::String(value) => result.push_str(&Value::String(value.clone()).to_string()),
+        Value::Array(array) => {
+            result.push('[');
+            for (index, value) in array.iter().enumerate() {
+                if index > 0 {
+                    result.push(',');
+                }
+                write_value(value, result);
+            }
+            result.push(']');
+        }
+        Value::Object(object) => {
+            let mut entries: Vec<(&String, &Value)> = object.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+
+            result.push('{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    result.push(',');
+                }
+                write_value(&Value::String(key.clone()), result);
+                result.push(':');
+                write_value(value, result);
+            }
+            result.push('}');
+        }
+    }
+}
+
+// Number::toString of ECMAScript, given the shortest digits that round trip, which the LowerExp
+// formatting of Rust returns, e.g., 1.5e-7
+// Reference: https://tc39.es/ecma262/#sec-numeric-types-number-tostring
+fn format_number(number: &serde_json::Number) -> String {
+    if number.is_i64() || number.is_u64() {
+        return number.to_string();
+    }
+
+    // serde_json has no NaN nor infinite numbers
+    let value = number.as_f64().unwrap_or_default();
+    if value == 0.0 {
+        return "0".to_string();
+    }
+
+    let sign = if value < 0.0 { "-" } else { "" };
+    let exponential = format!("{:e}", value.abs());
+    let (mantissa, exponent) = exponential.split_once('e').unwrap_or((&exponential, "0"));
+    let digits = mantissa.replace('.', "");
+    let k = digits.len() as i32;
+    // The value is 0.<digits> x 10^n
+    let n = exponent.parse::<i32>().unwrap_or_default() + 1;
+
+    let result = if k <= n && n <= 21 {
+        [digits.as_str(), &"0".repeat((n - k) as usize)].concat()
+    } else if 0 < n && n <= 21 {
+        let (integer, fraction) = digits.split_at(n as usize);
+        [integer, ".", fraction].concat()
+    } else if -6 < n && n <= 0 {
+        ["0.", &"0".repeat(-n as usize), digits.as_str()].concat()
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let fraction = if rest.is_empty() {
+            String::new()
+        } else {
+            [".", rest].concat()
+        };
+        let exponent_sign = if n - 1 < 0 { "-" } else { "+" };
+        format!("{}{}e{}{}", first, fraction, exponent_sign, (n - 1).abs())
+    };
+
+    [sign, result.as_str()].concat()
+}
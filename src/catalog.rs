@@ -0,0 +1,172 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::image;
+
+use anyhow::{Context, Result};
+use oci_spec::image::{ImageConfiguration, ImageManifest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = "index.json";
+const CONFIG_FILE: &str = "config.json";
+const MANIFEST_FILE: &str = "manifest.json";
+const POLICY_FILE: &str = "policy.json";
+
+// Image refs resolved to the manifest digests when the catalog was built
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    images: BTreeMap<String, String>,
+}
+
+// A directory of pre-generated policies of curated images, keyed by the manifest digest:
+//
+// index.json
+// sha256-<digest>/config.json
+// sha256-<digest>/manifest.json
+// sha256-<digest>/policy.json
+//
+// The image configs and manifests in the catalog are used instead of pulling them. Note that
+// tags are resolved when the catalog is built, and only refreshed by building it again.
+pub struct Catalog {
+    path: PathBuf,
+    index: Index,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> Result<Catalog> {
+        let index_path = path.join(INDEX_FILE);
+
+        let index = if index_path.exists() {
+            let content = fs::read_to_string(&index_path).context(loc!())?;
+            serde_json::from_str(&content).with_context(|| {
+                format!("failed to parse catalog index {}", index_path.display())
+            })?
+        } else {
+            Index::default()
+        };
+
+        Ok(Catalog {
+            path: path.to_path_buf(),
+            index,
+        })
+    }
+
+    fn get_entry_path(&self, digest: &str) -> PathBuf {
+        self.path.join(digest.replace(':', "-"))
+    }
+
+    // Return the digest of the image ref, which is either pinned by the ref or in the index
+    fn get_digest(&self, image_ref: &str) -> Option<String> {
+        let reference = image::get_image_reference(image_ref);
+
+        if let Some(digest) = self.index.images.get(&reference) {
+            return Some(digest.clone());
+        }
+
+        match reference.split_once('@') {
+            Some((_, digest)) if self.get_entry_path(digest).exists() => Some(digest.to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, image_ref: &str) -> bool {
+        self.get_digest(image_ref).is_some()
+    }
+
+    fn read<T: serde::de::DeserializeOwned>(
+        &self,
+        image_ref: &str,
+        file: &str,
+    ) -> Result<Option<T>> {
+        let digest = match self.get_digest(image_ref) {
+            Some(digest) => digest,
+            None => return Ok(None),
+        };
+
+        let path = self.get_entry_path(&digest).join(file);
+        let content = fs::read_to_string(&path).context(loc!())?;
+
+        let value = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        Ok(Some(value))
+    }
+
+    pub fn get_image_config(&self, image_ref: &str) -> Result<Option<ImageConfiguration>> {
+        self.read(image_ref, CONFIG_FILE)
+    }
+
+    pub fn get_image_manifest(&self, image_ref: &str) -> Result<Option<(ImageManifest, String)>> {
+        let manifest = self.read(image_ref, MANIFEST_FILE)?;
+
+        Ok(manifest.map(|manifest| {
+            // Checked by read
+            let digest = self.get_digest(image_ref).unwrap();
+            (manifest, digest)
+        }))
+    }
+
+    // Return the path of the policy of the image, if any
+    pub fn lookup(&self, image_ref: &str) -> Option<PathBuf> {
+        let path = self
+            .get_entry_path(&self.get_digest(image_ref)?)
+            .join(POLICY_FILE);
+
+        if path.exists() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    // Pull the config and the manifest of the image into the catalog
+    pub fn add_image(&mut self, image_ref: &str) -> Result<()> {
+        let image_config = image::pull_image_config(image_ref, None)?;
+        let (manifest, digest) = image::pull_image_manifest(image_ref, None)?;
+
+        let entry_path = self.get_entry_path(&digest);
+        fs::create_dir_all(&entry_path).context(loc!())?;
+
+        fs::write(
+            entry_path.join(CONFIG_FILE),
+            serde_json::to_string_pretty(&image_config)?,
+        )
+        .context(loc!())?;
+        fs::write(
+            entry_path.join(MANIFEST_FILE),
+            serde_json::to_string_pretty(&manifest)?,
+        )
+        .context(loc!())?;
+
+        self.index
+            .images
+            .insert(image::get_image_reference(image_ref), digest);
+
+        Ok(())
+    }
+
+    pub fn add_policy(&self, image_ref: &str, policy: &str) -> Result<PathBuf> {
+        let digest = self
+            .get_digest(image_ref)
+            .with_context(|| format!("image {} is not in the catalog", image_ref))?;
+
+        let path = self.get_entry_path(&digest).join(POLICY_FILE);
+        fs::write(&path, policy).context(loc!())?;
+
+        Ok(path)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(&self.path).context(loc!())?;
+        fs::write(
+            self.path.join(INDEX_FILE),
+            serde_json::to_string_pretty(&self.index)?,
+        )
+        .context(loc!())?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::kubernetes::*;
+use crate::subprocess;
+use crate::PodYaml;
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const KUBECTL: &str = "kubectl";
+const DEFAULT_NAMESPACE: &str = "default";
+
+// The kubelet bind mounts the path of a local PersistentVolume into the directory of the pod,
+// whose UID is not known before it is created, and gives that directory to the runtime
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/volume/local/local.go
+const LOCAL_VOLUME_SOURCE_PREFIX: &str =
+    "^/var/lib/kubelet/pods/[0-9a-f-]+/volumes/kubernetes\\.io~local-volume/";
+
+// Return whether the mount source is the one of a local PersistentVolume, i.e., a path of the
+// node like the ones of the hostPath volumes
+pub fn is_local_volume_source(source: &str) -> bool {
+    source.starts_with(LOCAL_VOLUME_SOURCE_PREFIX)
+}
+
+pub fn kubectl(args: &[&str]) -> Result<String> {
+    subprocess::run_text(KUBECTL, args)
+}
+
+pub fn get_yaml(args: &[&str]) -> Result<serde_yaml::Value> {
+    let mut args = args.to_vec();
+    args.extend(["-o", "yaml"]);
+
+    let output = kubectl(&args)?;
+
+    Ok(serde_yaml::from_str(&output)?)
+}
+
+// Submit the object with a server-side dry-run and return the object persisted by the API
+// server, i.e., with the defaulted fields and the changes of mutating admission webhooks.
+// Unique inputs given that the documents are processed in parallel
+static DRY_RUN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Note that webhooks targeting pods only mutate the template of workload controllers when
+// the pods are created, the dry-run of those objects only captures the defaulting.
+pub fn dry_run(yaml: &serde_yaml::Value) -> Result<serde_yaml::Value> {
+    let path = env::temp_dir().join(format!(
+        "cc-policy-dry-run-{}-{}.yaml",
+        std::process::id(),
+        DRY_RUN_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    fs::write(&path, serde_yaml::to_string(yaml)?).context(loc!())?;
+
+    let path_str = path.to_string_lossy().to_string();
+    let result = get_yaml(&["apply", "--dry-run=server", "-f", path_str.as_str()]);
+
+    fs::remove_file(&path).context(loc!())?;
+
+    result
+}
+
+// ReplicaSets controlled by the Deployment, i.e., the ones of its current and previous
+// revisions, which a rollback copies the pod template from
+// Reference: https://kubernetes.io/docs/concepts/workloads/controllers/deployment/#rolling-back-a-deployment
+pub fn get_replica_sets(namespace: &str, deployment: &str) -> Result<Vec<serde_yaml::Value>> {
+    let replica_sets = get_yaml(&["get", "replicasets", "-n", subprocess::operand(namespace)?])?;
+
+    Ok(replica_sets["items"]
+        .as_sequence()
+        .into_iter()
+        .flatten()
+        .filter(|replica_set| {
+            replica_set["metadata"]["ownerReferences"]
+                .as_sequence()
+                .into_iter()
+                .flatten()
+                .any(|owner| {
+                    owner["kind"].as_str() == Some("Deployment")
+                        && owner["name"].as_str() == Some(deployment)
+                        && owner["controller"].as_bool() == Some(true)
+                })
+        })
+        .cloned()
+        .collect())
+}
+
+// Set the annotation of the pod template of the live ReplicaSet, which only changes the pods
+// it creates from then on
+pub fn annotate_replica_set(namespace: &str, name: &str, key: &str, value: &str) -> Result<()> {
+    let patch = serde_json::json!({
+        "spec": {"template": {"metadata": {"annotations": {key: value}}}}
+    });
+
+    kubectl(&[
+        "patch",
+        "replicaset",
+        subprocess::operand(name)?,
+        "-n",
+        subprocess::operand(namespace)?,
+        "--type",
+        "merge",
+        "-p",
+        &patch.to_string(),
+    ])?;
+
+    Ok(())
+}
+
+// Use the namespace of the object if specified, the one of the current context otherwise
+pub fn get_namespace(pod_yaml: &PodYaml) -> Result<String> {
+    if !pod_yaml.namespace.is_empty() {
+        return Ok(subprocess::operand(pod_yaml.namespace)?.to_string());
+    }
+
+    get_context_namespace()
+}
+
+pub fn get_context_namespace() -> Result<String> {
+    let namespace = kubectl(&["config", "view", "--minify", "-o", "jsonpath={..namespace}"])?;
+    let namespace = namespace.trim();
+
+    if namespace.is_empty() {
+        Ok(DEFAULT_NAMESPACE.to_string())
+    } else {
+        Ok(namespace.to_string())
+    }
+}
+
+// Whether the current context is allowed the verb on the resource (<resource>[.<group>]), in
+// the namespace unless the resource is cluster-scoped
+pub fn can_i(verb: &str, resource: &str, namespace: Option<&str>) -> Result<bool> {
+    let mut args = vec!["auth", "can-i", verb, resource];
+    if let Some(namespace) = namespace {
+        args.extend(["-n", subprocess::operand(namespace)?]);
+    }
+
+    // The exit code is 1 and the output is "no" when the action is denied
+    let output = subprocess::output(KUBECTL, &args)?;
+    if output.code == Some(1) && output.stdout_text()?.trim() == "no" {
+        return Ok(false);
+    }
+
+    output.check()?;
+
+    Ok(true)
+}
+
+fn get_label<'a>(labels: Option<&'a serde_yaml::Mapping>, key: &str) -> Option<&'a str> {
+    labels
+        .and_then(|labels| labels.get(key))
+        .and_then(|v| v.as_str())
+}
+
+// Evaluate a metav1.LabelSelector. A missing selector matches everything.
+// Reference: https://github.com/kubernetes/apimachinery/blob/release-1.26/pkg/apis/meta/v1/helpers.go#L34
+pub fn matches_selector(
+    selector: Option<&serde_yaml::Value>,
+    labels: Option<&serde_yaml::Mapping>,
+) -> Result<bool> {
+    let selector = match selector {
+        Some(selector) => selector,
+        None => return Ok(true),
+    };
+
+    if let Some(match_labels) = selector.get("matchLabels") {
+        let match_labels = match_labels
+            .as_mapping()
+            .ok_or_else(|| anyhow!("failed to parse matchLabels into mapping"))?;
+
+        for (key, value) in match_labels {
+            let key = key
+                .as_str()
+                .ok_or_else(|| anyhow!("failed to parse label key into str"))?;
+
+            if get_label(labels, key) != value.as_str() {
+                return Ok(false);
+            }
+        }
+    }
+
+    if let Some(expressions) = selector.get("matchExpressions") {
+        let expressions = expressions
+            .as_sequence()
+            .ok_or_else(|| anyhow!("failed to parse matchExpressions into sequence"))?;
+
+        for expression in expressions {
+            let key = expression["key"]
+                .as_str()
+                .ok_or_else(|| anyhow!("failed to parse key into str"))?;
+
+            let values: Vec<&str> = match expression["values"].as_sequence() {
+                Some(seq) => seq.iter().filter_map(|v| v.as_str()).collect(),
+                None => Vec::new(),
+            };
+
+            let label = get_label(labels, key);
+
+            let matched = match expression["operator"].as_str() {
+                Some("In") => matches!(label, Some(v) if values.contains(&v)),
+                Some("NotIn") => !matches!(label, Some(v) if values.contains(&v)),
+                Some("Exists") => label.is_some(),
+                Some("DoesNotExist") => label.is_none(),
+                operator => bail!("{}: unsupported selector operator: {:?}", loc!(), operator),
+            };
+
+            if !matched {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn contains_any(rule: &serde_yaml::Value, field: &str, targets: &[&str]) -> bool {
+    match rule[field].as_sequence() {
+        Some(seq) => seq
+            .iter()
+            .filter_map(|v| v.as_str())
+            .any(|v| targets.contains(&v)),
+        None => false,
+    }
+}
+
+// Whether any of the rules of the webhook intercepts the creation of pods
+fn intercepts_pod_creation(webhook: &serde_yaml::Value) -> bool {
+    match webhook["rules"].as_sequence() {
+        Some(rules) => rules.iter().any(|rule| {
+            contains_any(rule, "operations", &["CREATE", "*"])
+                && contains_any(rule, "resources", &["pods", "*", "*/*"])
+        }),
+        None => false,
+    }
+}
+
+// Return the names of the mutating webhooks that will be triggered when the pod is created
+pub fn get_matching_webhooks(pod_yaml: &PodYaml) -> Result<Vec<String>> {
+    let namespace = get_namespace(pod_yaml)?;
+    let namespace_yaml = get_yaml(&["get", "namespace", namespace.as_str()])?;
+    let namespace_labels = namespace_yaml["metadata"]["labels"].as_mapping();
+
+    let configurations = get_yaml(&["get", "mutatingwebhookconfigurations"])?;
+
+    let mut results = Vec::new();
+
+    if let Some(items) = configurations["items"].as_sequence() {
+        for item in items {
+            let webhooks = match item["webhooks"].as_sequence() {
+                Some(webhooks) => webhooks,
+                None => continue,
+            };
+
+            for webhook in webhooks {
+                if intercepts_pod_creation(webhook)
+                    && matches_selector(webhook.get("namespaceSelector"), namespace_labels)?
+                    && matches_selector(webhook.get("objectSelector"), pod_yaml.labels)?
+                {
+                    let name = webhook["name"]
+                        .as_str()
+                        .ok_or_else(|| anyhow!("failed to parse name into str"))?;
+
+                    results.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+// Return the auths of the docker config of the Secret, None if it is missing or of another type
+// Reference: https://kubernetes.io/docs/concepts/configuration/secret/#docker-config-secrets
+fn get_secret_auths(
+    namespace: &str,
+    name: &str,
+) -> Result<Option<serde_json::Map<String, serde_json::Value>>> {
+    let secret = get_yaml(&[
+        "get",
+        "secret",
+        subprocess::operand(name)?,
+        "-n",
+        subprocess::operand(namespace)?,
+        "--ignore-not-found",
+    ])?;
+
+    if secret.is_null() {
+        warn!("imagePullSecret {}/{} is not found", namespace, name);
+        return Ok(None);
+    }
+
+    // The legacy .dockercfg is the auths of .dockerconfigjson
+    let (key, legacy) = match secret["type"].as_str() {
+        Some("kubernetes.io/dockerconfigjson") => (".dockerconfigjson", false),
+        Some("kubernetes.io/dockercfg") => (".dockercfg", true),
+        r#type => {
+            warn!(
+                "imagePullSecret {}/{} is of type {}, not kubernetes.io/dockerconfigjson, and is ignored",
+                namespace,
+                name,
+                r#type.unwrap_or("Opaque")
+            );
+            return Ok(None);
+        }
+    };
+
+    let data = secret["data"][key]
+        .as_str()
+        .ok_or_else(|| anyhow!("imagePullSecret {}/{} has no {}", namespace, name, key))?;
+    let data = base64::decode(data.trim()).with_context(|| {
+        format!(
+            "failed to decode {} of imagePullSecret {}/{}",
+            key, namespace, name
+        )
+    })?;
+    let config: serde_json::Value = serde_json::from_slice(&data).with_context(|| {
+        format!(
+            "failed to parse {} of imagePullSecret {}/{}",
+            key, namespace, name
+        )
+    })?;
+
+    let auths = if legacy { &config } else { &config["auths"] };
+
+    Ok(auths.as_object().cloned())
+}
+
+// Return the authfile of skopeo merging the imagePullSecrets of the pod, where the first Secret
+// with the credentials of a registry takes precedence like the kubelet tries them in order.
+// None if the pod has no imagePullSecrets.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/credentialprovider/secrets/secrets.go
+pub fn get_registry_auth(pod_yaml: &PodYaml) -> Result<Option<String>> {
+    let names = pod_yaml.get_image_pull_secrets()?;
+    if names.is_empty() {
+        return Ok(None);
+    }
+
+    let namespace = get_namespace(pod_yaml)?;
+
+    let mut auths = serde_json::Map::new();
+    for name in &names {
+        for (registry, auth) in get_secret_auths(&namespace, name)?.into_iter().flatten() {
+            auths.entry(registry).or_insert(auth);
+        }
+    }
+
+    let mut config = serde_json::Map::new();
+    config.insert("auths".to_string(), auths.into());
+
+    Ok(Some(serde_json::to_string(&config)?))
+}
+
+// Return the regex of the mount source of the PersistentVolumeClaim, None if it is missing, not
+// bound yet, or bound to a PersistentVolume that is not on the node (e.g., of a CSI driver).
+// The hostPath PersistentVolumes are mounted from their path directly.
+pub fn get_claim_source(namespace: &str, claim: &str) -> Result<Option<String>> {
+    let claim_yaml = get_yaml(&[
+        "get",
+        "persistentvolumeclaim",
+        subprocess::operand(claim)?,
+        "-n",
+        subprocess::operand(namespace)?,
+        "--ignore-not-found",
+    ])?;
+
+    if claim_yaml.is_null() {
+        warn!("persistentVolumeClaim {}/{} is not found", namespace, claim);
+        return Ok(None);
+    }
+
+    let volume_name = match claim_yaml["spec"]["volumeName"].as_str() {
+        Some(volume_name) if !volume_name.is_empty() => volume_name,
+        _ => {
+            warn!(
+                "persistentVolumeClaim {}/{} is not bound yet, e.g., with volumeBindingMode: WaitForFirstConsumer, its mount source matches nothing",
+                namespace, claim
+            );
+            return Ok(None);
+        }
+    };
+
+    let volume = get_yaml(&["get", "persistentvolume", subprocess::operand(volume_name)?])?;
+
+    if volume["spec"]["local"]["path"].as_str().is_some() {
+        return Ok(Some(
+            [LOCAL_VOLUME_SOURCE_PREFIX, &regex::escape(volume_name), "$"].concat(),
+        ));
+    }
+
+    if let Some(path) = volume["spec"]["hostPath"]["path"].as_str() {
+        return Ok(Some(path.to_string()));
+    }
+
+    Ok(None)
+}
+
+// Return None for the services that do not get environment variables, i.e., the ones without
+// the cluster IP (headless and ExternalName services) or ports
+fn parse_service(service: &serde_yaml::Value) -> Result<Option<Service>> {
+    let name = service["metadata"]["name"]
+        .as_str()
+        .ok_or_else(|| anyhow!("failed to parse name into str"))?;
+
+    let namespace = service["metadata"]["namespace"]
+        .as_str()
+        .unwrap_or_default();
+
+    match service["spec"]["clusterIP"].as_str() {
+        None | Some("") | Some("None") => return Ok(None),
+        _ => {}
+    }
+
+    let mut ports = Vec::new();
+
+    if let Some(seq) = service["spec"]["ports"].as_sequence() {
+        for port in seq {
+            let number = port["port"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("failed to parse port into u64"))?;
+
+            ports.push(ServicePort {
+                name: port["name"].as_str().unwrap_or_default().to_string(),
+                port: u16::try_from(number)?,
+                protocol: port["protocol"].as_str().unwrap_or("TCP").to_uppercase(),
+            });
+        }
+    }
+
+    if ports.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Service {
+        name: name.to_string(),
+        namespace: namespace.to_string(),
+        ports,
+    }))
+}
+
+// Return the services of the namespace plus the master service, whose environment variables
+// are added to the containers in every namespace
+pub fn get_services(namespace: &str) -> Result<Vec<Service>> {
+    let mut results = Vec::new();
+
+    let services = get_yaml(&["get", "services", "-n", subprocess::operand(namespace)?])?;
+
+    if let Some(items) = services["items"].as_sequence() {
+        for item in items {
+            results.extend(parse_service(item)?);
+        }
+    }
+
+    if namespace != KUBERNETES_MASTER_SERVICE_NAMESPACE {
+        let master = get_yaml(&[
+            "get",
+            "service",
+            KUBERNETES_MASTER_SERVICE_NAME,
+            "-n",
+            KUBERNETES_MASTER_SERVICE_NAMESPACE,
+        ])?;
+
+        results.extend(parse_service(&master)?);
+    }
+
+    Ok(results)
+}
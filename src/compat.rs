@@ -0,0 +1,120 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+
+pub const KATA_AGENT_API: &str = "kata-agent-api";
+
+// Fields of the policy that not every version of the policy checks of the agent enforces
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Layers,
+    EncryptedLayers,
+    ArgsBase64,
+    ArgsMatch,
+    ImageConfig,
+    HostAliases,
+    Dns,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Field::Layers => "custom.layers",
+            Field::EncryptedLayers => "custom.encrypted_layers",
+            Field::ArgsBase64 => "custom.args_base64",
+            Field::ArgsMatch => "custom.args_match",
+            Field::ImageConfig => "custom.image_config",
+            Field::HostAliases => "metadata.host_aliases",
+            Field::Dns => "metadata.dns",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// Agent API version (major, minor) from which each field is enforced. Only the versions that
+// can be traced to the policy format an agent parses are listed, the fields missing from the
+// list are not enforced by any of them:
+// - 0.1 is the format of the first release of cc-policy, whose custom section only has layers
+//   Reference: src/policy.rs of the initial commit of this repository, struct Custom
+// No kata-agent release documents which of the later fields it enforces, hence no later
+// version is listed.
+const FIELDS: &[(Field, (u32, u32))] = &[(Field::Layers, (0, 1))];
+
+pub const AGENT_API_VERSIONS: &[&str] = &["0.1"];
+
+// Targeted agent API given by --target kata-agent-api=<version>. All the fields are emitted
+// without a target.
+#[derive(Clone, Copy)]
+pub struct AgentApi {
+    version: (u32, u32),
+}
+
+impl fmt::Display for AgentApi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}={}.{}",
+            KATA_AGENT_API, self.version.0, self.version.1
+        )
+    }
+}
+
+impl AgentApi {
+    pub fn parse(target: &str) -> Result<AgentApi> {
+        let (name, version) = target.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "invalid target {}, expecting {}=<version>",
+                target,
+                KATA_AGENT_API
+            )
+        })?;
+
+        if name != KATA_AGENT_API {
+            bail!(
+                "unknown target {}, the supported target is {}",
+                name,
+                KATA_AGENT_API
+            );
+        }
+
+        if !AGENT_API_VERSIONS.contains(&version) {
+            bail!(
+                "unknown {} version {}, the supported versions are: {}",
+                KATA_AGENT_API,
+                version,
+                AGENT_API_VERSIONS.join(", ")
+            );
+        }
+
+        // Checked above
+        let (major, minor) = version.split_once('.').unwrap();
+
+        Ok(AgentApi {
+            version: (major.parse()?, minor.parse()?),
+        })
+    }
+
+    pub fn supports(&self, field: Field) -> bool {
+        FIELDS
+            .iter()
+            .find(|(f, _)| *f == field)
+            .is_some_and(|(_, since)| self.version >= *since)
+    }
+
+    // Return whether the field is kept, warning about the rules that the target cannot enforce
+    pub fn keep(&self, field: Field, context: &str) -> bool {
+        if self.supports(field) {
+            return true;
+        }
+
+        warn!(
+            "{}: {} is not enforced by {}, omitting it",
+            context, field, self
+        );
+
+        false
+    }
+}
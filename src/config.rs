@@ -0,0 +1,139 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::cluster;
+use crate::node_pool::NodePool;
+use crate::subprocess;
+use crate::target::Target;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+// Settings loaded from config.toml. Options given on the command line take precedence.
+// Relative paths are resolved against the directory of the config file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub with_default_rules: bool,
+    pub sidecars: Vec<PathBuf>,
+    pub overlay: Option<PathBuf>,
+    pub key_provider: Option<String>,
+    pub node_pools: BTreeMap<String, NodePool>,
+    pub target: Target,
+    pub catalog: Option<PathBuf>,
+    pub plugins: Vec<PathBuf>,
+    pub oci_layouts: Vec<PathBuf>,
+    // Maximum number of loose rules of a policy, e.g., env wildcards
+    pub max_loose_rules: Option<usize>,
+    // Overlay of the ConfigMap of the cluster, see merge_cluster_config
+    #[serde(skip)]
+    pub cluster_overlay: Option<String>,
+}
+
+// Organization defaults stored in the cluster by the platform teams, read from the ConfigMap
+// cc-policy-config of the namespace: config.toml holds the settings below, and overlay.yaml
+// the overlay. The settings referring to local files (e.g., the plugins, which would then run
+// code given by the cluster) are not allowed.
+pub const CLUSTER_CONFIG_MAP: &str = "cc-policy-config";
+const CLUSTER_CONFIG_KEY: &str = "config.toml";
+const CLUSTER_OVERLAY_KEY: &str = "overlay.yaml";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ClusterConfig {
+    with_default_rules: bool,
+    key_provider: Option<String>,
+    node_pools: BTreeMap<String, NodePool>,
+    target: Target,
+    max_loose_rules: Option<usize>,
+    // Rejected rather than unknown, see merge_cluster_config
+    annotation_key: Option<String>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let content = read_to_string(path).context(loc!())?;
+        let mut config: Config = toml::from_str(&content)
+            .with_context(|| format!("failed to parse config {}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        config.sidecars = config.sidecars.iter().map(|p| base.join(p)).collect();
+        config.overlay = config.overlay.map(|p| base.join(p));
+        config.catalog = config.catalog.map(|p| base.join(p));
+        config.plugins = config.plugins.iter().map(|p| base.join(p)).collect();
+        config.oci_layouts = config.oci_layouts.iter().map(|p| base.join(p)).collect();
+
+        Ok(config)
+    }
+
+    // Merge the defaults of the ConfigMap of the namespace if any, the local config taking
+    // precedence, i.e., its node pools, key provider, target rules, loose rule budget, and overlay
+    // are kept
+    pub fn merge_cluster_config(&mut self, namespace: &str) -> Result<bool> {
+        let config_map = cluster::get_yaml(&[
+            "get",
+            "configmap",
+            CLUSTER_CONFIG_MAP,
+            "-n",
+            subprocess::operand(namespace)?,
+            "--ignore-not-found",
+        ])?;
+        if config_map.is_null() {
+            return Ok(false);
+        }
+
+        let data = &config_map["data"];
+        let source = format!("ConfigMap {}/{}", namespace, CLUSTER_CONFIG_MAP);
+
+        let cluster_config: ClusterConfig = match data.get(CLUSTER_CONFIG_KEY) {
+            Some(content) => {
+                let content = content.as_str().ok_or_else(|| {
+                    anyhow!("{} of {} must be a string", CLUSTER_CONFIG_KEY, source)
+                })?;
+                toml::from_str(content).with_context(|| {
+                    format!("failed to parse {} of {}", CLUSTER_CONFIG_KEY, source)
+                })?
+            }
+            None => ClusterConfig::default(),
+        };
+
+        // The annotation key is the same for every namespace of a run, the documents of other
+        // namespaces would otherwise be annotated with another key than the one checked by
+        // their nodes
+        if cluster_config.annotation_key.is_some() {
+            bail!(
+                "annotation_key of {} is not supported, use --annotation-key",
+                source
+            );
+        }
+
+        self.with_default_rules |= cluster_config.with_default_rules;
+        if self.key_provider.is_none() {
+            self.key_provider = cluster_config.key_provider;
+        }
+        for (name, node_pool) in cluster_config.node_pools {
+            self.node_pools.entry(name).or_insert(node_pool);
+        }
+        if self.target.is_empty() {
+            self.target = cluster_config.target;
+        }
+        if self.max_loose_rules.is_none() {
+            self.max_loose_rules = cluster_config.max_loose_rules;
+        }
+
+        if let Some(overlay) = data.get(CLUSTER_OVERLAY_KEY) {
+            if self.overlay.is_none() {
+                let overlay = overlay.as_str().ok_or_else(|| {
+                    anyhow!("{} of {} must be a string", CLUSTER_OVERLAY_KEY, source)
+                })?;
+                self.cluster_overlay = Some(overlay.to_string());
+            }
+        }
+
+        Ok(true)
+    }
+}
@@ -1,10 +1,13 @@
 // Copyright (c) Cc-Policy Authors.
 // Licensed under the Apache 2.0 license.
 
-use anyhow::{anyhow, bail, Result};
+use crate::guest::GuestLayout;
+use crate::oci::{bind_mount, empty_spec, normalize_destination};
+use crate::pattern;
+
+use anyhow::{anyhow, bail, Context, Result};
 use oci_spec::image::ImageConfiguration;
 use oci_spec::runtime::{Mount, Process, Spec};
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
@@ -58,7 +61,6 @@ const DEFAULT_MOUNTS: &str = r#"
     },
     {
         "destination": "/dev/shm",
-        "source": "^/run/kata-containers/sandbox/shm$",
         "type": "bind",
         "options": [
             "rbind"
@@ -77,16 +79,36 @@ const DEFAULT_MOUNTS: &str = r#"
     }
 ]"#;
 
-fn get_container_rules(privileged: bool, tty: bool) -> Result<Spec> {
+// ociVersion of the default rules, overridden by --oci-version or the node pool
+pub const DEFAULT_OCI_VERSION: &str = "1.0.2-dev";
+
+// The default env rules are anchored regexes like the ones of the image config, including the
+// exact values, so that a rule has the same form regardless of the layer it comes from
+// Reference: https://github.com/containerd/containerd/blob/release/1.6/oci/spec.go#L33
+pub const DEFAULT_PATH_ENV: &str =
+    "^PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin$";
+pub const DEFAULT_HOSTNAME_ENV: &str = "^HOSTNAME=.+$";
+const DEFAULT_TERM_ENV: &str = "^TERM=xterm$";
+
+fn get_default_mounts(layout: &GuestLayout) -> Result<Vec<Mount>> {
+    let mut mounts: Vec<Mount> = serde_json::from_str(DEFAULT_MOUNTS)?;
+
+    for mount in &mut mounts {
+        if mount.destination() == Path::new("/dev/shm") {
+            mount.set_source(Some(PathBuf::from(&layout.sandbox_shm)));
+        }
+    }
+
+    Ok(mounts)
+}
+
+fn get_container_rules(privileged: bool, tty: bool, layout: &GuestLayout) -> Result<Spec> {
     // Default version is based on specs-go
     // Reference:
     // https://github.com/containerd/containerd/blob/release/1.6/oci/spec.go#L139
     // https://github.com/opencontainers/runtime-spec/blob/main/specs-go/version.go#L18
-    let mut spec: Spec = serde_json::from_str(
-        r#"{
-        "ociVersion": "1.0.2-dev"
-    }"#,
-    )?;
+    let mut spec = empty_spec()?;
+    spec.set_version(DEFAULT_OCI_VERSION.to_string());
 
     // Default values are based on populateDefaultUnixSpec
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/oci/spec.go#L143
@@ -104,16 +126,17 @@ fn get_container_rules(privileged: bool, tty: bool) -> Result<Spec> {
 
     // Add HOSTNAME env
     // Reference: https://github.com/containerd/containerd/blob/main/pkg/cri/server/container_create_linux.go#L161
-    env.push("^HOSTNAME=.+".to_string());
+    env.push(DEFAULT_HOSTNAME_ENV.to_string());
 
-    // Add PATH env
+    // Add PATH env, the default PATH of populateDefaultUnixSpec like the sandbox, which the
+    // Env of the image then overrides
     // Reference: https://github.com/containerd/containerd/blob/main/pkg/cri/server/container_create_linux.go#L141
-    env.push("^PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin$".to_string());
+    env.push(DEFAULT_PATH_ENV.to_string());
 
     // Add TERM based on tty
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L151
     if tty {
-        env.push("TERM=xterm".to_string());
+        env.push(DEFAULT_TERM_ENV.to_string());
     }
 
     process.set_env(Some(env));
@@ -124,7 +147,7 @@ fn get_container_rules(privileged: bool, tty: bool) -> Result<Spec> {
 
     // Add default mounts
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/oci/mounts.go#L26
-    let default_mounts: Vec<Mount> = serde_json::from_str(DEFAULT_MOUNTS)?;
+    let default_mounts = get_default_mounts(layout)?;
 
     mounts.extend(default_mounts);
 
@@ -149,44 +172,27 @@ fn get_container_rules(privileged: bool, tty: bool) -> Result<Spec> {
 
     // Add /etc/hostname, /etc/hosts, and /etc/resolv.conf
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L60
-    // TODO: Add "rw" or "ro" based on securityContext.readOnlyRootFilesystem
+    // TODO: Add "rw" or "ro" to /etc/hostname and /etc/hosts based on securityContext.readOnlyRootFilesystem,
+    // which is applied to /etc/resolv.conf by the caller
     // Note that the function also adds /dev/shm, which is ignored given that the default rules already include it
-    let container_mounts: Vec<Mount> = serde_json::from_str(
-        r#"
-    [
-        {
-            "destination": "/etc/hostname",
-            "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-hostname$",
-            "type": "bind",
-            "options": [
-                "rbind",
-                "rprivate",
-                "rw"
-            ]
-        },
-        {
-            "destination": "/etc/hosts",
-            "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-hosts$",
-            "type": "bind",
-            "options": [
-                "rbind",
-                "rprivate",
-                "rw"
-            ]
-        },
-        {
-            "destination": "/etc/resolv.conf",
-            "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-resolv.conf$",
-            "type": "bind",
-            "options": [
-                "rbind",
-                "rprivate",
-                "rw"
-            ]
-        }
-    ]
-    "#,
-    )?;
+    let options = ["rbind", "rprivate", "rw"];
+    let container_mounts = vec![
+        bind_mount(
+            "/etc/hostname",
+            &layout.get_shared_source(&layout.hostname),
+            &options,
+        ),
+        bind_mount(
+            "/etc/hosts",
+            &layout.get_shared_source(&layout.hosts),
+            &options,
+        ),
+        bind_mount(
+            "/etc/resolv.conf",
+            &layout.get_shared_source(&layout.resolv_conf),
+            &options,
+        ),
+    ];
 
     mounts.extend(container_mounts);
 
@@ -235,16 +241,13 @@ fn get_container_rules(privileged: bool, tty: bool) -> Result<Spec> {
     Ok(spec)
 }
 
-fn get_sandbox_rules(privileged: bool, tty: bool) -> Result<Spec> {
+fn get_sandbox_rules(privileged: bool, tty: bool, layout: &GuestLayout) -> Result<Spec> {
     // Default version is based on specs-go
     // Reference:
     // https://github.com/containerd/containerd/blob/release/1.6/oci/spec.go#L139
     // https://github.com/opencontainers/runtime-spec/blob/main/specs-go/version.go#L18
-    let mut spec: Spec = serde_json::from_str(
-        r#"{
-        "ociVersion": "1.0.2-dev"
-    }"#,
-    )?;
+    let mut spec = empty_spec()?;
+    spec.set_version(DEFAULT_OCI_VERSION.to_string());
 
     // Default values are based on populateDefaultUnixSpec
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/oci/spec.go#L143
@@ -258,13 +261,17 @@ fn get_sandbox_rules(privileged: bool, tty: bool) -> Result<Spec> {
     }"#,
     )?;
 
-    let mut env = Vec::new();
+    // The sandbox has the default PATH of populateDefaultUnixSpec, which the Env of the pause
+    // image then overrides. Unlike the containers, it has no HOSTNAME env given that the
+    // hostname of the pod is only set as the hostname of the spec.
+    // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/sandbox_run_linux.go#L48
+    let mut env = vec![DEFAULT_PATH_ENV.to_string()];
 
     // TODO: Double check if the there is a way to set tty for the sandbox container
     // Add TERM based on tty
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L151
     if tty {
-        env.push("TERM=xterm".to_string());
+        env.push(DEFAULT_TERM_ENV.to_string());
     }
 
     process.set_env(Some(env));
@@ -274,24 +281,16 @@ fn get_sandbox_rules(privileged: bool, tty: bool) -> Result<Spec> {
     let mut mounts: Vec<Mount> = Vec::new();
 
     // Add default mounts
-    let default_mounts: Vec<Mount> = serde_json::from_str(DEFAULT_MOUNTS)?;
+    let default_mounts = get_default_mounts(layout)?;
 
     mounts.extend(default_mounts);
 
     // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/sandbox_run_linux.go#L111
-    mounts.push(serde_json::from_str(
-        r#"
-    {
-        "destination": "/etc/resolv.conf",
-        "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-resolv.conf$",
-        "type": "bind",
-        "options": [
-            "rbind",
-            "ro"
-        ]
-    }
-    "#,
-    )?);
+    mounts.push(bind_mount(
+        "/etc/resolv.conf",
+        &layout.get_shared_source(&layout.resolv_conf),
+        &["rbind", "ro"],
+    ));
 
     // TODO: Double check if the there is a way to set privileged for the sandbox container
     if privileged {
@@ -326,16 +325,76 @@ fn get_sandbox_rules(privileged: bool, tty: bool) -> Result<Spec> {
     Ok(spec)
 }
 
-pub fn get_rules(is_sandbox: bool, privileged: bool, tty: bool) -> Result<Spec> {
+pub fn get_rules(
+    is_sandbox: bool,
+    privileged: bool,
+    tty: bool,
+    layout: &GuestLayout,
+) -> Result<Spec> {
     if !is_sandbox {
-        get_container_rules(privileged, tty)
+        get_container_rules(privileged, tty, layout)
     } else {
-        get_sandbox_rules(privileged, tty)
+        get_sandbox_rules(privileged, tty, layout)
+    }
+}
+
+// Shells of the shell form of ENTRYPOINT and CMD, i.e., the default SHELL of the Dockerfile and
+// the common overrides
+// Reference: https://docs.docker.com/engine/reference/builder/#shell
+const SHELLS: &[&str] = &["/bin/sh", "/bin/bash", "/bin/ash", "sh", "bash"];
+
+fn is_shell_form(argv: &[String]) -> bool {
+    argv.len() == 3 && SHELLS.contains(&argv[0].as_str()) && argv[1] == "-c"
+}
+
+// The shell form of ENTRYPOINT and CMD is stored in the image config as the argv of the shell,
+// e.g., ["/bin/sh", "-c", "nginx -g 'daemon off;'"], which is what the runtime executes and
+// the policy matches. Warn that the commands run by the script are not enforced.
+pub fn check_shell_form(
+    name: &str,
+    container_command: &[String],
+    container_args: &[String],
+    image_config: &ImageConfiguration,
+) {
+    let config = match image_config.config() {
+        Some(config) => config,
+        None => return,
+    };
+
+    // The entry point of the image is not used
+    if !container_command.is_empty() {
+        return;
+    }
+
+    let entrypoint = config.entrypoint().clone().unwrap_or_default();
+    let cmd = config.cmd().clone().unwrap_or_default();
+
+    if is_shell_form(&entrypoint) {
+        warn!(
+            "{}: the ENTRYPOINT of the image is in shell form, the policy matches the script {:?} run by {} but not the commands it runs",
+            name, entrypoint[2], entrypoint[0]
+        );
+
+        if !container_args.is_empty() || !cmd.is_empty() {
+            warn!(
+                "{}: the args are passed to the shell form of ENTRYPOINT as $0 and the positional parameters, which the script may ignore",
+                name
+            );
+        }
+    } else if (entrypoint.is_empty() || entrypoint == [""])
+        && container_args.is_empty()
+        && is_shell_form(&cmd)
+    {
+        warn!(
+            "{}: the CMD of the image is in shell form, the policy matches the script {:?} run by {} but not the commands it runs",
+            name, cmd[2], cmd[0]
+        );
     }
 }
 
 // Based on the logic of WithProcessArgs
 // https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/opts/spec.go#L55
+// The result is the command followed by the args, each of them keeping the original order
 pub fn merge_process_args(
     container_command: &[String],
     container_args: &[String],
@@ -404,78 +463,112 @@ pub fn merge_process_cwd(
     }
 }
 
-// The following logic is based on replaceOrAppendEnvValues
-// https://github.com/containerd/containerd/blob/release/1.6/oci/spec_opts.go#L178
-pub fn merge_process_env(defaults: &mut Vec<String>, overrides: &[String]) -> Result<()> {
-    let mut cache = HashMap::new();
+// Return the name of the variable of an env rule, i.e., without the leading ^ and the escapes
+// of regex rules so that the exact rules (e.g., PATH=/bin of the pod yaml) replace the regex
+// rules of the same variable (e.g., ^PATH=/usr/bin$ of the image config), and whether the rule
+// has a value. The value is everything after the first '=', which may contain more '='.
+pub fn split_env(rule: &str) -> Result<(String, bool)> {
+    let (name, has_value) = match rule.split_once('=') {
+        Some((name, _)) => (name, true),
+        None => (rule, false),
+    };
 
-    for (index, env) in defaults.iter_mut().enumerate() {
-        let eqpos = env.find('=').unwrap();
-        let (name, _) = env.split_at(eqpos);
+    let name = if pattern::is_regex(name) {
+        name[1..].replace('\\', "")
+    } else {
+        name.to_string()
+    };
 
-        *cache.entry(name.to_string()).or_insert_with(|| 0) = index;
+    if name.is_empty() {
+        bail!("invalid env rule {}, expecting <name>=<value>", rule);
     }
 
-    let mut removes = Vec::new();
-
-    for env in overrides.iter() {
-        if let Some(eqpos) = env.find('=') {
-            let (name, _) = env.split_at(eqpos);
+    Ok((name, has_value))
+}
 
-            if let Entry::Occupied(entry) = cache.entry(name.to_string()) {
-                let index = entry.get();
-                defaults[index.to_owned()] = env.to_string();
-            } else {
-                defaults.push(env.to_string())
+// The kubelet collects the env of the container into a map, so that a later entry replaces an
+// earlier one of the same name, which is then kept at the position of the first one. Return
+// the env and the names given more than once.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L662
+pub fn dedup_env(env: &[String]) -> Result<(Vec<String>, Vec<String>)> {
+    let mut indexes = HashMap::new();
+    let mut results: Vec<String> = Vec::new();
+    let mut duplicates = Vec::new();
+
+    for rule in env {
+        let (name, _) = split_env(rule)?;
+
+        match indexes.get(&name) {
+            Some(index) => {
+                results[*index] = rule.to_string();
+                duplicates.push(name);
+            }
+            None => {
+                indexes.insert(name, results.len());
+                results.push(rule.to_string());
             }
-        } else {
-            // Values with out '=' indicates the env should be removed
-            removes.push(env.to_string());
         }
     }
 
-    // TODO: Check the case of removing two variables with the same key in a list
-    for remove in removes {
-        if let Entry::Occupied(entry) = cache.entry(remove) {
-            let index = entry.get();
-            defaults.remove(index.to_owned());
+    Ok((results, duplicates))
+}
+
+// The following logic is based on replaceOrAppendEnvValues, i.e., the overrides are applied
+// in order, and an override without '=' unsets the variable of the defaults. Defaults without
+// '=' are keyed by the whole entry like containerd does.
+// https://github.com/containerd/containerd/blob/release/1.6/oci/spec_opts.go#L178
+pub fn merge_process_env(defaults: &mut Vec<String>, overrides: &[String]) -> Result<()> {
+    let mut cache = HashMap::new();
+    // The entries that are unset are None until the end
+    let mut results: Vec<Option<String>> = Vec::new();
+
+    for (index, env) in defaults.iter().enumerate() {
+        let (name, _) = split_env(env)?;
+
+        cache.insert(name, index);
+        results.push(Some(env.to_string()));
+    }
+
+    for env in overrides {
+        let (name, has_value) = split_env(env)?;
+
+        match cache.get(&name) {
+            Some(index) if has_value => results[*index] = Some(env.to_string()),
+            Some(index) => results[*index] = None,
+            None if has_value => results.push(Some(env.to_string())),
+            None => (),
         }
     }
 
+    *defaults = results.into_iter().flatten().collect();
+
     Ok(())
 }
 
-pub fn get_image_volume_mounts(image_config: &ImageConfiguration) -> Result<Vec<Mount>> {
+pub fn get_image_volume_mounts(
+    image_config: &ImageConfiguration,
+    layout: &GuestLayout,
+) -> Result<Vec<Mount>> {
     let mut mounts = Vec::new();
 
     if let Some(config) = image_config.config() {
         if let Some(volumes) = config.volumes() {
-            volumes.iter().for_each(|volume| {
-                let path = Path::new(volume);
-                let file_name = path.file_name().unwrap();
-                let file_name = file_name.to_str().unwrap();
-
-                let mut mount = Mount::default();
-
-                mount.set_destination(PathBuf::from(volume.to_string()));
-                mount.set_source(Some(PathBuf::from(
-                    [
-                        "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-",
-                        file_name,
-                        "$",
-                    ]
-                    .concat(),
-                )));
-                mount.set_typ(Some(String::from("bind")));
-                mount.set_options(Some(
-                    vec!["rbind", "rprivate", "rw"]
-                        .into_iter()
-                        .map(String::from)
-                        .collect(),
-                ));
+            for volume in volumes {
+                let path = normalize_destination(Path::new(volume))
+                    .with_context(|| format!("invalid volume of the image config {}", volume))?;
+                let file_name = path
+                    .file_name()
+                    .and_then(|file_name| file_name.to_str())
+                    .ok_or_else(|| anyhow!("invalid volume of the image config {}", volume))?;
+
+                let mount = bind_mount(
+                    &path.to_string_lossy(),
+                    &layout.get_shared_source(file_name),
+                    &["rbind", "rprivate", "rw"],
+                );
 
                 mounts.push(mount);
-            });
+            }
         }
     }
 
@@ -485,25 +578,96 @@ pub fn get_image_volume_mounts(image_config: &ImageConfiguration) -> Result<Vec<
 // The following logic is based on
 // https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/opts/spec_linux.go#L95
 pub fn merge_mounts(mounts: &[Mount], extras: &[Mount]) -> Result<Vec<Mount>> {
-    let mut results = HashMap::new();
+    let mut results: Vec<Mount> = Vec::new();
 
     // Rule:
     // - mounts takes precedence over extras if two mount points share the same destination
     // - mount point comes later in the list takes precedence previous ones that share the same
     //   destination
+    // - the order is deterministic: extras first, then the mounts with new destinations
+    // - destinations are compared in their clean form, e.g., /data/ is the same as /data
     // TODO: Check how CRI handles mount points with the same destination in the same list
+    for mount in extras.iter().chain(mounts.iter()) {
+        let mut mount = mount.clone();
+        mount.set_destination(normalize_destination(mount.destination())?);
+
+        match results
+            .iter_mut()
+            .find(|result| result.destination() == mount.destination())
+        {
+            Some(result) => *result = mount,
+            None => results.push(mount),
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(defaults: &[&str], overrides: &[&str]) -> Vec<String> {
+        let mut defaults: Vec<String> = defaults.iter().map(|env| env.to_string()).collect();
+        let overrides: Vec<String> = overrides.iter().map(|env| env.to_string()).collect();
+
+        merge_process_env(&mut defaults, &overrides).unwrap();
 
-    extras.iter().for_each(|mount| {
-        *results
-            .entry(mount.destination().as_os_str())
-            .or_insert_with(|| Mount::default()) = mount.clone();
-    });
+        defaults
+    }
+
+    #[test]
+    fn test_split_env() {
+        assert_eq!(split_env("A=b=c").unwrap(), ("A".to_string(), true));
+        assert_eq!(split_env("A=").unwrap(), ("A".to_string(), true));
+        assert_eq!(split_env("A").unwrap(), ("A".to_string(), false));
+        assert_eq!(
+            split_env("^PATH=/usr/bin$").unwrap(),
+            ("PATH".to_string(), true)
+        );
+        assert_eq!(
+            split_env(&pattern::literal("JAVA.OPTS=-Xmx1g")).unwrap(),
+            ("JAVA.OPTS".to_string(), true)
+        );
+        assert!(split_env("=a").is_err());
+        assert!(split_env("^=a$").is_err());
+    }
+
+    #[test]
+    fn test_merge_process_env() {
+        // Replaced in place, the regex rules of the image config by name
+        assert_eq!(
+            merge(&["^PATH=/usr/bin$", "^HOME=/root$"], &["PATH=/bin"]),
+            ["PATH=/bin", "^HOME=/root$"]
+        );
+
+        // Appended, including the names given more than once, which containerd does not
+        // cache
+        assert_eq!(merge(&["A=1"], &["B=1", "B=2"]), ["A=1", "B=1", "B=2"]);
+
+        // The value may contain '='
+        assert_eq!(merge(&["A=1"], &["A=b=c"]), ["A=b=c"]);
+    }
+
+    #[test]
+    fn test_merge_process_env_unset() {
+        // An override without '=' unsets the variable
+        assert_eq!(merge(&["A=1", "B=1"], &["A"]), ["B=1"]);
+        assert_eq!(merge(&["^A=1$", "B=1"], &["A"]), ["B=1"]);
+
+        // Unknown variables are ignored, and never appended
+        assert_eq!(merge(&["A=1"], &["B"]), ["A=1"]);
 
-    mounts.iter().for_each(|mount| {
-        *results
-            .entry(mount.destination().as_os_str())
-            .or_insert_with(|| Mount::default()) = mount.clone();
-    });
+        // Set again at the position of the default
+        assert_eq!(merge(&["A=1", "B=1"], &["A", "A=2"]), ["A=2", "B=1"]);
 
-    Ok(results.values().cloned().collect())
+        // Set after the unset only, as the overrides are applied in order
+        assert_eq!(merge(&["A=1", "B=1"], &["A=2", "A"]), ["B=1"]);
+
+        // The appended overrides are not cached, hence not unset
+        assert_eq!(merge(&[], &["A=1", "A"]), ["A=1"]);
+
+        // Every default of the name is kept but the last one, which is the cached one
+        assert_eq!(merge(&["A=1", "A=2"], &["A"]), ["A=1"]);
+    }
 }
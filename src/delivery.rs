@@ -0,0 +1,130 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::image;
+use crate::registry;
+
+use anyhow::{bail, Result};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+// Annotations are limited to 256 KiB per object, and ConfigMaps to 1 MiB
+// Reference: https://kubernetes.io/docs/concepts/configuration/configmap/#motivation
+const CONFIG_MAP_MAX_SIZE: usize = 1024 * 1024;
+const CONFIG_MAP_KEY: &str = "policy.json";
+const CONFIG_MAP_SCHEME: &str = "configmap://";
+const OCI_SCHEME: &str = "oci://";
+
+// How the policy is given to the pod, set by --delivery
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    // base64 in the io.katacontainers.cc_policy annotation
+    Inline,
+    // In a ConfigMap in the namespace of the pod
+    Configmap,
+    // As an OCI artifact in a repository
+    Oci,
+}
+
+// Value of the io.katacontainers.cc_policy_ref annotation, e.g.,
+// {"uri":"configmap://cc-policy-pod-app/policy.json","digest":"sha256:..."}. The digest is the
+// one of the policy document, for a consumer to check before enforcing it. Neither the agent
+// nor the runtime reads the annotation yet, i.e., the policy is not enforced.
+#[derive(Serialize, Deserialize)]
+pub struct PolicyReference {
+    pub uri: String,
+    pub digest: String,
+}
+
+impl PolicyReference {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+fn get_config_map_name(kind: &str, name: &str) -> String {
+    format!("cc-policy-{}-{}", kind.to_lowercase(), name)
+}
+
+// Return the ConfigMap holding the policy of the workload and the reference to it, which is
+// relative to the namespace of the pod
+pub fn to_config_map(
+    kind: &str,
+    name: &str,
+    namespace: &str,
+    policy: &str,
+) -> Result<(serde_yaml::Value, PolicyReference)> {
+    if policy.len() > CONFIG_MAP_MAX_SIZE {
+        bail!(
+            "the policy of {} {} is {} bytes, more than the {} bytes of a ConfigMap",
+            kind,
+            name,
+            policy.len(),
+            CONFIG_MAP_MAX_SIZE
+        );
+    }
+
+    let config_map_name = get_config_map_name(kind, name);
+
+    let mut metadata = serde_yaml::Mapping::new();
+    metadata.insert("name".into(), config_map_name.clone().into());
+    if !namespace.is_empty() {
+        metadata.insert("namespace".into(), namespace.into());
+    }
+
+    let mut data = serde_yaml::Mapping::new();
+    data.insert(CONFIG_MAP_KEY.into(), policy.into());
+
+    let mut config_map = serde_yaml::Mapping::new();
+    config_map.insert("apiVersion".into(), "v1".into());
+    config_map.insert("kind".into(), "ConfigMap".into());
+    config_map.insert("metadata".into(), metadata.into());
+    config_map.insert("data".into(), data.into());
+
+    let reference = PolicyReference {
+        uri: [CONFIG_MAP_SCHEME, &config_map_name, "/", CONFIG_MAP_KEY].concat(),
+        digest: image::get_sha256(policy.as_bytes()),
+    };
+
+    Ok((config_map.into(), reference))
+}
+
+fn get_tag(digest: &str) -> String {
+    digest.replace(':', "-")
+}
+
+// Return the reference to the artifact of the policy by tag, before it is pushed
+pub fn to_artifact_reference(repository: &str, policy: &str) -> PolicyReference {
+    let digest = image::get_sha256(policy.as_bytes());
+
+    PolicyReference {
+        uri: [OCI_SCHEME, repository, ":", &get_tag(&digest)].concat(),
+        digest,
+    }
+}
+
+// Push the policy to the repository, tagged by its digest, and return the reference to the
+// artifact by manifest digest
+pub fn push(repository: &str, policy: &str) -> Result<PolicyReference> {
+    let digest = image::get_sha256(policy.as_bytes());
+
+    let manifest_digest =
+        registry::push_artifact(&[repository, ":", &get_tag(&digest)].concat(), policy)?;
+
+    Ok(PolicyReference {
+        uri: [OCI_SCHEME, repository, "@", &manifest_digest].concat(),
+        digest,
+    })
+}
+
+// Whether the values of two cc_policy_ref annotations refer to the same policy, e.g., pushed
+// again under another manifest digest
+pub fn is_same_policy(reference: &str, other: &str) -> bool {
+    match (
+        serde_json::from_str::<PolicyReference>(reference),
+        serde_json::from_str::<PolicyReference>(other),
+    ) {
+        (Ok(reference), Ok(other)) => reference.digest == other.digest,
+        _ => reference == other,
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::render::*;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct Report<'a> {
+    identical: bool,
+    containers: &'a [ContainerSummary],
+    diff: String,
+}
+
+fn get_strings(value: &Value) -> BTreeSet<String> {
+    match value.as_array() {
+        Some(array) => array
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(String::from)
+            .collect(),
+        None => BTreeSet::new(),
+    }
+}
+
+fn get_mounts(container: &Value) -> Vec<(String, &Value)> {
+    match container["oci_spec"]["mounts"].as_array() {
+        Some(mounts) => mounts
+            .iter()
+            .map(|mount| {
+                let destination = mount["destination"].as_str().unwrap_or_default();
+                (destination.to_string(), mount)
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn summarize_container(name: &str, old: &Value, new: &Value) -> ContainerSummary {
+    let old_env = get_strings(&old["oci_spec"]["process"]["env"]);
+    let new_env = get_strings(&new["oci_spec"]["process"]["env"]);
+
+    let old_mounts = get_mounts(old);
+    let new_mounts = get_mounts(new);
+
+    let find = |mounts: &[(String, &Value)], destination: &str| {
+        mounts
+            .iter()
+            .find(|(d, _)| d == destination)
+            .map(|(_, m)| *m)
+    };
+
+    let mut mounts_added = Vec::new();
+    let mut mounts_changed = Vec::new();
+
+    for (destination, mount) in &new_mounts {
+        match find(&old_mounts, destination) {
+            Some(old_mount) if old_mount != *mount => mounts_changed.push(destination.clone()),
+            Some(_) => {}
+            None => mounts_added.push(destination.clone()),
+        }
+    }
+
+    let mounts_removed = old_mounts
+        .iter()
+        .filter(|(destination, _)| find(&new_mounts, destination).is_none())
+        .map(|(destination, _)| destination.clone())
+        .collect();
+
+    let args_changed = old["oci_spec"]["process"]["args"] != new["oci_spec"]["process"]["args"];
+
+    let status = if old == new {
+        Status::Unchanged
+    } else {
+        Status::Changed
+    };
+
+    ContainerSummary {
+        name: name.to_string(),
+        status,
+        env_added: new_env.difference(&old_env).cloned().collect(),
+        env_removed: old_env.difference(&new_env).cloned().collect(),
+        mounts_added,
+        mounts_removed,
+        mounts_changed,
+        args_changed,
+    }
+}
+
+fn added_or_removed(name: &str, status: Status) -> ContainerSummary {
+    ContainerSummary {
+        name: name.to_string(),
+        status,
+        env_added: Vec::new(),
+        env_removed: Vec::new(),
+        mounts_added: Vec::new(),
+        mounts_removed: Vec::new(),
+        mounts_changed: Vec::new(),
+        args_changed: false,
+    }
+}
+
+// Summarize the differences of every container between two policies
+pub fn summarize(old: &Value, new: &Value) -> Vec<ContainerSummary> {
+    let old_containers = old["containers"].as_object();
+    let new_containers = new["containers"].as_object();
+
+    let mut names = BTreeSet::new();
+    names.extend(old_containers.iter().flat_map(|c| c.keys()));
+    names.extend(new_containers.iter().flat_map(|c| c.keys()));
+
+    names
+        .into_iter()
+        .map(|name| {
+            let old_container = old_containers.and_then(|c| c.get(name));
+            let new_container = new_containers.and_then(|c| c.get(name));
+
+            match (old_container, new_container) {
+                (Some(old), Some(new)) => summarize_container(name, old, new),
+                (None, _) => added_or_removed(name, Status::Added),
+                (_, None) => added_or_removed(name, Status::Removed),
+            }
+        })
+        .collect()
+}
+
+// Render the differences between two policies given in JSON
+pub fn render(
+    old_name: &str,
+    new_name: &str,
+    old: &Value,
+    new: &Value,
+    no_color: bool,
+    output: &OutputFormat,
+) -> Result<String> {
+    let summaries = summarize(old, new);
+
+    let old_text = serde_json::to_string_pretty(old)?;
+    let new_text = serde_json::to_string_pretty(new)?;
+
+    match output {
+        OutputFormat::Json => {
+            let renderer = Renderer::new(true);
+            let report = Report {
+                identical: old == new,
+                containers: &summaries,
+                diff: renderer.unified_diff(old_name, new_name, &old_text, &new_text),
+            };
+
+            Ok(serde_json::to_string_pretty(&report)?)
+        }
+        OutputFormat::Text => {
+            let renderer = Renderer::new(no_color);
+
+            Ok([
+                renderer.unified_diff(old_name, new_name, &old_text, &new_text),
+                renderer.container_summaries(&summaries),
+            ]
+            .join("\n"))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WorkloadReport<'a> {
+    workload: &'a str,
+    status: Status,
+    containers: Vec<ContainerSummary>,
+}
+
+// Render the differences of the rules between the policies of the workloads of two manifests,
+// keyed by <kind>/<name>. Unchanged workloads and containers are omitted.
+pub fn render_workloads(
+    old: &BTreeMap<String, Value>,
+    new: &BTreeMap<String, Value>,
+    no_color: bool,
+    output: &OutputFormat,
+) -> Result<String> {
+    let mut names = BTreeSet::new();
+    names.extend(old.keys());
+    names.extend(new.keys());
+
+    let mut reports = Vec::new();
+
+    for name in names {
+        let (status, old_policy, new_policy) = match (old.get(name), new.get(name)) {
+            (Some(old), Some(new)) if old == new => continue,
+            (Some(old), Some(new)) => (Status::Changed, old, new),
+            (None, Some(new)) => (Status::Added, &Value::Null, new),
+            (Some(old), None) => (Status::Removed, old, &Value::Null),
+            (None, None) => continue,
+        };
+
+        let containers = summarize(old_policy, new_policy)
+            .into_iter()
+            .filter(|summary| summary.status != Status::Unchanged)
+            .collect();
+
+        reports.push(WorkloadReport {
+            workload: name,
+            status,
+            containers,
+        });
+    }
+
+    match output {
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(&reports)?),
+        OutputFormat::Text => {
+            if reports.is_empty() {
+                return Ok("No policy-relevant differences".to_string());
+            }
+
+            let renderer = Renderer::new(no_color);
+            let mut lines = Vec::new();
+
+            for report in &reports {
+                lines.push(format!(
+                    "{}: {}",
+                    report.workload,
+                    renderer.status(&report.status)
+                ));
+
+                let summaries = renderer.container_summaries(&report.containers);
+                lines.extend(summaries.lines().map(|line| format!("  {}", line)));
+            }
+
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+pub fn diff_policies(old: &Path, new: &Path, no_color: bool, output: &OutputFormat) -> Result<()> {
+    let load = |path: &Path| -> Result<Value> {
+        let content = read_to_string(path).context(loc!())?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse policy {}", path.display()))
+    };
+
+    let result = render(
+        &old.display().to_string(),
+        &new.display().to_string(),
+        &load(old)?,
+        &load(new)?,
+        no_color,
+        output,
+    )?;
+
+    println!("{}", result);
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::io::{BufRead, Read, Write};
+use std::mem;
+
+// A document of a YAML stream together with its markers, kept verbatim so that unchanged
+// documents, empty documents, and separators are written back exactly as they are read
+#[derive(Default)]
+pub struct Document {
+    // Line of the document start marker (---), if any
+    pub start: Option<String>,
+    // Line number of the first line of the body
+    pub line: usize,
+    pub body: String,
+    // Line of the document end marker (...), if any
+    pub end: Option<String>,
+}
+
+fn is_marker(line: &str, marker: &str) -> bool {
+    match line.strip_prefix(marker) {
+        Some(rest) => rest.is_empty() || rest.starts_with(char::is_whitespace),
+        None => false,
+    }
+}
+
+impl Document {
+    fn is_empty(&self) -> bool {
+        self.start.is_none() && self.body.is_empty() && self.end.is_none()
+    }
+
+    // Return the parsed body, or None if it has no content (e.g., only comments). The body is
+    // preceded by the lines before it, so that the parse errors give the lines of the file.
+    pub fn parse(&self) -> Result<Option<serde_yaml::Value>> {
+        let source = "\n".repeat(self.line.saturating_sub(1)) + &self.body;
+        let value: serde_yaml::Value = serde_yaml::from_str(&source)?;
+
+        if value.is_null() {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        if let Some(start) = &self.start {
+            writer.write_all(start.as_bytes())?;
+        }
+
+        writer.write_all(self.body.as_bytes())?;
+
+        if let Some(end) = &self.end {
+            writer.write_all(end.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+// Reader of the documents of a stream one at a time, so that large manifests (e.g., rendered
+// Helm charts) are not held in memory
+pub struct Reader<R> {
+    reader: R,
+    // Lines read ahead by peek_char
+    pending: VecDeque<String>,
+    // Number of lines consumed
+    line: usize,
+    current: Document,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Reader<R> {
+        Reader {
+            reader,
+            pending: VecDeque::new(),
+            line: 0,
+            current: Document {
+                line: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>> {
+        let line = match self.pending.pop_front() {
+            Some(line) => line,
+            None => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Ok(None);
+                }
+                line
+            }
+        };
+
+        self.line += 1;
+
+        Ok(Some(line))
+    }
+
+    // Return the first non-whitespace character of the stream without consuming it
+    pub fn peek_char(&mut self) -> Result<Option<char>> {
+        if let Some(c) = self
+            .pending
+            .iter()
+            .find_map(|line| line.trim_start().chars().next())
+        {
+            return Ok(Some(c));
+        }
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+
+            let c = line.trim_start().chars().next();
+            self.pending.push_back(line);
+
+            if c.is_some() {
+                return Ok(c);
+            }
+        }
+    }
+
+    // Return the rest of the stream as a whole
+    pub fn read_to_string(mut self) -> Result<String> {
+        let mut content: String = self.pending.drain(..).collect();
+        self.reader.read_to_string(&mut content)?;
+
+        Ok(content)
+    }
+
+    // Split the stream by the markers at the beginning of the lines. Content following the
+    // start marker on the same line (e.g., --- !!map) is not supported.
+    pub fn next_document(&mut self) -> Result<Option<Document>> {
+        while let Some(line) = self.read_line()? {
+            if is_marker(&line, "---") {
+                let previous = mem::replace(
+                    &mut self.current,
+                    Document {
+                        start: Some(line),
+                        line: self.line + 1,
+                        ..Default::default()
+                    },
+                );
+
+                if !previous.is_empty() {
+                    return Ok(Some(previous));
+                }
+            } else if is_marker(&line, "...") {
+                self.current.end = Some(line);
+
+                let next = Document {
+                    line: self.line + 1,
+                    ..Default::default()
+                };
+
+                return Ok(Some(mem::replace(&mut self.current, next)));
+            } else {
+                self.current.body.push_str(&line);
+            }
+        }
+
+        let current = mem::take(&mut self.current);
+
+        if current.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(current))
+        }
+    }
+}
+
+pub fn split(content: &str) -> Vec<Document> {
+    let mut reader = Reader::new(content.as_bytes());
+    let mut results = Vec::new();
+
+    // Reading from a string never fails
+    while let Some(document) = reader.next_document().unwrap() {
+        results.push(document);
+    }
+
+    results
+}
+
+pub fn join(documents: &[Document]) -> String {
+    let mut result = String::new();
+
+    for document in documents {
+        if let Some(start) = &document.start {
+            result.push_str(start);
+        }
+
+        result.push_str(&document.body);
+
+        if let Some(end) = &document.end {
+            result.push_str(end);
+        }
+    }
+
+    result
+}
@@ -0,0 +1,81 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{bail, Result};
+
+pub const GO_SHIM: &str = "go-shim";
+pub const RUNTIME_RS: &str = "runtime-rs";
+pub const PEER_PODS: &str = "peer-pods";
+
+// Where the files shared by the host (e.g., /etc/hosts and the volumes) are mounted in the
+// guest, which depends on the Kata runtime. The source of a shared file is matched by
+// <share_path><file_prefix><file name>$.
+#[derive(Clone)]
+pub struct GuestLayout {
+    // Regex of the directory of the shared files
+    pub share_path: String,
+    // Regex of the prefix identifying the container and the mount
+    pub file_prefix: String,
+    pub hostname: String,
+    pub hosts: String,
+    pub resolv_conf: String,
+    // Regex of the source of /dev/shm
+    pub sandbox_shm: String,
+    // Whether the volumes of the node are copied into the guest when the container is created
+    // rather than shared with it
+    pub copies_volumes: bool,
+}
+
+impl Default for GuestLayout {
+    // The go shim names the shared files <container id>-<random hex>-<file name>
+    // Reference: https://github.com/kata-containers/kata-containers/blob/CCv0/src/runtime/virtcontainers/kata_agent.go#L77
+    fn default() -> Self {
+        GuestLayout {
+            share_path: "^/run/kata-containers/shared/containers/".to_string(),
+            file_prefix: "[a-z0-9]+-[a-z0-9]+-".to_string(),
+            hostname: "hostname".to_string(),
+            hosts: "hosts".to_string(),
+            resolv_conf: "resolv.conf".to_string(),
+            sandbox_shm: "^/run/kata-containers/sandbox/shm$".to_string(),
+            copies_volumes: false,
+        }
+    }
+}
+
+impl GuestLayout {
+    pub fn from_name(name: &str) -> Result<GuestLayout> {
+        match name {
+            GO_SHIM => Ok(GuestLayout::default()),
+            // runtime-rs shares the files through the passthrough directory of virtio-fs
+            // Reference: https://github.com/kata-containers/kata-containers/blob/main/src/runtime-rs/crates/resource/src/share_fs/mod.rs
+            RUNTIME_RS => Ok(GuestLayout {
+                share_path: "^/run/kata-containers/shared/containers/passthrough/".to_string(),
+                ..Default::default()
+            }),
+            // The pod VMs of the peer pods (cloud-api-adaptor) run on another host, so the go
+            // shim copies the files into the guest with the agent (shared_fs = none) under the
+            // same names
+            // Reference: https://github.com/confidential-containers/cloud-api-adaptor/blob/main/docs/architecture.md
+            PEER_PODS => Ok(GuestLayout {
+                copies_volumes: true,
+                ..Default::default()
+            }),
+            _ => bail!(
+                "unknown guest layout {}, the supported layouts are: {}, {}, {}",
+                name,
+                GO_SHIM,
+                RUNTIME_RS,
+                PEER_PODS
+            ),
+        }
+    }
+
+    // Regex of the sources of the shared files, followed by the file name
+    pub fn get_shared_prefix(&self) -> String {
+        [self.share_path.as_str(), self.file_prefix.as_str()].concat()
+    }
+
+    pub fn get_shared_source(&self, file_name: &str) -> String {
+        [self.get_shared_prefix().as_str(), file_name, "$"].concat()
+    }
+}
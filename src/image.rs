@@ -1,54 +1,623 @@
-use anyhow::{bail, Context, Result};
-use oci_spec::image::ImageConfiguration;
-use std::process::Command;
+use crate::pattern;
+use crate::ratelimit;
+use crate::subprocess;
 
-const SKOPEO: &str = "skopeo";
+use anyhow::{anyhow, bail, Context, Result};
+use oci_spec::image::{ImageConfiguration, ImageManifest};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub const SKOPEO: &str = "skopeo";
 const DOCKER_URI_PREFIX: &str = "docker://";
 const DOCKER_RESGISTRY_PREFIX: &str = "docker.io/library/";
 
-pub fn pull_image_config(image_ref: &str) -> Result<ImageConfiguration> {
-    let image_uri = match image_ref.rfind("://") {
+// Transports of skopeo given as <transport>:<reference>
+// Reference: https://github.com/containers/image/blob/main/docs/containers-transports.5.md
+const DOCKER_DAEMON_TRANSPORT: &str = "docker-daemon:";
+const CONTAINERS_STORAGE_TRANSPORT: &str = "containers-storage:";
+const OCI_LAYOUT_TRANSPORT: &str = "oci:";
+const TRANSPORTS: &[&str] = &[
+    DOCKER_DAEMON_TRANSPORT,
+    CONTAINERS_STORAGE_TRANSPORT,
+    OCI_LAYOUT_TRANSPORT,
+    "oci-archive:",
+    "docker-archive:",
+    "dir:",
+];
+
+// Platform of the confidential sandboxes (SNP and TDX), which cannot run the images of other
+// platforms, e.g., windows images, or arm64 images under emulation
+const DEFAULT_OS: &str = "linux";
+const DEFAULT_ARCH: &str = "amd64";
+
+// Options of skopeo inspect set by --skopeo-arg, e.g., --authfile or --override-variant
+static SKOPEO_ARGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Outputs of skopeo inspect by uri, option, and digest of the auth of the pod (empty without
+// one), so that the images used by several containers or documents are only requested once per
+// run, given the rate limits of the registries, while the pods with other credentials still
+// inspect the images themselves
+static INSPECTIONS: Mutex<BTreeMap<(String, String, String), String>> = Mutex::new(BTreeMap::new());
+
+static AUTHFILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_skopeo_args(args: &[String]) {
+    *SKOPEO_ARGS.lock().unwrap() = args.to_vec();
+}
+
+fn get_image_uri(image_ref: &str) -> String {
+    if TRANSPORTS
+        .iter()
+        .any(|transport| image_ref.starts_with(transport))
+    {
+        return image_ref.to_owned();
+    }
+
+    match image_ref.rfind("://") {
         Some(_) => image_ref.to_owned(),
         None => match image_ref.rfind('/') {
             Some(_) => [DOCKER_URI_PREFIX, image_ref].concat(),
             None => [DOCKER_URI_PREFIX, DOCKER_RESGISTRY_PREFIX, image_ref].concat(),
         },
+    }
+}
+
+// Fully qualified reference without the transport, e.g., docker.io/library/nginx:latest
+pub fn get_image_reference(image_ref: &str) -> String {
+    let image_uri = get_image_uri(image_ref);
+
+    match image_uri.find("://") {
+        Some(index) => image_uri[index + 3..].to_string(),
+        None => image_uri,
+    }
+}
+
+// Docker Hub is the registry of the references without a domain, e.g., docker://user/app
+fn is_docker_hub(image_uri: &str) -> bool {
+    let reference = match image_uri.strip_prefix(DOCKER_URI_PREFIX) {
+        Some(reference) => reference,
+        None => return false,
+    };
+
+    match reference.split_once('/') {
+        Some((domain, _)) if domain.contains(['.', ':']) || domain == "localhost" => {
+            matches!(
+                domain,
+                "docker.io" | "index.docker.io" | "registry-1.docker.io"
+            )
+        }
+        _ => true,
+    }
+}
+
+// Return the Docker Hub repository and tag of the uri, e.g., library/nginx and latest, None for
+// the other registries and the uris pinned by digest
+fn get_docker_hub_tag(image_uri: &str) -> Option<(String, String)> {
+    if !is_docker_hub(image_uri) {
+        return None;
+    }
+
+    let reference = image_uri.strip_prefix(DOCKER_URI_PREFIX)?;
+    if reference.contains('@') {
+        return None;
+    }
+
+    let reference = match reference.split_once('/') {
+        Some((domain, path)) if domain.contains(['.', ':']) || domain == "localhost" => path,
+        _ => reference,
+    };
+
+    let (repository, tag) = match reference.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+        _ => (reference, "latest"),
     };
 
-    let output = Command::new(SKOPEO)
-        .arg("inspect")
-        .arg(&image_uri)
-        .arg("--config")
-        .output()
-        .context(loc!())?;
+    let repository = match repository.contains('/') {
+        true => repository.to_string(),
+        false => ["library/", repository].concat(),
+    };
+
+    Some((repository, tag.to_string()))
+}
+
+// Return the digest of the manifest of the image ref by tag without pulling the manifest, from
+// a HEAD request. Only the public images of Docker Hub are resolved, None otherwise.
+pub fn head_image_digest(image_ref: &str) -> Option<String> {
+    let (repository, tag) = get_docker_hub_tag(&get_image_uri(image_ref))?;
+
+    ratelimit::get_docker_hub_digest(&repository, &tag)
+}
+
+// Return the image ref pinned by the digest, e.g., docker://docker.io/library/nginx@sha256:...
+pub fn get_image_ref_by_digest(image_ref: &str, digest: &str) -> String {
+    get_image_uri_by_digest(&get_image_uri(image_ref), digest)
+}
+
+// Replace the tag or digest of the uri with the given digest
+fn get_image_uri_by_digest(image_uri: &str, digest: &str) -> String {
+    let (repository, name) = match image_uri.rfind('/') {
+        Some(index) => image_uri.split_at(index + 1),
+        None => ("", image_uri),
+    };
+
+    let name = match name.find('@').or_else(|| name.find(':')) {
+        Some(index) => &name[..index],
+        None => name,
+    };
+
+    [repository, name, "@", digest].concat()
+}
+
+// The auth is the content of the authfile of skopeo, e.g., from the imagePullSecrets of the pod,
+// which is written to a temporary file readable by the user only for the inspection
+fn inspect(image_uri: &str, option: &str, auth: Option<&str>) -> Result<String> {
+    // The config of a manifest list is the one of the platform of the host unless overridden,
+    // which is then consistent with the manifest resolved by pull_image_manifest. The output is
+    // decoded without any replacement given that the digests are computed from it. The options
+    // of --skopeo-arg come after the default ones, which they override, and the authfile of
+    // the pod after them.
+    let mut args = vec![
+        "--override-os".to_string(),
+        DEFAULT_OS.to_string(),
+        "--override-arch".to_string(),
+        DEFAULT_ARCH.to_string(),
+        "inspect".to_string(),
+    ];
+    args.extend(SKOPEO_ARGS.lock().unwrap().iter().cloned());
+
+    let key = (
+        image_uri.to_string(),
+        option.to_string(),
+        auth.map(|auth| get_sha256(auth.as_bytes()))
+            .unwrap_or_default(),
+    );
+    if let Some(result) = INSPECTIONS.lock().unwrap().get(&key) {
+        return Ok(result.clone());
+    }
+
+    let authfile = match auth {
+        Some(auth) => {
+            let path = env::temp_dir().join(format!(
+                "cc-policy-auth-{}-{}.json",
+                std::process::id(),
+                AUTHFILE_COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .context(loc!())?;
+            file.write_all(merge_user_auth(auth)?.as_bytes())
+                .context(loc!())?;
+
+            args.extend(["--authfile".to_string(), path.to_string_lossy().to_string()]);
+            Some(path)
+        }
+        None => None,
+    };
+
+    args.extend([
+        subprocess::operand(image_uri)?.to_string(),
+        option.to_string(),
+    ]);
+
+    let result = run_inspect(image_uri, &args);
+
+    if let Some(path) = authfile {
+        fs::remove_file(&path).context(loc!())?;
+    }
+
+    let result = result?;
+
+    INSPECTIONS.lock().unwrap().insert(key, result.clone());
+
+    Ok(result)
+}
+
+// Authfile of the user that skopeo would read without --authfile, i.e., the one of
+// --skopeo-arg, REGISTRY_AUTH_FILE, or the first of the default paths that exists
+// Reference: https://github.com/containers/image/blob/main/docs/containers-auth.json.5.md
+fn get_user_authfile() -> Option<PathBuf> {
+    let args = SKOPEO_ARGS.lock().unwrap();
+    let mut authfile = None;
+    for (index, arg) in args.iter().enumerate() {
+        if let Some(path) = arg.strip_prefix("--authfile=") {
+            authfile = Some(PathBuf::from(path));
+        } else if arg == "--authfile" {
+            authfile = args.get(index + 1).map(PathBuf::from);
+        }
+    }
+    if authfile.is_some() {
+        return authfile;
+    }
+
+    if let Some(path) = env::var_os("REGISTRY_AUTH_FILE") {
+        return Some(PathBuf::from(path));
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
+        candidates.push(PathBuf::from(runtime_dir).join("containers/auth.json"));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        candidates.push(PathBuf::from(&home).join(".config/containers/auth.json"));
+        candidates.push(PathBuf::from(&home).join(".docker/config.json"));
+    }
+
+    candidates.into_iter().find(|path| path.exists())
+}
+
+// The --authfile of the pod replaces the credentials of the user for skopeo, which are then
+// merged into it, the ones of the pod taking precedence for their registries
+fn merge_user_auth(auth: &str) -> Result<String> {
+    let path = match get_user_authfile() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(auth.to_string()),
+    };
+
+    let user = fs::read_to_string(&path)
+        .with_context(|| format!("failed to read authfile {}", path.display()))?;
+
+    merge_auth(&user, auth).with_context(|| format!("failed to merge authfile {}", path.display()))
+}
 
-    let config = String::from_utf8_lossy(&output.stdout);
+fn merge_auth(user: &str, auth: &str) -> Result<String> {
+    let mut merged: serde_json::Value = serde_json::from_str(user)?;
+    let auth: serde_json::Value = serde_json::from_str(auth)?;
 
-    if config.is_empty() {
+    let merged_auths = merged
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the authfile must be an object"))?
+        .entry("auths")
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("auths of the authfile must be an object"))?;
+
+    for (registry, credentials) in auth["auths"].as_object().into_iter().flatten() {
+        merged_auths.insert(registry.clone(), credentials.clone());
+    }
+
+    Ok(serde_json::to_string(&merged)?)
+}
+
+fn run_inspect(image_uri: &str, args: &[String]) -> Result<String> {
+    let mut retry = 0;
+    let output = loop {
+        let output = subprocess::output(SKOPEO, args)?;
+        if output.code == Some(0) || !ratelimit::is_rate_limited(&output.stderr) {
+            break output;
+        }
+
+        if retry == ratelimit::get_retries() {
+            let quota = match is_docker_hub(image_uri) {
+                true => ratelimit::get_docker_hub_quota(),
+                false => None,
+            };
+
+            return output.check_text().with_context(|| {
+                format!(
+                    "the registry of {} rejects the requests because of its rate limit after {} retries{}, log in to the registry (e.g., --skopeo-arg=--authfile=<file>) or retry later",
+                    image_uri,
+                    retry,
+                    match quota {
+                        Some(quota) => format!(" (remaining anonymous pulls: {})", quota),
+                        None => String::new(),
+                    }
+                )
+            });
+        }
+
+        ratelimit::backoff(retry);
+        retry += 1;
+    };
+
+    let result = output.check_text()?;
+
+    if result.is_empty() {
         bail!(
-            "{}: failed to get image config with the uri {}",
+            "{}: failed to inspect the image with the uri {}",
             loc!(),
             image_uri
         );
     }
 
+    Ok(result)
+}
+
+// Return the uri of the image in the local image stores, i.e., the docker daemon, the
+// containers-storage of CRI-O and podman, and the given OCI layout directories, for containers
+// with imagePullPolicy: Never whose image may not be in any registry
+pub fn find_local_image(image_ref: &str, oci_layouts: &[PathBuf]) -> Result<String> {
+    let reference = get_image_reference(image_ref);
+
+    let mut candidates = vec![
+        [DOCKER_DAEMON_TRANSPORT, reference.as_str()].concat(),
+        [CONTAINERS_STORAGE_TRANSPORT, reference.as_str()].concat(),
+    ];
+
+    // Images of an OCI layout are referenced by the org.opencontainers.image.ref.name
+    // annotation of the index, e.g., the tag
+    let name = reference.rsplit('/').next().unwrap_or_default();
+    let name = name.split('@').next().unwrap_or_default();
+    let tag = match name.split_once(':') {
+        Some((_, tag)) => tag,
+        None => "latest",
+    };
+    for oci_layout in oci_layouts {
+        candidates.push(format!(
+            "{}{}:{}",
+            OCI_LAYOUT_TRANSPORT,
+            oci_layout.display(),
+            tag
+        ));
+    }
+
+    for candidate in &candidates {
+        if inspect(candidate, "--raw", None).is_ok() {
+            return Ok(candidate.clone());
+        }
+    }
+
+    bail!(
+        "image {} uses imagePullPolicy: Never but is not found in the local image stores: {}",
+        image_ref,
+        candidates.join(", ")
+    );
+}
+
+pub fn pull_image_config(image_ref: &str, auth: Option<&str>) -> Result<ImageConfiguration> {
+    let image_uri = get_image_uri(image_ref);
+
+    let config = inspect(&image_uri, "--config", auth)
+        .with_context(|| format!("failed to get image config with the uri {}", image_uri))?;
+
     let image_config: ImageConfiguration = serde_json::from_str(&config).context(loc!())?;
 
     Ok(image_config)
 }
 
+// Warn if the image is not built for the platform of the confidential sandbox, e.g., for the
+// sandboxes of other platforms or the images whose config declares the wrong platform
+pub fn check_platform(image_ref: &str, image_config: &ImageConfiguration) {
+    let os = image_config.os().to_string();
+    let arch = image_config.architecture().to_string();
+
+    if os != DEFAULT_OS || arch != DEFAULT_ARCH {
+        warn!(
+            "image {} is built for {}/{}, but the confidential sandbox only runs {}/{} images",
+            image_ref, os, arch, DEFAULT_OS, DEFAULT_ARCH
+        );
+    }
+}
+
+pub fn get_sha256(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+// Return the manifest of the default platform and the digest of the manifest referenced by
+// the image_ref, i.e., the digest of the manifest list (index) for multi-platform images
+pub fn pull_image_manifest(image_ref: &str, auth: Option<&str>) -> Result<(ImageManifest, String)> {
+    let image_uri = get_image_uri(image_ref);
+
+    let manifest = inspect(&image_uri, "--raw", auth)
+        .with_context(|| format!("failed to get image manifest with the uri {}", image_uri))?;
+
+    let digest = get_sha256(manifest.as_bytes());
+
+    let value: serde_json::Value = serde_json::from_str(&manifest).context(loc!())?;
+
+    // Resolve the manifest list (index) into the manifest of the default platform,
+    // which is consistent with the config returned by skopeo
+    let manifest = if let Some(manifests) = value["manifests"].as_array() {
+        let digest = manifests
+            .iter()
+            .find(|m| {
+                m["platform"]["os"] == DEFAULT_OS && m["platform"]["architecture"] == DEFAULT_ARCH
+            })
+            .and_then(|m| m["digest"].as_str())
+            .ok_or_else(|| {
+                let platforms: Vec<String> = manifests
+                    .iter()
+                    .map(|m| {
+                        format!(
+                            "{}/{}",
+                            m["platform"]["os"].as_str().unwrap_or("unknown"),
+                            m["platform"]["architecture"].as_str().unwrap_or("unknown")
+                        )
+                    })
+                    .collect();
+
+                anyhow!(
+                    "{}: failed to find the {}/{} manifest of {}, which the confidential sandbox requires, the image is only built for {}",
+                    loc!(),
+                    DEFAULT_OS,
+                    DEFAULT_ARCH,
+                    image_uri,
+                    platforms.join(", ")
+                )
+            })?;
+
+        inspect(&get_image_uri_by_digest(&image_uri, digest), "--raw", auth)?
+    } else {
+        manifest
+    };
+
+    let image_manifest: ImageManifest = serde_json::from_str(&manifest).context(loc!())?;
+
+    Ok((image_manifest, digest))
+}
+
 pub fn get_env(image_config: &ImageConfiguration) -> Result<Vec<String>> {
     let mut results = Vec::new();
 
-    // Surround the env with ^ and $ to comply the regex syntax
+    // Regex rules matching the env exactly, the metacharacters of the values (e.g., + in
+    // PATH or ? in URLs) are escaped
     if let Some(config) = image_config.config() {
         if let Some(image_envs) = config.env() {
-            results = image_envs
-                .iter()
-                .map(|env| ["^", env, "$"].concat())
-                .collect();
+            results = image_envs.iter().map(|env| pattern::literal(env)).collect();
         }
     }
 
     Ok(results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    // Configs of official images, trimmed to the fields read by the generation
+    fn read_image_configs() -> Vec<(String, ImageConfiguration)> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/image-configs");
+        let mut configs: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let content = fs::read_to_string(&path).unwrap();
+                (
+                    path.display().to_string(),
+                    serde_json::from_str(&content).unwrap(),
+                )
+            })
+            .collect();
+        configs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        configs
+    }
+
+    #[test]
+    fn test_get_docker_hub_tag() {
+        let tag = |image_ref: &str| get_docker_hub_tag(&get_image_uri(image_ref));
+        let expected =
+            |repository: &str, tag: &str| Some((repository.to_string(), tag.to_string()));
+
+        assert_eq!(tag("nginx"), expected("library/nginx", "latest"));
+        assert_eq!(tag("nginx:1.25"), expected("library/nginx", "1.25"));
+        assert_eq!(tag("user/app:v1"), expected("user/app", "v1"));
+        assert_eq!(
+            tag("docker.io/library/redis:7"),
+            expected("library/redis", "7")
+        );
+        assert_eq!(
+            tag("index.docker.io/user/app"),
+            expected("user/app", "latest")
+        );
+
+        assert_eq!(tag("nginx@sha256:1234"), None);
+        assert_eq!(tag("mcr.microsoft.com/oss/nginx:1.25"), None);
+        assert_eq!(tag("localhost:5000/app:v1"), None);
+        assert_eq!(tag("docker-daemon:nginx:latest"), None);
+
+        assert_eq!(
+            get_image_ref_by_digest("nginx:1.25", "sha256:1234"),
+            "docker://docker.io/library/nginx@sha256:1234"
+        );
+    }
+
+    #[test]
+    fn test_get_env_corpus() {
+        let configs = read_image_configs();
+        assert!(!configs.is_empty());
+
+        for (path, config) in &configs {
+            let envs = config.config().as_ref().unwrap().env().clone().unwrap();
+            let rules = get_env(config).unwrap();
+            assert_eq!(rules.len(), envs.len(), "{}", path);
+
+            for (env, rule) in envs.iter().zip(&rules) {
+                assert!(
+                    pattern::check(rule).unwrap().is_empty(),
+                    "{}: {}",
+                    path,
+                    rule
+                );
+                assert!(pattern::matches(rule, env).unwrap(), "{}: {}", path, rule);
+
+                // Exactly the value, e.g., the . of a version is not any character
+                let other = [env.as_str(), "x"].concat();
+                assert!(
+                    !pattern::matches(rule, &other).unwrap(),
+                    "{}: {}",
+                    path,
+                    rule
+                );
+                let other = env.replace('.', "x");
+                if other != *env {
+                    assert!(
+                        !pattern::matches(rule, &other).unwrap(),
+                        "{}: {}",
+                        path,
+                        rule
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_env_metacharacters() {
+        let config: ImageConfiguration = serde_json::from_str(
+            r#"{
+                "architecture": "amd64",
+                "os": "linux",
+                "config": {
+                    "Env": [
+                        "PG_VERSION=16.2-1.pgdg120+2",
+                        "ENDPOINT=http://collector:14268/api/traces?format=jaeger.thrift",
+                        "PATTERN=^[a-z]+(\\.[a-z]+)*$",
+                        "EMPTY="
+                    ]
+                },
+                "rootfs": {"type": "layers", "diff_ids": []}
+            }"#,
+        )
+        .unwrap();
+
+        let rules = get_env(&config).unwrap();
+        assert!(!pattern::matches(&rules[0], "PG_VERSION=16.2-1.pgdg12002").unwrap());
+
+        for (env, rule) in config
+            .config()
+            .as_ref()
+            .unwrap()
+            .env()
+            .iter()
+            .flatten()
+            .zip(&rules)
+        {
+            assert!(pattern::matches(rule, env).unwrap(), "{}", rule);
+        }
+        assert!(!pattern::matches(&rules[3], "EMPTY=x").unwrap());
+    }
+
+    #[test]
+    fn test_merge_auth() {
+        let user = r#"{"auths":{"docker.io":{"auth":"dXNlcg=="},"ghcr.io":{"auth":"dXNlcg=="}},"credHelpers":{"gcr.io":"gcloud"}}"#;
+        let pod = r#"{"auths":{"ghcr.io":{"auth":"cG9k"},"quay.io":{"auth":"cG9k"}}}"#;
+
+        let merged: serde_json::Value =
+            serde_json::from_str(&merge_auth(user, pod).unwrap()).unwrap();
+        assert_eq!(merged["auths"]["docker.io"]["auth"], "dXNlcg==");
+        assert_eq!(merged["auths"]["ghcr.io"]["auth"], "cG9k");
+        assert_eq!(merged["auths"]["quay.io"]["auth"], "cG9k");
+        assert_eq!(merged["credHelpers"]["gcr.io"], "gcloud");
+
+        // An authfile without auths, e.g., with the credential helpers only
+        let merged: serde_json::Value =
+            serde_json::from_str(&merge_auth(r#"{"credsStore":"desktop"}"#, pod).unwrap()).unwrap();
+        assert_eq!(merged["auths"]["quay.io"]["auth"], "cG9k");
+        assert_eq!(merged["credsStore"], "desktop");
+
+        assert!(merge_auth("[]", pod).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+const CONFIG_TEMPLATE: &str = r#"# cc-policy configuration, used with `cc-policy --config config.toml`.
+# Options given on the command line take precedence. Relative paths are
+# resolved against the directory of this file.
+
+# Add the rules of the CRI defaults and the sandbox (pause) container
+with_default_rules = true
+
+# Profiles of the sidecars injected at admission time, e.g., by a service mesh
+sidecars = []
+# sidecars = ["profiles/example-sidecar.yaml"]
+
+# Organization-specific rules applied on top of the generated ones
+overlay = "rules/overlay.yaml"
+"#;
+
+const OVERLAY_TEMPLATE: &str = r#"# Rules applied on top of the generated policies.
+# Env rules override the generated ones with the same name and mounts override the
+# generated ones with the same destination. Use "*" to match every container.
+containers:
+  "*":
+    env: []
+    mounts: []
+#   app:
+#     env:
+#       - "^ORG_REGION=[a-z0-9-]+$"
+"#;
+
+const PROFILE_TEMPLATE: &str = r#"# Sidecar profile, i.e., the containers and volumes injected into the pod at
+# admission time. Reference it from config.toml or pass it with --sidecar.
+name: example-sidecar
+spec:
+  containers:
+    - name: example-sidecar
+      image: busybox:1.36
+      command: ["sleep", "infinity"]
+"#;
+
+const GOLDEN_MANIFEST_TEMPLATE: &str = r#"apiVersion: v1
+kind: Pod
+metadata:
+  name: example
+spec:
+  containers:
+    - name: example
+      image: busybox:1.36
+      command: ["sleep", "infinity"]
+"#;
+
+const GOLDEN_SCRIPT_TEMPLATE: &str = r#"#!/bin/sh
+# Regenerate the policy of every manifest under golden/ and compare it with the expected
+# one (<manifest>.policy.json). Run with --update to accept the current output.
+set -e
+cd "$(dirname "$0")/.."
+
+status=0
+for manifest in golden/*.yaml; do
+    expected="${manifest%.yaml}.policy.json"
+    actual="$(mktemp)"
+
+    cc-policy --config config.toml --no-metadata --force -i "$manifest" -p "$actual" > /dev/null
+
+    if [ "$1" = "--update" ]; then
+        mv "$actual" "$expected"
+        continue
+    fi
+
+    if ! diff -u "$expected" "$actual"; then
+        status=1
+    fi
+    rm -f "$actual"
+done
+
+exit $status
+"#;
+
+const FILES: [(&str, &str); 5] = [
+    ("config.toml", CONFIG_TEMPLATE),
+    ("rules/overlay.yaml", OVERLAY_TEMPLATE),
+    ("profiles/example-sidecar.yaml", PROFILE_TEMPLATE),
+    ("golden/example.yaml", GOLDEN_MANIFEST_TEMPLATE),
+    ("golden/check.sh", GOLDEN_SCRIPT_TEMPLATE),
+];
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).context(loc!())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Scaffold a policy project. Existing files are never overwritten.
+pub fn init(path: &Path) -> Result<()> {
+    for (name, _) in FILES {
+        if path.join(name).exists() {
+            bail!("{} already exists", path.join(name).display());
+        }
+    }
+
+    for (name, content) in FILES {
+        let file = path.join(name);
+
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).context(loc!())?;
+        }
+
+        fs::write(&file, content).context(loc!())?;
+
+        if file.extension().and_then(|ext| ext.to_str()) == Some("sh") {
+            set_executable(&file)?;
+        }
+
+        info!("{} created.", file.display());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::plugin::{RulePlugin, Stage, StageContext};
+use crate::PodYaml;
+
+use anyhow::Result;
+use oci_spec::image::ImageConfiguration;
+use oci_spec::runtime::{Mount, Spec};
+use serde::Serialize;
+use std::sync::Mutex;
+
+// Intermediate representation of the rule generation dumped by --dump-ir, which separates the
+// facts parsed from the input from the rules emitted by each stage. The rules of a stage are
+// the ones of the container after the stage, i.e., including the previous stages and the
+// plugins.
+
+// Facts of the container of the pod yaml
+#[derive(Serialize)]
+pub struct ContainerFacts {
+    pub image: String,
+    pub command: Vec<String>,
+    pub args: Vec<String>,
+    pub working_dir: String,
+    // Env rules of the container, i.e., ^<name>=<value>$ or a regex of the value
+    pub env: Vec<String>,
+}
+
+// Facts of the image config
+#[derive(Default, Serialize)]
+pub struct ImageFacts {
+    pub entrypoint: Vec<String>,
+    pub cmd: Vec<String>,
+    pub working_dir: String,
+    pub env: Vec<String>,
+    pub user: String,
+    pub volumes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct StageRules {
+    pub stage: Stage,
+    pub args: Vec<String>,
+    pub cwd: String,
+    pub env: Vec<String>,
+    pub mounts: Vec<Mount>,
+}
+
+#[derive(Serialize)]
+pub struct ContainerIr {
+    pub name: String,
+    // None if the policy is not generated from a pod yaml
+    pub container: Option<ContainerFacts>,
+    pub image: Option<ImageFacts>,
+    pub stages: Vec<StageRules>,
+}
+
+impl ContainerFacts {
+    fn from_container_yaml(container: &serde_yaml::Value) -> Result<Option<ContainerFacts>> {
+        if container.is_null() {
+            return Ok(None);
+        }
+
+        let (working_dir, command, args) = PodYaml::get_entry_point(container)?;
+
+        Ok(Some(ContainerFacts {
+            image: container["image"].as_str().unwrap_or_default().to_string(),
+            command,
+            args,
+            working_dir,
+            // enableServiceLinks of the pod is not known here, i.e., its default
+            env: PodYaml::get_env(container, true)?,
+        }))
+    }
+}
+
+impl ImageFacts {
+    fn from_image_config(image_config: &ImageConfiguration) -> ImageFacts {
+        match image_config.config() {
+            Some(config) => ImageFacts {
+                entrypoint: config.entrypoint().clone().unwrap_or_default(),
+                cmd: config.cmd().clone().unwrap_or_default(),
+                working_dir: config.working_dir().clone().unwrap_or_default(),
+                env: config.env().clone().unwrap_or_default(),
+                user: config.user().clone().unwrap_or_default(),
+                volumes: config.volumes().clone().unwrap_or_default(),
+            },
+            None => ImageFacts::default(),
+        }
+    }
+}
+
+impl StageRules {
+    fn from_spec(stage: Stage, spec: &Spec) -> StageRules {
+        let process = spec.process().as_ref();
+
+        StageRules {
+            stage,
+            args: process
+                .and_then(|process| process.args().clone())
+                .unwrap_or_default(),
+            cwd: process
+                .map(|process| process.cwd().to_string_lossy().to_string())
+                .unwrap_or_default(),
+            env: process
+                .and_then(|process| process.env().clone())
+                .unwrap_or_default(),
+            mounts: spec.mounts().clone().unwrap_or_default(),
+        }
+    }
+}
+
+// Plugin recording the IR of the containers in the order they are generated
+#[derive(Default)]
+pub struct Recorder {
+    containers: Mutex<Vec<ContainerIr>>,
+}
+
+impl Recorder {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(
+            &*self.containers.lock().unwrap(),
+        )?)
+    }
+}
+
+impl RulePlugin for Recorder {
+    fn name(&self) -> &str {
+        "ir"
+    }
+
+    fn apply(&self, stage: Stage, context: &StageContext, spec: &mut Spec) -> Result<()> {
+        let mut containers = self.containers.lock().unwrap();
+
+        // The defaults stage starts the generation of a container
+        if stage == Stage::Defaults {
+            containers.push(ContainerIr {
+                name: context.name.to_string(),
+                container: ContainerFacts::from_container_yaml(context.container)?,
+                image: context.image_config.map(ImageFacts::from_image_config),
+                stages: Vec::new(),
+            });
+        }
+
+        if let Some(ir) = containers
+            .iter_mut()
+            .rev()
+            .find(|ir| ir.name == context.name)
+        {
+            ir.stages.push(StageRules::from_spec(stage, spec));
+        }
+
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
 // Copyright (c) Cc-Policy Authors.
 // Licensed under the Apache 2.0 license.
 
+use crate::guest::GuestLayout;
+use crate::oci::bind_mount;
+
 use anyhow::Result;
 use oci_spec::runtime::{Process, Spec};
+use regex::Regex;
+use std::collections::HashMap;
 
 // The default image version of the pause container is based
 // on https://github.com/kubernetes/kubernetes/blob/release-1.23/cmd/kubeadm/app/constants/constants.go#L415
@@ -10,9 +15,151 @@ use oci_spec::runtime::{Process, Spec};
 // https://github.com/kata-containers/kata-containers/blob/CCv0/versions.yaml#L243
 pub const KUBERNETES_PAUSE_VERSION: &str = "3.6";
 pub const KUBERNETES_PAUSE_NAME: &str = "pause";
+// Entrypoint of the pause image, which has no Cmd
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.23/build/pause/Dockerfile#L20
+pub const KUBERNETES_PAUSE_ARGS: &[&str] = &["/pause"];
 pub const KUBERNETES_REGISTRY: &str = "registry.k8s.io";
 
-fn get_container_rules() -> Result<Spec> {
+const KUBERNETES_MASTER_SERVICE_PREFIX: &str = "KUBERNETES";
+pub const KUBERNETES_MASTER_SERVICE_NAME: &str = "kubernetes";
+pub const KUBERNETES_MASTER_SERVICE_NAMESPACE: &str = "default";
+
+const IPV4_ADDRESS_PATTERN: &str = r"((25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])";
+
+pub struct ServicePort {
+    pub name: String,
+    pub port: u16,
+    // Upper case, e.g., TCP
+    pub protocol: String,
+}
+
+// Service with the cluster IP set and at least one port, i.e., the services
+// that get environment variables
+pub struct Service {
+    pub name: String,
+    pub namespace: String,
+    pub ports: Vec<ServicePort>,
+}
+
+impl Service {
+    pub fn is_master(&self) -> bool {
+        self.name == KUBERNETES_MASTER_SERVICE_NAME
+            && self.namespace == KUBERNETES_MASTER_SERVICE_NAMESPACE
+    }
+}
+
+// Return the rules of the environment variables that allow the container to find services.
+// When enableServiceLinks is false, only the variables of the master service (i.e., the
+// kubernetes service in the default namespace) are added.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/envvars/envvars.go#L32
+// https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L576
+fn get_service_env_patterns(enable_service_links: bool) -> Vec<String> {
+    let name = if enable_service_links {
+        "[A-Z0-9_]+"
+    } else {
+        KUBERNETES_MASTER_SERVICE_PREFIX
+    };
+
+    vec![
+        ["^", name, "_SERVICE_HOST=", IPV4_ADDRESS_PATTERN, "$"].concat(),
+        ["^", name, "_SERVICE_PORT=[0-9]+$"].concat(),
+        ["^", name, "_SERVICE_PORT_[A-Z0-9_]+=[0-9]+$"].concat(),
+        [
+            "^",
+            name,
+            "_PORT=[a-z]+://",
+            IPV4_ADDRESS_PATTERN,
+            ":[0-9]+$",
+        ]
+        .concat(),
+        [
+            "^",
+            name,
+            "_PORT_[0-9]+_[A-Z]+=[a-z]+://",
+            IPV4_ADDRESS_PATTERN,
+            ":[0-9]+$",
+        ]
+        .concat(),
+        ["^", name, "_PORT_[0-9]+_[A-Z]+_PROTO=[a-z]+$"].concat(),
+        ["^", name, "_PORT_[0-9]+_[A-Z]+_PORT=[0-9]+$"].concat(),
+        [
+            "^",
+            name,
+            "_PORT_[0-9]+_[A-Z]+_ADDR=",
+            IPV4_ADDRESS_PATTERN,
+            "$",
+        ]
+        .concat(),
+    ]
+}
+
+fn make_env_variable_name(name: &str) -> String {
+    name.replace('-', "_").to_uppercase()
+}
+
+// Return the exact rules of the environment variables for the given services, following
+// FromServices and makeLinkVariables of the kubelet. The cluster IP is matched by a regex
+// given that it changes when the service is recreated.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/envvars/envvars.go#L32
+fn get_service_env_rules(services: &[Service], enable_service_links: bool) -> Vec<String> {
+    let mut env = Vec::new();
+
+    for service in services {
+        if !enable_service_links && !service.is_master() {
+            continue;
+        }
+
+        // Checked by the caller
+        let first = &service.ports[0];
+        let prefix = make_env_variable_name(&service.name);
+
+        env.push(format!(
+            "^{}_SERVICE_HOST={}$",
+            prefix, IPV4_ADDRESS_PATTERN
+        ));
+        env.push(format!("^{}_SERVICE_PORT={}$", prefix, first.port));
+
+        for port in &service.ports {
+            if !port.name.is_empty() {
+                env.push(format!(
+                    "^{}_SERVICE_PORT_{}={}$",
+                    prefix,
+                    make_env_variable_name(&port.name),
+                    port.port
+                ));
+            }
+        }
+
+        env.push(format!(
+            "^{}_PORT={}://{}:{}$",
+            prefix,
+            first.protocol.to_lowercase(),
+            IPV4_ADDRESS_PATTERN,
+            first.port
+        ));
+
+        for port in &service.ports {
+            let protocol = port.protocol.to_lowercase();
+            let port_prefix = format!("{}_PORT_{}_{}", prefix, port.port, port.protocol);
+
+            env.push(format!(
+                "^{}={}://{}:{}$",
+                port_prefix, protocol, IPV4_ADDRESS_PATTERN, port.port
+            ));
+            env.push(format!("^{}_PROTO={}$", port_prefix, protocol));
+            env.push(format!("^{}_PORT={}$", port_prefix, port.port));
+            env.push(format!("^{}_ADDR={}$", port_prefix, IPV4_ADDRESS_PATTERN));
+        }
+    }
+
+    env
+}
+
+fn get_container_rules(
+    enable_service_links: bool,
+    services: Option<&[Service]>,
+    layout: &GuestLayout,
+) -> Result<Spec> {
     let mut spec: Spec = serde_json::from_str("{}")?;
 
     // Initialize with necessary fields
@@ -26,50 +173,28 @@ fn get_container_rules() -> Result<Spec> {
     }"#,
     )?;
 
-    // Add environment variables that allow the container to find services
-    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/envvars/envvars.go#L32
-    let env = [
-        "^[A-Z0-9_]+_SERVICE_HOST=^((25[0-5]|(2[0-4]|1\\d|[1-9]|)\\d).?\\b){4}$",
-        "^[A-Z0-9_]+_SERVICE_PORT=[0-9]+",
-        "^[A-Z0-9_]+_SERVICE_PORT_[A-Z]+=[0-9]+",
-        "^[A-Z0-9_]+_PORT=[a-z]+://^((25[0-5]|(2[0-4]|1\\d|[1-9]|)\\d).?\\b){4}:[0-9]+",
-        "^[A-Z0-9_]+_PORT_[0-9]+_[A-Z]+=[a-z]+://^((25[0-5]|(2[0-4]|1\\d|[1-9]|)\\d).?\\b){4}:[0-9]+",
-        "^[A-Z0-9_]+_PORT_[0-9]+_[A-Z]+_PROTO=[a-z]+",
-        "^[A-Z0-9_]+_PORT_[0-9]+_[A-Z]+_PORT=[0-9]+",
-        "^[A-Z0-9_]+_PORT_[0-9]+_[A-Z]+_ADDR=^((25[0-5]|(2[0-4]|1\\d|[1-9]|)\\d).?\\b){4}$" 
-    ].map(String::from).to_vec();
+    let env = match services {
+        Some(services) => get_service_env_rules(services, enable_service_links),
+        None => get_service_env_patterns(enable_service_links),
+    };
 
     process.set_env(Some(env));
 
     spec.set_process(Some(process));
 
     // TODO: Add reference
-    let mounts = serde_json::from_str(
-        r#"
-    [
-        {
-            "destination": "/dev/termination-log",
-            "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-termination-log$",
-            "type": "bind",
-            "options": [
-                "rbind",
-                "rprivate",
-                "rw"
-            ]
-        },
-        {
-            "destination": "/var/run/secrets/kubernetes.io/serviceaccount",
-            "source": "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-serviceaccount$",
-            "type": "bind",
-            "options": [
-                "rbind",
-                "rprivate",
-                "ro"
-            ]
-        }
-    ]
-    "#,
-    )?;
+    let mounts = vec![
+        bind_mount(
+            "/dev/termination-log",
+            &layout.get_shared_source("termination-log"),
+            &["rbind", "rprivate", "rw"],
+        ),
+        bind_mount(
+            "/var/run/secrets/kubernetes.io/serviceaccount",
+            &layout.get_shared_source("serviceaccount"),
+            &["rbind", "rprivate", "ro"],
+        ),
+    ];
 
     spec.set_mounts(Some(mounts));
 
@@ -83,9 +208,15 @@ fn get_sandbox_rules() -> Result<Spec> {
     Ok(spec)
 }
 
-pub fn get_rules(is_sandbox: bool) -> Result<Spec> {
+// Use the exact rules of the given services if any, the generic ones otherwise
+pub fn get_rules(
+    is_sandbox: bool,
+    enable_service_links: bool,
+    services: Option<&[Service]>,
+    layout: &GuestLayout,
+) -> Result<Spec> {
     if !is_sandbox {
-        get_container_rules()
+        get_container_rules(enable_service_links, services, layout)
     } else {
         get_sandbox_rules()
     }
@@ -101,3 +232,266 @@ pub fn get_pause_image_ref() -> String {
     ]
     .concat()
 }
+
+// Value of an env variable that the $(VAR) references expand to
+#[derive(Clone)]
+pub enum EnvValue {
+    Literal(String),
+    // Only known at runtime, e.g., from the downward API or a secret
+    Runtime,
+}
+
+// Env of a container, in the order the kubelet builds it, i.e., the variables of the services
+// first, then the ones of envFrom, and the ones of env last, the later ones overriding the
+// earlier ones.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L620
+#[derive(Default)]
+pub struct EnvValues {
+    values: HashMap<String, EnvValue>,
+    // Names of the variables whose values and existence are only known at runtime, e.g.,
+    // the ones of a Secret of envFrom, by regex
+    runtime_names: Vec<Regex>,
+}
+
+impl EnvValues {
+    // The variables of the services of the cluster, see get_service_env_patterns
+    pub fn with_service_links(enable_service_links: bool) -> Result<EnvValues> {
+        let mut values = EnvValues::default();
+
+        for pattern in get_service_env_patterns(enable_service_links) {
+            if let Some((name, _)) = pattern.split_once('=') {
+                values.insert_runtime_names(&[name, "$"].concat())?;
+            }
+        }
+
+        Ok(values)
+    }
+
+    pub fn insert(&mut self, name: String, value: EnvValue) {
+        self.values.insert(name, value);
+    }
+
+    // Every variable with a name matched by the regex, overriding the previous ones
+    pub fn insert_runtime_names(&mut self, pattern: &str) -> Result<()> {
+        let regex = Regex::new(pattern)?;
+
+        self.values.retain(|name, _| !regex.is_match(name));
+        self.runtime_names.push(regex);
+
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<EnvValue> {
+        match self.values.get(name) {
+            Some(value) => Some(value.clone()),
+            None if self.runtime_names.iter().any(|regex| regex.is_match(name)) => {
+                Some(EnvValue::Runtime)
+            }
+            None => None,
+        }
+    }
+}
+
+// Expand the $(VAR) references of the command and args of a container like the kubelet does,
+// i.e., $$ is an escaped $, and the references to undefined variables are kept as is.
+// Return the exact arg, or a regex with .* in place of the references to runtime values
+// along with true.
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/third_party/forked/golang/expansion/expand.go#L50
+pub fn expand(input: &str, env: &EnvValues) -> (String, bool) {
+    // Literal parts of the result, split at the references to runtime values
+    let mut parts = vec![String::new()];
+    let mut rest = input;
+
+    while let Some(index) = rest.find('$') {
+        let part = parts.last_mut().unwrap();
+        part.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+
+        match rest.chars().next() {
+            // A trailing $ is kept as is
+            None => part.push('$'),
+            Some('$') => {
+                part.push('$');
+                rest = &rest[1..];
+            }
+            Some('(') => match rest.find(')') {
+                Some(end) => {
+                    let name = &rest[1..end];
+                    match env.get(name) {
+                        Some(EnvValue::Literal(value)) => part.push_str(&value),
+                        Some(EnvValue::Runtime) => parts.push(String::new()),
+                        None => part.push_str(&["$(", name, ")"].concat()),
+                    }
+                    rest = &rest[end + 1..];
+                }
+                // An unclosed reference is kept as is
+                None => {
+                    part.push_str("$(");
+                    rest = &rest[1..];
+                }
+            },
+            Some(_) => part.push('$'),
+        }
+    }
+
+    parts.last_mut().unwrap().push_str(rest);
+
+    if parts.len() == 1 {
+        return (parts.remove(0), false);
+    }
+
+    let parts: Vec<String> = parts.iter().map(|part| regex::escape(part)).collect();
+
+    (["^", &parts.join(".*"), "$"].concat(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern;
+
+    fn matches_any(rules: &[String], value: &str) -> bool {
+        rules
+            .iter()
+            .any(|rule| pattern::matches(rule, value).unwrap())
+    }
+
+    #[test]
+    fn test_service_env_patterns_are_valid() {
+        for enable_service_links in [false, true] {
+            for rule in get_service_env_patterns(enable_service_links) {
+                assert!(pattern::is_regex(&rule), "{}", rule);
+                assert!(pattern::check(&rule).unwrap().is_empty(), "{}", rule);
+            }
+        }
+    }
+
+    #[test]
+    fn test_service_env_patterns() {
+        let master = get_service_env_patterns(false);
+        let all = get_service_env_patterns(true);
+
+        for value in [
+            "KUBERNETES_SERVICE_HOST=10.0.0.1",
+            "KUBERNETES_SERVICE_PORT=443",
+            "KUBERNETES_SERVICE_PORT_HTTPS=443",
+            "KUBERNETES_PORT=tcp://10.0.0.1:443",
+            "KUBERNETES_PORT_443_TCP=tcp://10.0.0.1:443",
+            "KUBERNETES_PORT_443_TCP_PROTO=tcp",
+            "KUBERNETES_PORT_443_TCP_PORT=443",
+            "KUBERNETES_PORT_443_TCP_ADDR=10.0.0.1",
+        ] {
+            assert!(matches_any(&master, value), "{}", value);
+            assert!(matches_any(&all, value), "{}", value);
+        }
+
+        assert!(!matches_any(&master, "MY_SVC_SERVICE_HOST=10.0.0.2"));
+        assert!(matches_any(&all, "MY_SVC_SERVICE_HOST=10.0.0.2"));
+
+        assert!(!matches_any(&all, "KUBERNETES_SERVICE_HOST=256.0.0.1"));
+        assert!(!matches_any(&all, "KUBERNETES_SERVICE_HOST=10.0.0.1; id"));
+        assert!(!matches_any(&all, "LD_PRELOAD=/tmp/x.so"));
+    }
+
+    #[test]
+    fn test_service_env_rules() {
+        let services = [
+            Service {
+                name: "my-svc".to_string(),
+                namespace: "app".to_string(),
+                ports: vec![ServicePort {
+                    name: "http".to_string(),
+                    port: 80,
+                    protocol: "TCP".to_string(),
+                }],
+            },
+            Service {
+                name: KUBERNETES_MASTER_SERVICE_NAME.to_string(),
+                namespace: KUBERNETES_MASTER_SERVICE_NAMESPACE.to_string(),
+                ports: vec![ServicePort {
+                    name: "https".to_string(),
+                    port: 443,
+                    protocol: "TCP".to_string(),
+                }],
+            },
+        ];
+
+        let rules = get_service_env_rules(&services, true);
+        for rule in &rules {
+            assert!(pattern::check(rule).unwrap().is_empty(), "{}", rule);
+        }
+
+        for value in [
+            "MY_SVC_SERVICE_HOST=10.0.0.2",
+            "MY_SVC_SERVICE_PORT=80",
+            "MY_SVC_SERVICE_PORT_HTTP=80",
+            "MY_SVC_PORT=tcp://10.0.0.2:80",
+            "MY_SVC_PORT_80_TCP=tcp://10.0.0.2:80",
+            "MY_SVC_PORT_80_TCP_PROTO=tcp",
+            "MY_SVC_PORT_80_TCP_PORT=80",
+            "MY_SVC_PORT_80_TCP_ADDR=10.0.0.2",
+            "KUBERNETES_SERVICE_PORT=443",
+        ] {
+            assert!(matches_any(&rules, value), "{}", value);
+        }
+        assert!(!matches_any(&rules, "MY_SVC_SERVICE_PORT=81"));
+
+        // Only the master service without the service links
+        let rules = get_service_env_rules(&services, false);
+        assert!(!matches_any(&rules, "MY_SVC_SERVICE_PORT=80"));
+        assert!(matches_any(&rules, "KUBERNETES_SERVICE_PORT=443"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let mut env = EnvValues::default();
+        env.insert("A".to_string(), EnvValue::Literal("a+".to_string()));
+        env.insert("R".to_string(), EnvValue::Runtime);
+
+        assert_eq!(expand("x$(A)y", &env), ("xa+y".to_string(), false));
+        assert_eq!(expand("$$(A)", &env), ("$(A)".to_string(), false));
+        assert_eq!(expand("$(B)", &env), ("$(B)".to_string(), false));
+        assert_eq!(expand("$(A", &env), ("$(A".to_string(), false));
+        assert_eq!(expand("a$", &env), ("a$".to_string(), false));
+        assert_eq!(
+            expand("key=$(R)/$(A)", &env),
+            ("^key=.*/a\\+$".to_string(), true)
+        );
+    }
+    #[test]
+    fn test_expand_runtime_names() {
+        let mut env = EnvValues::with_service_links(false).unwrap();
+        env.insert(
+            "DB_USER".to_string(),
+            EnvValue::Literal("admin".to_string()),
+        );
+        env.insert("APP".to_string(), EnvValue::Literal("app".to_string()));
+
+        assert_eq!(
+            expand(
+                "$(KUBERNETES_SERVICE_HOST):$(KUBERNETES_SERVICE_PORT)",
+                &env
+            ),
+            ("^.*:.*$".to_string(), true)
+        );
+        assert_eq!(
+            expand("$(MY_SVC_SERVICE_HOST)", &env),
+            ("$(MY_SVC_SERVICE_HOST)".to_string(), false)
+        );
+
+        // A Secret of envFrom overrides the previous variables with its prefix
+        env.insert_runtime_names("^DB_").unwrap();
+        assert_eq!(expand("$(DB_USER)", &env), ("^.*$".to_string(), true));
+        assert_eq!(expand("$(APP)", &env), ("app".to_string(), false));
+
+        // env overrides envFrom
+        env.insert("DB_USER".to_string(), EnvValue::Literal("root".to_string()));
+        assert_eq!(expand("$(DB_USER)", &env), ("root".to_string(), false));
+
+        let env = EnvValues::with_service_links(true).unwrap();
+        assert_eq!(
+            expand("$(MY_SVC_SERVICE_HOST)", &env),
+            ("^.*$".to_string(), true)
+        );
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::recording;
+use crate::recording::Mode;
+
+use anyhow::{bail, Context, Result};
+use oci_spec::image::{Descriptor, ImageConfiguration, ImageManifest};
+use serde::{Deserialize, Serialize};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Media type suffix of the layers encrypted by ocicrypt, e.g.,
+// application/vnd.oci.image.layer.v1.tar+gzip+encrypted
+// Reference: https://github.com/containers/ocicrypt/blob/main/spec/spec.md
+const ENCRYPTED_SUFFIX: &str = "+encrypted";
+
+// Annotations carrying the wrapped symmetric key of the layer, one per key wrapping scheme, e.g.,
+// org.opencontainers.image.enc.keys.provider.attestation-agent
+const ENC_KEYS_ANNOTATION_PREFIX: &str = "org.opencontainers.image.enc.keys.";
+
+const KEY_PROVIDER_TIMEOUT_SECS: u64 = 5;
+
+// Media type prefixes of regular tar layers (uncompressed, gzip, or zstd)
+const OCI_LAYER_PREFIX: &str = "application/vnd.oci.image.layer.";
+const DOCKER_LAYER_PREFIX: &str = "application/vnd.docker.image.rootfs.";
+
+// Nydus images consist of RAFS blobs plus a bootstrap layer holding the filesystem metadata
+// Reference: https://github.com/containerd/nydus-snapshotter/blob/main/pkg/label/label.go
+const NYDUS_BLOB_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.nydus.blob.v1";
+const NYDUS_BOOTSTRAP_ANNOTATION: &str = "containerd.io/snapshot/nydus-bootstrap";
+
+// zstd:chunked layers are regular tar+zstd layers with an appended table of contents (TOC),
+// which is what partial pulls verify the reconstructed layer against
+// Reference: https://github.com/containers/storage/blob/main/pkg/chunked/internal/compression.go
+const ZSTD_CHUNKED_TOC_ANNOTATION: &str = "io.github.containers.zstd-chunked.manifest-checksum";
+
+#[derive(PartialEq, Eq)]
+enum LayerFormat {
+    Tar,
+    ZstdChunked,
+    Nydus,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EncryptedLayer {
+    // Digest of the encrypted blob referenced by the manifest
+    pub digest: String,
+    pub media_type: String,
+    // Digest of the decrypted and uncompressed layer
+    pub diff_id: String,
+    // Key wrapping schemes that the guest needs to support to decrypt the layer, e.g., jwe
+    // or provider.attestation-agent
+    pub keys: Vec<String>,
+}
+
+fn get_annotation<'a>(layer: &'a Descriptor, key: &str) -> Option<&'a String> {
+    match layer.annotations() {
+        Some(annotations) => annotations.get(key),
+        None => None,
+    }
+}
+
+fn get_layer_format(layer: &Descriptor) -> LayerFormat {
+    let media_type = layer.media_type().to_string();
+    let media_type = media_type.trim_end_matches(ENCRYPTED_SUFFIX);
+
+    if media_type == NYDUS_BLOB_MEDIA_TYPE
+        || get_annotation(layer, NYDUS_BOOTSTRAP_ANNOTATION).is_some()
+    {
+        LayerFormat::Nydus
+    } else if get_annotation(layer, ZSTD_CHUNKED_TOC_ANNOTATION).is_some() {
+        LayerFormat::ZstdChunked
+    } else if media_type.starts_with(OCI_LAYER_PREFIX)
+        || media_type.starts_with(DOCKER_LAYER_PREFIX)
+    {
+        LayerFormat::Tar
+    } else {
+        LayerFormat::Unknown
+    }
+}
+
+// Pair each layer of the manifest with its diff_id from the image config
+fn get_layer_pairs<'a>(
+    image_config: &'a ImageConfiguration,
+    manifest: &'a ImageManifest,
+) -> Result<Vec<(&'a Descriptor, &'a String)>> {
+    let diff_ids = image_config.rootfs().diff_ids();
+    let layers = manifest.layers();
+
+    if diff_ids.len() != layers.len() {
+        bail!(
+            "{}: the image config has {} diff_ids while the manifest has {} layers",
+            loc!(),
+            diff_ids.len(),
+            layers.len()
+        );
+    }
+
+    Ok(layers.iter().zip(diff_ids).collect())
+}
+
+// Return the integrity value of each layer in the order they are stacked:
+// - Regular tar layers: the digest of the uncompressed layer (diff_id)
+// - zstd:chunked layers: the digest of the TOC, given that the layer may be reconstructed
+//   from chunks without the digest of the uncompressed tar stream being computed
+// Nydus layers are mounted as RAFS rather than unpacked, so their integrity cannot be derived
+// from the diff_ids and generating the policy fails instead of recording wrong values.
+pub fn get_layers(
+    image_config: &ImageConfiguration,
+    manifest: &ImageManifest,
+) -> Result<Vec<String>> {
+    let mut results = Vec::new();
+
+    for (layer, diff_id) in get_layer_pairs(image_config, manifest)? {
+        match get_layer_format(layer) {
+            LayerFormat::Tar => results.push(diff_id.clone()),
+            LayerFormat::ZstdChunked => {
+                // Checked by get_layer_format
+                let toc_digest = get_annotation(layer, ZSTD_CHUNKED_TOC_ANNOTATION).unwrap();
+                results.push(toc_digest.clone());
+            }
+            LayerFormat::Nydus => {
+                bail!(
+                    "{}: layer {} is a nydus layer, which is not supported",
+                    loc!(),
+                    layer.digest()
+                );
+            }
+            LayerFormat::Unknown => {
+                bail!(
+                    "{}: layer {} has unsupported media type {}",
+                    loc!(),
+                    layer.digest(),
+                    layer.media_type()
+                );
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+pub fn get_encrypted_layers(
+    image_config: &ImageConfiguration,
+    manifest: &ImageManifest,
+) -> Result<Vec<EncryptedLayer>> {
+    let mut results = Vec::new();
+
+    for (layer, diff_id) in get_layer_pairs(image_config, manifest)? {
+        let media_type = layer.media_type().to_string();
+
+        if !media_type.ends_with(ENCRYPTED_SUFFIX) {
+            continue;
+        }
+
+        let mut keys: Vec<String> = match layer.annotations() {
+            Some(annotations) => annotations
+                .keys()
+                .filter_map(|key| key.strip_prefix(ENC_KEYS_ANNOTATION_PREFIX))
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        if keys.is_empty() {
+            bail!(
+                "{}: encrypted layer {} does not have any wrapped key",
+                loc!(),
+                layer.digest()
+            );
+        }
+
+        keys.sort();
+
+        results.push(EncryptedLayer {
+            digest: layer.digest().to_string(),
+            media_type,
+            diff_id: diff_id.clone(),
+            keys,
+        });
+    }
+
+    Ok(results)
+}
+
+// The reachability does not change the policy, it is not checked when replaying a recording
+pub fn check_key_provider(address: &str) -> Result<()> {
+    if recording::get_mode() == Some(Mode::Replay) {
+        return Ok(());
+    }
+
+    let addrs = address
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve key provider {}", address))?;
+
+    for addr in addrs {
+        if TcpStream::connect_timeout(&addr, Duration::from_secs(KEY_PROVIDER_TIMEOUT_SECS)).is_ok()
+        {
+            return Ok(());
+        }
+    }
+
+    bail!("{}: key provider {} is not reachable", loc!(), address)
+}
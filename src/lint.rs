@@ -0,0 +1,463 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::cluster;
+use crate::cri::DEFAULT_HOSTNAME_ENV;
+use crate::kubernetes::KUBERNETES_PAUSE_NAME;
+use crate::oci::{normalize_destination, normalize_mount_options};
+use crate::pattern;
+use crate::policy::{CcPolicy, ANY_ARGS, ANY_ENV, ARGS_MATCH_REGEX, RELAXED_SANDBOX};
+use crate::render::OutputFormat;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Serialize)]
+struct Finding {
+    severity: Severity,
+    check: &'static str,
+    // Path of the field in the policy, e.g., containers.app.oci_spec.mounts[0].destination
+    path: String,
+    message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn add(&mut self, severity: Severity, check: &'static str, path: String, message: String) {
+        self.0.push(Finding {
+            severity,
+            check,
+            path,
+            message,
+        });
+    }
+}
+
+// Fields that are left out of the serialization when unset, see find_unknown_fields
+fn is_default(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Bool(value) => !value,
+        Value::Number(number) => number.as_i64() == Some(0),
+        Value::String(value) => value.is_empty(),
+        Value::Array(array) => array.is_empty(),
+        Value::Object(object) => object.is_empty(),
+    }
+}
+
+// The policy and the OCI spec ignore unknown fields, e.g., typos, which are found by comparing
+// the policy with its round trip through the types of the policy
+fn find_unknown_fields(input: &Value, parsed: &Value, path: &str, findings: &mut Findings) {
+    match (input, parsed) {
+        (Value::Object(input), Value::Object(parsed)) => {
+            for (key, value) in input {
+                let path = join_key(path, key);
+                match parsed.get(key) {
+                    Some(parsed) => find_unknown_fields(value, parsed, &path, findings),
+                    None if is_default(value) => {}
+                    None => findings.add(
+                        Severity::Warning,
+                        "unknown-field",
+                        path,
+                        format!("unknown field {}, it is ignored", key),
+                    ),
+                }
+            }
+        }
+        (Value::Array(input), Value::Array(parsed)) => {
+            for (index, (value, parsed)) in input.iter().zip(parsed).enumerate() {
+                find_unknown_fields(value, parsed, &format!("{}[{}]", path, index), findings);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn join_key(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn get_strings<'a>(value: &'a Value, path: &str) -> Vec<(String, &'a str)> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter_map(|(index, value)| Some((format!("{}[{}]", path, index), value.as_str()?)))
+        .collect()
+}
+
+// The rules checked as regexes by the generation, see ContainerPolicy::validate
+fn get_rules<'a>(container: &'a Value, path: &str) -> Vec<(String, &'a str)> {
+    let spec = &container["oci_spec"];
+    let spec_path = join_key(path, "oci_spec");
+
+    let mut results = get_strings(
+        &spec["process"]["env"],
+        &join_key(&spec_path, "process.env"),
+    );
+
+    let args_match = &container["custom"]["args_match"];
+    let args = spec["process"]["args"].as_array();
+    for (index, arg) in args.into_iter().flatten().enumerate() {
+        if let (Some(arg), Some(ARGS_MATCH_REGEX)) = (arg.as_str(), args_match[index].as_str()) {
+            results.push((format!("{}.process.args[{}]", spec_path, index), arg));
+        }
+    }
+
+    if let Some(annotations) = spec["annotations"].as_object() {
+        for (key, value) in annotations {
+            if let Some(value) = value.as_str() {
+                results.push((join_key(&spec_path, &["annotations.", key].concat()), value));
+            }
+        }
+    }
+
+    for (index, mount) in spec["mounts"].as_array().into_iter().flatten().enumerate() {
+        if let Some(source) = mount["source"].as_str() {
+            results.push((format!("{}.mounts[{}].source", spec_path, index), source));
+        }
+    }
+
+    results
+}
+
+fn check_rules(container: &Value, path: &str, findings: &mut Findings) {
+    for (path, rule) in get_rules(container, path) {
+        if !pattern::is_regex(rule) {
+            continue;
+        }
+
+        match pattern::check(rule) {
+            Ok(warnings) => {
+                for warning in warnings {
+                    findings.add(Severity::Warning, "regex", path.clone(), warning);
+                }
+            }
+            Err(e) => findings.add(Severity::Error, "regex", path, e.to_string()),
+        }
+    }
+}
+
+fn check_destinations(container: &Value, path: &str, findings: &mut Findings) {
+    let mut destinations: BTreeMap<PathBuf, usize> = BTreeMap::new();
+
+    let mounts = container["oci_spec"]["mounts"].as_array();
+    for (index, mount) in mounts.into_iter().flatten().enumerate() {
+        let path = format!("{}.oci_spec.mounts[{}].destination", path, index);
+
+        let destination = match mount["destination"].as_str() {
+            Some(destination) => destination,
+            None => continue,
+        };
+
+        let destination = match normalize_destination(Path::new(destination)) {
+            Ok(destination) => destination,
+            Err(e) => {
+                findings.add(Severity::Error, "destination", path, e.to_string());
+                continue;
+            }
+        };
+
+        if let Some(first) = destinations.get(&destination) {
+            findings.add(
+                Severity::Error,
+                "duplicate-destination",
+                path,
+                format!(
+                    "mount destination {} is also the one of mounts[{}]",
+                    destination.display(),
+                    first
+                ),
+            );
+        } else {
+            destinations.insert(destination, index);
+        }
+    }
+}
+
+// The enforcers compare the mount options literally, see normalize_mount_options
+fn check_mount_options(container: &Value, path: &str, findings: &mut Findings) {
+    let mounts = container["oci_spec"]["mounts"].as_array();
+    for (index, mount) in mounts.into_iter().flatten().enumerate() {
+        let options: Vec<String> = get_strings(&mount["options"], "")
+            .into_iter()
+            .map(|(_, option)| option.to_string())
+            .collect();
+
+        let normalized = normalize_mount_options(&options);
+        if normalized != options {
+            findings.add(
+                Severity::Warning,
+                "mount-options",
+                format!("{}.oci_spec.mounts[{}].options", path, index),
+                format!(
+                    "mount options are not in the canonical order, expecting {:?}",
+                    normalized
+                ),
+            );
+        }
+    }
+}
+
+// Env rules matching any value of the variable, e.g., the ones of secretKeyRef and of envFrom
+// secrets. The hostname is only known once the pod is scheduled.
+fn is_env_wildcard(rule: &str) -> bool {
+    if !pattern::is_regex(rule) || rule == DEFAULT_HOSTNAME_ENV || rule == ANY_ENV {
+        return false;
+    }
+
+    match rule.split_once('=') {
+        Some((_, value)) => matches!(value, "." | ".*" | ".+" | ".*$" | ".+$"),
+        None => false,
+    }
+}
+
+// Rule loosening the policy, described as "<kind> <rule> <reason>", e.g., "env rule ^A=.*$
+// matches any value"
+#[derive(Clone, Serialize)]
+pub struct LooseRule {
+    // Path of the rule in the policy, e.g., containers.app.oci_spec.process.env[0]
+    pub path: String,
+    // Empty for the relaxed rules
+    pub kind: &'static str,
+    pub rule: String,
+    pub reason: &'static str,
+}
+
+impl LooseRule {
+    fn new(path: String, kind: &'static str, rule: &str, reason: &'static str) -> LooseRule {
+        LooseRule {
+            path,
+            kind,
+            rule: rule.to_string(),
+            reason,
+        }
+    }
+}
+
+impl fmt::Display for LooseRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.kind.is_empty() {
+            write!(f, "{} {}", self.rule, self.reason)
+        } else {
+            write!(f, "{} {} {}", self.kind, self.rule, self.reason)
+        }
+    }
+}
+
+// Rules loosening the policy: the rules relaxed by the allow-any flags and the sandbox strategy
+// none, the env wildcards, the regex args, and the writable mounts of the paths of the node
+// (hostPath and local PersistentVolume volumes)
+pub fn get_loose_rules(policy: &Value) -> Vec<LooseRule> {
+    let mut results = Vec::new();
+
+    let relaxed_rules = get_strings(
+        &policy["metadata"]["relaxed_rules"],
+        "metadata.relaxed_rules",
+    );
+
+    // The policies generated before the sandbox strategy was recorded in the relaxed rules
+    if policy["metadata"]["sandbox"].as_str() == Some("none")
+        && !relaxed_rules
+            .iter()
+            .any(|(_, rule)| *rule == RELAXED_SANDBOX)
+    {
+        results.push(LooseRule::new(
+            "metadata.sandbox".to_string(),
+            "",
+            RELAXED_SANDBOX,
+            "rules are relaxed",
+        ));
+    }
+
+    for (path, rule) in relaxed_rules {
+        results.push(LooseRule::new(path, "", rule, "rules are relaxed"));
+    }
+
+    for (name, container) in policy["containers"].as_object().into_iter().flatten() {
+        let spec_path = join_key(&join_key("containers", name), "oci_spec");
+        let spec = &container["oci_spec"];
+
+        let env = get_strings(
+            &spec["process"]["env"],
+            &join_key(&spec_path, "process.env"),
+        );
+        for (path, rule) in env {
+            if is_env_wildcard(rule) {
+                results.push(LooseRule::new(path, "env rule", rule, "matches any value"));
+            }
+        }
+
+        // The args relaxed by --allow-any-args are counted as a relaxed rule
+        let args = get_strings(&spec["process"]["args"], "");
+        let args_match = get_strings(
+            &container["custom"]["args_match"],
+            &join_key(&join_key("containers", name), "custom.args_match"),
+        );
+        for ((path, args_match), (_, arg)) in args_match.into_iter().zip(args) {
+            if args_match == ARGS_MATCH_REGEX && arg != ANY_ARGS {
+                results.push(LooseRule::new(path, "arg", arg, "is matched as a regex"));
+            }
+        }
+
+        for (index, mount) in spec["mounts"].as_array().into_iter().flatten().enumerate() {
+            let source = mount["source"].as_str().unwrap_or_default();
+            let writable = get_strings(&mount["options"], "")
+                .iter()
+                .any(|(_, option)| *option == "rw");
+            let node_path = source.starts_with('/') || cluster::is_local_volume_source(source);
+
+            if mount["type"].as_str() == Some("bind") && node_path && writable {
+                results.push(LooseRule::new(
+                    format!("{}.mounts[{}]", spec_path, index),
+                    "path",
+                    source,
+                    "of the node is writable",
+                ));
+            }
+        }
+    }
+
+    results
+}
+
+fn check_loose_rules(policy: &Value, max_loose_rules: usize, findings: &mut Findings) {
+    let loose_rules = get_loose_rules(policy);
+    let count = loose_rules.len();
+    if count <= max_loose_rules {
+        return;
+    }
+
+    for loose_rule in loose_rules {
+        let message = loose_rule.to_string();
+        findings.add(Severity::Warning, "loose-rules", loose_rule.path, message);
+    }
+
+    findings.add(
+        Severity::Error,
+        "loose-rules",
+        String::new(),
+        format!(
+            "the policy has {} loose rules, more than the maximum of {}",
+            count, max_loose_rules
+        ),
+    );
+}
+
+fn lint(content: &str, sandbox: bool, max_loose_rules: Option<usize>) -> Findings {
+    let mut findings = Findings::default();
+
+    let input: Value = match serde_json::from_str(content) {
+        Ok(input) => input,
+        Err(e) => {
+            findings.add(Severity::Error, "syntax", String::new(), e.to_string());
+            return findings;
+        }
+    };
+
+    // The errors of serde_json are located by line and column
+    match serde_json::from_str::<CcPolicy>(content) {
+        Ok(policy) => match serde_json::to_value(&policy) {
+            Ok(parsed) => find_unknown_fields(&input, &parsed, "", &mut findings),
+            Err(e) => findings.add(Severity::Error, "schema", String::new(), e.to_string()),
+        },
+        Err(e) => findings.add(Severity::Error, "schema", String::new(), e.to_string()),
+    }
+
+    let containers = input["containers"].as_object();
+
+    if sandbox
+        && !containers.is_some_and(|containers| containers.contains_key(KUBERNETES_PAUSE_NAME))
+    {
+        findings.add(
+            Severity::Error,
+            "missing-sandbox",
+            "containers".to_string(),
+            format!(
+                "no {} container, the sandbox of the pod is not allowed",
+                KUBERNETES_PAUSE_NAME
+            ),
+        );
+    }
+
+    for (name, container) in containers.into_iter().flatten() {
+        let path = join_key("containers", name);
+        check_rules(container, &path, &mut findings);
+        check_destinations(container, &path, &mut findings);
+        // Only the policies generated with --normalize-mount-options are in the canonical order
+        if input["metadata"]["normalized_mount_options"] == true {
+            check_mount_options(container, &path, &mut findings);
+        }
+    }
+
+    if let Some(max_loose_rules) = max_loose_rules {
+        check_loose_rules(&input, max_loose_rules, &mut findings);
+    }
+
+    findings
+}
+
+// Check a hand-edited policy. The policies of images (e.g., --image_ref) have no sandbox.
+pub fn lint_policy(
+    path: &Path,
+    sandbox: bool,
+    max_loose_rules: Option<usize>,
+    output: &OutputFormat,
+) -> Result<()> {
+    let content = read_to_string(path)
+        .with_context(|| format!("failed to read policy {}", path.display()))?;
+
+    let findings = lint(&content, sandbox, max_loose_rules);
+
+    match output {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&findings.0)?),
+        OutputFormat::Text => {
+            for finding in &findings.0 {
+                let severity = match finding.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                };
+
+                if finding.path.is_empty() {
+                    println!("{}: {} [{}]", severity, finding.message, finding.check);
+                } else {
+                    println!(
+                        "{}: {}: {} [{}]",
+                        severity, finding.path, finding.message, finding.check
+                    );
+                }
+            }
+        }
+    }
+
+    let errors = findings
+        .0
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+
+    if errors > 0 {
+        bail!("{} has {} errors", path.display(), errors);
+    }
+
+    Ok(())
+}
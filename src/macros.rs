@@ -7,3 +7,20 @@ macro_rules! loc {
         concat!(file!(), " line ", line!(), " column ", column!())
     };
 }
+
+// Informational output on stdout, suppressed by --quiet
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::annotation::warning(&format!($($arg)*))
+    };
+}
@@ -3,27 +3,227 @@
 
 #[macro_use]
 mod macros;
+mod affinity;
+mod annotation;
+mod canonical;
+mod catalog;
+mod cluster;
+mod compat;
+mod config;
 mod cri;
+mod delivery;
+mod diff;
+mod document;
+mod guest;
 mod image;
+mod init;
+mod ir;
 mod kubernetes;
+mod layer;
+mod lint;
+mod node_pool;
 mod oci;
+mod overlay;
+mod pattern;
+mod plugin;
 mod pod_yaml;
 mod policy;
+mod preflight;
+mod preset;
+mod profile;
+mod provenance;
+mod ratelimit;
+mod recording;
+mod registry;
+mod render;
+mod report;
+mod roundtrip;
+mod scalar;
+mod sidecar;
+mod span;
+mod subprocess;
+mod summary;
+mod target;
+mod template;
 
+use annotation::DiagnosticsFormat;
+use catalog::Catalog;
+use compat::AgentApi;
+use config::Config;
+use delivery::Delivery;
+use document::Document;
+use overlay::Overlay;
+use plugin::{ExecPlugin, RulePlugin};
 use pod_yaml::*;
 use policy::*;
+use preset::Preset;
+use provenance::Statement;
+use render::OutputFormat;
+use sidecar::SidecarProfile;
+use span::Spans;
+use summary::SummaryFormat;
+use target::Target;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::{read_to_string, File};
+use std::io;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
-use serde::{Deserialize, Serialize};
+// Set by --quiet, see the info macro
+pub static QUIET: AtomicBool = AtomicBool::new(false);
 
-#[derive(Parser)]
+// Distinguishes the temporary files of write_with, whose threads share the pid
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Subcommand)]
+enum CatalogCommand {
+    /// Add the images listed in the file to the catalog and generate their policies
+    Build {
+        /// File with one image ref per line, - for stdin
+        #[clap(long = "file", default_value = "-")]
+        file: PathBuf,
+    },
+    /// Print the policy of the image in the catalog
+    Lookup { image_ref: String },
+}
+
+#[derive(Clone, Subcommand)]
+enum ProfileCommand {
+    /// Print the default rules of the profile after the overrides of the config
+    Show {
+        name: String,
+        /// Node pool profile defined in the config
+        #[clap(long = "node-pool")]
+        node_pool: Option<String>,
+    },
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Scaffold a policy project with a config, a rules overlay, profiles, and golden tests
+    Init {
+        #[clap(default_value = ".")]
+        path: PathBuf,
+    },
+    /// Compare two policies and summarize the differences per container
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+        /// Do not colorize the output
+        #[clap(long = "no-color")]
+        no_color: bool,
+        /// Output format, text for humans or json for automation
+        #[clap(long = "output", arg_enum, default_value = "text")]
+        output: OutputFormat,
+    },
+    /// Generate the policies of two versions of a manifest and report the differences of the
+    /// rules, e.g., to review the security impact of a manifest change
+    DiffManifests {
+        old: PathBuf,
+        new: PathBuf,
+        /// Do not colorize the output
+        #[clap(long = "no-color")]
+        no_color: bool,
+        /// Output format, text for humans or json for automation
+        #[clap(long = "output", arg_enum, default_value = "text")]
+        output: OutputFormat,
+    },
+    /// Publish the policy as an OCI artifact referring to the workload image
+    Push {
+        #[clap(short = 'p', long = "policy")]
+        policy: PathBuf,
+        /// Image the policy is attached to
+        #[clap(long = "subject")]
+        subject: String,
+    },
+    /// Generate one policy per image ref, e.g., for a catalog of curated images
+    Images {
+        /// File with one image ref per line, - for stdin
+        #[clap(long = "file", default_value = "-")]
+        file: PathBuf,
+        /// Directory the policies are written to
+        #[clap(long = "output-dir")]
+        output_dir: PathBuf,
+        /// Number of images processed in parallel
+        #[clap(long = "jobs", default_value = "4")]
+        jobs: usize,
+    },
+    /// Maintain a catalog of pre-generated policies of curated images, keyed by digest
+    Catalog {
+        #[clap(subcommand)]
+        command: CatalogCommand,
+    },
+    /// Inspect the runtime profiles used by --with_default_rules
+    Profile {
+        #[clap(subcommand)]
+        command: ProfileCommand,
+    },
+    /// Inject the policies attached to the images instead of generating them
+    Inject {
+        #[clap(short = 'i', long = "input")]
+        input_yaml: PathBuf,
+        #[clap(short = 'o', long = "output")]
+        output_yaml: PathBuf,
+        /// Fetch the policy of each image from the registry (referrers API)
+        #[clap(long = "from-registry")]
+        from_registry: bool,
+    },
+    /// Check a hand-edited policy: the schema, the regex rules, the mount destinations, the
+    /// sandbox entry, and the unknown fields
+    LintPolicy {
+        policy: PathBuf,
+        /// The policy has no sandbox (pause) entry, e.g., the policy of an image
+        #[clap(long = "no-sandbox")]
+        no_sandbox: bool,
+        /// Fail if the policy has more loose rules, i.e., rules relaxed by the allow-any flags,
+        /// env rules matching any value, and writable paths of the node
+        #[clap(long = "max-loose-rules")]
+        max_loose_rules: Option<usize>,
+        /// Output format, text for humans or json for automation
+        #[clap(long = "output", arg_enum, default_value = "text")]
+        output: OutputFormat,
+    },
+    /// Summarize the policy per container for the reviewers: entrypoint, writable mounts, host
+    /// paths, env wildcards, and privileges
+    Summarize {
+        #[clap(short = 'p', long = "policy")]
+        policy: PathBuf,
+        /// Output format, markdown for the change reviews or json for automation
+        #[clap(long = "format", arg_enum, default_value = "markdown")]
+        format: SummaryFormat,
+    },
+    /// Print an OCI spec (e.g., the config.json of a container) with the mount options in the
+    /// canonical order of the policies, e.g., before comparing it with a policy
+    NormalizeSpec { spec: PathBuf },
+    /// Check that the current context has the permissions needed with the cluster flags
+    /// (e.g., --check-webhooks) and print the Role granting them
+    Preflight {
+        /// Namespace of the workloads, the one of the current context by default
+        #[clap(long = "namespace")]
+        namespace: Option<String>,
+    },
+}
+
+#[derive(Clone, Parser)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Configuration file (config.toml)
+    #[clap(long = "config", default_value = "")]
+    config: PathBuf,
+    /// Catalog directory consulted for the image configs and manifests before pulling them
+    #[clap(long = "catalog", default_value = "")]
+    catalog: PathBuf,
     #[clap(short = 'i', long = "input", default_value = "")]
     input_yaml: PathBuf,
     #[clap(long = "image_ref", default_value = "")]
@@ -32,111 +232,1473 @@ struct Cli {
     output_yaml: PathBuf,
     #[clap(short = 'p', long = "policy", default_value = "")]
     output_policy: PathBuf,
+    /// Write the annotated manifest back to the input file
+    #[clap(long = "in-place")]
+    in_place: bool,
+    /// Keep the original of each overwritten output as <path>.bak
+    #[clap(long = "backup")]
+    backup: bool,
+    /// Keep the policy annotations of the manifest that differ from the generated ones
+    #[clap(long = "preserve-existing", conflicts_with = "force")]
+    preserve_existing: bool,
+    /// Overwrite the policy annotations of the manifest that differ from the generated ones
+    #[clap(long = "force")]
+    force: bool,
     #[clap(long = "with_default_rules")]
     with_default_rules: bool,
+    /// Sidecar profile describing containers injected at admission time (repeatable)
+    #[clap(long = "sidecar")]
+    sidecars: Vec<PathBuf>,
+    /// Executable adding or modifying the rules after each stage of the generation (repeatable)
+    #[clap(long = "plugin")]
+    plugins: Vec<PathBuf>,
+    /// Tera template rendering the policies, e.g., into Terraform variables or ARM parameters
+    #[clap(long = "template", default_value = "")]
+    template: PathBuf,
+    /// Output of --template, stdout if unset
+    #[clap(long = "template-output", default_value = "")]
+    template_output: PathBuf,
+    /// Write an in-toto statement of the SLSA provenance of the policy
+    #[clap(long = "provenance", default_value = "")]
+    provenance: PathBuf,
+    /// Sign the provenance into a DSSE envelope with the cosign key
+    #[clap(long = "provenance-key")]
+    provenance_key: Option<String>,
+    /// Write the facts parsed from the input and the rules emitted by each stage as JSON, the
+    /// documents being processed one at a time regardless of --jobs
+    #[clap(long = "dump-ir", default_value = "")]
+    dump_ir: PathBuf,
+    /// Write a JSON report of the generation, e.g., the values the policies assume from the
+    /// image configs and the defaults rather than the manifest
+    #[clap(long = "report", default_value = "")]
+    report: PathBuf,
+    /// Warn about mutating webhooks in the cluster that will alter the pod
+    #[clap(long = "check-webhooks")]
+    check_webhooks: bool,
+    /// Generate the policy from the object returned by a server-side dry-run
+    #[clap(long = "from-dry-run")]
+    from_dry_run: bool,
+    /// Also annotate the live ReplicaSets of the annotated Deployments, i.e., their previous and
+    /// paused revisions, with the policies of their pod templates, so that the rollbacks carry
+    /// them
+    #[clap(long = "annotate-replicasets")]
+    annotate_replica_sets: bool,
+    /// Key provider address (host:port) to check for images with encrypted layers
+    #[clap(long = "key-provider")]
+    key_provider: Option<String>,
+    /// Replace the env rules with a wildcard (for bring-up only)
+    #[clap(long = "allow-any-env")]
+    allow_any_env: bool,
+    /// Replace the mount source rules with a wildcard (for bring-up only)
+    #[clap(long = "allow-any-mount-source")]
+    allow_any_mount_source: bool,
+    /// Replace the args rules with a wildcard (for bring-up only)
+    #[clap(long = "allow-any-args")]
+    allow_any_args: bool,
+    /// Keep every env entry of the containers instead of the last one of each name like the
+    /// kubelet, e.g., to audit the shadowed entries
+    #[clap(long = "keep-duplicate-env")]
+    keep_duplicate_env: bool,
+    /// Sort the mount options of the policy into the canonical order of normalize-spec, which
+    /// the enforcer must then apply to the incoming specs too
+    #[clap(long = "normalize-mount-options")]
+    normalize_mount_options: bool,
+    /// Whether the enforcer matches the env rules as a set or in order, the ordered rules follow
+    /// the exact order of containerd and the kubelet
+    #[clap(
+        long = "env-match",
+        arg_enum,
+        default_value = "set",
+        conflicts_with = "keep_duplicate_env"
+    )]
+    env_match: EnvMatch,
+    /// Generate exact service env rules from the Services in the namespace of the pod
+    #[clap(long = "services-from-cluster")]
+    services_from_cluster: bool,
+    /// Inspect the images of the pods with the credentials of their imagePullSecrets, read from
+    /// the cluster (kubernetes.io/dockerconfigjson Secrets)
+    #[clap(long = "pull-secrets-from-cluster")]
+    pull_secrets_from_cluster: bool,
+    /// Resolve the persistentVolumeClaim volumes to their bound local (or hostPath)
+    /// PersistentVolumes in the cluster, whose mount sources are known
+    #[clap(long = "volumes-from-cluster")]
+    volumes_from_cluster: bool,
+    /// Merge the defaults of the cc-policy-config ConfigMap of the namespace of each document
+    #[clap(long = "cluster-config")]
+    cluster_config: bool,
+    /// Platform whose settings are used, e.g., aks for the confidential containers on AKS: the
+    /// default rules of containerd, and a node pool named after it with the pause image, if the
+    /// config does not define it
+    #[clap(long = "preset", arg_enum)]
+    preset: Option<Preset>,
+    /// Node pool profile defined in the config, selected by the nodeSelector of the pod if unset
+    #[clap(long = "node-pool")]
+    node_pool: Option<String>,
+    /// Only generate the policy of the sandbox (pause) container, e.g., for debugging
+    #[clap(long = "sandbox-only")]
+    sandbox_only: bool,
+    /// How the policy of the sandbox is generated, pause for the pause image, peer-pod for the
+    /// pause bundle of the pod VM of the peer pods, or none, overridden per workload by the
+    /// io.cc-policy/sandbox annotation. The one of the node pool (pause by default) if unset.
+    #[clap(long = "sandbox", arg_enum)]
+    sandbox: Option<SandboxStrategy>,
+    /// Record the image config of each container under custom, either full (default) or digest
+    #[clap(
+        long = "embed-image-config",
+        arg_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "full"
+    )]
+    embed_image_config: Option<ImageConfigEmbedding>,
+    /// Omit the generation metadata (tool version, timestamp, and digests) for reproducible output
+    #[clap(long = "no-metadata")]
+    no_metadata: bool,
+    /// Omit the timestamps, sort the keys, and require the images to be pinned by digest, so
+    /// that the outputs can be rebuilt bit-identically
+    #[clap(long = "reproducible")]
+    reproducible: bool,
+    /// OCI layout directory searched for the images with imagePullPolicy: Never (repeatable)
+    #[clap(long = "oci-layout")]
+    oci_layouts: Vec<PathBuf>,
+    /// ociVersion of the runtime spec, overriding the one of the node pool and the default rules
+    #[clap(long = "oci-version")]
+    oci_version: Option<String>,
+    /// Agent API whose policy checks are targeted, e.g., kata-agent-api=0.1, omitting the fields
+    /// it does not enforce
+    #[clap(long = "target")]
+    target: Option<String>,
+    /// Fail instead of warning when the manifest conflicts with the semantics of Kubernetes
+    #[clap(long = "strict")]
+    strict: bool,
+    /// Fail the generation of the policies with more loose rules, i.e., rules relaxed by the
+    /// allow-any flags, env rules matching any value, and writable paths of the node
+    #[clap(long = "max-loose-rules")]
+    max_loose_rules: Option<usize>,
+    /// Number of documents of the manifest processed in parallel
+    #[clap(long = "jobs", default_value = "4")]
+    jobs: usize,
+    /// Base64 alphabet of the encoded policy
+    #[clap(long = "base64-alphabet", arg_enum, default_value = "standard")]
+    base64_alphabet: Base64Alphabet,
+    /// Omit the base64 padding
+    #[clap(long = "base64-no-pad")]
+    base64_no_pad: bool,
+    /// Wrap the encoded policy into lines of the given length, 0 to disable wrapping
+    #[clap(long = "base64-wrap", default_value = "0")]
+    base64_wrap: usize,
+    /// Deliver the policy inline (base64 annotation), in a ConfigMap, or as an OCI artifact,
+    /// the latter two being referenced by URI and digest in the cc_policy_ref annotation
+    #[clap(long = "delivery", arg_enum, default_value = "inline")]
+    delivery: Delivery,
+    /// File the ConfigMaps of the policies are written to with --delivery configmap
+    #[clap(long = "delivery-output", default_value = "")]
+    delivery_output: PathBuf,
+    /// Repository the policies are pushed to with --delivery oci
+    #[clap(long = "delivery-repository", default_value = "")]
+    delivery_repository: String,
+    /// Encode the canonical JSON (RFC 8785) of the policy in the annotation, so that the
+    /// encoded policy does not depend on the formatting
+    #[clap(long = "canonical-json")]
+    canonical_json: bool,
+    /// Key of the policy annotation, io.katacontainers.cc_policy if empty
+    #[clap(long = "annotation-key", default_value = "")]
+    annotation_key: String,
+    /// Style of the policy annotation in the YAML output, e.g., single-quoted or literal for
+    /// the tools that reject long plain scalars
+    #[clap(long = "annotation-style", arg_enum, default_value = "auto")]
+    annotation_style: AnnotationStyle,
+    /// Format of the warnings and errors, github for workflow commands that annotate the
+    /// lines of the manifest in pull requests
+    #[clap(long = "diagnostics", arg_enum, default_value = "text")]
+    diagnostics: DiagnosticsFormat,
+    /// Seconds after which the external tools (kubectl, skopeo, oras, cosign, az, and the
+    /// exec plugins) are killed
+    #[clap(long = "command-timeout", default_value = "300")]
+    command_timeout: u64,
+    /// Path of skopeo, e.g., a wrapper running it in a toolbox container
+    #[clap(long = "skopeo-path", default_value = "")]
+    skopeo_path: PathBuf,
+    /// Path of kubectl
+    #[clap(long = "kubectl-path", default_value = "")]
+    kubectl_path: PathBuf,
+    /// Option passed to skopeo inspect (repeatable), e.g., --skopeo-arg=--authfile=auth.json
+    #[clap(long = "skopeo-arg", allow_hyphen_values = true)]
+    skopeo_args: Vec<String>,
+    /// Retries of the image inspections rejected by the rate limit of the registry (HTTP 429),
+    /// with a delay doubling from one second
+    #[clap(long = "registry-retries", default_value = "3")]
+    registry_retries: u32,
+    /// Record the results of the external tools into the directory
+    #[clap(long = "record", default_value = "")]
+    record: PathBuf,
+    /// Replay the results of the external tools recorded with --record instead of running them
+    #[clap(long = "replay", default_value = "", conflicts_with = "record")]
+    replay: PathBuf,
     #[clap(short = 'v', long = "verbose")]
     verbose: bool,
+    /// Only print the errors and warnings
+    #[clap(long = "quiet")]
+    quiet: bool,
+    /// Read a query of a Terraform external data source from stdin, i.e., a JSON object with
+    /// input or image_ref, and print the policy and its encoding as a JSON object
+    #[clap(long = "stdout-json")]
+    stdout_json: bool,
 }
 
 fn get_policy_from_yaml(
     yaml: &serde_yaml::Value,
-    with_default_rules: bool,
+    options: &PolicyOptions,
+    sidecars: &[SidecarProfile],
 ) -> Result<(String, String, String)> {
-    let pod_yaml = PodYaml::from(yaml)?;
+    let kind = PodYaml::from(yaml)
+        .map_err(|e| span::resolve(e, yaml))?
+        .kind
+        .to_string();
 
-    let policy = CcPolicy::from_pod_yaml(&pod_yaml, with_default_rules)?;
+    // Generate the policy against the pod spec the API server will eventually see,
+    // i.e., including the sidecars. The injected containers are not written back.
+    let mut yaml = yaml.clone();
+    for sidecar in sidecars {
+        sidecar.inject(get_spec_mut(&mut yaml, &kind)?)?;
+    }
+
+    // The errors are located by the paths of the nodes, which are the same in the original
+    // document except for the ones of the sidecars
+    let pod_yaml = PodYaml::from(&yaml).map_err(|e| span::resolve(e, &yaml))?;
+
+    let policy =
+        CcPolicy::from_pod_yaml(&pod_yaml, options).map_err(|e| span::resolve(e, &yaml))?;
 
     Ok((
         pod_yaml.kind.to_string(),
         policy.to_string(),
-        policy.to_base64(),
+        policy.to_base64(&options.base64),
     ))
 }
 
+// Inject the policy into the document if it is a supported workload, returning the policy
+// and its encoding
+fn inject_document(
+    args: &Cli,
+    options: &PolicyOptions,
+    sidecars: &[SidecarProfile],
+    target: &Target,
+    spans: Option<&Spans>,
+    config_maps: &Mutex<Vec<serde_yaml::Value>>,
+    yaml: &mut serde_yaml::Value,
+) -> Result<Option<(String, String)>> {
+    if let Ok(pod_yaml) = PodYaml::from(yaml) {
+        let name = yaml["metadata"]["name"].as_str().unwrap_or_default();
+
+        match target
+            .get_match(&pod_yaml)
+            .map_err(|e| span::locate(e, spans))?
+        {
+            Some(reason) => {
+                if !target.is_empty() {
+                    info!("{} {}: annotated ({})", pod_yaml.kind, name, reason);
+                }
+            }
+            None => {
+                info!(
+                    "{} {}: skipped (does not target the confidential node pools)",
+                    pod_yaml.kind, name
+                );
+                return Ok(None);
+            }
+        }
+    }
+
+    if args.check_webhooks {
+        if let Ok(pod_yaml) = PodYaml::from(yaml) {
+            for webhook in cluster::get_matching_webhooks(&pod_yaml)? {
+                warn!(
+                    "{} {} will be mutated by the webhook {}, the generated policy may not match the final pod",
+                    pod_yaml.kind,
+                    yaml["metadata"]["name"].as_str().unwrap_or_default(),
+                    webhook
+                );
+            }
+        }
+    }
+
+    // The policy is generated from the API server's final object, which includes
+    // defaulted fields and the mutations of admission webhooks, while the annotation
+    // is still injected into the original document
+    let source = if args.from_dry_run && PodYaml::from(yaml).is_ok() {
+        cluster::dry_run(yaml)?
+    } else {
+        yaml.clone()
+    };
+
+    match get_policy_from_yaml(&source, options, sidecars) {
+        Ok((kind, policy, policy_base64)) => {
+            let existing = get_existing_policy(args);
+            let patched = match &args.delivery {
+                Delivery::Inline => patch_yaml(yaml, &kind, &policy_base64, existing)?,
+                // The policies are only published for the annotated documents
+                Delivery::Configmap => {
+                    let pod_yaml = PodYaml::from(yaml)?;
+                    let (config_map, reference) =
+                        delivery::to_config_map(&kind, pod_yaml.name, pod_yaml.namespace, &policy)?;
+                    let patched = patch_reference(
+                        yaml,
+                        &kind,
+                        &policy_base64,
+                        &reference.to_json()?,
+                        existing,
+                    )?;
+                    if patched {
+                        config_maps.lock().unwrap().push(config_map);
+                    }
+                    patched
+                }
+                Delivery::Oci => {
+                    // The reference by manifest digest is only known once pushed, the one by
+                    // tag refers to the same policy
+                    let reference =
+                        delivery::to_artifact_reference(&args.delivery_repository, &policy);
+                    let mut probe = yaml.clone();
+                    if patch_reference(
+                        &mut probe,
+                        &kind,
+                        &policy_base64,
+                        &reference.to_json()?,
+                        existing,
+                    )? {
+                        let reference = delivery::push(&args.delivery_repository, &policy)?;
+                        patch_reference(
+                            yaml,
+                            &kind,
+                            &policy_base64,
+                            &reference.to_json()?,
+                            ExistingPolicy::Overwrite,
+                        )?
+                    } else {
+                        false
+                    }
+                }
+            };
+
+            if args.annotate_replica_sets && kind == "Deployment" {
+                annotate_replica_sets(args, options, sidecars, yaml)?;
+            }
+
+            if !patched {
+                return Ok(None);
+            }
+
+            Ok(Some((policy, policy_base64)))
+        }
+        // The generation of a workload fails the run, e.g., with --max-loose-rules or --strict,
+        // while the documents that are not workloads are skipped silently
+        Err(e) => match PodYaml::from(yaml) {
+            Ok(pod_yaml) => Err(span::locate(e, spans).context(format!(
+                "failed to generate the policy of {} {}",
+                pod_yaml.kind, pod_yaml.name
+            ))),
+            Err(_) => Ok(None),
+        },
+    }
+}
+
+// Annotate the live ReplicaSets of the Deployment with the policies of their own pod templates,
+// the ones of the revisions rolled out before the policy was injected having none
+fn annotate_replica_sets(
+    args: &Cli,
+    options: &PolicyOptions,
+    sidecars: &[SidecarProfile],
+    deployment: &serde_yaml::Value,
+) -> Result<()> {
+    let pod_yaml = PodYaml::from(deployment)?;
+    let namespace = cluster::get_namespace(&pod_yaml)?;
+
+    for mut replica_set in cluster::get_replica_sets(&namespace, pod_yaml.name)? {
+        let name = replica_set["metadata"]["name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let (kind, _, policy_base64) = get_policy_from_yaml(&replica_set, options, sidecars)
+            .with_context(|| format!("failed to generate the policy of ReplicaSet {}", name))?;
+
+        let key = get_policy_key();
+        let annotations = &replica_set["spec"]["template"]["metadata"]["annotations"];
+        if annotations[key.as_str()].as_str() == Some(policy_base64.as_str()) {
+            continue;
+        }
+
+        if patch_yaml(
+            &mut replica_set,
+            &kind,
+            &policy_base64,
+            get_existing_policy(args),
+        )? {
+            cluster::annotate_replica_set(&namespace, &name, &key, &policy_base64)?;
+            info!(
+                "ReplicaSet {}: annotated (revision of Deployment {})",
+                name, pod_yaml.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Run f on the items with up to jobs threads, returning the results in the order of the items
+fn map_parallel<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let count = items.len();
+    let items = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<R>>>());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let next = items.lock().unwrap().next();
+                let (index, item) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+
+                let result = f(item);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    // Every item is processed once the threads are joined
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+// JSON manifests are detected by the extension or the content, e.g., the output of
+// kubectl get -o json
+fn is_json(path: &Path, first_char: Option<char>) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+        || first_char == Some('{')
+}
+
 fn create_and_inject_policy(
-    path: &PathBuf,
-    with_default_rules: bool,
-) -> Result<(String, String, String)> {
-    let yaml = read_to_string(path)?;
-    let mut buffer = Vec::new();
-    let mut ser = serde_yaml::Serializer::new(&mut buffer);
-    let mut policy_list = Vec::new();
-    let mut policy_base64_list = Vec::new();
+    args: &Cli,
+    configs: &NamespaceConfigs,
+    sidecars: &[SidecarProfile],
+    output: &mut dyn Write,
+) -> Result<Vec<(String, String)>> {
+    let mut reader = document::Reader::new(BufReader::new(File::open(&args.input_yaml)?));
+    let mut policies = Vec::new();
+
+    match args.delivery {
+        Delivery::Configmap if args.delivery_output.as_os_str().is_empty() => {
+            bail!("--delivery configmap requires --delivery-output for the ConfigMaps")
+        }
+        Delivery::Oci if args.delivery_repository.is_empty() => {
+            bail!("--delivery oci requires --delivery-repository")
+        }
+        Delivery::Configmap | Delivery::Oci if args.annotate_replica_sets => {
+            bail!("--annotate-replicasets requires --delivery inline")
+        }
+        _ => {}
+    }
+
+    // The ConfigMaps of the policies with --delivery configmap
+    let config_maps = Mutex::new(Vec::new());
+
+    // The IR recorder follows the stages of one container at a time
+    let jobs = match args.dump_ir.as_os_str().is_empty() {
+        true => args.jobs,
+        false => 1,
+    };
+
+    // Return whether each document is annotated, the policies are kept in the order of the
+    // documents, and the warnings are located at the line of each document
+    let file = args.input_yaml.display().to_string();
+    let mut inject =
+        |yamls: Vec<(Option<&Document>, &mut serde_yaml::Value)>| -> Result<Vec<bool>> {
+            let results = map_parallel(yamls, jobs, |(document, yaml)| {
+                let spans =
+                    document.map(|document| Spans::new(&file, &document.body, document.line));
+
+                annotation::with_location(&args.input_yaml, document.map(|d| d.line), || {
+                    let namespace = configs.get(yaml)?;
+                    let (options, target) = configs.resolve(namespace.as_deref());
+
+                    inject_document(
+                        args,
+                        options,
+                        sidecars,
+                        target,
+                        spans.as_ref(),
+                        &config_maps,
+                        yaml,
+                    )
+                    .map_err(|e| span::locate(e, spans.as_ref()))
+                })
+            });
+
+            let mut annotated = Vec::new();
+            for result in results {
+                match result? {
+                    Some(policy) => {
+                        policies.push(policy);
+                        annotated.push(true);
+                    }
+                    None => annotated.push(false),
+                }
+            }
+
+            Ok(annotated)
+        };
+
+    if is_json(&args.input_yaml, reader.peek_char()?) {
+        // A JSON manifest is a single object, which is read as a whole
+        let content = reader.read_to_string()?;
+
+        // Deserialize into a YAML value to keep the order of the keys in the output
+        let mut yaml: serde_yaml::Value = serde_json::from_str(&content)?;
+
+        // kubectl wraps multiple objects into a List
+        if yaml["kind"].as_str() == Some("List") {
+            if let Some(items) = yaml
+                .get_mut("items")
+                .and_then(|items| items.as_sequence_mut())
+            {
+                inject(items.iter_mut().map(|item| (None, item)).collect())?;
+            }
+        } else {
+            inject(vec![(None, &mut yaml)])?;
+        }
+
+        output.write_all((serde_json::to_string_pretty(&yaml)? + "\n").as_bytes())?;
+    } else {
+        // The documents are read, annotated, and written in batches of --jobs documents
+        loop {
+            let mut documents = Vec::new();
+            while documents.len() < jobs.max(1) {
+                match reader.next_document()? {
+                    Some(document) => documents.push(document),
+                    None => break,
+                }
+            }
+
+            if documents.is_empty() {
+                break;
+            }
+
+            // Index of the document of each parsed object
+            let mut yamls = Vec::new();
+            for (index, document) in documents.iter().enumerate() {
+                let parsed =
+                    annotation::with_location(&args.input_yaml, Some(document.line), || {
+                        document
+                            .parse()
+                            .with_context(|| format!("failed to parse {}", file))
+                    })?;
 
-    for doc in serde_yaml::Deserializer::from_str(yaml.as_str()) {
-        let mut yaml = serde_yaml::Value::deserialize(doc)?;
+                if let Some(yaml) = parsed {
+                    yamls.push((index, yaml));
+                }
+            }
 
-        if let Ok((kind, policy, policy_base64)) = get_policy_from_yaml(&yaml, with_default_rules) {
-            patch_yaml(&mut yaml, &kind, &policy_base64)?;
-            policy_list.push(policy.clone());
-            policy_base64_list.push(policy_base64.clone());
+            let originals: Vec<serde_yaml::Value> =
+                yamls.iter().map(|(_, yaml)| yaml.clone()).collect();
+
+            let annotated = inject(
+                yamls
+                    .iter_mut()
+                    .map(|(index, yaml)| (Some(&documents[*index]), yaml))
+                    .collect(),
+            )?;
+
+            // Only the annotated documents are rewritten, and checked to only differ by the
+            // annotation
+            for (((index, yaml), annotated), original) in
+                yamls.iter().zip(annotated).zip(&originals)
+            {
+                if annotated {
+                    let document = &mut documents[*index];
+                    let spans = Spans::new(&file, &document.body, document.line);
+                    document.body = patch_body(&document.body, yaml)?;
+                    document.body = set_annotation_style(&document.body, args.annotation_style)?;
+
+                    annotation::with_location(&args.input_yaml, Some(document.line), || {
+                        roundtrip::check_injection(
+                            yaml["kind"].as_str().unwrap_or_default(),
+                            yaml["metadata"]["name"].as_str().unwrap_or_default(),
+                            original,
+                            &spans,
+                            &document.body,
+                        )
+                    })?;
+                }
+            }
+
+            for document in &documents {
+                document.write_to(output)?;
+            }
+        }
+    }
+
+    if args.delivery == Delivery::Configmap {
+        let mut config_maps = config_maps.into_inner().unwrap();
+        config_maps.sort_by_key(|config_map| {
+            config_map["metadata"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string()
+        });
+
+        let documents = config_maps
+            .iter()
+            .map(serde_yaml::to_string)
+            .collect::<Result<Vec<String>, _>>()?;
+        write_to_file(&documents.join("---\n"), &args.delivery_output, args.backup)?;
+    }
+
+    Ok(policies)
+}
+
+// Return the documents of the manifest, with the objects of a JSON List given individually,
+// and the spans of the YAML documents
+fn read_documents(path: &Path) -> Result<Vec<(serde_yaml::Value, Option<Spans>)>> {
+    let content = read_to_string(path)?;
+
+    if is_json(path, content.trim_start().chars().next()) {
+        let yaml: serde_yaml::Value = serde_json::from_str(&content)?;
+
+        if yaml["kind"].as_str() == Some("List") {
+            let items = yaml["items"].as_sequence().cloned().unwrap_or_default();
+            return Ok(items.into_iter().map(|item| (item, None)).collect());
+        }
+
+        return Ok(vec![(yaml, None)]);
+    }
+
+    let file = path.display().to_string();
+    let mut documents = Vec::new();
+    for document in document::split(&content) {
+        let parsed = document
+            .parse()
+            .with_context(|| format!("failed to parse {}", file))?;
+
+        if let Some(yaml) = parsed {
+            documents.push((yaml, Some(Spans::new(&file, &document.body, document.line))));
         }
+    }
+
+    Ok(documents)
+}
+
+// Return the policies of the targeted workloads of the manifest, keyed by <kind>/<name>
+fn get_workload_policies(
+    path: &Path,
+    configs: &NamespaceConfigs,
+    sidecars: &[SidecarProfile],
+) -> Result<BTreeMap<String, serde_json::Value>> {
+    let mut policies = BTreeMap::new();
+
+    for (index, (yaml, spans)) in read_documents(path)?.into_iter().enumerate() {
+        if !pod_yaml::is_workload(&yaml) {
+            continue;
+        }
+
+        // A workload that fails to parse would be missing from the diff
+        let pod_yaml = PodYaml::from(&yaml)
+            .map_err(|e| span::locate(e, spans.as_ref()))
+            .with_context(|| format!("failed to parse document {} of {}", index, path.display()))?;
 
-        yaml.serialize(&mut ser)?;
+        let namespace = configs.get(&yaml)?;
+        let (options, target) = configs.resolve(namespace.as_deref());
+
+        if target
+            .get_match(&pod_yaml)
+            .map_err(|e| span::locate(e, spans.as_ref()))?
+            .is_none()
+        {
+            continue;
+        }
+
+        let name = format!("{}/{}", pod_yaml.kind, pod_yaml.name);
+        let (_, policy, _) = get_policy_from_yaml(&yaml, options, sidecars)
+            .map_err(|e| span::locate(e, spans.as_ref()))
+            .with_context(|| format!("failed to generate the policy of {}", name))?;
+
+        policies.insert(name, serde_json::from_str(&policy)?);
     }
 
-    let yaml_with_policy = String::from_utf8_lossy(&buffer).to_string();
+    Ok(policies)
+}
+
+fn diff_manifests(
+    args: &Cli,
+    old: &Path,
+    new: &Path,
+    no_color: bool,
+    output: &OutputFormat,
+) -> Result<()> {
+    let config = load_local_config(args)?;
+
+    let sidecars = config
+        .sidecars
+        .iter()
+        .chain(args.sidecars.iter())
+        .map(|path| SidecarProfile::from_file(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Generation metadata (e.g., the timestamp) is not a policy-relevant difference
+    let mut options = get_policy_options(args, &config)?;
+    options.no_metadata = true;
 
-    let policy = policy_list.join("\n");
-    let policy_base64 = policy_base64_list.join("\n");
+    let configs = NamespaceConfigs::new(args, &config, &options);
+    let old_policies = get_workload_policies(old, &configs, &sidecars)?;
+    let new_policies = get_workload_policies(new, &configs, &sidecars)?;
 
-    Ok((policy, policy_base64, yaml_with_policy))
+    println!(
+        "{}",
+        diff::render_workloads(&old_policies, &new_policies, no_color, output)?
+    );
+
+    Ok(())
 }
 
 fn create_policy_by_image_ref(
     image_ref: &str,
-    with_default_rules: bool,
+    options: &PolicyOptions,
 ) -> Result<(String, String)> {
-    let policy = CcPolicy::from_image_ref(image_ref, with_default_rules)?;
+    let policy = CcPolicy::from_image_ref(image_ref, options)?;
+
+    Ok((policy.to_string(), policy.to_base64(&options.base64)))
+}
+
+fn write_to_file(data: &str, path: &Path, backup: bool) -> Result<()> {
+    write_with(path, backup, |file| Ok(file.write_all(data.as_bytes())?))
+}
+
+// Write through f to a temporary file next to the target and rename it over the target, so
+// that the target is either left untouched or fully written. With backup, the original target
+// is kept as <path>.bak.
+fn write_with<T, F>(path: &Path, backup: bool, f: F) -> Result<T>
+where
+    F: FnOnce(&mut dyn Write) -> Result<T>,
+{
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid output path {}", path.display()))?;
+
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::SeqCst)
+    ));
+    let temp_path = path.with_file_name(temp_name);
+
+    let write = |temp_path: &Path| -> Result<T> {
+        let mut file = BufWriter::new(File::create(temp_path)?);
+        let result = f(&mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        Ok(result)
+    };
+
+    let result = match write(&temp_path) {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+
+    if backup && path.exists() {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+
+        fs::copy(path, &backup_path)?;
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    info!("{} created.", path.display());
+
+    Ok(result)
+}
+
+// The defaults of the cluster change the policies, so they are only read when asked for
+fn uses_cluster_config(args: &Cli) -> bool {
+    args.cluster_config
+}
+
+// Config of the documents, whose namespaces' defaults are merged by NamespaceConfigs
+fn load_local_config(args: &Cli) -> Result<Config> {
+    let mut config = if args.config.as_os_str().is_empty() {
+        Config::default()
+    } else {
+        Config::from_file(&args.config)?
+    };
+
+    if let Some(preset) = &args.preset {
+        preset.apply(&mut config);
+    }
+
+    Ok(config)
+}
+
+fn merge_cluster_config(config: &mut Config, namespace: &str) -> Result<bool> {
+    let merged = config.merge_cluster_config(namespace)?;
+    if merged {
+        info!(
+            "Merged the defaults of ConfigMap {}/{}.",
+            namespace,
+            config::CLUSTER_CONFIG_MAP
+        );
+    }
+
+    Ok(merged)
+}
+
+// Config of the commands without documents, e.g., the image refs, merged with the defaults of
+// the namespace of the current context
+fn load_config(args: &Cli) -> Result<Config> {
+    let mut config = load_local_config(args)?;
+
+    if uses_cluster_config(args) {
+        merge_cluster_config(&mut config, &cluster::get_context_namespace()?)?;
+    }
+
+    Ok(config)
+}
+
+struct NamespaceOptions {
+    config: Config,
+    options: PolicyOptions,
+}
+
+// Options of the documents by namespace, i.e., the local config merged with the defaults of
+// the ConfigMap of the namespace of each document, or of the current context for the documents
+// without a namespace
+struct NamespaceConfigs<'a> {
+    args: &'a Cli,
+    config: &'a Config,
+    options: &'a PolicyOptions,
+    // IR recorder of the documents of every namespace
+    recorder: Option<Arc<ir::Recorder>>,
+    // None for the namespaces without the ConfigMap, whose documents use the local options
+    namespaces: Mutex<BTreeMap<String, Option<Arc<NamespaceOptions>>>>,
+}
+
+impl<'a> NamespaceConfigs<'a> {
+    fn new(args: &'a Cli, config: &'a Config, options: &'a PolicyOptions) -> Self {
+        NamespaceConfigs {
+            args,
+            config,
+            options,
+            recorder: None,
+            namespaces: Mutex::default(),
+        }
+    }
+
+    fn get(&self, yaml: &serde_yaml::Value) -> Result<Option<Arc<NamespaceOptions>>> {
+        if !uses_cluster_config(self.args) {
+            return Ok(None);
+        }
+
+        let pod_yaml = match PodYaml::from(yaml) {
+            Ok(pod_yaml) => pod_yaml,
+            Err(_) => return Ok(None),
+        };
+        let namespace = cluster::get_namespace(&pod_yaml)?;
+
+        // Each ConfigMap is read once, however many documents the namespace has
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(options) = namespaces.get(&namespace) {
+            return Ok(options.clone());
+        }
+
+        let mut config = self.config.clone();
+        let options = if merge_cluster_config(&mut config, &namespace)? {
+            // The policies of every namespace are collected together, e.g., for the report
+            let mut options = get_policy_options(self.args, &config)?;
+            options.no_metadata = self.options.no_metadata;
+            options.images = self.options.images.clone();
+            options.report = self.options.report.clone();
+            if let Some(recorder) = &self.recorder {
+                options.plugins.push(Box::new(recorder.clone()));
+            }
+
+            Some(Arc::new(NamespaceOptions { config, options }))
+        } else {
+            None
+        };
+
+        namespaces.insert(namespace, options.clone());
+
+        Ok(options)
+    }
+
+    fn resolve<'b>(
+        &'b self,
+        namespace: Option<&'b NamespaceOptions>,
+    ) -> (&'b PolicyOptions, &'b Target) {
+        match namespace {
+            Some(namespace) => (&namespace.options, &namespace.config.target),
+            None => (self.options, &self.config.target),
+        }
+    }
+}
+
+fn get_catalog_path(args: &Cli, config: &Config) -> Option<PathBuf> {
+    if args.catalog.as_os_str().is_empty() {
+        config.catalog.clone()
+    } else {
+        Some(args.catalog.clone())
+    }
+}
+
+fn read_image_refs(file: &Path) -> Result<Vec<String>> {
+    let content = if file.as_os_str() == "-" {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        read_to_string(file)?
+    };
+
+    // One image ref per line, ignoring empty lines, comments, and duplicated refs
+    let mut image_refs: Vec<String> = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if !line.is_empty() && !line.starts_with('#') && !image_refs.iter().any(|r| r == line) {
+            image_refs.push(line.to_string());
+        }
+    }
+
+    Ok(image_refs)
+}
+
+fn catalog(args: &Cli, command: &CatalogCommand) -> Result<()> {
+    let config = load_config(args)?;
+    let path = get_catalog_path(args, &config)
+        .ok_or_else(|| anyhow!("Please specify the catalog with --catalog or the config"))?;
+
+    match command {
+        CatalogCommand::Build { file } => {
+            let mut options = get_policy_options(args, &config)?;
+            options.catalog = Some(Catalog::open(&path)?);
+
+            for image_ref in read_image_refs(file)? {
+                // Checked above
+                options.catalog.as_mut().unwrap().add_image(&image_ref)?;
+
+                let policy = CcPolicy::from_image_ref(&image_ref, &options)?;
+                let catalog = options.catalog.as_ref().unwrap();
+                let policy_path = catalog.add_policy(&image_ref, &policy.to_string())?;
+
+                println!("{}: {}", image_ref, policy_path.display());
+            }
+
+            options.catalog.as_ref().unwrap().save()
+        }
+        CatalogCommand::Lookup { image_ref } => {
+            let catalog = Catalog::open(&path)?;
+            let policy_path = catalog
+                .lookup(image_ref)
+                .ok_or_else(|| anyhow!("image {} is not in the catalog", image_ref))?;
+
+            print!("{}", read_to_string(policy_path)?);
+
+            Ok(())
+        }
+    }
+}
+
+fn get_policy_options(args: &Cli, config: &Config) -> Result<PolicyOptions> {
+    let input_yaml_sha256 = if args.input_yaml.as_os_str().is_empty() {
+        None
+    } else {
+        Some(image::get_sha256(&fs::read(&args.input_yaml)?))
+    };
+
+    let overlay = match (&config.overlay, &config.cluster_overlay) {
+        (Some(path), _) => Some(Overlay::from_file(path)?),
+        (None, Some(content)) => Some(Overlay::from_content(
+            content,
+            &format!("of ConfigMap {}", config::CLUSTER_CONFIG_MAP),
+        )?),
+        (None, None) => None,
+    };
+
+    let base64 = Base64Encoding {
+        alphabet: args.base64_alphabet.clone(),
+        no_pad: args.base64_no_pad,
+        wrap: args.base64_wrap,
+        canonical: args.canonical_json,
+    };
+    if let Some(preset) = &args.preset {
+        preset.check_encoding(&base64, &args.delivery)?;
+    }
 
-    Ok((policy.to_string(), policy.to_base64()))
+    Ok(PolicyOptions {
+        with_default_rules: args.with_default_rules || config.with_default_rules,
+        key_provider: args
+            .key_provider
+            .clone()
+            .or_else(|| config.key_provider.clone()),
+        allow_any_env: args.allow_any_env,
+        allow_any_mount_source: args.allow_any_mount_source,
+        allow_any_args: args.allow_any_args,
+        keep_duplicate_env: args.keep_duplicate_env,
+        env_match: args.env_match,
+        normalize_mount_options: args.normalize_mount_options,
+        services_from_cluster: args.services_from_cluster,
+        pull_secrets_from_cluster: args.pull_secrets_from_cluster,
+        volumes_from_cluster: args.volumes_from_cluster,
+        overlay,
+        node_pools: config.node_pools.clone(),
+        node_pool: args
+            .node_pool
+            .clone()
+            .or_else(|| args.preset?.get_default_node_pool(config)),
+        sandbox_only: args.sandbox_only,
+        sandbox: args.sandbox,
+        embed_image_config: args.embed_image_config.clone(),
+        no_metadata: args.no_metadata,
+        reproducible: args.reproducible,
+        strict: args.strict,
+        max_loose_rules: args.max_loose_rules.or(config.max_loose_rules),
+        oci_version: args.oci_version.clone(),
+        agent_api: args.target.as_deref().map(AgentApi::parse).transpose()?,
+        oci_layouts: config
+            .oci_layouts
+            .iter()
+            .chain(args.oci_layouts.iter())
+            .cloned()
+            .collect(),
+        input_yaml_sha256,
+        base64,
+        catalog: match get_catalog_path(args, config) {
+            Some(path) => Some(Catalog::open(&path)?),
+            None => None,
+        },
+        plugins: config
+            .plugins
+            .iter()
+            .chain(args.plugins.iter())
+            .map(|path| Box::new(ExecPlugin::new(path)) as Box<dyn RulePlugin>)
+            .collect(),
+        images: Arc::default(),
+        report: if args.report.as_os_str().is_empty() {
+            None
+        } else {
+            Some(Arc::default())
+        },
+    })
 }
 
-fn write_to_file(data: &str, path: &PathBuf) -> Result<()> {
-    let mut file = File::create(path)?;
-    file.write_all(data.as_bytes())?;
+// Generate one policy per image ref in parallel, writing <output_dir>/<image ref>.json
+fn generate_images(args: &Cli, file: &Path, output_dir: &Path, jobs: usize) -> Result<()> {
+    let config = load_config(args)?;
+    let options = get_policy_options(args, &config)?;
+
+    let image_refs = read_image_refs(file)?;
+
+    // Distinct refs may map to the same file, e.g., reg:5000/app and reg_5000/app
+    let mut file_names: BTreeMap<String, &str> = BTreeMap::new();
+    for image_ref in &image_refs {
+        let file_name = image_ref.replace(['/', ':', '@'], "_") + ".json";
+        if let Some(other) = file_names.insert(file_name.clone(), image_ref) {
+            bail!(
+                "the policies of {} and {} would both be written to {}",
+                other,
+                image_ref,
+                output_dir.join(file_name).display()
+            );
+        }
+    }
 
-    println!("{} created.", path.display());
+    fs::create_dir_all(output_dir)?;
+
+    let results = map_parallel(
+        file_names.into_iter().collect(),
+        jobs,
+        |(file_name, image_ref)| {
+            let result = CcPolicy::from_image_ref(image_ref, &options).and_then(|policy| {
+                write_to_file(
+                    &policy.to_string(),
+                    &output_dir.join(file_name),
+                    args.backup,
+                )
+            });
+
+            match result {
+                Ok(()) => None,
+                Err(e) => {
+                    warn!("failed to generate the policy of {}: {:?}", image_ref, e);
+                    Some(image_ref)
+                }
+            }
+        },
+    );
+
+    let failures: Vec<&str> = results.into_iter().flatten().collect();
+    if !failures.is_empty() {
+        bail!(
+            "failed to generate the policies of {} of {} images: {}",
+            failures.len(),
+            image_refs.len(),
+            failures.join(", ")
+        );
+    }
 
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args = Cli::parse();
+fn generate(args: &Cli) -> Result<()> {
+    // The defaults of the namespaces of the documents are merged per document
+    let config = if args.input_yaml.as_os_str().is_empty() {
+        load_config(args)?
+    } else {
+        load_local_config(args)?
+    };
 
-    if args.input_yaml.as_os_str().is_empty() && args.image_ref.is_empty() {
-        bail!("Please specify either input_yaml or image_ref");
+    if args.input_yaml.as_os_str().is_empty() && args.image_ref.is_empty() && !args.sandbox_only {
+        bail!("Please specify either input_yaml, image_ref, or sandbox-only");
     }
 
     if !args.input_yaml.as_os_str().is_empty() && !args.image_ref.is_empty() {
         bail!("Cannot specify input_yaml and image_ref at the same time");
     }
 
-    let policy;
-    let policy_encoded;
-    let mut patched_yaml = String::new();
+    if args.in_place && args.input_yaml.as_os_str().is_empty() {
+        bail!("Cannot specify in-place without input_yaml");
+    }
+
+    if args.in_place && !args.output_yaml.as_os_str().is_empty() {
+        bail!("Cannot specify in-place and output_yaml at the same time");
+    }
+
+    let sidecars = config
+        .sidecars
+        .iter()
+        .chain(args.sidecars.iter())
+        .map(|path| SidecarProfile::from_file(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut options = get_policy_options(args, &config)?;
+
+    let recorder = Arc::new(ir::Recorder::default());
+    if !args.dump_ir.as_os_str().is_empty() {
+        options.plugins.push(Box::new(recorder.clone()));
+    }
+
+    let output_yaml = if args.in_place {
+        &args.input_yaml
+    } else {
+        &args.output_yaml
+    };
+
+    // Policy and its encoding of each workload
+    let policies;
 
     if !args.input_yaml.as_os_str().is_empty() {
-        (policy, policy_encoded, patched_yaml) =
-            create_and_inject_policy(&args.input_yaml, args.with_default_rules)?;
+        let mut configs = NamespaceConfigs::new(args, &config, &options);
+        if !args.dump_ir.as_os_str().is_empty() {
+            configs.recorder = Some(recorder.clone());
+        }
+
+        // The annotated manifest is written while the documents are read
+        policies = if output_yaml.as_os_str().is_empty() {
+            create_and_inject_policy(args, &configs, &sidecars, &mut io::sink())?
+        } else {
+            write_with(output_yaml, args.backup, |output| {
+                create_and_inject_policy(args, &configs, &sidecars, output)
+            })?
+        };
+    } else if !args.image_ref.is_empty() {
+        policies = vec![create_policy_by_image_ref(&args.image_ref, &options)?];
     } else {
-        (policy, policy_encoded) =
-            create_policy_by_image_ref(&args.image_ref, args.with_default_rules)?;
+        let sandbox_policy = CcPolicy::from_sandbox(&options)?;
+        policies = vec![(
+            sandbox_policy.to_string(),
+            sandbox_policy.to_base64(&options.base64),
+        )];
     }
 
+    let policy = policies
+        .iter()
+        .map(|(policy, _)| policy.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let policy_encoded = policies
+        .iter()
+        .map(|(_, policy_encoded)| policy_encoded.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+
     if args.verbose {
-        println!("Security Policy: {}", policy);
-        println!("Base64 encoding: {}", policy_encoded);
-        println!("Encoding size: {}", policy_encoded.len());
+        info!("Security Policy: {}", policy);
+        info!("Base64 encoding: {}", policy_encoded);
+        info!("Encoding size: {}", policy_encoded.len());
     }
 
     if !args.output_policy.as_os_str().is_empty() {
-        write_to_file(&policy, &args.output_policy)?;
+        write_to_file(&policy, &args.output_policy, args.backup)?;
+    }
+
+    if args.stdout_json {
+        let result = BTreeMap::from([("policy", &policy), ("policy_base64", &policy_encoded)]);
+        println!("{}", serde_json::to_string(&result)?);
+    }
+
+    if !args.template.as_os_str().is_empty() {
+        let rendered = template::render(&args.template, &policies)?;
+
+        if args.template_output.as_os_str().is_empty() {
+            print!("{}", rendered);
+        } else {
+            write_to_file(&rendered, &args.template_output, args.backup)?;
+        }
+    }
+
+    if !args.dump_ir.as_os_str().is_empty() {
+        write_to_file(&recorder.to_json()?, &args.dump_ir, false)?;
+    }
+
+    if let Some(report) = &options.report {
+        let report = serde_json::to_string_pretty(&*report.lock().unwrap())? + "\n";
+        write_to_file(&report, &args.report, false)?;
     }
 
-    if !args.output_yaml.as_os_str().is_empty() {
-        write_to_file(&patched_yaml, &args.output_yaml)?;
+    if !args.provenance.as_os_str().is_empty() {
+        let statement = get_provenance(args, &options, &policy)?;
+        write_to_file(
+            &statement.to_json(args.provenance_key.as_deref())?,
+            &args.provenance,
+            args.backup,
+        )?;
     }
 
     Ok(())
 }
+
+// Terraform external data source, whose query and result are JSON objects of strings
+// Reference: https://registry.terraform.io/providers/hashicorp/external/latest/docs/data-sources/external#external-program-protocol
+fn generate_external_data(args: &Cli) -> Result<()> {
+    let mut query = String::new();
+    io::stdin().read_to_string(&mut query)?;
+
+    let query: BTreeMap<String, String> = serde_json::from_str(&query)
+        .context("invalid query, expecting a JSON object of strings")?;
+
+    let mut args = args.clone();
+    for (key, value) in query {
+        match key.as_str() {
+            "input" => args.input_yaml = PathBuf::from(value),
+            "image_ref" => args.image_ref = value,
+            "node_pool" => args.node_pool = Some(value),
+            _ => bail!(
+                "unknown key {} of the query, the supported keys are: input, image_ref, node_pool",
+                key
+            ),
+        }
+    }
+
+    generate(&args)
+}
+
+fn get_existing_policy(args: &Cli) -> ExistingPolicy {
+    if args.force {
+        ExistingPolicy::Overwrite
+    } else if args.preserve_existing {
+        ExistingPolicy::Preserve
+    } else {
+        ExistingPolicy::Fail
+    }
+}
+
+// The subject digest is the one of the policy as written to -p, byte for byte
+fn get_provenance(args: &Cli, options: &PolicyOptions, policy: &str) -> Result<Statement> {
+    let mut parameters = BTreeMap::new();
+
+    let input = if !args.input_yaml.as_os_str().is_empty() {
+        let name = args.input_yaml.display().to_string();
+        parameters.insert("input".to_string(), name.clone());
+        Some((name, fs::read(&args.input_yaml)?))
+    } else {
+        if !args.image_ref.is_empty() {
+            parameters.insert("image_ref".to_string(), args.image_ref.clone());
+        }
+        None
+    };
+
+    if !args.config.as_os_str().is_empty() {
+        parameters.insert("config".to_string(), args.config.display().to_string());
+    }
+    if let Some(preset) = &args.preset {
+        parameters.insert("preset".to_string(), preset.get_name().to_string());
+    }
+    if let Some(node_pool) = &options.node_pool {
+        parameters.insert("node_pool".to_string(), node_pool.clone());
+    }
+    if options.with_default_rules {
+        parameters.insert("with_default_rules".to_string(), "true".to_string());
+    }
+    if let Some(agent_api) = &options.agent_api {
+        parameters.insert("target".to_string(), agent_api.to_string());
+    }
+    if let Some(sandbox) = &options.sandbox {
+        parameters.insert("sandbox".to_string(), sandbox.to_string());
+    }
+
+    let policy_name = if args.output_policy.as_os_str().is_empty() {
+        "policy.json".to_string()
+    } else {
+        args.output_policy.display().to_string()
+    };
+
+    Ok(Statement::new(
+        &policy_name,
+        policy,
+        parameters,
+        input
+            .as_ref()
+            .map(|(name, content)| (name.as_str(), content.as_slice())),
+        &options.images.lock().unwrap(),
+        options.reproducible,
+    ))
+}
+
+fn inject(
+    input_yaml: &Path,
+    output_yaml: &Path,
+    from_registry: bool,
+    existing: ExistingPolicy,
+    style: AnnotationStyle,
+) -> Result<()> {
+    if !from_registry {
+        bail!("inject currently only supports --from-registry");
+    }
+
+    let content = read_to_string(input_yaml)?;
+    let mut documents = document::split(&content);
+
+    for document in &mut documents {
+        if let Some(mut yaml) = document.parse()? {
+            if let Ok(pod_yaml) = PodYaml::from(&yaml) {
+                let kind = pod_yaml.kind.to_string();
+                let policy = CcPolicy::from_attached_policies(&pod_yaml)?;
+
+                if patch_yaml(
+                    &mut yaml,
+                    &kind,
+                    &policy.to_base64(&Base64Encoding::default()),
+                    existing,
+                )? {
+                    document.body = patch_body(&document.body, &yaml)?;
+                    document.body = set_annotation_style(&document.body, style)?;
+                }
+            }
+        }
+    }
+
+    write_to_file(&document::join(&documents), output_yaml, false)
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse();
+
+    // stdout is the result of the external data source
+    QUIET.store(args.quiet || args.stdout_json, Ordering::Relaxed);
+    annotation::set_format(&args.diagnostics);
+    subprocess::set_timeout(args.command_timeout);
+    if !args.skopeo_path.as_os_str().is_empty() {
+        subprocess::set_path(image::SKOPEO, &args.skopeo_path);
+    }
+    if !args.kubectl_path.as_os_str().is_empty() {
+        subprocess::set_path(cluster::KUBECTL, &args.kubectl_path);
+    }
+    image::set_skopeo_args(&args.skopeo_args);
+    ratelimit::set_retries(args.registry_retries);
+    if !args.annotation_key.is_empty() {
+        pod_yaml::set_policy_key(&args.annotation_key);
+    }
+    if !args.record.as_os_str().is_empty() {
+        recording::set(recording::Mode::Record, &args.record)?;
+    }
+    if !args.replay.as_os_str().is_empty() {
+        recording::set(recording::Mode::Replay, &args.replay)?;
+    }
+
+    let result = match &args.command {
+        Some(Command::Init { path }) => init::init(path),
+        Some(Command::Diff {
+            old,
+            new,
+            no_color,
+            output,
+        }) => diff::diff_policies(old, new, *no_color, output),
+        Some(Command::DiffManifests {
+            old,
+            new,
+            no_color,
+            output,
+        }) => diff_manifests(&args, old, new, *no_color, output),
+        Some(Command::Push { policy, subject }) => registry::push_policy(policy, subject),
+        Some(Command::Catalog { command }) => catalog(&args, command),
+        Some(Command::Profile {
+            command: ProfileCommand::Show { name, node_pool },
+        }) => profile::show(name, &load_config(&args)?, node_pool.as_deref()),
+        Some(Command::Images {
+            file,
+            output_dir,
+            jobs,
+        }) => generate_images(&args, file, output_dir, *jobs),
+        Some(Command::Inject {
+            input_yaml,
+            output_yaml,
+            from_registry,
+        }) => inject(
+            input_yaml,
+            output_yaml,
+            *from_registry,
+            get_existing_policy(&args),
+            args.annotation_style,
+        ),
+        Some(Command::LintPolicy {
+            policy,
+            no_sandbox,
+            max_loose_rules,
+            output,
+        }) => lint::lint_policy(policy, !*no_sandbox, *max_loose_rules, output),
+        Some(Command::Summarize { policy, format }) => summary::summarize_policy(policy, format),
+        Some(Command::NormalizeSpec { spec }) => oci::normalize_spec(spec),
+        Some(Command::Preflight { namespace }) => preflight::preflight(
+            namespace.as_deref(),
+            &preflight::ClusterAccess {
+                services_from_cluster: args.services_from_cluster,
+                pull_secrets_from_cluster: args.pull_secrets_from_cluster,
+                volumes_from_cluster: args.volumes_from_cluster,
+                check_webhooks: args.check_webhooks,
+                from_dry_run: args.from_dry_run,
+                annotate_replica_sets: args.annotate_replica_sets,
+            },
+        ),
+        None if args.stdout_json => generate_external_data(&args),
+        None => generate(&args),
+    };
+
+    if let Err(e) = &result {
+        annotation::error(&format!("{:#}", e));
+    }
+
+    result
+}
@@ -0,0 +1,138 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::affinity;
+use crate::guest::{GuestLayout, GO_SHIM, PEER_PODS};
+use crate::policy::SandboxStrategy;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Sandbox flavor of a node pool, defined under [node_pools.<name>] in config.toml
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodePool {
+    // Image of the sandbox (pause) container, e.g., registry.k8s.io/pause:3.6
+    pub pause_image: Option<String>,
+    // argv of the pause image if not /pause, e.g., for the custom pause images with another
+    // entrypoint
+    pub pause_args: Option<Vec<String>>,
+    // Guest layout of the shared files, i.e., go-shim (default), runtime-rs, or peer-pods
+    pub guest_layout: Option<String>,
+    // Regex of the directory of the shared files overriding the one of the guest layout
+    pub guest_path: Option<String>,
+    // Kata runtime configuration of the node pool, e.g., kata-qemu-snp
+    pub runtime_profile: Option<String>,
+    // Strategy of the sandbox policy, peer-pod with the peer-pods guest layout and pause
+    // otherwise
+    pub sandbox: Option<SandboxStrategy>,
+    // ociVersion expected by the agent of the node pool, e.g., 1.0.2-dev
+    pub oci_version: Option<String>,
+    // Annotations of the sandbox set from the RuntimeClass (e.g., the pod overhead) or the
+    // handler config, e.g., io.katacontainers.config.hypervisor.default_memory. Values starting
+    // with ^ are regex rules.
+    pub sandbox_annotations: BTreeMap<String, String>,
+    // Whether the runtime of the node pool supports the recursive read-only mounts (rro) of
+    // recursiveReadOnly, e.g., containerd 2.0 with runc 1.1 on Linux 5.12
+    pub recursive_read_only: bool,
+    // Labels that the nodeSelector of a pod must contain to select the node pool
+    pub node_selector: BTreeMap<String, String>,
+    // Labels of the nodes of the node pool, which select it for the pods whose nodeSelector and
+    // required node affinity match them
+    pub node_labels: BTreeMap<String, String>,
+}
+
+impl NodePool {
+    pub fn get_guest_layout(&self) -> Result<GuestLayout> {
+        let mut layout = GuestLayout::from_name(self.guest_layout.as_deref().unwrap_or(GO_SHIM))?;
+
+        if let Some(guest_path) = &self.guest_path {
+            layout.share_path = guest_path.clone();
+        }
+
+        Ok(layout)
+    }
+
+    pub fn get_sandbox_strategy(&self) -> SandboxStrategy {
+        match self.sandbox {
+            Some(sandbox) => sandbox,
+            None if self.guest_layout.as_deref() == Some(PEER_PODS) => SandboxStrategy::PeerPod,
+            None => SandboxStrategy::Pause,
+        }
+    }
+
+    fn matches(&self, spec: Option<&serde_yaml::Value>) -> Result<bool> {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => return Ok(false),
+        };
+
+        if !self.node_selector.is_empty() {
+            let node_selector = spec["nodeSelector"].as_mapping();
+
+            let matched = self.node_selector.iter().all(|(key, value)| {
+                node_selector
+                    .and_then(|m| m.get(&serde_yaml::Value::String(key.clone())))
+                    .and_then(|v| v.as_str())
+                    == Some(value.as_str())
+            });
+
+            if matched {
+                return Ok(true);
+            }
+        }
+
+        // The pods without node constraints may be scheduled on any node pool
+        if !self.node_labels.is_empty() && affinity::has_node_constraints(spec) {
+            return affinity::matches_node_labels(spec, &self.node_labels);
+        }
+
+        Ok(false)
+    }
+}
+
+// Return the node pool given by name if any, otherwise the one whose node_selector labels are
+// all contained in the nodeSelector of the pod, or whose node_labels the nodeSelector and the
+// node affinity of the pod match
+pub fn select<'a>(
+    node_pools: &'a BTreeMap<String, NodePool>,
+    name: Option<&str>,
+    spec: Option<&serde_yaml::Value>,
+) -> Result<Option<(&'a str, &'a NodePool)>> {
+    if let Some(name) = name {
+        let (name, node_pool) = node_pools
+            .get_key_value(name)
+            .ok_or_else(|| anyhow!("node pool {} is not defined in the config", name))?;
+
+        return Ok(Some((name.as_str(), node_pool)));
+    }
+
+    let mut matches = Vec::new();
+    for (name, node_pool) in node_pools {
+        if node_pool.matches(spec)? {
+            matches.push((name, node_pool));
+        }
+    }
+
+    match matches.as_slice() {
+        [] => Ok(None),
+        [(name, node_pool)] => Ok(Some((name.as_str(), *node_pool))),
+        _ => bail!(
+            "{}: the nodeSelector and the node affinity match multiple node pools: {}",
+            loc!(),
+            matches
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+pub fn get_guest_layout(node_pool: Option<&NodePool>) -> Result<GuestLayout> {
+    match node_pool {
+        Some(node_pool) => node_pool.get_guest_layout(),
+        None => Ok(GuestLayout::default()),
+    }
+}
@@ -1,8 +1,10 @@
 // Copyright (c) Cc-Policy Authors.
 // Licensed under the Apache 2.0 license.
 
-use anyhow::{Context, Result};
-use oci_spec::runtime::{Process, Spec};
+use anyhow::{anyhow, bail, Context, Result};
+use oci_spec::runtime::{Mount, Process, Spec};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 
 pub fn empty_process() -> Result<Process> {
     let process: Process = serde_json::from_str(
@@ -29,3 +31,193 @@ pub fn empty_spec() -> Result<Spec> {
 
     Ok(spec)
 }
+
+pub fn bind_mount(destination: &str, source: &str, options: &[&str]) -> Mount {
+    let mut mount = Mount::default();
+
+    mount.set_destination(PathBuf::from(destination));
+    mount.set_source(Some(PathBuf::from(source)));
+    mount.set_typ(Some(String::from("bind")));
+    mount.set_options(Some(options.iter().map(|o| o.to_string()).collect()));
+
+    mount
+}
+
+// Return the clean form of a mount destination, e.g., /data for /data/ or //data/./, so that
+// the destinations compare equal to the ones the runtime mounts
+pub fn normalize_destination(destination: &Path) -> Result<PathBuf> {
+    if !destination.is_absolute() {
+        bail!(
+            "mount destination {} is not an absolute path",
+            destination.display()
+        );
+    }
+
+    let mut result = PathBuf::new();
+
+    for component in destination.components() {
+        match component {
+            Component::RootDir => result.push(component),
+            Component::CurDir => (),
+            Component::Normal(name) => result.push(name),
+            Component::ParentDir | Component::Prefix(_) => bail!(
+                "mount destination {} must not contain ..",
+                destination.display()
+            ),
+        }
+    }
+
+    Ok(result)
+}
+
+// Options of the kind of the bind mounts, where rbind includes bind
+const BIND_OPTIONS: &[&str] = &["bind", "rbind"];
+
+// Propagation options, the last one wins
+const PROPAGATION_OPTIONS: &[&str] = &[
+    "private",
+    "rprivate",
+    "shared",
+    "rshared",
+    "slave",
+    "rslave",
+    "unbindable",
+    "runbindable",
+];
+
+// Flags that clear each other, the last one of each group wins like runc applies them
+// Reference: https://github.com/opencontainers/runc/blob/main/libcontainer/specconv/spec_linux.go
+const FLAG_GROUPS: &[&[&str]] = &[
+    &["ro", "rw"],
+    &["nosuid", "suid"],
+    &["nodev", "dev"],
+    &["noexec", "exec"],
+    &["sync", "async"],
+    &["dirsync"],
+    &["rro"],
+    &["mand", "nomand"],
+    &["noatime", "atime"],
+    &["nodiratime", "diratime"],
+    &["relatime", "norelatime"],
+    &["strictatime", "nostrictatime"],
+    &["remount"],
+];
+
+// Return the canonical form of the options of a mount, which the enforcers compare literally:
+// - rbind or bind (rbind if both are given)
+// - the propagation option, the last one if several are given
+// - ro or rw, the last one if both are given
+// - the other flags of the same kind, resolved the same way and sorted by name
+// - the filesystem-specific data (e.g., mode=755), in the given order without duplicates
+// The incoming specs are expected to be normalized the same way before the comparison, e.g.,
+// with the normalize-spec subcommand.
+pub fn normalize_mount_options(options: &[String]) -> Vec<String> {
+    let bind = if options.iter().any(|option| option == "rbind") {
+        Some("rbind")
+    } else if options.iter().any(|option| option == "bind") {
+        Some("bind")
+    } else {
+        None
+    };
+
+    let propagation = options
+        .iter()
+        .rev()
+        .find(|option| PROPAGATION_OPTIONS.contains(&option.as_str()));
+
+    let flags: Vec<&String> = FLAG_GROUPS
+        .iter()
+        .filter_map(|group| {
+            options
+                .iter()
+                .rev()
+                .find(|option| group.contains(&option.as_str()))
+        })
+        .collect();
+    let (access, mut flags): (Vec<&String>, Vec<&String>) = flags
+        .into_iter()
+        .partition(|option| *option == "ro" || *option == "rw");
+    flags.sort();
+
+    let mut results: Vec<String> = bind.into_iter().map(|option| option.to_string()).collect();
+    results.extend(propagation.cloned());
+    results.extend(access.into_iter().cloned());
+    results.extend(flags.into_iter().cloned());
+
+    for option in options {
+        let known = BIND_OPTIONS.contains(&option.as_str())
+            || PROPAGATION_OPTIONS.contains(&option.as_str())
+            || FLAG_GROUPS
+                .iter()
+                .any(|group| group.contains(&option.as_str()));
+        if !known && !results.contains(option) {
+            results.push(option.clone());
+        }
+    }
+
+    results
+}
+
+pub fn normalize_mounts(spec: &mut Spec) {
+    if let Some(mut mounts) = spec.mounts().clone() {
+        for mount in &mut mounts {
+            if let Some(options) = mount.options() {
+                mount.set_options(Some(normalize_mount_options(options)));
+            }
+        }
+
+        spec.set_mounts(Some(mounts));
+    }
+}
+
+// Print the spec (e.g., the config.json of a container) with the mount options normalized like
+// the ones of the policies
+pub fn normalize_spec(path: &Path) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read spec {}", path.display()))?;
+    let mut spec: Spec = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse spec {}", path.display()))?;
+
+    normalize_mounts(&mut spec);
+
+    println!("{}", serde_json::to_string_pretty(&spec)?);
+
+    Ok(())
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    // Pre-release and build suffixes, e.g., 1.0.2-dev, are ignored
+    let core = version.split(['-', '+']).next()?;
+    let mut numbers = core.split('.').map(|number| number.parse::<u32>().ok());
+
+    let major = numbers.next()??;
+    let minor = numbers.next()??;
+    let _patch = numbers.next()??;
+
+    if numbers.next().is_some() {
+        return None;
+    }
+
+    Some((major, minor))
+}
+
+// Check that the ociVersion is a semantic version that the oci-spec crate can represent, i.e.,
+// of the same major version and not newer than the one of the crate
+pub fn validate_oci_version(version: &str) -> Result<()> {
+    let crate_version = Spec::default().version().clone();
+
+    let (major, minor) = parse_version(version)
+        .ok_or_else(|| anyhow!("invalid ociVersion {}, expecting e.g. 1.0.2", version))?;
+    let (crate_major, crate_minor) = parse_version(&crate_version)
+        .ok_or_else(|| anyhow!("{}: invalid version of oci-spec {}", loc!(), crate_version))?;
+
+    if major != crate_major || minor > crate_minor {
+        bail!(
+            "ociVersion {} is not supported by the runtime spec {} of oci-spec",
+            version,
+            crate_version
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{Context, Result};
+use oci_spec::runtime::Mount;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Rules of the containers with the given name, or of every container with "*"
+pub const OVERLAY_WILDCARD: &str = "*";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlayRules {
+    pub env: Vec<String>,
+    pub mounts: Vec<Mount>,
+}
+
+// An overlay holds organization-specific rules applied on top of the generated ones.
+// Env rules override the generated ones with the same name and mounts override the
+// generated ones with the same destination. Example:
+//
+// containers:
+//   "*":
+//     env:
+//       - "^ORG_REGION=[a-z0-9-]+$"
+//   app:
+//     mounts:
+//       - destination: /etc/org
+//         source: "^/run/kata-containers/shared/containers/[a-z0-9]+-[a-z0-9]+-org$"
+//         type: bind
+//         options: [rbind, rprivate, ro]
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Overlay {
+    pub containers: HashMap<String, OverlayRules>,
+}
+
+impl Overlay {
+    pub fn from_file(path: &Path) -> Result<Overlay> {
+        let content = read_to_string(path).context(loc!())?;
+
+        Self::from_content(&content, &path.display().to_string())
+    }
+
+    pub fn from_content(content: &str, source: &str) -> Result<Overlay> {
+        let overlay = serde_yaml::from_str(content)
+            .with_context(|| format!("failed to parse overlay {}", source))?;
+
+        Ok(overlay)
+    }
+
+    // Return the wildcard rules followed by the rules of the container, if any
+    pub fn get_rules(&self, name: &str) -> Vec<&OverlayRules> {
+        [OVERLAY_WILDCARD, name]
+            .iter()
+            .filter_map(|key| self.containers.get(*key))
+            .collect()
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{anyhow, Result};
+use regex::Regex;
+
+// Rules starting with ^ are treated as regular expressions, the others as exact strings
+pub fn is_regex(rule: &str) -> bool {
+    rule.starts_with('^')
+}
+
+// Regex rule matching exactly the value, e.g., ^PATH=/usr/local/bin:/usr/bin$ for values
+// with regex metacharacters such as + or ?
+pub fn literal(value: &str) -> String {
+    ["^", &regex::escape(value), "$"].concat()
+}
+
+// Whether the rule matches the value, i.e., the regex matches it or the exact string equals it
+pub fn matches(rule: &str, value: &str) -> Result<bool> {
+    if !is_regex(rule) {
+        return Ok(rule == value);
+    }
+
+    let regex = Regex::new(rule).map_err(|e| anyhow!("invalid regex rule {}: {}", rule, e))?;
+
+    Ok(regex.is_match(value))
+}
+
+// Return the positions of ^ and $ that are neither escaped nor inside a character class
+fn get_anchor_positions(rule: &str) -> Vec<(usize, char)> {
+    let mut results = Vec::new();
+    let mut escaped = false;
+    let mut in_class = false;
+
+    for (index, c) in rule.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match c {
+            '\\' => escaped = true,
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '^' | '$' if !in_class => results.push((index, c)),
+            _ => {}
+        }
+    }
+
+    results
+}
+
+// Fail on patterns that do not compile and return the anchors in the middle of the pattern,
+// which are unlikely to match as intended
+pub fn check(rule: &str) -> Result<Vec<String>> {
+    Regex::new(rule).map_err(|e| anyhow!("invalid regex rule {}: {}", rule, e))?;
+
+    Ok(get_anchor_positions(rule)
+        .into_iter()
+        .filter(|(index, anchor)| {
+            let at_start = *anchor == '^' && *index == 0;
+            let at_end = *anchor == '$' && index + 1 == rule.len();
+            !at_start && !at_end
+        })
+        .map(|(index, anchor)| {
+            format!(
+                "regex rule {} has the anchor {} in the middle at position {}",
+                rule, anchor, index
+            )
+        })
+        .collect())
+}
+
+// Fail on patterns that do not compile and warn about anchors in the middle of the pattern
+pub fn validate(rule: &str) -> Result<()> {
+    for warning in check(rule)? {
+        warn!("{}", warning);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal() {
+        let rule = literal("PATH=/usr/local/bin:/usr/bin+x?");
+
+        assert!(is_regex(&rule));
+        assert!(matches(&rule, "PATH=/usr/local/bin:/usr/bin+x?").unwrap());
+        assert!(!matches(&rule, "PATH=/usr/local/bin:/usr/binx").unwrap());
+        assert!(!matches(&rule, "XPATH=/usr/local/bin:/usr/bin+x?").unwrap());
+    }
+
+    #[test]
+    fn test_matches_exact_string() {
+        assert!(matches("A=.*", "A=.*").unwrap());
+        assert!(!matches("A=.*", "A=b").unwrap());
+    }
+
+    #[test]
+    fn test_check_anchors() {
+        assert!(check("^A=b$").unwrap().is_empty());
+        // Anchors inside a character class or escaped are not anchors
+        assert!(check("^A=[^b]\\$$").unwrap().is_empty());
+        assert_eq!(check("^A_SERVICE_HOST=^10$").unwrap().len(), 1);
+        assert_eq!(check("^A=b$c$").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_check_invalid() {
+        assert!(check("^A=(b$").is_err());
+        assert!(matches("^A=[b$", "A=b").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::subprocess;
+
+use anyhow::{Context, Result};
+use oci_spec::image::ImageConfiguration;
+use oci_spec::runtime::Spec;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Stages of the rule generation of a container, in order. The rules of a stage override the
+// ones of the previous stages, and the plugins run after each stage.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    // Rules of the runtime profile, i.e., --with_default_rules
+    Defaults,
+    // Env and mounts inserted by the kubelet
+    Kubelet,
+    // Entry point, env, and volumes of the image config
+    Image,
+    // Fields of the container in the pod yaml
+    Yaml,
+    // Rules of the overlay
+    Overlay,
+}
+
+// Input of a stage available to the plugins
+#[derive(Serialize)]
+pub struct StageContext<'a> {
+    pub name: &'a str,
+    // Null if the policy is not generated from a pod yaml
+    pub container: &'a serde_yaml::Value,
+    // None in the overlay stage
+    pub image_config: Option<&'a ImageConfiguration>,
+}
+
+// Hook adding or modifying the rules of a container after each stage, e.g., org-specific
+// rule packs
+pub trait RulePlugin: Send + Sync {
+    fn name(&self) -> &str;
+    fn apply(&self, stage: Stage, context: &StageContext, spec: &mut Spec) -> Result<()>;
+}
+
+// Shared plugins whose state is read after the generation, e.g., the IR recorder
+impl<T: RulePlugin> RulePlugin for Arc<T> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn apply(&self, stage: Stage, context: &StageContext, spec: &mut Spec) -> Result<()> {
+        (**self).apply(stage, context, spec)
+    }
+}
+
+pub fn apply(
+    plugins: &[Box<dyn RulePlugin>],
+    stage: Stage,
+    context: &StageContext,
+    spec: &mut Spec,
+) -> Result<()> {
+    for plugin in plugins {
+        plugin
+            .apply(stage, context, spec)
+            .with_context(|| format!("plugin {} failed", plugin.name()))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ExecInput<'a> {
+    stage: Stage,
+    #[serde(flatten)]
+    context: &'a StageContext<'a>,
+    spec: &'a Spec,
+}
+
+// Executable run as `<path> <input>` after each stage, where the input is a JSON file with the
+// stage, the context, and the spec of the container. The plugin prints the resulting spec.
+pub struct ExecPlugin {
+    name: String,
+    path: PathBuf,
+}
+
+// Unique inputs given that the containers are generated in parallel by the images command
+static EXEC_INPUT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl ExecPlugin {
+    pub fn new(path: &Path) -> ExecPlugin {
+        ExecPlugin {
+            name: path.to_string_lossy().to_string(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn exec(&self, input: &Path) -> Result<String> {
+        subprocess::run_text(&self.path, &[input])
+            .with_context(|| format!("plugin {} failed", self.name))
+    }
+}
+
+impl RulePlugin for ExecPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn apply(&self, stage: Stage, context: &StageContext, spec: &mut Spec) -> Result<()> {
+        let input = ExecInput {
+            stage,
+            context,
+            spec,
+        };
+
+        let path = env::temp_dir().join(format!(
+            "cc-policy-plugin-{}-{}.json",
+            std::process::id(),
+            EXEC_INPUT_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        fs::write(&path, serde_json::to_string(&input)?).context(loc!())?;
+
+        let result = self.exec(&path);
+
+        fs::remove_file(&path).context(loc!())?;
+
+        *spec = serde_json::from_str(&result?)
+            .with_context(|| format!("failed to parse the output of plugin {}", self.name))?;
+
+        Ok(())
+    }
+}
@@ -1,22 +1,83 @@
 // Copyright (c) Cc-Policy Authors.
 // Licensed under the Apache 2.0 license.
 
-use anyhow::{anyhow, bail, Result};
-use checked_command::{CheckedCommand, Error};
+use crate::cluster;
+use crate::delivery;
+use crate::image;
+use crate::kubernetes;
+use crate::kubernetes::{EnvValue, EnvValues};
+use crate::node_pool;
+use crate::node_pool::NodePool;
+use crate::oci::normalize_destination;
+use crate::pattern;
+use crate::policy::PolicyOptions;
+use crate::scalar;
+use crate::span;
+use crate::span::Spans;
+use crate::subprocess;
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ArgEnum;
+use oci_spec::image::{ImageConfiguration, ImageManifest};
 use oci_spec::runtime::Mount;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
-
-const KUBECTL: &str = "kubectl";
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 const CC_POLICY_KEY: &str = "io.katacontainers.cc_policy";
+// Reference (URI and digest) of the policy with --delivery configmap or oci
+const CC_POLICY_REF_KEY: &str = "io.katacontainers.cc_policy_ref";
+
+// Annotations of the pod with the image config and manifest JSON of a container, given by
+// the suffix, used in preference to pulling them
+const INLINE_IMAGE_CONFIG_PREFIX: &str = "io.cc-policy/image-config.";
+const INLINE_IMAGE_MANIFEST_PREFIX: &str = "io.cc-policy/image-manifest.";
+
+// Annotation of the pod selecting how the policy of the sandbox is generated, see
+// SandboxStrategy
+const SANDBOX_STRATEGY_KEY: &str = "io.cc-policy/sandbox";
 
 // Supported keys used by valueFrom and EnvFrom
 const CONFIG_MAP_KEY_REF: &str = "configMapKeyRef";
 const FIELD_REF: &str = "fieldRef";
 const RESOURCE_FIELD_REF: &str = "resourceFieldRef";
 const SECRET_KEY_REF: &str = "secretKeyRef";
+const CONFIG_MAP_REF: &str = "configMapRef";
+const SECRET_REF: &str = "secretRef";
+
+// Names of the env variables, the keys of the envFrom sources that do not match are skipped
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/staging/src/k8s.io/apimachinery/pkg/util/validation/validation.go#L413
+const ENV_NAME_PATTERN: &str = "[-._a-zA-Z][-._a-zA-Z0-9]*";
+
+// Data of the ConfigMaps read from the cluster by name, None if missing
+static CONFIG_MAPS: Mutex<BTreeMap<String, Option<serde_yaml::Mapping>>> =
+    Mutex::new(BTreeMap::new());
+
+// Variables of the envFrom sources by their YAML, which are resolved once given that the env
+// of a container is resolved several times, see get_env_from
+static ENV_FROM: Mutex<BTreeMap<String, Vec<EnvFrom>>> = Mutex::new(BTreeMap::new());
+
+// Key of the policy annotation set by --annotation-key, CC_POLICY_KEY if empty
+static POLICY_KEY: Mutex<String> = Mutex::new(String::new());
+
+pub fn set_policy_key(key: &str) {
+    *POLICY_KEY.lock().unwrap() = key.to_string();
+}
+
+pub fn get_policy_key() -> String {
+    let key = POLICY_KEY.lock().unwrap();
+    if key.is_empty() {
+        CC_POLICY_KEY.to_string()
+    } else {
+        key.clone()
+    }
+}
+
+// Mount sources of the PersistentVolumeClaims read from the cluster by namespace/name, None if
+// not known
+static CLAIM_SOURCES: Mutex<BTreeMap<String, Option<String>>> = Mutex::new(BTreeMap::new());
 
 // Readonly volume type
 // See: https://github.com/kubernetes/kubernetes/issues/60814
@@ -27,6 +88,17 @@ const VOLUME_TYPE_PROJECTED: &str = "projected";
 
 const VOLUME_TYPE_EMPTY_DIR: &str = "emptyDir";
 const VOLUME_TYPE_HOST_PATH: &str = "hostPath";
+const VOLUME_TYPE_PERSISTENT_VOLUME_CLAIM: &str = "persistentVolumeClaim";
+const VOLUME_TYPE_CSI: &str = "csi";
+
+// The Secrets Store CSI driver rejects the volumes without readOnly: true
+// Reference: https://secrets-store-csi-driver.sigs.k8s.io/getting-started/usage.html
+const SECRETS_STORE_CSI_DRIVER: &str = "secrets-store.csi.k8s.io";
+
+// The kubelet publishes the CSI volumes at .../volumes/kubernetes.io~csi/<volume>/mount, which
+// Kata shares with the guest under the base name of the directory
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/volume/csi/csi_mounter.go
+const CSI_MOUNT_DIR: &str = "mount";
 
 const SPEC_CONTAINERS: &str = "containers";
 const SPEC_INIT_CONTAINERS: &str = "initContainers";
@@ -40,6 +112,36 @@ pub enum VolumeType {
     DownwardAPI,
     Projected,
     HostPath,
+    PersistentVolumeClaim,
+    Csi,
+}
+
+impl VolumeType {
+    fn name(&self) -> &str {
+        match self {
+            VolumeType::Unknown => "unknown",
+            VolumeType::EmptyDir => VOLUME_TYPE_EMPTY_DIR,
+            VolumeType::Secret => VOLUME_TYPE_SECRET,
+            VolumeType::ConfigMap => VOLUME_TYPE_CONFIG_MAP,
+            VolumeType::DownwardAPI => VOLUME_TYPE_DOWNWARD_API,
+            VolumeType::Projected => VOLUME_TYPE_PROJECTED,
+            VolumeType::HostPath => VOLUME_TYPE_HOST_PATH,
+            VolumeType::PersistentVolumeClaim => VOLUME_TYPE_PERSISTENT_VOLUME_CLAIM,
+            VolumeType::Csi => VOLUME_TYPE_CSI,
+        }
+    }
+
+    // Whether the volumes of the type are always read-only, the other ones are read-only when
+    // their source sets readOnly: true
+    fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            VolumeType::Secret
+                | VolumeType::ConfigMap
+                | VolumeType::DownwardAPI
+                | VolumeType::Projected
+        )
+    }
 }
 
 impl Default for VolumeType {
@@ -47,18 +149,82 @@ impl Default for VolumeType {
         VolumeType::Unknown
     }
 }
+
+// What the kubelet checks (or creates) at the path of a hostPath volume before mounting it, the
+// empty type checks nothing
+// Reference: https://kubernetes.io/docs/concepts/storage/volumes/#hostpath
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HostPathType {
+    Unset,
+    DirectoryOrCreate,
+    Directory,
+    FileOrCreate,
+    File,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl HostPathType {
+    fn parse(value: &serde_yaml::Value) -> Result<Self> {
+        let name = value
+            .as_str()
+            .ok_or_else(|| span::error(value, "failed to parse hostPath type into str"))?;
+
+        Ok(match name {
+            "" => HostPathType::Unset,
+            "DirectoryOrCreate" => HostPathType::DirectoryOrCreate,
+            "Directory" => HostPathType::Directory,
+            "FileOrCreate" => HostPathType::FileOrCreate,
+            "File" => HostPathType::File,
+            "Socket" => HostPathType::Socket,
+            "CharDevice" => HostPathType::CharDevice,
+            "BlockDevice" => HostPathType::BlockDevice,
+            _ => {
+                return Err(span::error(
+                    value,
+                    format!(
+                        "unsupported hostPath type {}, expected one of DirectoryOrCreate, Directory, FileOrCreate, File, Socket, CharDevice, or BlockDevice",
+                        name
+                    ),
+                ))
+            }
+        })
+    }
+
+    fn is_device(&self) -> bool {
+        matches!(self, HostPathType::CharDevice | HostPathType::BlockDevice)
+    }
+}
+
+impl Default for HostPathType {
+    fn default() -> Self {
+        HostPathType::Unset
+    }
+}
+
 #[derive(Default)]
 pub struct Volume {
     pub r#_type: VolumeType,
     pub readonly: bool,
     pub host_path: String,
+    pub host_path_type: HostPathType,
+    pub claim_name: String,
+    // Claim of the volumeClaimTemplates of a StatefulSet, i.e., one claim per replica
+    pub claim_template: bool,
     pub local: bool,
 }
 
 pub struct PodYaml<'input> {
     pub kind: &'input str,
+    pub name: &'input str,
+    pub namespace: &'input str,
+    pub labels: Option<&'input serde_yaml::Mapping>,
+    pub annotations: Option<&'input serde_yaml::Mapping>,
+    pub spec: &'input serde_yaml::Value,
     pub containers: Option<&'input Vec<serde_yaml::Value>>,
     pub init_containers: Option<&'input Vec<serde_yaml::Value>>,
+    pub enable_service_links: bool,
     volumes: HashMap<String, Volume>,
 }
 
@@ -66,6 +232,17 @@ pub struct PodYaml<'input> {
 pub struct SecurityContext {
     pub allow_elevated: bool,
     pub privileged: bool,
+    pub no_new_privileges: bool,
+    pub read_only_root_filesystem: bool,
+}
+
+// Fields of the pod and container securityContext deciding the user of the process, where the
+// container ones take precedence
+#[derive(Default)]
+pub struct UserContext {
+    pub run_as_non_root: bool,
+    pub run_as_user: Option<u32>,
+    pub run_as_group: Option<u32>,
 }
 
 #[derive(Default, Serialize, Deserialize)]
@@ -73,24 +250,164 @@ pub struct Debugging {
     pub tty: bool,
 }
 
+// Expected content of the resolv.conf written by the kubelet. The nameservers and searches of
+// the cluster or the node are only known at runtime unless the source is none.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Dns {
+    // cluster, host, or none
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub searches: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub options: Vec<String>,
+}
+
+impl Dns {
+    // The DNS of the cluster without any dnsConfig
+    pub fn is_default(&self) -> bool {
+        self.source == "cluster"
+            && self.nameservers.is_empty()
+            && self.searches.is_empty()
+            && self.options.is_empty()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HostAlias {
+    pub ip: String,
+    pub hostnames: Vec<String>,
+}
+
+// Workload identity of the pod, e.g., for binding the release policies of the KBS to it
+#[derive(Serialize, Deserialize)]
+pub struct ServiceAccount {
+    pub name: String,
+    // automountServiceAccountToken of the pod, the one of the service account if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub automount_token: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<ServiceAccountToken>,
+}
+
+// serviceAccountToken source of a projected volume
+#[derive(Serialize, Deserialize)]
+pub struct ServiceAccountToken {
+    pub volume: String,
+    pub path: String,
+    // The API server if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    pub expiration_seconds: u32,
+    // Containers mounting the volume
+    pub containers: Vec<String>,
+}
+
+// Variable of an envFrom source, see get_env_from
+#[derive(Clone)]
+pub enum EnvFrom {
+    // Of a ConfigMap, by name
+    Value(String, String),
+    // Any variable of a Secret, by prefix
+    Secret(String),
+}
+
+// Paths of the node that are usually sockets or devices, e.g., /var/run/docker.sock or /dev/kvm
+fn is_socket_or_device_path(path: &str) -> bool {
+    let path = Path::new(path);
+
+    path.starts_with("/dev")
+        || path
+            .extension()
+            .is_some_and(|extension| extension == "sock" || extension == "socket")
+}
+
+// Follow IsEnvVarName, see ENV_NAME_PATTERN
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || matches!(c, '-' | '.' | '_') => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+// Port exposed by a container, see get_ports
+#[derive(Serialize, Deserialize)]
+pub struct ContainerPort {
+    pub container: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub container_port: u32,
+    // TCP, UDP, or SCTP
+    pub protocol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_port: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_ip: Option<String>,
+}
+
+// IANA service name, e.g., http-envoy-prom, which Services and probes refer to
+// Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/staging/src/k8s.io/apimachinery/pkg/util/validation/validation.go#L301
+fn is_valid_port_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 15
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && name.chars().any(|c| c.is_ascii_lowercase())
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !name.contains("--")
+}
+
+fn get_port_number(value: &serde_yaml::Value, field: &str) -> Result<u32> {
+    let port = scalar::get_u32(value, field)?;
+    if port > 65535 {
+        return Err(span::error(
+            value,
+            format!("{} {} is not a valid port number", field, port),
+        ));
+    }
+
+    Ok(port)
+}
+
+// Return whether the document is of a kind PodYaml::from supports, the other documents (e.g.,
+// Services) being skipped by the commands
+pub fn is_workload(yaml: &serde_yaml::Value) -> bool {
+    yaml["kind"].as_str().and_then(get_template_path).is_some()
+}
+
 impl<'input> PodYaml<'input> {
     pub fn from(yaml: &'input serde_yaml::Value) -> Result<PodYaml> {
         let kind = if let Some(kind) = yaml.get("kind") {
             kind.as_str()
-                .ok_or_else(|| anyhow!("failed to parse kind into str"))?
+                .ok_or_else(|| span::error(kind, "failed to parse kind into str"))?
         } else {
             ""
         };
 
-        let spec = match kind {
-            "Pod" => &yaml["spec"],
-            "Job" | "Deployment" | "ReplicationController" => &yaml["spec"]["template"]["spec"],
-            _ => {
+        let template = match get_template_path(kind) {
+            Some(path) => get_template_keys(path).fold(yaml, |value, key| &value[key]),
+            None => {
                 bail!("unsupported kind: {}", kind);
             }
         };
+        let (metadata, spec) = (&template["metadata"], &template["spec"]);
+
+        let name = yaml["metadata"]["name"].as_str().unwrap_or("");
+        let namespace = yaml["metadata"]["namespace"].as_str().unwrap_or("");
 
-        let volumes = Self::get_volmues(spec)?;
+        let labels = metadata.get("labels").and_then(|v| v.as_mapping());
+        let annotations = metadata.get("annotations").and_then(|v| v.as_mapping());
+
+        let mut volumes = Self::get_volmues(spec)?;
+        if kind == "StatefulSet" {
+            Self::add_claim_templates(&yaml["spec"], &mut volumes)?;
+        }
 
         let mut containers = None;
         if let Some(v) = spec.get(SPEC_CONTAINERS) {
@@ -106,18 +423,95 @@ impl<'input> PodYaml<'input> {
             }
         }
 
+        // Defaults to true
+        // Reference: https://github.com/kubernetes/api/blob/release-1.26/core/v1/types.go#L3380
+        let enable_service_links = match spec.get("enableServiceLinks") {
+            Some(v) => scalar::get_bool(v, "enableServiceLinks")?,
+            None => true,
+        };
+
         Ok(PodYaml {
             kind,
+            name,
+            namespace,
+            labels,
+            annotations,
+            spec,
             containers,
             init_containers,
+            enable_service_links,
             volumes,
         })
     }
 
+    fn get_annotation(&self, key: &str) -> Result<Option<&str>> {
+        match self
+            .annotations
+            .and_then(|annotations| annotations.get(key))
+        {
+            Some(v) => Ok(Some(v.as_str().ok_or_else(|| {
+                span::error(v, format!("failed to parse annotation {} into str", key))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_sandbox_strategy(&self) -> Result<Option<&str>> {
+        self.get_annotation(SANDBOX_STRATEGY_KEY)
+    }
+
+    // Return the image config and the manifest (with its digest) of the container inlined in
+    // the annotations, if any. The inlined manifest must refer to the inlined config.
+    pub fn get_inline_image(
+        &self,
+        name: &str,
+    ) -> Result<(Option<ImageConfiguration>, Option<(ImageManifest, String)>)> {
+        let config_key = [INLINE_IMAGE_CONFIG_PREFIX, name].concat();
+        let manifest_key = [INLINE_IMAGE_MANIFEST_PREFIX, name].concat();
+
+        let config = self.get_annotation(&config_key)?;
+        let manifest = self.get_annotation(&manifest_key)?;
+
+        if let (Some(config), Some(manifest)) = (config, manifest) {
+            let manifest: ImageManifest = serde_json::from_str(manifest)
+                .with_context(|| format!("invalid image manifest in {}", manifest_key))?;
+            let digest = image::get_sha256(config.as_bytes());
+
+            if manifest.config().digest().to_string() != digest {
+                bail!(
+                    "the image config in {} has the digest {}, but the manifest in {} refers to {}",
+                    config_key,
+                    digest,
+                    manifest_key,
+                    manifest.config().digest()
+                );
+            }
+        }
+
+        let image_config = match config {
+            Some(config) => Some(
+                serde_json::from_str(config)
+                    .with_context(|| format!("invalid image config in {}", config_key))?,
+            ),
+            None => None,
+        };
+
+        let image_manifest = match manifest {
+            Some(manifest) => Some((
+                serde_json::from_str(manifest)
+                    .with_context(|| format!("invalid image manifest in {}", manifest_key))?,
+                image::get_sha256(manifest.as_bytes()),
+            )),
+            None => None,
+        };
+
+        Ok((image_config, image_manifest))
+    }
+
     pub fn get_name(container: &serde_yaml::Value) -> Result<String> {
         let name = container["name"]
             .as_str()
-            .ok_or_else(|| anyhow!("failed to parse name into string"))?;
+            .ok_or_else(|| span::error(&container["name"], "failed to parse name into string"))?;
 
         Ok(name.to_owned())
     }
@@ -127,75 +521,113 @@ impl<'input> PodYaml<'input> {
 
         if let Some(security_context) = container.get("securityContext") {
             if let Some(privileged) = security_context.get("privileged") {
-                let privileged = privileged
-                    .as_bool()
-                    .ok_or_else(|| anyhow!("failed to parse privileged into bool"))?;
+                let privileged = scalar::get_bool(privileged, "privileged")?;
 
                 context.privileged = privileged;
             }
 
+            if let Some(v) = security_context.get("readOnlyRootFilesystem") {
+                context.read_only_root_filesystem = scalar::get_bool(v, "readOnlyRootFilesystem")?;
+            }
+
             if let Some(allow_elevated) = security_context.get("allowPrivilegeEscalation") {
-                let allow_elevated = allow_elevated
-                    .as_bool()
-                    .ok_or_else(|| anyhow!("failed to parse allowPrivilegeEscalation into bool"))?;
+                let allow_elevated = scalar::get_bool(allow_elevated, "allowPrivilegeEscalation")?;
 
                 context.allow_elevated = allow_elevated;
+
+                // Rejected by the API server
+                // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/validation/validation.go#L6597
+                if context.privileged && !allow_elevated {
+                    bail!("cannot set allowPrivilegeEscalation to false and privileged to true");
+                }
+
+                // Only an explicit false sets no_new_privs, and privileged containers never set it
+                // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/securitycontext/util.go#L180
+                context.no_new_privileges = !allow_elevated && !context.privileged;
             }
         }
 
         Ok(context)
     }
 
-    fn get_value_from_config_map(map: &serde_yaml::Value) -> Result<String> {
-        let map = map
-            .as_mapping()
-            .ok_or_else(|| anyhow!("failed in convert configMapKeyRef into map"))?;
+    // Return the data of the ConfigMap, None if it does not exist. The ConfigMaps are read once
+    // given that the env of a container is resolved several times.
+    fn get_config_map_data(name: &str) -> Result<Option<serde_yaml::Mapping>> {
+        if let Some(data) = CONFIG_MAPS.lock().unwrap().get(name) {
+            return Ok(data.clone());
+        }
+
+        let config_map = cluster::get_yaml(&[
+            "get",
+            "configmap",
+            subprocess::operand(name)?,
+            "--ignore-not-found",
+        ])?;
+
+        let data = if config_map.is_null() {
+            None
+        } else {
+            match config_map.get("data") {
+                Some(data) => Some(
+                    data.as_mapping()
+                        .ok_or_else(|| span::error(data, "failed to parse data into mapping"))?
+                        .clone(),
+                ),
+                None => Some(serde_yaml::Mapping::new()),
+            }
+        };
+
+        CONFIG_MAPS
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), data.clone());
+
+        Ok(data)
+    }
+
+    fn is_optional(map: &serde_yaml::Value) -> Result<bool> {
+        match map.get("optional") {
+            Some(v) => scalar::get_bool(v, "optional"),
+            None => Ok(false),
+        }
+    }
+
+    // None if the ConfigMap or the key is missing but optional, in which case the kubelet does
+    // not set the variable
+    fn get_value_from_config_map(map: &serde_yaml::Value) -> Result<Option<String>> {
+        if !map.is_mapping() {
+            return Err(span::error(
+                map,
+                "failed to parse configMapKeyRef into mapping",
+            ));
+        }
 
         let name = map["name"]
             .as_str()
-            .ok_or_else(|| anyhow!("failed to parse name into str"))?;
+            .ok_or_else(|| span::error(&map["name"], "failed to parse name into str"))?;
 
         let key = map["key"]
             .as_str()
-            .ok_or_else(|| anyhow!("failed to parse key into str"))?;
-
-        let output = match CheckedCommand::new(KUBECTL)
-            .arg("get")
-            .arg("configmap")
-            .arg(name)
-            .arg("-o")
-            .arg("yaml")
-            .output()
-        {
-            Ok(result) => String::from_utf8(result.stdout)?,
-            Err(Error::Failure(ex, output)) => {
-                println!("failed with exit code: {:?}", ex.code());
-                if let Some(output) = output {
-                    bail!(
-                        "{}: kubectl failed: {}",
-                        loc!(),
-                        String::from_utf8_lossy(&*output.stderr)
-                    );
-                }
-                bail!("{}", loc!());
-            }
-            Err(Error::Io(io_err)) => {
-                bail!("{}: unexpected I/O error: {:?}", loc!(), io_err);
-            }
-        };
+            .ok_or_else(|| span::error(&map["key"], "failed to parse key into str"))?;
 
-        let config_map: serde_yaml::Value = serde_yaml::from_str(&output)?;
+        let optional = Self::is_optional(map)?;
 
-        let data = config_map["data"]
-            .as_mapping()
-            .ok_or_else(|| anyhow!("failed to parse data into mapping"))?;
+        let data = match Self::get_config_map_data(name)? {
+            Some(data) => data,
+            None if optional => return Ok(None),
+            None => bail!("configMap {} of configMapKeyRef is not found", name),
+        };
 
         if let Some(value) = data.get(key) {
             let value = value
                 .as_str()
-                .ok_or_else(|| anyhow!("failed to parse value into str"))?;
+                .ok_or_else(|| span::error(value, "failed to parse value into str"))?;
+
+            return Ok(Some(value.to_string()));
+        }
 
-            return Ok(value.to_string());
+        if optional {
+            return Ok(None);
         }
 
         bail!(
@@ -206,20 +638,23 @@ impl<'input> PodYaml<'input> {
         )
     }
 
-    fn get_value_from(env: &serde_yaml::Value, name: &str) -> Result<(String, String)> {
+    fn get_value_from(env: &serde_yaml::Value, name: &str) -> Result<Option<(String, String)>> {
         // default values
         let mut rule = [name, "="].concat();
         let mut strategy = String::from("string");
 
         if let Some(value_from) = env.get("valueFrom") {
-            let value_from = value_from
-                .as_mapping()
-                .ok_or_else(|| anyhow!("failed to convert valueFrom into mapping"))?;
+            let value_from = value_from.as_mapping().ok_or_else(|| {
+                span::error(value_from, "failed to convert valueFrom into mapping")
+            })?;
 
             if value_from.contains_key(CONFIG_MAP_KEY_REF) {
                 let config_map = value_from.get(CONFIG_MAP_KEY_REF).unwrap();
-                let value = Self::get_value_from_config_map(config_map)?;
-                rule = ["^", name, "=", &value, "$"].concat();
+                let value = match Self::get_value_from_config_map(config_map)? {
+                    Some(value) => value,
+                    None => return Ok(None),
+                };
+                rule = pattern::literal(&[name, "=", &value].concat());
                 strategy = String::from("string");
             } else if value_from.contains_key(SECRET_KEY_REF)
                 || value_from.contains_key(FIELD_REF)
@@ -232,7 +667,7 @@ impl<'input> PodYaml<'input> {
             }
         }
 
-        Ok((rule, strategy))
+        Ok(Some((rule, strategy)))
     }
 
     pub fn get_volmues(spec: &serde_yaml::Value) -> Result<HashMap<String, Volume>> {
@@ -243,15 +678,17 @@ impl<'input> PodYaml<'input> {
                 for vol in seq {
                     let vol = vol
                         .as_mapping()
-                        .ok_or_else(|| anyhow!("failed to convert volume into mapping"))?;
+                        .ok_or_else(|| span::error(vol, "failed to convert volume into mapping"))?;
 
-                    let name = vol["name"]
-                        .as_str()
-                        .ok_or_else(|| anyhow!("failed to parse name into str"))?;
+                    let name = vol["name"].as_str().ok_or_else(|| {
+                        span::error(&vol["name"], "failed to parse name into str")
+                    })?;
 
                     let mut r#_type = VolumeType::default();
                     let mut readonly = false;
                     let mut host_path = String::new();
+                    let mut host_path_type = HostPathType::default();
+                    let mut claim_name = String::new();
                     let mut local = false;
 
                     if vol.contains_key(VOLUME_TYPE_EMPTY_DIR) {
@@ -282,6 +719,45 @@ impl<'input> PodYaml<'input> {
                                 host_path = String::from(path);
                             }
                         }
+
+                        if let Some(v) = vol[VOLUME_TYPE_HOST_PATH].get("type") {
+                            host_path_type = HostPathType::parse(v)?;
+                        }
+                    } else if vol.contains_key(VOLUME_TYPE_PERSISTENT_VOLUME_CLAIM) {
+                        r#_type = VolumeType::PersistentVolumeClaim;
+
+                        let claim = &vol[VOLUME_TYPE_PERSISTENT_VOLUME_CLAIM];
+                        claim_name = claim["claimName"]
+                            .as_str()
+                            .ok_or_else(|| {
+                                span::error(
+                                    &claim["claimName"],
+                                    "failed to parse claimName into str",
+                                )
+                            })?
+                            .to_string();
+
+                        if let Some(v) = claim.get("readOnly") {
+                            readonly = scalar::get_bool(v, "readOnly")?;
+                        }
+                    } else if vol.contains_key(VOLUME_TYPE_CSI) {
+                        r#_type = VolumeType::Csi;
+
+                        let csi = &vol[VOLUME_TYPE_CSI];
+                        if let Some(v) = csi.get("readOnly") {
+                            readonly = scalar::get_bool(v, "readOnly")?;
+                        }
+
+                        let driver = csi["driver"].as_str().ok_or_else(|| {
+                            span::error(&csi["driver"], "failed to parse driver into str")
+                        })?;
+
+                        if driver == SECRETS_STORE_CSI_DRIVER && !readonly {
+                            warn!(
+                                "csi volume {} of driver {} does not set readOnly: true, which the driver rejects",
+                                name, driver
+                            );
+                        }
                     }
 
                     volumes.insert(
@@ -290,6 +766,9 @@ impl<'input> PodYaml<'input> {
                             r#_type,
                             readonly,
                             host_path,
+                            host_path_type,
+                            claim_name,
+                            claim_template: false,
                             local,
                         },
                     );
@@ -300,163 +779,1053 @@ impl<'input> PodYaml<'input> {
         Ok(volumes)
     }
 
-    pub fn get_debugging(container: &serde_yaml::Value) -> Result<Debugging> {
-        let tty = if let Some(v) = container.get("tty") {
-            v.as_bool()
-                .ok_or_else(|| anyhow!("failed to parse tty into bool"))?
-        } else {
-            false
+    // The StatefulSet controller adds a persistentVolumeClaim volume per claim template, named
+    // after it and replacing the volume of the same name of the pod template, whose claim is
+    // <template>-<statefulset>-<ordinal>
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/controller/statefulset/stateful_set_utils.go
+    fn add_claim_templates(
+        spec: &serde_yaml::Value,
+        volumes: &mut HashMap<String, Volume>,
+    ) -> Result<()> {
+        let templates = match spec.get("volumeClaimTemplates") {
+            Some(templates) => templates.as_sequence().ok_or_else(|| {
+                span::error(
+                    templates,
+                    "failed to parse volumeClaimTemplates into sequence",
+                )
+            })?,
+            None => return Ok(()),
         };
 
-        Ok(Debugging { tty })
+        for template in templates {
+            let name = template["metadata"]["name"].as_str().ok_or_else(|| {
+                span::error(
+                    &template["metadata"]["name"],
+                    "failed to parse the name of the claim template into str",
+                )
+            })?;
+
+            volumes.insert(
+                name.to_string(),
+                Volume {
+                    r#_type: VolumeType::PersistentVolumeClaim,
+                    claim_template: true,
+                    ..Default::default()
+                },
+            );
+        }
+
+        Ok(())
     }
 
-    pub fn get_env(container: &serde_yaml::Value) -> Result<Vec<String>> {
+    // The kubelet appends the host aliases to the managed /etc/hosts, which changes the content
+    // but not the path of the mount
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L357
+    pub fn get_host_aliases(&self) -> Result<Vec<HostAlias>> {
         let mut results = Vec::new();
 
-        if let Some(env) = container.get("env") {
-            let env = env
-                .as_sequence()
-                .ok_or_else(|| anyhow!("failed to parse env into sequence"))?;
+        if let Some(host_aliases) = self.spec.get("hostAliases") {
+            let host_aliases = host_aliases.as_sequence().ok_or_else(|| {
+                span::error(host_aliases, "failed to parse hostAliases into sequence")
+            })?;
 
-            for map in env {
-                let name = map["name"]
+            for host_alias in host_aliases {
+                let ip = host_alias["ip"]
                     .as_str()
-                    .ok_or_else(|| anyhow!("failed to parse name into string"))?;
+                    .ok_or_else(|| span::error(&host_alias["ip"], "failed to parse ip into str"))?;
 
-                let rule;
+                let hostnames = match host_alias.get("hostnames") {
+                    Some(v) => Self::get_string_sequence(v, "hostnames")?,
+                    None => Vec::new(),
+                };
+
+                results.push(HostAlias {
+                    ip: ip.to_string(),
+                    hostnames,
+                });
+            }
+        }
+
+        Ok(results)
+    }
 
-                if let Some(v) = map.get("value") {
-                    let value = v
+    // Follow validateContainerPorts and SetDefaults_Pod, i.e., the names are unique in each
+    // container (but not in the pod, e.g., the sidecars injected by istio), hostPort, protocol,
+    // and hostIP are unique in the pod, and hostPort defaults to containerPort, which it must
+    // match, under hostNetwork
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/validation/validation.go#L2855
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/v1/defaults.go#L212
+    pub fn get_ports(&self) -> Result<Vec<ContainerPort>> {
+        let host_network = self.get_host_network()?;
+
+        let mut results = Vec::new();
+        let mut host_ports = BTreeSet::new();
+
+        let containers = self.containers.into_iter().chain(self.init_containers);
+        for container in containers.flatten() {
+            let ports = match container.get("ports") {
+                Some(ports) => ports
+                    .as_sequence()
+                    .ok_or_else(|| span::error(ports, "failed to parse ports into sequence"))?,
+                None => continue,
+            };
+
+            let container_name = container["name"].as_str().unwrap_or_default();
+            let mut names = BTreeSet::new();
+
+            for port in ports {
+                let container_port = get_port_number(&port["containerPort"], "containerPort")?;
+                if container_port == 0 {
+                    return Err(span::error(
+                        &port["containerPort"],
+                        "containerPort must be between 1 and 65535",
+                    ));
+                }
+
+                let protocol = match port.get("protocol") {
+                    Some(v) => v
                         .as_str()
-                        .ok_or_else(|| anyhow!("failed to parse value into string"))?;
+                        .ok_or_else(|| span::error(v, "failed to parse protocol into str"))?,
+                    None => "TCP",
+                };
+                if !matches!(protocol, "TCP" | "UDP" | "SCTP") {
+                    return Err(span::error(
+                        &port["protocol"],
+                        format!("unsupported protocol {}", protocol),
+                    ));
+                }
 
-                    rule = [name, "=", value].concat();
-                } else {
-                    (rule, _) = Self::get_value_from(map, name)?;
+                let name = match port.get("name") {
+                    Some(v) => {
+                        let name = v
+                            .as_str()
+                            .ok_or_else(|| span::error(v, "failed to parse name into str"))?;
+
+                        if !is_valid_port_name(name) {
+                            return Err(span::error(
+                                v,
+                                format!("port name {} is not a valid IANA service name", name),
+                            ));
+                        }
+
+                        if !names.insert(name) {
+                            return Err(span::error(
+                                v,
+                                format!(
+                                    "port name {} is used twice in container {}",
+                                    name, container_name
+                                ),
+                            ));
+                        }
+
+                        Some(name.to_string())
+                    }
+                    None => None,
+                };
+
+                let host_port = match port.get("hostPort") {
+                    Some(v) => get_port_number(v, "hostPort")?,
+                    None => 0,
+                };
+                let host_port = match host_port {
+                    0 if host_network => Some(container_port),
+                    0 => None,
+                    host_port if host_network && host_port != container_port => {
+                        return Err(span::error(
+                            &port["hostPort"],
+                            format!(
+                                "hostPort {} must match containerPort {} under hostNetwork",
+                                host_port, container_port
+                            ),
+                        ))
+                    }
+                    host_port => Some(host_port),
+                };
+
+                let host_ip = match port.get("hostIP") {
+                    Some(v) => Some(
+                        v.as_str()
+                            .ok_or_else(|| span::error(v, "failed to parse hostIP into str"))?
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+
+                if let Some(host_port) = host_port {
+                    let key = (host_port, protocol, host_ip.clone().unwrap_or_default());
+                    if !host_ports.insert(key) {
+                        return Err(span::error(
+                            port,
+                            format!(
+                                "hostPort {}/{} of container {} is used twice in the pod",
+                                host_port, protocol, container_name
+                            ),
+                        ));
+                    }
                 }
 
-                results.push(rule);
+                results.push(ContainerPort {
+                    container: container_name.to_string(),
+                    name,
+                    container_port,
+                    protocol: protocol.to_string(),
+                    host_port,
+                    host_ip,
+                });
             }
         }
 
         Ok(results)
     }
 
-    // Return workingDir, command, and args
-    pub fn get_entry_point(
-        container: &serde_yaml::Value,
-    ) -> Result<(String, Vec<String>, Vec<String>)> {
-        let mut working_dir = String::new();
-        let mut command = Vec::new();
-        let mut args = Vec::new();
-
-        if let Some(v) = container.get("workingDir") {
-            working_dir = v
-                .as_str()
-                .ok_or_else(|| anyhow!("failed to parse workingDir into string"))?
-                .to_string();
+    fn get_host_network(&self) -> Result<bool> {
+        match self.spec.get("hostNetwork") {
+            Some(v) => scalar::get_bool(v, "hostNetwork"),
+            None => Ok(false),
         }
+    }
 
-        if let Some(v) = container.get("command") {
-            if let Some(seq) = v.as_sequence() {
-                command = seq
-                    .iter()
-                    .filter_map(|arg| arg.as_str())
-                    .map(String::from)
-                    .collect::<Vec<_>>();
+    pub fn get_user_context(&self, container: &serde_yaml::Value) -> Result<UserContext> {
+        let mut context = UserContext::default();
+
+        for security_context in [&self.spec["securityContext"], &container["securityContext"]] {
+            if let Some(v) = security_context.get("runAsNonRoot") {
+                context.run_as_non_root = scalar::get_bool(v, "runAsNonRoot")?;
             }
-        }
 
-        if let Some(v) = container.get("args") {
-            if let Some(seq) = v.as_sequence() {
-                args = seq
-                    .iter()
-                    .filter_map(|arg| arg.as_str())
-                    .map(String::from)
-                    .collect::<Vec<_>>();
+            if let Some(v) = security_context.get("runAsUser") {
+                context.run_as_user = Some(scalar::get_u32(v, "runAsUser")?);
             }
-        };
 
-        Ok((working_dir, command, args))
+            if let Some(v) = security_context.get("runAsGroup") {
+                context.run_as_group = Some(scalar::get_u32(v, "runAsGroup")?);
+            }
+        }
+
+        Ok(context)
     }
 
-    pub fn get_mounts(&self, container: &serde_yaml::Value) -> Result<Vec<Mount>> {
-        let mut results = Vec::new();
+    // Follow getPodDNSType, i.e., ClusterFirst falls back to the DNS of the node under
+    // hostNetwork unless ClusterFirstWithHostNet is used, and dnsConfig is merged on top
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/network/dns/dns.go#L341
+    pub fn get_dns(&self) -> Result<Dns> {
+        let dns_policy = match self.spec.get("dnsPolicy") {
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| span::error(v, "failed to parse dnsPolicy into string"))?,
+            None => "ClusterFirst",
+        };
 
-        if let Some(volume_mounts) = container.get("volumeMounts") {
-            let volume_mounts = volume_mounts
-                .as_sequence()
-                .ok_or_else(|| anyhow!("failed to parse volumeMounts into sequence"))?;
+        let host_network = self.get_host_network()?;
 
-            for volume_mount in volume_mounts {
-                let destination = volume_mount["mountPath"]
-                    .as_str()
-                    .ok_or_else(|| anyhow!("failed to prase mountPath into string"))?;
+        let source = match dns_policy {
+            "ClusterFirstWithHostNet" => "cluster",
+            "ClusterFirst" if host_network => "host",
+            "ClusterFirst" => "cluster",
+            "Default" => "host",
+            "None" => "none",
+            _ => bail!("unsupported dnsPolicy: {}", dns_policy),
+        };
 
-                let destination = PathBuf::from(destination);
+        let dns_config = &self.spec["dnsConfig"];
 
-                let mut propagation: &str = &String::from("None");
-                if let Some(v) = volume_mount.get("mountPropagation") {
-                    propagation = v
-                        .as_str()
-                        .ok_or_else(|| anyhow!("failed to parse mountPropagation into string"))?;
-                }
+        if source == "none" && dns_config.is_null() {
+            bail!("dnsConfig is required with dnsPolicy None");
+        }
 
-                let name = volume_mount["name"]
-                    .as_str()
-                    .ok_or_else(|| anyhow!("failed to prase name into string"))?;
+        let mut dns = Dns {
+            source: source.to_string(),
+            ..Default::default()
+        };
 
-                let volume = self
-                    .volumes
-                    .get(name)
-                    .ok_or_else(|| anyhow!("failed to find volume {}", name))?;
+        if let Some(v) = dns_config.get("nameservers") {
+            dns.nameservers = Self::get_string_sequence(v, "nameservers")?;
+        }
 
-                let source = PathBuf::from(&volume.host_path);
+        if let Some(v) = dns_config.get("searches") {
+            dns.searches = Self::get_string_sequence(v, "searches")?;
+        }
 
-                let mut read_only = volume.readonly;
-                // Readonly volume takes precedence over the readOnly field
-                if !read_only {
-                    if let Some(v) = volume_mount.get("readOnly") {
-                        read_only = v
-                            .as_bool()
-                            .ok_or_else(|| anyhow!("failed to parse readOnly into bool"))?;
-                    }
+        if let Some(options) = dns_config.get("options") {
+            let options = options.as_sequence().ok_or_else(|| {
+                span::error(options, "failed to parse dnsConfig.options into sequence")
+            })?;
+
+            for option in options {
+                let name = option["name"].as_str().ok_or_else(|| {
+                    span::error(
+                        &option["name"],
+                        "failed to parse dnsConfig option name into string",
+                    )
+                })?;
+
+                match option.get("value").and_then(|v| v.as_str()) {
+                    Some(value) => dns.options.push(format!("{}:{}", name, value)),
+                    None => dns.options.push(name.to_string()),
                 }
+            }
+        }
 
-                let mut r#type = String::from("bind");
+        Ok(dns)
+    }
 
-                if volume.local {
-                    r#type = String::from("local");
-                }
+    // serviceAccountName defaults to default, the deprecated serviceAccount is its alias, and
+    // expirationSeconds of the projected tokens defaults to one hour, with a minimum of ten
+    // minutes
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/v1/defaults.go#L425
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/validation/validation.go#L1149
+    pub fn get_service_account(&self) -> Result<ServiceAccount> {
+        let name = match self
+            .spec
+            .get("serviceAccountName")
+            .or_else(|| self.spec.get("serviceAccount"))
+        {
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| span::error(v, "failed to parse serviceAccountName into str"))?,
+            None => "default",
+        };
 
-                let mut options: Vec<String> =
-                    vec!["rbind"].into_iter().map(String::from).collect();
+        let automount_token = match self.spec.get("automountServiceAccountToken") {
+            Some(v) => Some(scalar::get_bool(v, "automountServiceAccountToken")?),
+            None => None,
+        };
 
-                match propagation {
-                    "None" => {
-                        options.push(String::from("rprivate"));
-                    }
-                    "HostToContainer" => {
-                        options.push(String::from("rslave"));
-                    }
-                    "Bidirectional" => {
-                        options.push(String::from("rshared"));
-                    }
-                    _ => {
-                        return Err(anyhow!("Unknown mountPropagation type"));
+        let mut tokens = Vec::new();
+
+        for volume in self.spec["volumes"].as_sequence().into_iter().flatten() {
+            let volume_name = volume["name"].as_str().unwrap_or_default();
+            let sources = volume[VOLUME_TYPE_PROJECTED]["sources"].as_sequence();
+
+            for source in sources.into_iter().flatten() {
+                let token = match source.get("serviceAccountToken") {
+                    Some(token) => token,
+                    None => continue,
+                };
+
+                let path = token["path"].as_str().ok_or_else(|| {
+                    span::error(
+                        &token["path"],
+                        "failed to parse serviceAccountToken path into str",
+                    )
+                })?;
+
+                let audience = match token.get("audience") {
+                    Some(v) => Some(
+                        v.as_str()
+                            .ok_or_else(|| span::error(v, "failed to parse audience into str"))?
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+
+                let expiration_seconds = match token.get("expirationSeconds") {
+                    Some(v) => {
+                        let seconds = scalar::get_u32(v, "expirationSeconds")?;
+                        if seconds < 600 {
+                            return Err(span::error(
+                                v,
+                                format!(
+                                    "expirationSeconds {} is less than 600, the minimum of the API server",
+                                    seconds
+                                ),
+                            ));
+                        }
+                        seconds
                     }
-                }
+                    None => 3600,
+                };
+
+                let containers = self
+                    .containers
+                    .into_iter()
+                    .chain(self.init_containers)
+                    .flatten()
+                    .filter(|container| {
+                        container["volumeMounts"]
+                            .as_sequence()
+                            .into_iter()
+                            .flatten()
+                            .any(|volume_mount| volume_mount["name"].as_str() == Some(volume_name))
+                    })
+                    .map(Self::get_name)
+                    .collect::<Result<Vec<String>>>()?;
+
+                tokens.push(ServiceAccountToken {
+                    volume: volume_name.to_string(),
+                    path: path.to_string(),
+                    audience,
+                    expiration_seconds,
+                    containers,
+                });
+            }
+        }
 
-                if read_only {
-                    options.push(String::from("ro"));
-                } else {
-                    options.push(String::from("rw"));
-                }
+        Ok(ServiceAccount {
+            name: name.to_string(),
+            automount_token,
+            tokens,
+        })
+    }
 
-                let mut mount = Mount::default();
+    // Names of the Secrets with the credentials of the registries of the images
+    pub fn get_image_pull_secrets(&self) -> Result<Vec<String>> {
+        let mut results = Vec::new();
 
-                mount.set_destination(destination);
+        if let Some(secrets) = self.spec.get("imagePullSecrets") {
+            let secrets = secrets.as_sequence().ok_or_else(|| {
+                span::error(secrets, "failed to parse imagePullSecrets into sequence")
+            })?;
+
+            for secret in secrets {
+                let name = secret["name"]
+                    .as_str()
+                    .ok_or_else(|| span::error(&secret["name"], "failed to parse name into str"))?;
+                results.push(name.to_string());
+            }
+        }
+
+        Ok(results)
+    }
+
+    pub fn get_debugging(container: &serde_yaml::Value) -> Result<Debugging> {
+        let tty = if let Some(v) = container.get("tty") {
+            scalar::get_bool(v, "tty")?
+        } else {
+            false
+        };
+
+        Ok(Debugging { tty })
+    }
+
+    // The rule of a Secret of envFrom without a prefix matches any variable, which is only
+    // allowed along with the other relaxed env rules of --allow-any-env
+    pub fn check_env_from(container: &serde_yaml::Value, allow_any_env: bool) -> Result<()> {
+        if allow_any_env {
+            return Ok(());
+        }
+
+        for source in container["envFrom"].as_sequence().into_iter().flatten() {
+            if let Some(secret) = source.get(SECRET_REF) {
+                if source["prefix"].as_str().unwrap_or_default().is_empty() {
+                    return Err(span::error(
+                        secret,
+                        format!(
+                            "secret {} of envFrom has no prefix, its rule would match any env variable, set a prefix or use --allow-any-env",
+                            secret["name"].as_str().unwrap_or_default()
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Variables of the envFrom sources in order, a later source overriding the earlier ones.
+    // The keys (with the prefix) that are not env names are skipped like the kubelet does, and
+    // the missing optional sources are ignored. The keys of a Secret are never read, its rule
+    // matches any variable with the prefix.
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L600
+    pub fn get_env_from(container: &serde_yaml::Value) -> Result<Vec<EnvFrom>> {
+        let sources = match container.get("envFrom") {
+            Some(sources) => sources,
+            None => return Ok(Vec::new()),
+        };
+
+        let key = serde_yaml::to_string(sources)?;
+        if let Some(results) = ENV_FROM.lock().unwrap().get(&key) {
+            return Ok(results.clone());
+        }
+
+        let results = Self::resolve_env_from(sources)?;
+        ENV_FROM.lock().unwrap().insert(key, results.clone());
+
+        Ok(results)
+    }
+
+    fn resolve_env_from(sources: &serde_yaml::Value) -> Result<Vec<EnvFrom>> {
+        let mut results = Vec::new();
+
+        let sources = sources
+            .as_sequence()
+            .ok_or_else(|| span::error(sources, "failed to parse envFrom into sequence"))?;
+
+        for source in sources {
+            let prefix = match source.get("prefix") {
+                Some(v) => v
+                    .as_str()
+                    .ok_or_else(|| span::error(v, "failed to parse prefix into str"))?,
+                None => "",
+            };
+
+            if let Some(config_map) = source.get(CONFIG_MAP_REF) {
+                let name = config_map["name"].as_str().ok_or_else(|| {
+                    span::error(&config_map["name"], "failed to parse name into str")
+                })?;
+
+                let data = match Self::get_config_map_data(name)? {
+                    Some(data) => data,
+                    None if Self::is_optional(config_map)? => continue,
+                    None => bail!("configMap {} of envFrom is not found", name),
+                };
+
+                for (key, value) in &data {
+                    let key = key
+                        .as_str()
+                        .ok_or_else(|| span::error(key, "failed to parse key into str"))?;
+                    let value = value
+                        .as_str()
+                        .ok_or_else(|| span::error(value, "failed to parse value into str"))?;
+
+                    let variable = [prefix, key].concat();
+                    if !is_env_name(&variable) {
+                        warn!(
+                            "configMap {}: key {} is skipped by the kubelet, {} is not a valid env name",
+                            name, key, variable
+                        );
+                        continue;
+                    }
+
+                    results.retain(
+                        |env_from| !matches!(env_from, EnvFrom::Value(other, _) if *other == variable),
+                    );
+                    results.push(EnvFrom::Value(variable, value.to_string()));
+                }
+            } else if let Some(secret) = source.get(SECRET_REF) {
+                secret["name"]
+                    .as_str()
+                    .ok_or_else(|| span::error(&secret["name"], "failed to parse name into str"))?;
+
+                // The variables of a Secret override the earlier ones of the same prefix
+                results.retain(
+                    |env_from| !matches!(env_from, EnvFrom::Secret(other) if other == prefix),
+                );
+                results.push(EnvFrom::Secret(prefix.to_string()));
+            } else {
+                bail!("{} unsupported envFrom source: {:?}", loc!(), source);
+            }
+        }
+
+        Ok(results)
+    }
+
+    // The env of the container overrides the variables of envFrom. The values are the ones the
+    // kubelet expands, see expand_env.
+    pub fn get_env(
+        container: &serde_yaml::Value,
+        enable_service_links: bool,
+    ) -> Result<Vec<String>> {
+        let mut results = Vec::new();
+        let mut names = BTreeSet::new();
+
+        let (_, expanded) = Self::expand_env(container, enable_service_links)?;
+
+        if let Some(env) = container.get("env") {
+            let env = env
+                .as_sequence()
+                .ok_or_else(|| span::error(env, "failed to parse env into sequence"))?;
+
+            for (map, expanded) in env.iter().zip(expanded) {
+                let name = map["name"]
+                    .as_str()
+                    .ok_or_else(|| span::error(&map["name"], "failed to parse name into string"))?;
+                names.insert(name);
+
+                let rule;
+
+                if let Some((value, is_regex)) = expanded {
+                    rule = match is_regex {
+                        // The regex of expand, anchored at the start of the value
+                        true => ["^", &regex::escape(name), "=", &value[1..]].concat(),
+                        false => [name, "=", &value].concat(),
+                    };
+                } else {
+                    rule = match Self::get_value_from(map, name)? {
+                        Some((rule, _)) => rule,
+                        None => continue,
+                    };
+                }
+
+                results.push(rule);
+            }
+        }
+
+        let mut env_from_rules = Vec::new();
+        for env_from in Self::get_env_from(container)? {
+            match env_from {
+                EnvFrom::Value(name, _) if names.contains(name.as_str()) => {}
+                EnvFrom::Value(name, value) => {
+                    env_from_rules.push(pattern::literal(&[&name, "=", &value].concat()))
+                }
+                EnvFrom::Secret(prefix) => env_from_rules
+                    .push(["^", &regex::escape(&prefix), ENV_NAME_PATTERN, "=.*$"].concat()),
+            }
+        }
+
+        Ok([env_from_rules, results].concat())
+    }
+
+    // Return the values of the env of the container, which the $(VAR) references of the
+    // command and args expand to. The values can refer to the previous ones and to the ones of
+    // envFrom, which are not expanded, and the values from references (e.g., the downward API),
+    // the variables of the Secrets of envFrom, and the ones of the services are only known at
+    // runtime.
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go#L662
+    pub fn get_env_values(
+        container: &serde_yaml::Value,
+        enable_service_links: bool,
+    ) -> Result<EnvValues> {
+        Ok(Self::expand_env(container, enable_service_links)?.0)
+    }
+
+    // Return the values of get_env_values, and the value of each entry of env as expanded by
+    // kubernetes::expand, None for the entries without a value
+    pub fn expand_env(
+        container: &serde_yaml::Value,
+        enable_service_links: bool,
+    ) -> Result<(EnvValues, Vec<Option<(String, bool)>>)> {
+        let mut values = EnvValues::with_service_links(enable_service_links)?;
+        let mut expanded = Vec::new();
+
+        for env_from in Self::get_env_from(container)? {
+            match env_from {
+                EnvFrom::Value(name, value) => values.insert(name, EnvValue::Literal(value)),
+                EnvFrom::Secret(prefix) => {
+                    values.insert_runtime_names(&["^", &regex::escape(&prefix)].concat())?
+                }
+            }
+        }
+
+        if let Some(env) = container.get("env") {
+            let env = env
+                .as_sequence()
+                .ok_or_else(|| span::error(env, "failed to parse env into sequence"))?;
+
+            for map in env {
+                let name = map["name"]
+                    .as_str()
+                    .ok_or_else(|| span::error(&map["name"], "failed to parse name into string"))?;
+
+                let value = match map.get("value") {
+                    Some(v) => {
+                        let value = v
+                            .as_str()
+                            .ok_or_else(|| span::error(v, "failed to parse value into string"))?;
+
+                        let (value, is_regex) = kubernetes::expand(value, &values);
+                        expanded.push(Some((value.clone(), is_regex)));
+
+                        match is_regex {
+                            false => EnvValue::Literal(value),
+                            true => EnvValue::Runtime,
+                        }
+                    }
+                    None => {
+                        expanded.push(None);
+
+                        match map.get("valueFrom") {
+                            Some(_) => EnvValue::Runtime,
+                            None => EnvValue::Literal(String::new()),
+                        }
+                    }
+                };
+
+                values.insert(name.to_string(), value);
+            }
+        }
+
+        Ok((values, expanded))
+    }
+
+    // Every entry is kept as is and in order, entries that are not strings are rejected
+    // rather than dropped given that dropping them would change the argv
+    fn get_string_sequence(value: &serde_yaml::Value, field: &str) -> Result<Vec<String>> {
+        let seq = value.as_sequence().ok_or_else(|| {
+            span::error(value, format!("failed to parse {} into sequence", field))
+        })?;
+
+        seq.iter()
+            .map(|entry| {
+                entry.as_str().map(String::from).ok_or_else(|| {
+                    span::error(
+                        entry,
+                        format!("failed to parse {} entry {:?} into string", field, entry),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_image_pull_policy(container: &serde_yaml::Value) -> Result<Option<&str>> {
+        match container.get("imagePullPolicy") {
+            Some(v) => Ok(Some(v.as_str().ok_or_else(|| {
+                span::error(v, "failed to parse imagePullPolicy into string")
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    // Return workingDir, command, and args
+    pub fn get_entry_point(
+        container: &serde_yaml::Value,
+    ) -> Result<(String, Vec<String>, Vec<String>)> {
+        let mut working_dir = String::new();
+        let mut command = Vec::new();
+        let mut args = Vec::new();
+
+        if let Some(v) = container.get("workingDir") {
+            working_dir = v
+                .as_str()
+                .ok_or_else(|| span::error(v, "failed to parse workingDir into string"))?
+                .to_string();
+        }
+
+        if let Some(v) = container.get("command") {
+            command = Self::get_string_sequence(v, "command")?;
+        }
+
+        if let Some(v) = container.get("args") {
+            args = Self::get_string_sequence(v, "args")?;
+        }
+
+        Ok((working_dir, command, args))
+    }
+
+    // Warn once per hostPath volume whose type changes what the containers get in the VM. Kata
+    // shares every bind mount with the guest with the same options whatever the type, so the
+    // rules do not depend on it, except the block devices, which are hotplugged, and the devices
+    // under /dev, which are not shared.
+    // Reference: https://github.com/kata-containers/kata-containers/blob/3.2.0/src/runtime/virtcontainers/container.go (mountSharedDirMounts)
+    // Reference: https://github.com/kata-containers/kata-containers/blob/3.2.0/src/runtime/virtcontainers/mount.go (isHostDevice)
+    pub fn check_host_paths(&self) {
+        let mut names: Vec<&String> = self.volumes.keys().collect();
+        names.sort();
+
+        for name in names {
+            let volume = &self.volumes[name];
+            if volume.r#_type != VolumeType::HostPath {
+                continue;
+            }
+
+            let path = &volume.host_path;
+            match volume.host_path_type {
+                // The guest has its own kernel, which cannot connect the socket to the listener
+                // on the host
+                HostPathType::Socket => warn!(
+                    "hostPath volume {} is the socket {}, which the containers cannot connect to from the VM",
+                    name, path
+                ),
+                HostPathType::CharDevice | HostPathType::BlockDevice => warn!(
+                    "hostPath volume {} is the device {}, the containers get the device of the guest at that path",
+                    name, path
+                ),
+                // The kubelet mounts whatever is at the path, which only matters for the sockets
+                // and the devices
+                HostPathType::Unset if is_socket_or_device_path(path) => warn!(
+                    "hostPath volume {} has no type and {} looks like a socket or a device, which the containers do not get from the node in the VM",
+                    name, path
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    // The read-only mount only prevents changing the node, not writing through it
+    fn check_host_path(name: &str, volume: &Volume, destination: &Path, read_only: bool) {
+        if read_only
+            && (volume.host_path_type == HostPathType::Socket || volume.host_path_type.is_device())
+        {
+            warn!(
+                "hostPath volume {} is mounted read-only at {}, which does not prevent writing to {}",
+                name,
+                destination.display(),
+                volume.host_path
+            );
+        }
+    }
+
+    // Return the mount source of the PersistentVolumeClaim, read once given that the claim may be
+    // mounted by several containers
+    fn get_claim_source(&self, claim: &str) -> Result<Option<String>> {
+        let namespace = cluster::get_namespace(self)?;
+        let key = format!("{}/{}", namespace, claim);
+
+        if let Some(source) = CLAIM_SOURCES.lock().unwrap().get(&key) {
+            return Ok(source.clone());
+        }
+
+        let source = cluster::get_claim_source(&namespace, claim)?;
+
+        CLAIM_SOURCES.lock().unwrap().insert(key, source.clone());
+
+        Ok(source)
+    }
+
+    // Return whether the mount is recursively read-only (rro), which the API server only allows
+    // with readOnly: true and no propagation. IfPossible falls back to a read-only mount when the
+    // runtime does not support it, while the kubelet fails to start the container with Enabled.
+    // Reference: https://kubernetes.io/docs/concepts/storage/volumes/#recursive-read-only-mounts
+    fn get_recursive_read_only(
+        name: &str,
+        volume_mount: &serde_yaml::Value,
+        read_only_field: bool,
+        propagation: &str,
+        supported: bool,
+        strict: bool,
+    ) -> Result<bool> {
+        let value = match volume_mount.get("recursiveReadOnly") {
+            Some(v) => v
+                .as_str()
+                .ok_or_else(|| span::error(v, "failed to parse recursiveReadOnly into string"))?,
+            None => return Ok(false),
+        };
+
+        match value {
+            "Disabled" => return Ok(false),
+            "IfPossible" | "Enabled" => {}
+            _ => {
+                return Err(span::error(
+                    &volume_mount["recursiveReadOnly"],
+                    format!(
+                        "unsupported recursiveReadOnly {}, expected one of Disabled, IfPossible, or Enabled",
+                        value
+                    ),
+                ))
+            }
+        }
+
+        // Rejected by the API server
+        // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.30/pkg/apis/core/validation/validation.go
+        if !read_only_field {
+            bail!(
+                "volume {} sets recursiveReadOnly: {} without readOnly: true",
+                name,
+                value
+            );
+        }
+
+        if propagation != "None" {
+            bail!(
+                "volume {} sets recursiveReadOnly: {} with mountPropagation {}, which must be None",
+                name,
+                value,
+                propagation
+            );
+        }
+
+        if supported {
+            return Ok(true);
+        }
+
+        if value == "Enabled" {
+            let message = format!(
+                "volume {} sets recursiveReadOnly: Enabled, which the runtime of the node pool does not support, the kubelet fails to start the container",
+                name
+            );
+
+            if strict {
+                bail!("{}: {}", loc!(), message);
+            }
+
+            warn!("{}", message);
+        } else {
+            warn!(
+                "volume {} sets recursiveReadOnly: IfPossible, which the runtime of the node pool does not support, its submounts stay writable",
+                name
+            );
+        }
+
+        Ok(false)
+    }
+
+    // With --strict, fail instead of warning when readOnly: false is given for a read-only
+    // volume. With --volumes-from-cluster, the sources of the persistentVolumeClaim volumes are
+    // resolved from their bound PersistentVolumes. The node pool gives the guest layout of the
+    // shared sources and whether the runtime supports the recursive read-only mounts.
+    pub fn get_mounts(
+        &self,
+        container: &serde_yaml::Value,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<Vec<Mount>> {
+        let strict = options.strict;
+        let volumes_from_cluster = options.volumes_from_cluster;
+        let recursive_read_only = node_pool.is_some_and(|node_pool| node_pool.recursive_read_only);
+        let layout = node_pool::get_guest_layout(node_pool)?;
+
+        let mut results = Vec::new();
+        // Volume names by destination
+        let mut destinations: HashMap<PathBuf, String> = HashMap::new();
+
+        if let Some(volume_mounts) = container.get("volumeMounts") {
+            let volume_mounts = volume_mounts.as_sequence().ok_or_else(|| {
+                span::error(volume_mounts, "failed to parse volumeMounts into sequence")
+            })?;
+
+            for volume_mount in volume_mounts {
+                let destination = volume_mount["mountPath"].as_str().ok_or_else(|| {
+                    span::error(
+                        &volume_mount["mountPath"],
+                        "failed to prase mountPath into string",
+                    )
+                })?;
+
+                let destination = normalize_destination(Path::new(destination))?;
+
+                let mut propagation: &str = &String::from("None");
+                if let Some(v) = volume_mount.get("mountPropagation") {
+                    propagation = v.as_str().ok_or_else(|| {
+                        span::error(v, "failed to parse mountPropagation into string")
+                    })?;
+                }
+
+                let name = volume_mount["name"].as_str().ok_or_else(|| {
+                    span::error(&volume_mount["name"], "failed to prase name into string")
+                })?;
+
+                let volume = self
+                    .volumes
+                    .get(name)
+                    .ok_or_else(|| anyhow!("failed to find volume {}", name))?;
+
+                // The API server rejects containers whose mountPaths are not unique
+                // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/apis/core/validation/validation.go#L2696
+                if let Some(other) = destinations.insert(destination.clone(), name.to_string()) {
+                    bail!(
+                        "{}: volumes {} and {} are both mounted at {}, mountPath must be unique",
+                        loc!(),
+                        other,
+                        name,
+                        destination.display()
+                    );
+                }
+
+                let source = if volume.claim_template {
+                    // Each replica mounts its own claim, which a single policy cannot resolve
+                    if volumes_from_cluster {
+                        warn!(
+                            "volume {} is a claim template of the StatefulSet, the claims of the replicas are not resolved",
+                            name
+                        );
+                    }
+                    PathBuf::new()
+                } else if volume.r#_type == VolumeType::PersistentVolumeClaim
+                    && volumes_from_cluster
+                {
+                    PathBuf::from(
+                        self.get_claim_source(&volume.claim_name)?
+                            .unwrap_or_default(),
+                    )
+                } else if volume.r#_type == VolumeType::Csi {
+                    PathBuf::from(layout.get_shared_source(CSI_MOUNT_DIR))
+                } else {
+                    PathBuf::from(&volume.host_path)
+                };
+
+                let mut read_only = volume.readonly;
+                let mut read_only_field = false;
+                // Readonly volume takes precedence over the readOnly field
+                if let Some(v) = volume_mount.get("readOnly") {
+                    read_only_field = scalar::get_bool(v, "readOnly")?;
+
+                    if read_only && !read_only_field {
+                        let reason = if volume.r#_type.is_read_only() {
+                            format!(
+                                "{} volumes are always mounted read-only",
+                                volume.r#_type.name()
+                            )
+                        } else {
+                            format!("the {} volume sets readOnly: true", volume.r#_type.name())
+                        };
+                        let message = format!(
+                            "volume {} is mounted at {} with readOnly: false, but {}",
+                            name,
+                            destination.display(),
+                            reason
+                        );
+
+                        if strict {
+                            bail!("{}: {}", loc!(), message);
+                        }
+
+                        warn!("{}", message);
+                    }
+
+                    read_only = read_only || read_only_field;
+                }
+
+                if volume.r#_type == VolumeType::HostPath {
+                    Self::check_host_path(name, volume, &destination, read_only);
+                }
+
+                // The directories of the node are snapshots in the guest of the peer pods
+                if layout.copies_volumes
+                    && !read_only
+                    && !volume.local
+                    && matches!(
+                        volume.r#_type,
+                        VolumeType::EmptyDir
+                            | VolumeType::HostPath
+                            | VolumeType::PersistentVolumeClaim
+                    )
+                {
+                    warn!(
+                        "{} volume {} is copied into the guest when the container is created, the writes to {} are not propagated to the node",
+                        volume.r#_type.name(),
+                        name,
+                        destination.display()
+                    );
+                }
+
+                let recursive = Self::get_recursive_read_only(
+                    name,
+                    volume_mount,
+                    read_only_field,
+                    propagation,
+                    recursive_read_only,
+                    strict,
+                )?;
+
+                let mut r#type = String::from("bind");
+
+                if volume.local {
+                    r#type = String::from("local");
+                }
+
+                let mut options: Vec<String> =
+                    vec!["rbind"].into_iter().map(String::from).collect();
+
+                match propagation {
+                    "None" => {
+                        options.push(String::from("rprivate"));
+                    }
+                    "HostToContainer" => {
+                        options.push(String::from("rslave"));
+                    }
+                    "Bidirectional" => {
+                        options.push(String::from("rshared"));
+                    }
+                    _ => {
+                        return Err(anyhow!("Unknown mountPropagation type"));
+                    }
+                }
+
+                if read_only {
+                    options.push(String::from("ro"));
+                } else {
+                    options.push(String::from("rw"));
+                }
+
+                // containerd adds rro on top of ro
+                // Reference: https://github.com/containerd/containerd/blob/release/2.0/internal/cri/opts/spec_linux_opts.go
+                if recursive {
+                    options.push(String::from("rro"));
+                }
+
+                let mut mount = Mount::default();
+
+                mount.set_destination(destination);
                 mount.set_typ(Some(r#type));
                 mount.set_source(Some(source));
                 mount.set_options(Some(options));
@@ -469,54 +1838,970 @@ impl<'input> PodYaml<'input> {
     }
 }
 
-pub fn patch_yaml(yaml: &mut serde_yaml::Value, kind: &str, policy_base64: &str) -> Result<()> {
-    let template = match kind {
-        "Pod" => yaml
-            .as_mapping_mut()
-            .ok_or_else(|| anyhow!("failed to parse pod into mapping")),
-        "Job" | "Deployment" | "ReplicationController" => yaml["spec"]["template"]
-            .as_mapping_mut()
-            .ok_or_else(|| anyhow!("failed to parse pod into mapping")),
-        _ => {
+pub fn get_spec_mut<'a>(
+    yaml: &'a mut serde_yaml::Value,
+    kind: &str,
+) -> Result<&'a mut serde_yaml::Value> {
+    let path = match get_template_path(kind) {
+        Some(path) => path,
+        None => {
             bail!("{}: unsupported kind: {}", loc!(), kind);
         }
-    }?;
+    };
 
-    if template.get("metadata").is_none() {
-        let mapping = serde_yaml::Mapping::new();
-        template.insert(
-            serde_yaml::Value::String("metadata".to_owned()),
-            serde_yaml::Value::Mapping(mapping),
-        );
+    let template = get_template_keys(path).fold(yaml, |value, key| &mut value[key]);
+    Ok(&mut template["spec"])
+}
+
+fn get_value_type(value: &serde_yaml::Value) -> &str {
+    match value {
+        serde_yaml::Value::Null => "null",
+        serde_yaml::Value::Bool(_) => "bool",
+        serde_yaml::Value::Number(_) => "number",
+        serde_yaml::Value::String(_) => "string",
+        serde_yaml::Value::Sequence(_) => "sequence",
+        serde_yaml::Value::Mapping(_) => "mapping",
+        serde_yaml::Value::Tagged(_) => "tagged value",
     }
+}
+
+// Return the mapping under the key, inserting an empty one if the key is missing or null
+fn get_mapping_mut<'a>(
+    mapping: &'a mut serde_yaml::Mapping,
+    key: &str,
+    path: &str,
+) -> Result<&'a mut serde_yaml::Mapping> {
+    let value = mapping
+        .entry(serde_yaml::Value::String(key.to_string()))
+        .or_insert(serde_yaml::Value::Null);
+
+    if value.is_null() {
+        *value = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+    }
+
+    let value_type = get_value_type(value).to_string();
 
-    let metadata = template["metadata"]
+    value
         .as_mapping_mut()
-        .ok_or_else(|| anyhow!("failed to get metadata"))?;
+        .ok_or_else(|| anyhow!("{} must be a mapping, found a {}", path, value_type))
+}
+
+// Return the value of the key, including the ones merged by the YAML merge key (<<), where
+// the keys of the mapping take precedence over the merged ones
+fn get_merged<'a>(mapping: &'a serde_yaml::Mapping, key: &str) -> Option<&'a serde_yaml::Value> {
+    if let Some(value) = mapping.get(key) {
+        return Some(value);
+    }
+
+    match mapping.get("<<") {
+        Some(serde_yaml::Value::Mapping(merged)) => get_merged(merged, key),
+        // Earlier mappings of the sequence take precedence
+        Some(serde_yaml::Value::Sequence(merged)) => merged
+            .iter()
+            .filter_map(|value| value.as_mapping())
+            .find_map(|merged| get_merged(merged, key)),
+        _ => None,
+    }
+}
+
+// Remove the key if its value is null or an empty mapping
+fn remove_if_empty(mapping: &mut serde_yaml::Mapping, key: &str) {
+    if mapping
+        .get(key)
+        .is_some_and(|value| value.is_null() || value.as_mapping().is_some_and(|m| m.is_empty()))
+    {
+        mapping.remove(key);
+    }
+}
+
+// Remove the policy annotation set by patch_yaml or patch_reference, and the annotations and the metadata of the
+// pod if nothing else is left, which kubectl reads the same as missing ones
+pub fn remove_policy_annotation(yaml: &mut serde_yaml::Value, kind: &str) {
+    let template = get_template_path(kind)
+        .and_then(|path| get_template_keys(path).try_fold(yaml, |value, key| value.get_mut(key)));
+
+    if let Some(template) = template.and_then(|template| template.as_mapping_mut()) {
+        if let Some(metadata) = template
+            .get_mut("metadata")
+            .and_then(|metadata| metadata.as_mapping_mut())
+        {
+            if let Some(annotations) = metadata
+                .get_mut("annotations")
+                .and_then(|annotations| annotations.as_mapping_mut())
+            {
+                annotations.remove(get_policy_key().as_str());
+                annotations.remove(CC_POLICY_REF_KEY);
+            }
+            remove_if_empty(metadata, "annotations");
+        }
+        remove_if_empty(template, "metadata");
+    }
+}
+
+// How to handle a policy annotation of the manifest that differs from the generated one
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ExistingPolicy {
+    Fail,
+    Preserve,
+    Overwrite,
+}
+
+// Return whether the annotation is set, i.e., false if the existing one is preserved
+pub fn patch_yaml(
+    yaml: &mut serde_yaml::Value,
+    kind: &str,
+    policy_base64: &str,
+    existing: ExistingPolicy,
+) -> Result<bool> {
+    patch_annotation(
+        yaml,
+        kind,
+        (&get_policy_key(), policy_base64),
+        Some(CC_POLICY_REF_KEY),
+        existing,
+    )
+}
+
+// Set the reference of a policy delivered out of band, see delivery.rs, along with the inline
+// policy, which is the one enforced as long as nothing reads the reference
+pub fn patch_reference(
+    yaml: &mut serde_yaml::Value,
+    kind: &str,
+    policy_base64: &str,
+    reference: &str,
+    existing: ExistingPolicy,
+) -> Result<bool> {
+    // Either both annotations are set or none
+    let mut patched = yaml.clone();
+    if !patch_annotation(
+        &mut patched,
+        kind,
+        (CC_POLICY_REF_KEY, reference),
+        None,
+        existing,
+    )? || !patch_annotation(
+        &mut patched,
+        kind,
+        (&get_policy_key(), policy_base64),
+        None,
+        existing,
+    )? {
+        return Ok(false);
+    }
+
+    *yaml = patched;
+    Ok(true)
+}
+
+// Style of the policy annotations in the YAML output, set by --annotation-style
+#[derive(ArgEnum, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationStyle {
+    // As serialized, i.e., plain unless the value requires quotes or a block
+    Auto,
+    Plain,
+    SingleQuoted,
+    Literal,
+}
+
+impl fmt::Display for AnnotationStyle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AnnotationStyle::Auto => "auto",
+            AnnotationStyle::Plain => "plain",
+            AnnotationStyle::SingleQuoted => "single-quoted",
+            AnnotationStyle::Literal => "literal",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+fn get_indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+// The value of the annotation key given in the style, as the rest of its line and the following
+// lines indented relative to the key
+fn emit_scalar(value: &str, style: AnnotationStyle) -> String {
+    match style {
+        AnnotationStyle::Auto | AnnotationStyle::Plain => format!(" {}\n", value),
+        // A line break is folded into a space, so the line breaks of the value are empty lines
+        AnnotationStyle::SingleQuoted => {
+            let lines: Vec<String> = value
+                .split('\n')
+                .map(|line| line.replace('\'', "''"))
+                .collect();
+            format!(" '{}'\n", lines.join("\n\n  "))
+        }
+        AnnotationStyle::Literal => {
+            let (chomping, content) = match value.strip_suffix('\n') {
+                Some(content) if content.ends_with('\n') => ("+", content),
+                Some(content) => ("", content),
+                None => ("-", value),
+            };
+            let indentation = if value.starts_with([' ', '\n']) {
+                "2"
+            } else {
+                ""
+            };
+
+            let mut result = format!(" |{}{}\n", indentation, chomping);
+            for line in content.split('\n') {
+                if !line.is_empty() {
+                    result.push_str("  ");
+                    result.push_str(line);
+                }
+                result.push('\n');
+            }
+            result
+        }
+    }
+}
+
+// Rewrite the policy annotations of a serialized document in the style, e.g., for the tools
+// requiring the long base64 value to be quoted or a block scalar. The rewritten document must
+// parse to the same value.
+pub fn set_annotation_style(body: &str, style: AnnotationStyle) -> Result<String> {
+    if style == AnnotationStyle::Auto {
+        return Ok(body.to_string());
+    }
+
+    let policy_key = get_policy_key();
+    let lines: Vec<&str> = body.split_inclusive('\n').collect();
+    let mut result = String::new();
+    let mut index = 0;
+
+    while index < lines.len() {
+        let line = lines[index];
+        let indent = get_indent(line);
+        let key = [policy_key.as_str(), CC_POLICY_REF_KEY]
+            .into_iter()
+            .find(|key| {
+                line[indent..]
+                    .strip_prefix(key)
+                    .is_some_and(|rest| rest.starts_with(':'))
+            });
+
+        let key = match key {
+            Some(key) => key,
+            None => {
+                result.push_str(line);
+                index += 1;
+                continue;
+            }
+        };
+
+        // The value is on the line of the key and the following lines indented more, e.g.,
+        // the lines of a block scalar
+        let mut end = index + 1;
+        while end < lines.len() && (lines[end].trim().is_empty() || get_indent(lines[end]) > indent)
+        {
+            end += 1;
+        }
+
+        let snippet: String = lines[index..end]
+            .iter()
+            .map(|line| line.get(indent..).unwrap_or("\n"))
+            .collect();
+        let parsed: serde_yaml::Value = serde_yaml::from_str(&snippet).context(loc!())?;
+
+        let value = match parsed[key].as_str() {
+            Some(value) => value,
+            None => {
+                lines[index..end]
+                    .iter()
+                    .for_each(|line| result.push_str(line));
+                index = end;
+                continue;
+            }
+        };
+
+        let emitted = [key, ":", &emit_scalar(value, style)].concat();
+        // e.g., a plain scalar cannot hold line breaks nor start with a quote
+        let reparsed: Option<serde_yaml::Value> = serde_yaml::from_str(&emitted).ok();
+        if reparsed
+            .as_ref()
+            .and_then(|reparsed| reparsed[key].as_str())
+            != Some(value)
+        {
+            bail!(
+                "the {} annotation cannot be written as a {} scalar, see --annotation-style",
+                key,
+                style
+            );
+        }
 
-    if metadata.get("annotations").is_none() {
-        let mapping = serde_yaml::Mapping::new();
-        metadata.insert(
-            serde_yaml::Value::String("annotations".to_owned()),
-            serde_yaml::Value::Mapping(mapping),
+        for line in emitted.split_inclusive('\n') {
+            if line != "\n" {
+                result.push_str(&" ".repeat(indent));
+            }
+            result.push_str(line);
+        }
+
+        index = end;
+    }
+
+    let expected: serde_yaml::Value = serde_yaml::from_str(body).context(loc!())?;
+    let actual: serde_yaml::Value = serde_yaml::from_str(&result).context(loc!())?;
+    if expected != actual {
+        bail!(
+            "{}: failed to write the policy annotations as {} scalars",
+            loc!(),
+            style
         );
     }
 
-    let annotations = metadata["annotations"]
-        .as_mapping_mut()
-        .ok_or_else(|| anyhow!("failed to get annotations"))?;
+    Ok(result)
+}
+
+// Return the path of the pod template of a workload, the root of a Pod
+pub fn get_template_path(kind: &str) -> Option<&'static str> {
+    match kind {
+        "Pod" => Some(""),
+        "Job"
+        | "Deployment"
+        | "ReplicaSet"
+        | "ReplicationController"
+        | "StatefulSet"
+        | "DaemonSet" => Some("spec.template"),
+        "CronJob" => Some("spec.jobTemplate.spec.template"),
+        _ => None,
+    }
+}
+
+// Keys of the path of get_template_path, none for a Pod
+fn get_template_keys(path: &str) -> impl Iterator<Item = &str> {
+    path.split('.').filter(|key| !key.is_empty())
+}
+
+fn join_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        [path, ".", key].concat()
+    }
+}
+
+// Lines of the policy annotations of the pod template set in the annotated value, indented
+fn get_annotation_lines(
+    annotated: &serde_yaml::Value,
+    path: &str,
+    indent: usize,
+) -> Result<String> {
+    let template = get_template_keys(path).fold(annotated, |value, key| &value[key]);
+    let annotations = &template["metadata"]["annotations"];
+
+    let mut entries = serde_yaml::Mapping::new();
+    for key in [get_policy_key().as_str(), CC_POLICY_REF_KEY] {
+        if let Some(value) = annotations.get(key) {
+            entries.insert(serde_yaml::Value::String(key.to_string()), value.clone());
+        }
+    }
+
+    let mut result = String::new();
+    for line in serde_yaml::to_string(&entries)?.split_inclusive('\n') {
+        if line != "\n" {
+            result.push_str(&" ".repeat(indent));
+        }
+        result.push_str(line);
+    }
 
-    match annotations.get_mut(CC_POLICY_KEY) {
-        Some(value) => {
-            *value = serde_yaml::Value::String(String::from(policy_base64));
+    Ok(result)
+}
+
+// Patch the lines of the policy annotations of the body, keeping the rest of the document (e.g.,
+// the comments and the plain scalars that kubectl reads as YAML 1.1, like 0644) as is. Return
+// None if the annotations cannot be patched in place, e.g., in flow mappings.
+fn patch_annotation_lines(body: &str, annotated: &serde_yaml::Value) -> Result<Option<String>> {
+    let original: serde_yaml::Value = serde_yaml::from_str(body)?;
+    let template_path = match annotated["kind"].as_str().and_then(get_template_path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let spans = Spans::new("", body, 1);
+    let mut lines: Vec<String> = body.split_inclusive('\n').map(String::from).collect();
+    if let Some(last) = lines.last_mut().filter(|last| !last.ends_with('\n')) {
+        last.push('\n');
+    }
+
+    let metadata_path = join_path(template_path, "metadata");
+    let annotations_path = join_path(&metadata_path, "annotations");
+
+    // The current policy annotations, on the line of their key and the following lines
+    // indented more
+    for key in [get_policy_key().as_str(), CC_POLICY_REF_KEY] {
+        if let Some((line, column)) = spans.position(&join_path(&annotations_path, key)) {
+            let mut end = line;
+            while end < lines.len()
+                && (lines[end].trim().is_empty() || get_indent(&lines[end]) >= column)
+            {
+                end += 1;
+            }
+            while lines[end - 1].trim().is_empty() {
+                end -= 1;
+            }
+            lines[line - 1..end].iter_mut().for_each(String::clear);
         }
+    }
+
+    // Where the annotations go, i.e., the line of their parent and their indentation. The
+    // parent is either a block mapping or empty.
+    let get_parent = |path: &str| -> Option<(usize, usize)> {
+        let (line, column) = spans.position(path)?;
+        let mut value = &original;
+        for key in path.split('.') {
+            value = value.get(key)?;
+        }
+
+        match (value, spans.child_column(path)) {
+            (serde_yaml::Value::Mapping(_), Some(child_column)) => Some((line, child_column - 1)),
+            (serde_yaml::Value::Null, None) if spans.scalar_text(path).is_none() => {
+                Some((line, column + 1))
+            }
+            _ => None,
+        }
+    };
+
+    let (line, insertion) = if let Some((line, indent)) = get_parent(&annotations_path) {
+        (
+            line,
+            get_annotation_lines(annotated, template_path, indent)?,
+        )
+    } else if let Some((line, indent)) = get_parent(&metadata_path) {
+        if spans.position(&annotations_path).is_some() {
+            return Ok(None);
+        }
+        (
+            line,
+            [
+                &" ".repeat(indent),
+                "annotations:\n",
+                &get_annotation_lines(annotated, template_path, indent + 2)?,
+            ]
+            .concat(),
+        )
+    } else {
+        return Ok(None);
+    };
+
+    lines.insert(line, insertion);
+    let result = lines.concat();
+
+    // e.g., the anchors of the annotations or of the metadata
+    let reparsed: serde_yaml::Value = serde_yaml::from_str(&result)?;
+    if reparsed != *annotated {
+        return Ok(None);
+    }
+
+    Ok(Some(result))
+}
+
+// Return the body of the annotated document, patched in place if possible, the annotated value
+// serialized otherwise
+pub fn patch_body(body: &str, annotated: &serde_yaml::Value) -> Result<String> {
+    match patch_annotation_lines(body, annotated)? {
+        Some(result) => Ok(result),
+        None => Ok(serde_yaml::to_string(annotated)?),
+    }
+}
+
+// Set the annotation of the pod, the other form of the policy (inline or reference) is
+// removed and handled like a different existing policy
+fn patch_annotation(
+    yaml: &mut serde_yaml::Value,
+    kind: &str,
+    (key, value): (&str, &str),
+    other_key: Option<&str>,
+    existing: ExistingPolicy,
+) -> Result<bool> {
+    let name = yaml["metadata"]["name"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+
+    let root = yaml
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("{} {} must be a mapping", kind, name))?;
+
+    let template_path = match get_template_path(kind) {
+        Some(path) => path,
         None => {
-            annotations.insert(
-                serde_yaml::Value::String(String::from(CC_POLICY_KEY)),
-                serde_yaml::Value::String(String::from(policy_base64)),
-            );
+            bail!("{}: unsupported kind: {}", loc!(), kind);
+        }
+    };
+
+    let mut template = root;
+    let mut path = String::new();
+    for key in get_template_keys(template_path) {
+        path = join_path(&path, key);
+        template = get_mapping_mut(template, key, &path)?;
+    }
+
+    let metadata = get_mapping_mut(template, "metadata", "metadata of the pod")?;
+    let annotations = get_mapping_mut(metadata, "annotations", "annotations of the pod")?;
+
+    // Unrelated annotations, including the merged ones, are kept as is
+    let different = match get_merged(annotations, key).map(|current| current.as_str()) {
+        Some(Some(current)) if key == CC_POLICY_REF_KEY => {
+            !delivery::is_same_policy(current, value)
+        }
+        Some(current) => current != Some(value),
+        None => other_key.is_some_and(|other_key| get_merged(annotations, other_key).is_some()),
+    };
+
+    if different {
+        let policy_key = get_policy_key();
+
+        match existing {
+            ExistingPolicy::Fail => bail!(
+                "{} {} already has a different {} annotation, use --force to overwrite it or --preserve-existing to keep it",
+                kind,
+                name,
+                policy_key
+            ),
+            ExistingPolicy::Preserve => {
+                warn!(
+                    "{} {} already has a different {} annotation, which is preserved",
+                    kind, name, policy_key
+                );
+                return Ok(false);
+            }
+            ExistingPolicy::Overwrite => {}
         }
     }
 
-    Ok(())
+    // An explicit key overrides the merged one
+    if let Some(other_key) = other_key {
+        annotations.remove(other_key);
+    }
+    annotations.insert(
+        serde_yaml::Value::String(String::from(key)),
+        serde_yaml::Value::String(String::from(value)),
+    );
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest::GuestLayout;
+
+    fn get_mounts(
+        volume_mounts: &str,
+        volumes: &str,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<Vec<Mount>> {
+        let manifest = format!(
+            "kind: Pod\nmetadata:\n  name: test\nspec:\n  containers:\n  - name: app\n    image: app\n    volumeMounts: {}\n  volumes: {}\n",
+            volume_mounts, volumes
+        );
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&manifest).unwrap();
+        let pod_yaml = PodYaml::from(&yaml).unwrap();
+        let container = &pod_yaml.containers.unwrap()[0];
+
+        pod_yaml.get_mounts(container, options, node_pool)
+    }
+
+    fn get_options(mount: &Mount) -> Vec<String> {
+        mount.options().clone().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_get_mounts() {
+        let mounts = get_mounts(
+            r#"[{name: logs, mountPath: /var/log/app, readOnly: true}, {name: cache, mountPath: /cache}]"#,
+            r#"[{name: logs, hostPath: {path: /var/log, type: Directory}}, {name: cache, emptyDir: {}}]"#,
+            &PolicyOptions::default(),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(mounts.len(), 2);
+
+        assert_eq!(mounts[0].destination(), Path::new("/var/log/app"));
+        assert_eq!(mounts[0].source().as_deref(), Some(Path::new("/var/log")));
+        assert_eq!(mounts[0].typ().as_deref(), Some("bind"));
+        assert_eq!(get_options(&mounts[0]), ["rbind", "rprivate", "ro"]);
+
+        assert_eq!(mounts[1].destination(), Path::new("/cache"));
+        assert_eq!(mounts[1].typ().as_deref(), Some("local"));
+        assert_eq!(get_options(&mounts[1]), ["rbind", "rprivate", "rw"]);
+    }
+
+    #[test]
+    fn test_get_mounts_duplicate_destination() {
+        let result = get_mounts(
+            r#"[{name: a, mountPath: /data}, {name: b, mountPath: /data/}]"#,
+            r#"[{name: a, emptyDir: {}}, {name: b, emptyDir: {}}]"#,
+            &PolicyOptions::default(),
+            None,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_get_mounts_read_only_volume() {
+        let volume_mounts = r#"[{name: config, mountPath: /config, readOnly: false}]"#;
+        let volumes = r#"[{name: config, configMap: {name: config}}]"#;
+
+        let mounts = get_mounts(volume_mounts, volumes, &PolicyOptions::default(), None).unwrap();
+        assert_eq!(get_options(&mounts[0]), ["rbind", "rprivate", "ro"]);
+
+        let options = PolicyOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(get_mounts(volume_mounts, volumes, &options, None).is_err());
+    }
+
+    #[test]
+    fn test_get_mounts_propagation() {
+        let mounts = get_mounts(
+            r#"[{name: a, mountPath: /a, mountPropagation: HostToContainer}, {name: b, mountPath: /b, mountPropagation: Bidirectional}]"#,
+            r#"[{name: a, hostPath: {path: /a, type: Directory}}, {name: b, hostPath: {path: /b, type: Directory}}]"#,
+            &PolicyOptions::default(),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(get_options(&mounts[0]), ["rbind", "rslave", "rw"]);
+        assert_eq!(get_options(&mounts[1]), ["rbind", "rshared", "rw"]);
+
+        let result = get_mounts(
+            r#"[{name: a, mountPath: /a, mountPropagation: Both}]"#,
+            r#"[{name: a, emptyDir: {}}]"#,
+            &PolicyOptions::default(),
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_get_mounts_recursive_read_only() {
+        let volumes = r#"[{name: data, hostPath: {path: /data, type: Directory}}]"#;
+        let enabled =
+            r#"[{name: data, mountPath: /data, readOnly: true, recursiveReadOnly: Enabled}]"#;
+        let if_possible =
+            r#"[{name: data, mountPath: /data, readOnly: true, recursiveReadOnly: IfPossible}]"#;
+
+        let node_pool = NodePool {
+            recursive_read_only: true,
+            ..Default::default()
+        };
+        let mounts = get_mounts(
+            enabled,
+            volumes,
+            &PolicyOptions::default(),
+            Some(&node_pool),
+        )
+        .unwrap();
+        assert_eq!(get_options(&mounts[0]), ["rbind", "rprivate", "ro", "rro"]);
+
+        // Not supported by the runtime
+        let mounts = get_mounts(if_possible, volumes, &PolicyOptions::default(), None).unwrap();
+        assert_eq!(get_options(&mounts[0]), ["rbind", "rprivate", "ro"]);
+
+        let options = PolicyOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(get_mounts(enabled, volumes, &options, None).is_err());
+
+        // Rejected by the API server
+        let result = get_mounts(
+            r#"[{name: data, mountPath: /data, recursiveReadOnly: Enabled}]"#,
+            volumes,
+            &PolicyOptions::default(),
+            Some(&node_pool),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_get_mounts_csi() {
+        let mounts = get_mounts(
+            r#"[{name: secrets, mountPath: /mnt/secrets, readOnly: true}]"#,
+            r#"[{name: secrets, csi: {driver: file.csi.azure.com, readOnly: true}}]"#,
+            &PolicyOptions::default(),
+            None,
+        )
+        .unwrap();
+
+        let source = GuestLayout::default().get_shared_source(CSI_MOUNT_DIR);
+        assert_eq!(mounts[0].source().as_deref(), Some(Path::new(&source)));
+    }
+
+    #[test]
+    fn test_is_socket_or_device_path() {
+        assert!(is_socket_or_device_path("/dev/kvm"));
+        assert!(is_socket_or_device_path("/var/run/docker.sock"));
+        assert!(is_socket_or_device_path("/run/app/app.socket"));
+        assert!(!is_socket_or_device_path("/devices"));
+        assert!(!is_socket_or_device_path("/var/log"));
+    }
+
+    // The ConfigMaps are read from the cache instead of the cluster
+    fn set_config_map(name: &str, data: Option<&str>) {
+        CONFIG_MAPS.lock().unwrap().insert(
+            name.to_string(),
+            data.map(|data| serde_yaml::from_str(data).unwrap()),
+        );
+    }
+
+    fn is_literal(value: Option<EnvValue>, expected: &str) -> bool {
+        matches!(value, Some(EnvValue::Literal(value)) if value == expected)
+    }
+
+    #[test]
+    fn test_get_env_from_secret() {
+        let container: serde_yaml::Value = serde_yaml::from_str(
+            "{name: app, envFrom: [{secretRef: {name: db}, prefix: DB_}], env: [{name: DB_HOST, value: db}]}",
+        )
+        .unwrap();
+
+        // The env of the container overrides the variables of the Secret
+        assert_eq!(
+            PodYaml::get_env(&container, false).unwrap(),
+            ["^DB_[-._a-zA-Z][-._a-zA-Z0-9]*=.*$", "DB_HOST=db"]
+        );
+
+        let values = PodYaml::get_env_values(&container, false).unwrap();
+        assert!(is_literal(values.get("DB_HOST"), "db"));
+        assert!(matches!(values.get("DB_PASSWORD"), Some(EnvValue::Runtime)));
+        assert!(values.get("PASSWORD").is_none());
+    }
+
+    #[test]
+    fn test_get_env_expanded() {
+        let container: serde_yaml::Value = serde_yaml::from_str(
+            "\
+name: app
+env:
+- {name: A, value: x}
+- {name: B, value: $(A)-x}
+- {name: C, value: $$(A)}
+- {name: D, valueFrom: {fieldRef: {fieldPath: status.podIP}}}
+- {name: E, value: '$(D):80'}
+",
+        )
+        .unwrap();
+
+        // The values are the ones the kubelet expands, the references to runtime values
+        // becoming regex rules
+        let rules = PodYaml::get_env(&container, false).unwrap();
+        assert_eq!(rules, ["A=x", "B=x-x", "C=$(A)", "^D=.", "^E=.*:80$"]);
+        assert!(pattern::matches(&rules[4], "E=10.0.0.1:80").unwrap());
+
+        let values = PodYaml::get_env_values(&container, false).unwrap();
+        assert!(is_literal(values.get("B"), "x-x"));
+        assert!(matches!(values.get("E"), Some(EnvValue::Runtime)));
+    }
+
+    #[test]
+    fn test_check_env_from() {
+        let container: serde_yaml::Value =
+            serde_yaml::from_str("{name: app, envFrom: [{secretRef: {name: db}}]}").unwrap();
+
+        assert!(PodYaml::check_env_from(&container, false).is_err());
+        assert!(PodYaml::check_env_from(&container, true).is_ok());
+
+        let container: serde_yaml::Value =
+            serde_yaml::from_str("{name: app, envFrom: [{secretRef: {name: db}, prefix: DB_}]}")
+                .unwrap();
+        assert!(PodYaml::check_env_from(&container, false).is_ok());
+    }
+
+    #[test]
+    fn test_get_env_from_order() {
+        set_config_map("test-env-a", Some("{X_ONE: '1', 1BAD: x, KEEP: k}"));
+        set_config_map("test-env-b", Some("{ONE: '2'}"));
+        set_config_map("test-env-missing", None);
+
+        // A later source overrides the earlier ones, the invalid env names and the missing
+        // optional sources are skipped
+        let container: serde_yaml::Value = serde_yaml::from_str(
+            "\
+name: app
+envFrom:
+- configMapRef: {name: test-env-a}
+- secretRef: {name: s}
+  prefix: X_
+- configMapRef: {name: test-env-b}
+  prefix: X_
+- configMapRef: {name: test-env-missing, optional: true}
+",
+        )
+        .unwrap();
+
+        let values = PodYaml::get_env_values(&container, false).unwrap();
+        assert!(is_literal(values.get("KEEP"), "k"));
+        assert!(is_literal(values.get("X_ONE"), "2"));
+        assert!(matches!(values.get("X_TWO"), Some(EnvValue::Runtime)));
+        assert!(values.get("1BAD").is_none());
+
+        // The variables of a later Secret override the ones of the ConfigMaps, also when the
+        // prefix is repeated
+        let container: serde_yaml::Value = serde_yaml::from_str(
+            "\
+name: app
+envFrom:
+- secretRef: {name: s}
+  prefix: X_
+- configMapRef: {name: test-env-a}
+- secretRef: {name: t}
+  prefix: X_
+",
+        )
+        .unwrap();
+
+        let values = PodYaml::get_env_values(&container, false).unwrap();
+        assert!(is_literal(values.get("KEEP"), "k"));
+        assert!(matches!(values.get("X_ONE"), Some(EnvValue::Runtime)));
+
+        // A missing source that is not optional fails the generation
+        let container: serde_yaml::Value = serde_yaml::from_str(
+            "{name: app, envFrom: [{configMapRef: {name: test-env-missing}}]}",
+        )
+        .unwrap();
+        assert!(PodYaml::get_env_from(&container).is_err());
+    }
+
+    fn annotate(body: &str) -> (serde_yaml::Value, String) {
+        let mut yaml: serde_yaml::Value = serde_yaml::from_str(body).unwrap();
+        let kind = yaml["kind"].as_str().unwrap().to_string();
+        patch_yaml(&mut yaml, &kind, "cG9saWN5", ExistingPolicy::Overwrite).unwrap();
+
+        let patched = patch_body(body, &yaml).unwrap();
+        let reparsed: serde_yaml::Value = serde_yaml::from_str(&patched).unwrap();
+        assert_eq!(reparsed, yaml);
+
+        (yaml, patched)
+    }
+
+    #[test]
+    fn test_patch_body() {
+        let body = "\
+kind: Deployment
+metadata:
+  name: app # comment
+spec:
+  template:
+    metadata:
+      labels:
+        app: app
+    spec:
+      volumes:
+      - name: config
+        configMap:
+          name: config
+          defaultMode: 0644
+";
+        let (_, patched) = annotate(body);
+
+        assert_eq!(
+            patched,
+            body.replace(
+                "    metadata:\n",
+                "    metadata:\n      annotations:\n        io.katacontainers.cc_policy: cG9saWN5\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_patch_body_existing() {
+        // Replaced in place, with the other annotations and the reference removed
+        let body = "\
+kind: Pod
+metadata:
+  annotations:
+    a: b
+    io.katacontainers.cc_policy_ref: >-
+      {\"uri\": \"oci://example\"}
+    io.katacontainers.cc_policy: old
+
+    c: d
+spec:
+  containers:
+  - name: app
+    image: app
+    workingDir: 0755
+";
+        let (_, patched) = annotate(body);
+
+        assert_eq!(
+            patched,
+            "\
+kind: Pod
+metadata:
+  annotations:
+    io.katacontainers.cc_policy: cG9saWN5
+    a: b
+
+    c: d
+spec:
+  containers:
+  - name: app
+    image: app
+    workingDir: 0755
+"
+        );
+
+        // Empty annotations
+        let (_, patched) = annotate("kind: Pod\nmetadata:\n  annotations:\nspec: {}\n");
+        assert_eq!(
+            patched,
+            "kind: Pod\nmetadata:\n  annotations:\n    io.katacontainers.cc_policy: cG9saWN5\nspec: {}\n"
+        );
+    }
+
+    #[test]
+    fn test_patch_body_serialized() {
+        // Flow mappings are not patched in place
+        let body = "kind: Pod\nmetadata: {name: app, annotations: {}} # comment\nspec: {}\n";
+        let (yaml, patched) = annotate(body);
+
+        assert_eq!(patched, serde_yaml::to_string(&yaml).unwrap());
+    }
+
+    #[test]
+    fn test_patch_reference_same_policy() {
+        let mut yaml: serde_yaml::Value = serde_yaml::from_str(
+            "\
+kind: Pod
+metadata:
+  name: app
+  annotations:
+    io.katacontainers.cc_policy_ref: '{\"uri\":\"oci://r@sha256:1\",\"digest\":\"sha256:a\"}'
+",
+        )
+        .unwrap();
+
+        // The same policy pushed again, under another manifest digest
+        let reference = r#"{"uri":"oci://r:sha256-a","digest":"sha256:a"}"#;
+        assert!(
+            patch_reference(&mut yaml, "Pod", "YQ==", reference, ExistingPolicy::Fail).unwrap()
+        );
+
+        // The inline policy is kept alongside the reference
+        let annotations = &yaml["metadata"]["annotations"];
+        assert_eq!(annotations[CC_POLICY_KEY], "YQ==");
+        assert_eq!(annotations["io.katacontainers.cc_policy_ref"], reference);
+
+        let reference = r#"{"uri":"oci://r:sha256-b","digest":"sha256:b"}"#;
+        let result = patch_reference(&mut yaml, "Pod", "Yg==", reference, ExistingPolicy::Fail);
+        assert!(result.is_err());
+        let result = patch_reference(
+            &mut yaml,
+            "Pod",
+            "Yg==",
+            reference,
+            ExistingPolicy::Preserve,
+        );
+        assert!(!result.unwrap());
+        assert_eq!(yaml["metadata"]["annotations"][CC_POLICY_KEY], "YQ==");
+    }
 }
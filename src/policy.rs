@@ -1,28 +1,181 @@
 // Copyright (c) Cc-Policy Authors.
 // Licensed under the Apache 2.0 license.
 
+use crate::canonical;
+use crate::catalog::Catalog;
+use crate::cluster;
+use crate::compat::{AgentApi, Field};
 use crate::cri;
 use crate::cri::*;
+use crate::guest::GuestLayout;
 use crate::image;
-use crate::image::pull_image_config;
 use crate::kubernetes;
 use crate::kubernetes::*;
+use crate::layer;
+use crate::layer::EncryptedLayer;
+use crate::lint;
+use crate::node_pool;
+use crate::node_pool::NodePool;
 use crate::oci::*;
+use crate::overlay::{Overlay, OverlayRules};
+use crate::pattern;
+use crate::plugin;
+use crate::plugin::{RulePlugin, Stage, StageContext};
+use crate::pod_yaml::{
+    ContainerPort, Dns, EnvFrom, HostAlias, SecurityContext, ServiceAccount, UserContext,
+};
+use crate::registry;
+use crate::report;
+use crate::report::Report;
+use crate::span;
 use crate::PodYaml;
 
-use anyhow::{anyhow, Context, Result};
-use oci_spec::image::ImageConfiguration;
-use oci_spec::runtime::Spec;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ArgEnum;
+use oci_spec::image::{ImageConfiguration, ImageManifest};
+use oci_spec::runtime::{Mount, Process, Spec};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 const CC_POLICY_VERSION: &str = "0.1.0";
 
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+// Wildcard rules used by the --allow-any-* options, which replace the generated rules of
+// the respective category
+pub const ANY_ENV: &str = "^[^=]+=.*$";
+pub const ANY_MOUNT_SOURCE: &str = "^.*$";
+pub const ANY_ARGS: &str = "^.*$";
+
+pub const RELAXED_ENV: &str = "env";
+pub const RELAXED_MOUNT_SOURCE: &str = "mount_source";
+pub const RELAXED_ARGS: &str = "args";
+// No rules of the sandbox, see SandboxStrategy::None
+pub const RELAXED_SANDBOX: &str = "sandbox";
+
+// Matching of each of process.args, see get_args_match, and of each of process.env
+pub const ARGS_MATCH_STRING: &str = "string";
+pub const ARGS_MATCH_REGEX: &str = "regex";
+
 #[derive(Serialize, Deserialize)]
 pub struct Custom {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub layers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encrypted_layers: Vec<EncryptedLayer>,
+    // Base64 encoding of process.args, see get_args_base64
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args_base64: Vec<String>,
+    // Whether each of process.args is an exact string or a regex, empty if all of them are
+    // exact strings
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args_match: Vec<String>,
+    // Whether each of process.env is an exact string or a regex, see add_env_rule_match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub env_rule_match: Vec<String>,
+    // Digest of the image config blob referenced by the manifest, see --embed-image-config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_config_digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_config: Option<ImageConfiguration>,
+}
+
+#[derive(ArgEnum, Clone, Default)]
+pub enum Base64Alphabet {
+    #[default]
+    Standard,
+    UrlSafe,
+}
+
+// Encoding of the policy in the annotation, standard padded base64 without wrapping by default
+#[derive(Clone, Default)]
+pub struct Base64Encoding {
+    pub alphabet: Base64Alphabet,
+    pub no_pad: bool,
+    // Maximum line length, 0 to disable wrapping
+    pub wrap: usize,
+    // Encode the canonical JSON of the policy instead of the pretty-printed one
+    pub canonical: bool,
+}
+
+impl Base64Encoding {
+    pub fn encode<T: AsRef<[u8]>>(&self, data: T) -> String {
+        let config = match (&self.alphabet, self.no_pad) {
+            (Base64Alphabet::Standard, false) => base64::STANDARD,
+            (Base64Alphabet::Standard, true) => base64::STANDARD_NO_PAD,
+            (Base64Alphabet::UrlSafe, false) => base64::URL_SAFE,
+            (Base64Alphabet::UrlSafe, true) => base64::URL_SAFE_NO_PAD,
+        };
+
+        let encoded = base64::encode_config(data, config);
+
+        if self.wrap == 0 {
+            return encoded;
+        }
+
+        // The encoded string is ASCII, so splitting by bytes is safe
+        encoded
+            .as_bytes()
+            .chunks(self.wrap)
+            .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// How the enforcer compares the env of a container with the env rules, recorded in the metadata
+// of the policy if not the default
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvMatch {
+    // Every variable matches a rule and every rule matches a variable, in any order
+    #[default]
+    Set,
+    // The variables match the rules one by one, in the order of the rules, which is then the
+    // exact order of containerd and the kubelet
+    Ordered,
+}
+
+// How the policy of the sandbox is generated, given by --sandbox, the node pool, or per workload
+// by the io.cc-policy/sandbox annotation
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SandboxStrategy {
+    // The pause container of the pause image, which containerd creates on the node
+    #[default]
+    Pause,
+    // The pause container of the pod VM of the peer pods, which the agent creates from the
+    // pause bundle of the VM image instead of pulling the pause image
+    // Reference: https://github.com/confidential-containers/cloud-api-adaptor
+    PeerPod,
+    // No sandbox rules, e.g., for the remote hypervisors whose sandbox is not created through
+    // the agent
+    None,
+}
+
+impl fmt::Display for SandboxStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SandboxStrategy::Pause => "pause",
+            SandboxStrategy::PeerPod => "peer-pod",
+            SandboxStrategy::None => "none",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// Whether to record the image config that informed the rules, for audit
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+pub enum ImageConfigEmbedding {
+    // The digest and the content of the image config
+    Full,
+    // The digest of the image config only
+    Digest,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -30,64 +183,665 @@ pub struct ContainerPolicy {
     pub oci_spec: Spec,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub custom: Option<Custom>,
+    // Image reference and manifest digest, recorded in the generation metadata
+    #[serde(skip)]
+    image_digest: Option<(String, String)>,
+}
+
+#[derive(Default)]
+pub struct PolicyOptions {
+    pub with_default_rules: bool,
+    // Address of the key provider that must be reachable when the image has encrypted layers
+    pub key_provider: Option<String>,
+    pub allow_any_env: bool,
+    pub allow_any_mount_source: bool,
+    pub allow_any_args: bool,
+    // Keep the env entries of the containers shadowed by later ones of the same name
+    pub keep_duplicate_env: bool,
+    pub env_match: EnvMatch,
+    // Sort the mount options into the canonical order of normalize_mount_options
+    pub normalize_mount_options: bool,
+    // Generate exact service env rules from the services in the namespace of the pod
+    pub services_from_cluster: bool,
+    // Inspect the images with the credentials of the imagePullSecrets of the pods
+    pub pull_secrets_from_cluster: bool,
+    // Resolve the persistentVolumeClaim volumes to their bound local PersistentVolumes
+    pub volumes_from_cluster: bool,
+    pub overlay: Option<Overlay>,
+    pub node_pools: BTreeMap<String, NodePool>,
+    // Node pool given on the command line, which disables the selection by the node constraints
+    pub node_pool: Option<String>,
+    // Only generate the policy of the sandbox (pause) container
+    pub sandbox_only: bool,
+    // Strategy of the sandbox policy of the workloads without the io.cc-policy/sandbox annotation,
+    // the one of the node pool if None
+    pub sandbox: Option<SandboxStrategy>,
+    pub embed_image_config: Option<ImageConfigEmbedding>,
+    pub no_metadata: bool,
+    // No timestamps, sorted keys, and images pinned by digest for bit-identical rebuilds
+    pub reproducible: bool,
+    // Fail on the manifest fields that Kubernetes silently overrides
+    pub strict: bool,
+    // Maximum number of loose rules of a policy, see lint::get_loose_rules
+    pub max_loose_rules: Option<usize>,
+    pub oci_version: Option<String>,
+    // OCI layout directories searched for the images with imagePullPolicy: Never
+    pub oci_layouts: Vec<PathBuf>,
+    pub input_yaml_sha256: Option<String>,
+    pub base64: Base64Encoding,
+    // Catalog consulted for the image configs and manifests before pulling them
+    pub catalog: Option<Catalog>,
+    // Run after each stage of the rule generation of a container
+    pub plugins: Vec<Box<dyn RulePlugin>>,
+    // Agent API whose policy checks the policy targets, all the fields are emitted if None
+    pub agent_api: Option<AgentApi>,
+    // Manifest digest of every image the policies are generated from, e.g., for the provenance
+    pub images: Arc<Mutex<BTreeMap<String, String>>>,
+    // Sources of the values of the containers, collected with --report
+    pub report: Option<Arc<Mutex<Report>>>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Metadata {
+    // Rule categories replaced by wildcards, which the enforcer should treat as unenforced
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relaxed_rules: Vec<String>,
+    // Set if the env rules are ordered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_match: Option<EnvMatch>,
+    // Set if the mount options are in the canonical order of normalize_mount_options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized_mount_options: Option<bool>,
+    // Expected entries appended to /etc/hosts by the kubelet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_aliases: Vec<HostAlias>,
+    // Expected content of /etc/resolv.conf
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns: Option<Dns>,
+    // Ports exposed by the containers, with the defaulted hostPort under hostNetwork
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<ContainerPort>,
+    // Service account and projected token audiences of the pod
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account: Option<ServiceAccount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_pool: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_profile: Option<String>,
+    // Set if the sandbox is not the pause container of the pause image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<SandboxStrategy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<Generation>,
+}
+
+// How the policy was produced, omitted with --no-metadata for reproducible output
+#[derive(Serialize, Deserialize)]
+pub struct Generation {
+    pub tool_version: String,
+    // RFC 3339 in UTC, omitted with --reproducible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generated_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_yaml_sha256: Option<String>,
+    // Manifest digest of each image
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub image_digests: BTreeMap<String, String>,
+    // Node pool profile used for the sandbox
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CcPolicy {
     version: String,
-    containers: HashMap<String, ContainerPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
+    containers: BTreeMap<String, ContainerPolicy>,
+    // Serialize with sorted keys, given that the HashMaps of the OCI spec are not ordered
+    #[serde(skip)]
+    sorted_keys: bool,
 }
 
 impl CcPolicy {
     pub fn new() -> CcPolicy {
         let version = String::from(CC_POLICY_VERSION);
-        let containers = HashMap::new();
+        let containers = BTreeMap::new();
 
         CcPolicy {
             version,
+            metadata: None,
             containers,
+            sorted_keys: false,
         }
     }
 
+    fn apply_overlay(&mut self, options: &PolicyOptions) -> Result<()> {
+        let container = serde_yaml::Value::Null;
+
+        for (name, container_policy) in self.containers.iter_mut() {
+            if let Some(overlay) = &options.overlay {
+                for rules in overlay.get_rules(name) {
+                    container_policy.apply_overlay(rules)?;
+                }
+            }
+
+            let context = StageContext {
+                name,
+                container: &container,
+                image_config: None,
+            };
+            plugin::apply(
+                &options.plugins,
+                Stage::Overlay,
+                &context,
+                &mut container_policy.oci_spec,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn apply_node_pool(&mut self, name: &str, node_pool: &NodePool) {
+        let metadata = self.metadata.get_or_insert_with(Metadata::default);
+        metadata.node_pool = Some(name.to_string());
+        metadata.runtime_profile = node_pool.runtime_profile.clone();
+    }
+
+    // Omit the fields that the targeted agent API does not enforce, failing on the regex rules
+    // of process.args, which it would compare as exact strings
+    fn apply_agent_api(&mut self, options: &PolicyOptions) -> Result<()> {
+        let agent_api = match &options.agent_api {
+            Some(agent_api) => agent_api,
+            None => return Ok(()),
+        };
+
+        if let Some(metadata) = &mut self.metadata {
+            if !metadata.host_aliases.is_empty() && !agent_api.keep(Field::HostAliases, "policy") {
+                metadata.host_aliases = Vec::new();
+            }
+
+            if metadata.dns.is_some() && !agent_api.keep(Field::Dns, "policy") {
+                metadata.dns = None;
+            }
+        }
+
+        for (name, container_policy) in self.containers.iter_mut() {
+            let custom = match &mut container_policy.custom {
+                Some(custom) => custom,
+                None => continue,
+            };
+
+            if !custom.layers.is_empty() && !agent_api.keep(Field::Layers, name) {
+                custom.layers = Vec::new();
+            }
+
+            if !custom.encrypted_layers.is_empty() && !agent_api.keep(Field::EncryptedLayers, name)
+            {
+                custom.encrypted_layers = Vec::new();
+            }
+
+            if !custom.args_base64.is_empty() && !agent_api.keep(Field::ArgsBase64, name) {
+                custom.args_base64 = Vec::new();
+            }
+
+            if !custom.args_match.is_empty() && !agent_api.supports(Field::ArgsMatch) {
+                if custom.args_match.iter().any(|m| m == ARGS_MATCH_REGEX) {
+                    bail!(
+                        "{}: the regex rules of process.args cannot be expressed for {}, which compares them as exact strings",
+                        name,
+                        agent_api
+                    );
+                }
+
+                // Every rule is an exact string, as the target compares them
+                custom.args_match = Vec::new();
+            }
+
+            if (custom.image_config.is_some() || custom.image_config_digest.is_some())
+                && !agent_api.keep(Field::ImageConfig, name)
+            {
+                custom.image_config = None;
+                custom.image_config_digest = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Agents may compare the ociVersion strictly, the one given on the command line takes
+    // precedence over the one of the node pool
+    fn apply_oci_version(
+        &mut self,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<()> {
+        let version = match options
+            .oci_version
+            .as_ref()
+            .or_else(|| node_pool.and_then(|node_pool| node_pool.oci_version.as_ref()))
+        {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+
+        validate_oci_version(version)?;
+
+        for container_policy in self.containers.values_mut() {
+            container_policy.oci_spec.set_version(version.clone());
+        }
+
+        Ok(())
+    }
+
+    fn add_generation(&mut self, options: &PolicyOptions) {
+        if options.no_metadata {
+            return;
+        }
+
+        let image_digests = self
+            .containers
+            .values()
+            .filter_map(|container_policy| container_policy.image_digest.clone())
+            .collect();
+
+        let metadata = self.metadata.get_or_insert_with(Metadata::default);
+        let profile = metadata.node_pool.clone();
+
+        metadata.generation = Some(Generation {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            generated_at: if options.reproducible {
+                None
+            } else {
+                Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string())
+            },
+            input_yaml_sha256: options.input_yaml_sha256.clone(),
+            image_digests,
+            profile,
+        });
+    }
+
+    // With --reproducible, the policy only depends on the inputs, i.e., the images must be
+    // pinned by digest given that the digests of the tags change over time
+    fn check_reproducible(&mut self, options: &PolicyOptions) -> Result<()> {
+        if !options.reproducible {
+            return Ok(());
+        }
+
+        for (image_ref, digest) in self
+            .containers
+            .values()
+            .filter_map(|container_policy| container_policy.image_digest.as_ref())
+        {
+            if !image_ref.contains("@sha256:") {
+                bail!(
+                    "--reproducible requires the image {} to be pinned by digest, e.g., {}@{} (the pause image is set by pause_image of the node pool)",
+                    image_ref,
+                    image_ref,
+                    digest
+                );
+            }
+        }
+
+        self.sorted_keys = true;
+
+        Ok(())
+    }
+
+    // Fail if the policy has more loose rules (e.g., env wildcards) than the budget
+    fn check_loose_rules(&self, options: &PolicyOptions) -> Result<()> {
+        let max_loose_rules = match options.max_loose_rules {
+            Some(max_loose_rules) => max_loose_rules,
+            None => return Ok(()),
+        };
+
+        let loose_rules = lint::get_loose_rules(&serde_json::to_value(self)?);
+        if loose_rules.len() > max_loose_rules {
+            bail!(
+                "the policy has {} loose rules, more than the maximum of {} (--max-loose-rules):\n{}",
+                loose_rules.len(),
+                max_loose_rules,
+                loose_rules
+                    .iter()
+                    .map(|loose_rule| format!("  {}: {}", loose_rule.path, loose_rule))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            );
+        }
+
+        Ok(())
+    }
+
+    fn apply_env_match(&mut self, options: &PolicyOptions) {
+        if options.env_match == EnvMatch::Ordered {
+            self.metadata
+                .get_or_insert_with(Metadata::default)
+                .env_match = Some(EnvMatch::Ordered);
+        }
+    }
+
+    fn relax(&mut self, options: &PolicyOptions) {
+        let mut relaxed_rules = Vec::new();
+
+        if options.allow_any_env {
+            relaxed_rules.push(RELAXED_ENV.to_string());
+        }
+
+        if options.allow_any_mount_source {
+            relaxed_rules.push(RELAXED_MOUNT_SOURCE.to_string());
+        }
+
+        if options.allow_any_args {
+            relaxed_rules.push(RELAXED_ARGS.to_string());
+        }
+
+        // Any sandbox is allowed, e.g., the one of a remote hypervisor
+        if self
+            .metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.sandbox == Some(SandboxStrategy::None))
+        {
+            relaxed_rules.push(RELAXED_SANDBOX.to_string());
+        }
+
+        if relaxed_rules.is_empty() {
+            return;
+        }
+
+        for (name, container_policy) in self.containers.iter_mut() {
+            container_policy.relax(options, name == KUBERNETES_PAUSE_NAME);
+        }
+
+        self.metadata
+            .get_or_insert_with(Metadata::default)
+            .relaxed_rules = relaxed_rules;
+    }
+
+    // The enforcers compare the mount options literally, in the canonical order of
+    // normalize_mount_options once the overlay is applied. Only with --normalize-mount-options,
+    // given that the enforcer must then normalize the options of the incoming specs too.
+    fn normalize_mounts(&mut self, options: &PolicyOptions) {
+        if !options.normalize_mount_options {
+            return;
+        }
+
+        for container_policy in self.containers.values_mut() {
+            normalize_mounts(&mut container_policy.oci_spec);
+        }
+
+        self.metadata
+            .get_or_insert_with(Metadata::default)
+            .normalized_mount_options = Some(true);
+    }
+
+    // Encode the final args, i.e., once the overlay and the relaxation are applied
+    fn add_args_base64(&mut self) {
+        for container_policy in self.containers.values_mut() {
+            let args_base64 = ContainerPolicy::get_args_base64(&container_policy.oci_spec);
+
+            if let Some(custom) = &mut container_policy.custom {
+                custom.args_base64 = args_base64;
+            }
+        }
+    }
+
+    // The enforcer tells the regex env rules by their leading ^, which the policy also states
+    // explicitly for each rule once the overlay and the relaxation are applied
+    fn add_env_rule_match(&mut self) {
+        for container_policy in self.containers.values_mut() {
+            let env = container_policy
+                .oci_spec
+                .process()
+                .as_ref()
+                .and_then(|process| process.env().clone())
+                .unwrap_or_default();
+
+            if let Some(custom) = &mut container_policy.custom {
+                custom.env_rule_match = env
+                    .iter()
+                    .map(|rule| match pattern::is_regex(rule) {
+                        true => ARGS_MATCH_REGEX.to_string(),
+                        false => ARGS_MATCH_STRING.to_string(),
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    // Steps common to every policy once its containers are added, in order
+    fn finalize(&mut self, options: &PolicyOptions, node_pool: Option<&NodePool>) -> Result<()> {
+        self.apply_oci_version(options, node_pool)?;
+        self.apply_overlay(options)?;
+        self.apply_env_match(options);
+        self.relax(options);
+        self.add_env_rule_match();
+        self.add_args_base64();
+        self.normalize_mounts(options);
+        self.apply_agent_api(options)?;
+        self.add_generation(options);
+        self.check_reproducible(options)?;
+        self.validate()?;
+        self.check_loose_rules(options)
+    }
+
     fn get_container_policy(
         &mut self,
         pod_yaml: &PodYaml,
         containers: &Vec<serde_yaml::Value>,
-        with_default_rules: bool,
+        kube_rules: &Spec,
+        options: &PolicyOptions,
+        auth: Option<&str>,
+        node_pool: Option<&NodePool>,
     ) -> Result<()> {
+        let layout = node_pool::get_guest_layout(node_pool)?;
+
         for container in containers {
             let name = PodYaml::get_name(container)?;
-            let container_policy =
-                ContainerPolicy::from_container_yaml(container, pod_yaml, with_default_rules)?;
+            let container_policy = ContainerPolicy::from_container_yaml(
+                container, pod_yaml, kube_rules, options, auth, node_pool, &layout,
+            )?;
 
             self.containers.insert(name, container_policy);
         }
 
-        if with_default_rules {
-            let sandbox_policy = ContainerPolicy::create_sandbox_policy()?;
+        Ok(())
+    }
 
-            self.containers
-                .insert(KUBERNETES_PAUSE_NAME.to_string(), sandbox_policy);
+    // The annotation of the workload takes precedence over --sandbox, which takes precedence
+    // over the node pool
+    fn get_sandbox_strategy(
+        pod_yaml: Option<&PodYaml>,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<SandboxStrategy> {
+        if let Some(pod_yaml) = pod_yaml {
+            if let Some(value) = pod_yaml.get_sandbox_strategy()? {
+                return SandboxStrategy::from_str(value, false).map_err(|_| {
+                    anyhow!(
+                        "unsupported sandbox strategy {} of {} {}, expected one of pause, peer-pod, or none",
+                        value,
+                        pod_yaml.kind,
+                        pod_yaml.name
+                    )
+                });
+            }
+        }
+
+        Ok(match (options.sandbox, node_pool) {
+            (Some(sandbox), _) => sandbox,
+            (None, Some(node_pool)) => node_pool.get_sandbox_strategy(),
+            (None, None) => SandboxStrategy::Pause,
+        })
+    }
+
+    fn add_sandbox_policy(
+        &mut self,
+        strategy: SandboxStrategy,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<()> {
+        if strategy != SandboxStrategy::Pause {
+            self.metadata.get_or_insert_with(Metadata::default).sandbox = Some(strategy);
         }
 
+        let sandbox_policy = match strategy {
+            SandboxStrategy::Pause => ContainerPolicy::create_sandbox_policy(options, node_pool)?,
+            SandboxStrategy::PeerPod => ContainerPolicy::create_peer_pod_sandbox_policy(node_pool)?,
+            SandboxStrategy::None if options.sandbox_only => {
+                bail!("--sandbox-only cannot be used with the sandbox strategy none")
+            }
+            SandboxStrategy::None => return Ok(()),
+        };
+
+        self.containers
+            .insert(KUBERNETES_PAUSE_NAME.to_string(), sandbox_policy);
+
         Ok(())
     }
 
-    pub fn from_pod_yaml(pod_yaml: &PodYaml, with_default_rules: bool) -> Result<CcPolicy> {
+    // Policy with the sandbox (pause) container only, e.g., for debugging the sandbox
+    pub fn from_sandbox(options: &PolicyOptions) -> Result<CcPolicy> {
+        let mut cc_policy = CcPolicy::new();
+
+        let node_pool = node_pool::select(&options.node_pools, options.node_pool.as_deref(), None)?;
+
+        cc_policy.add_sandbox_policy(
+            Self::get_sandbox_strategy(None, options, node_pool.map(|(_, node_pool)| node_pool))?,
+            options,
+            node_pool.map(|(_, node_pool)| node_pool),
+        )?;
+
+        if let Some((name, node_pool)) = node_pool {
+            cc_policy.apply_node_pool(name, node_pool);
+        }
+
+        cc_policy.finalize(options, node_pool.map(|(_, node_pool)| node_pool))?;
+
+        Ok(cc_policy)
+    }
+
+    pub fn from_pod_yaml(pod_yaml: &PodYaml, options: &PolicyOptions) -> Result<CcPolicy> {
         let mut cc_policy = CcPolicy::new();
 
-        if let Some(containers) = pod_yaml.containers {
-            cc_policy.get_container_policy(pod_yaml, containers, with_default_rules)?;
+        let services = if options.services_from_cluster {
+            let namespace = cluster::get_namespace(pod_yaml)?;
+            Some(cluster::get_services(&namespace)?)
+        } else {
+            None
+        };
+
+        // The images of the containers are inspected with the credentials of the pod
+        let auth = if options.pull_secrets_from_cluster && !options.sandbox_only {
+            cluster::get_registry_auth(pod_yaml)?
+        } else {
+            None
+        };
+
+        let node_pool = node_pool::select(
+            &options.node_pools,
+            options.node_pool.as_deref(),
+            Some(pod_yaml.spec),
+        )?;
+        let layout = node_pool::get_guest_layout(node_pool.map(|(_, node_pool)| node_pool))?;
+
+        let kube_rules = kubernetes::get_rules(
+            false,
+            pod_yaml.enable_service_links,
+            services.as_deref(),
+            &layout,
+        )?;
+
+        let has_containers = pod_yaml.containers.is_some_and(|c| !c.is_empty());
+
+        if !options.sandbox_only {
+            pod_yaml.check_host_paths();
+
+            if !has_containers {
+                warn!(
+                    "{} {} has no containers, the policy only covers the init containers and the sandbox",
+                    pod_yaml.kind, pod_yaml.name
+                );
+            }
+
+            if let Some(containers) = pod_yaml.containers {
+                cc_policy.get_container_policy(
+                    pod_yaml,
+                    containers,
+                    &kube_rules,
+                    options,
+                    auth.as_deref(),
+                    node_pool.map(|(_, node_pool)| node_pool),
+                )?;
+            }
+
+            if let Some(init_containers) = pod_yaml.init_containers {
+                cc_policy.get_container_policy(
+                    pod_yaml,
+                    init_containers,
+                    &kube_rules,
+                    options,
+                    auth.as_deref(),
+                    node_pool.map(|(_, node_pool)| node_pool),
+                )?;
+            }
+        }
+
+        // The sandbox is created even if the pod has no containers
+        if options.with_default_rules || options.sandbox_only || !has_containers {
+            cc_policy.add_sandbox_policy(
+                Self::get_sandbox_strategy(
+                    Some(pod_yaml),
+                    options,
+                    node_pool.map(|(_, node_pool)| node_pool),
+                )?,
+                options,
+                node_pool.map(|(_, node_pool)| node_pool),
+            )?;
+        }
+
+        let host_aliases = pod_yaml.get_host_aliases()?;
+        if !host_aliases.is_empty() {
+            cc_policy
+                .metadata
+                .get_or_insert_with(Metadata::default)
+                .host_aliases = host_aliases;
         }
 
-        if let Some(init_containers) = pod_yaml.init_containers {
-            cc_policy.get_container_policy(pod_yaml, init_containers, with_default_rules)?;
+        let ports = pod_yaml.get_ports()?;
+        if !ports.is_empty() {
+            cc_policy
+                .metadata
+                .get_or_insert_with(Metadata::default)
+                .ports = ports;
         }
 
+        cc_policy
+            .metadata
+            .get_or_insert_with(Metadata::default)
+            .service_account = Some(pod_yaml.get_service_account()?);
+
+        let dns = pod_yaml.get_dns()?;
+        if dns.source == "host" {
+            warn!(
+                "{} {} uses the DNS of the node, which is not known when generating the policy",
+                pod_yaml.kind, pod_yaml.name
+            );
+        }
+
+        if !dns.is_default() {
+            cc_policy.metadata.get_or_insert_with(Metadata::default).dns = Some(dns);
+        }
+
+        if let Some((name, node_pool)) = node_pool {
+            cc_policy.apply_node_pool(name, node_pool);
+        }
+
+        cc_policy.finalize(options, node_pool.map(|(_, node_pool)| node_pool))?;
+
         Ok(cc_policy)
     }
 
-    pub fn from_image_ref(image_ref: &str, with_default_rules: bool) -> Result<CcPolicy> {
+    pub fn from_image_ref(image_ref: &str, options: &PolicyOptions) -> Result<CcPolicy> {
         let mut cc_policy = CcPolicy::new();
 
         let name = match image_ref.find(':') {
@@ -98,25 +852,99 @@ impl CcPolicy {
             None => image_ref,
         };
 
-        let container_policy = ContainerPolicy::from_image_ref(image_ref, with_default_rules)?;
+        let node_pool = node_pool::select(&options.node_pools, options.node_pool.as_deref(), None)?;
+        let layout = node_pool::get_guest_layout(node_pool.map(|(_, node_pool)| node_pool))?;
+
+        let container_policy = ContainerPolicy::from_image_ref(name, image_ref, options, &layout)?;
 
         cc_policy
             .containers
             .insert(name.to_owned(), container_policy);
 
+        if let Some((name, node_pool)) = node_pool {
+            cc_policy.apply_node_pool(name, node_pool);
+        }
+
+        cc_policy.finalize(options, node_pool.map(|(_, node_pool)| node_pool))?;
+
         Ok(cc_policy)
     }
 
-    pub fn to_base64(&self) -> String {
-        let json = self.to_string();
+    // Merge the policies attached to the images of the pod by their owners, keyed by
+    // the names of the containers
+    pub fn from_attached_policies(pod_yaml: &PodYaml) -> Result<CcPolicy> {
+        let mut cc_policy = CcPolicy::new();
+
+        let containers = pod_yaml
+            .containers
+            .into_iter()
+            .chain(pod_yaml.init_containers)
+            .flatten();
+
+        for container in containers {
+            let name = PodYaml::get_name(container)?;
+            let image_ref = container["image"].as_str().ok_or_else(|| {
+                span::error(&container["image"], "failed to parse image into string")
+            })?;
+
+            let attached = registry::pull_policy(image_ref)?;
+
+            if attached.containers.len() != 1 {
+                bail!(
+                    "{}: the policy attached to {} has {} containers, expected 1",
+                    loc!(),
+                    image_ref,
+                    attached.containers.len()
+                );
+            }
+
+            // Checked above
+            let (_, container_policy) = attached.containers.into_iter().next().unwrap();
+
+            cc_policy.containers.insert(name, container_policy);
+        }
+
+        cc_policy.validate()?;
+
+        Ok(cc_policy)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (name, container_policy) in &self.containers {
+            container_policy
+                .validate()
+                .with_context(|| format!("invalid rules for container {}", name))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn to_base64(&self, encoding: &Base64Encoding) -> String {
+        let json = if encoding.canonical {
+            self.to_canonical_json()
+        } else {
+            self.to_string()
+        };
+
+        encoding.encode(&json)
+    }
 
-        base64::encode(&json)
+    // RFC 8785 serialization, whose digest does not depend on the formatting
+    pub fn to_canonical_json(&self) -> String {
+        canonical::to_canonical_json(&serde_json::to_value(self).unwrap())
     }
 }
 
 impl fmt::Display for CcPolicy {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", serde_json::to_string_pretty(&self).unwrap())
+        let json = if self.sorted_keys {
+            // The maps of serde_json::Value are sorted by key
+            serde_json::to_string_pretty(&serde_json::to_value(self).unwrap())
+        } else {
+            serde_json::to_string_pretty(&self)
+        };
+
+        write!(f, "{}", json.unwrap())
     }
 }
 
@@ -124,133 +952,501 @@ impl ContainerPolicy {
     pub fn from_container_yaml(
         container: &serde_yaml::Value,
         pod_yaml: &PodYaml,
-        with_default_rules: bool,
+        kube_rules: &Spec,
+        options: &PolicyOptions,
+        auth: Option<&str>,
+        node_pool: Option<&NodePool>,
+        layout: &GuestLayout,
     ) -> Result<ContainerPolicy> {
+        let name = PodYaml::get_name(container)?;
         let security_context = PodYaml::get_security_context(container)?;
         let debugging = PodYaml::get_debugging(container)?;
-        let mut oci_spec = if with_default_rules {
-            cri::get_rules(false, security_context.privileged, debugging.tty)?
+        let image_name = container["image"]
+            .as_str()
+            .ok_or_else(|| span::error(&container["image"], "failed to parse image into string"))?;
+        let (inline_config, inline_manifest) = pod_yaml.get_inline_image(&name)?;
+
+        // Nothing is pulled if both the config and the manifest are inlined
+        let image_ref = if inline_config.is_some() && inline_manifest.is_some() {
+            image_name.to_string()
+        } else {
+            Self::get_image_source(container, image_name, options)?
+        };
+
+        let image_config = match inline_config {
+            Some(image_config) => image_config,
+            None => Self::pull_image_config(&image_ref, options, auth)?,
+        };
+        image::check_platform(image_name, &image_config);
+
+        let context = StageContext {
+            name: &name,
+            container,
+            image_config: Some(&image_config),
+        };
+
+        let mut oci_spec = if options.with_default_rules {
+            cri::get_rules(false, security_context.privileged, debugging.tty, layout)?
         } else {
             empty_spec()?
         };
-        let kube_rules = kubernetes::get_rules(false)?;
-        let image_name = container["image"]
-            .as_str()
-            .ok_or_else(|| anyhow!("failed to parse image into string"))?;
-        let layers = Vec::new();
-        let image_config = pull_image_config(image_name)?;
-        //let allow_elevated = security_context.allow_elevated;
+        plugin::apply(&options.plugins, Stage::Defaults, &context, &mut oci_spec)?;
+
+        Self::apply_kubelet_rules(&mut oci_spec, kube_rules)?;
+        plugin::apply(&options.plugins, Stage::Kubelet, &context, &mut oci_spec)?;
 
-        Self::get_process(&mut oci_spec, container, &image_config, &kube_rules)?;
+        Self::apply_image_rules(&mut oci_spec, &image_config, kube_rules, layout)?;
+        plugin::apply(&options.plugins, Stage::Image, &context, &mut oci_spec)?;
 
-        Self::get_mounts(
+        let regex_args = Self::apply_yaml_rules(
             &mut oci_spec,
-            Some(pod_yaml),
             container,
+            pod_yaml,
+            &security_context,
             &image_config,
-            &kube_rules,
-        )?;
+            kube_rules,
+            options,
+        )
+        .with_context(|| format!("invalid container {} of image {}", name, image_name))?;
+        Self::apply_mount_rules(
+            &mut oci_spec,
+            container,
+            pod_yaml,
+            &security_context,
+            options,
+            node_pool,
+        )
+        .with_context(|| format!("invalid volume mounts of container {}", name))?;
+        Self::check_env(
+            &oci_spec,
+            &image_config,
+            container,
+            pod_yaml.enable_service_links,
+        )
+        .with_context(|| format!("invalid env rules of container {}", name))?;
+        plugin::apply(&options.plugins, Stage::Yaml, &context, &mut oci_spec)?;
+
+        if let Some(report) = &options.report {
+            let delta = report::get_defaulting_delta(
+                &oci_spec,
+                container,
+                &pod_yaml.get_user_context(container)?,
+                &image_config,
+            )?;
+
+            report.lock().unwrap().defaulting_delta.insert(
+                format!("{}/{}/{}", pod_yaml.kind, pod_yaml.name, name),
+                delta,
+            );
+        }
 
-        let custom = Some(Custom { layers });
+        let (mut custom, digest) =
+            Self::get_custom(&image_ref, &image_config, inline_manifest, options, auth)?;
+        custom.args_match = Self::get_args_match(&oci_spec, &regex_args);
 
-        Ok(ContainerPolicy { oci_spec, custom })
+        Ok(ContainerPolicy {
+            oci_spec,
+            custom: Some(custom),
+            image_digest: Some((image_name.to_string(), digest)),
+        })
     }
 
-    pub fn from_image_ref(image_ref: &str, with_default_rules: bool) -> Result<ContainerPolicy> {
-        let layers = Vec::new();
-        let image_config = pull_image_config(image_ref).context(loc!())?;
+    // Policy of a container running the image as is, i.e., the defaults and image stages
+    fn from_image_config(
+        name: &str,
+        image_ref: &str,
+        mut oci_spec: Spec,
+        options: &PolicyOptions,
+        layout: &GuestLayout,
+    ) -> Result<ContainerPolicy> {
+        let image_config = Self::pull_image_config(image_ref, options, None).context(loc!())?;
+        image::check_platform(image_ref, &image_config);
+
+        let container = serde_yaml::Value::Null;
+        let context = StageContext {
+            name,
+            container: &container,
+            image_config: Some(&image_config),
+        };
+
+        plugin::apply(&options.plugins, Stage::Defaults, &context, &mut oci_spec)?;
 
-        let mut oci_spec = if with_default_rules {
-            cri::get_rules(false, false, false)?
+        Self::apply_image_rules(&mut oci_spec, &image_config, &empty_spec()?, layout)
+            .context(loc!())?;
+        Self::check_env(&oci_spec, &image_config, &container, false)
+            .with_context(|| format!("invalid env rules of container {}", name))?;
+        check_shell_form(name, &[], &[], &image_config);
+        plugin::apply(&options.plugins, Stage::Image, &context, &mut oci_spec)?;
+
+        let (custom, digest) =
+            Self::get_custom(image_ref, &image_config, None, options, None).context(loc!())?;
+
+        Ok(ContainerPolicy {
+            oci_spec,
+            custom: Some(custom),
+            image_digest: Some((image_ref.to_string(), digest)),
+        })
+    }
+
+    pub fn from_image_ref(
+        name: &str,
+        image_ref: &str,
+        options: &PolicyOptions,
+        layout: &GuestLayout,
+    ) -> Result<ContainerPolicy> {
+        let oci_spec = if options.with_default_rules {
+            cri::get_rules(false, false, false, layout)?
         } else {
             empty_spec()?
         };
 
-        let container = serde_yaml::Value::Null;
+        Self::from_image_config(name, image_ref, oci_spec, options, layout)
+    }
 
-        let empty_spec = empty_spec()?;
+    // The default rules of the sandbox with the annotations of the node pool
+    fn get_sandbox_rules(node_pool: Option<&NodePool>) -> Result<(Spec, GuestLayout)> {
+        let layout = node_pool::get_guest_layout(node_pool)?;
+        let mut oci_spec = cri::get_rules(true, false, false, &layout)?;
 
-        Self::get_process(&mut oci_spec, &container, &image_config, &empty_spec).context(loc!())?;
+        if let Some(node_pool) = node_pool {
+            if !node_pool.sandbox_annotations.is_empty() {
+                oci_spec.set_annotations(Some(
+                    node_pool.sandbox_annotations.clone().into_iter().collect(),
+                ));
+            }
+        }
 
-        Self::get_mounts(&mut oci_spec, None, &container, &image_config, &empty_spec)
-            .context(loc!())?;
+        Ok((oci_spec, layout))
+    }
 
-        let custom = Some(Custom { layers });
+    fn get_pause_args(node_pool: Option<&NodePool>) -> Result<Vec<String>> {
+        let args: Vec<String> = match node_pool.and_then(|node_pool| node_pool.pause_args.as_ref())
+        {
+            Some(pause_args) => pause_args.clone(),
+            None => KUBERNETES_PAUSE_ARGS
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+        };
+        if args.is_empty() {
+            bail!("pause_args of the node pool is empty");
+        }
 
-        Ok(ContainerPolicy { oci_spec, custom })
+        Ok(args)
     }
 
-    pub fn create_sandbox_policy() -> Result<ContainerPolicy> {
-        let mut oci_spec = cri::get_rules(true, false, false)?;
-        let layers = Vec::new();
+    pub fn create_sandbox_policy(
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<ContainerPolicy> {
+        let (oci_spec, layout) = Self::get_sandbox_rules(node_pool)?;
 
-        let image_ref = get_pause_image_ref();
+        let image_ref = match node_pool.and_then(|node_pool| node_pool.pause_image.as_ref()) {
+            Some(pause_image) => pause_image.clone(),
+            None => get_pause_image_ref(),
+        };
 
-        let image_config = pull_image_config(&image_ref)?;
+        let expected_args = Self::get_pause_args(node_pool)?;
 
-        let container = serde_yaml::Value::Null;
+        let policy = Self::from_image_config(
+            KUBERNETES_PAUSE_NAME,
+            &image_ref,
+            oci_spec,
+            options,
+            &layout,
+        )?;
 
-        let empty_spec = empty_spec()?;
+        // The argv of the sandbox comes from the image config only, check it so that a pause
+        // image with another entrypoint (or a Cmd) does not silently change the sandbox rule
+        let args = policy
+            .oci_spec
+            .process()
+            .as_ref()
+            .and_then(|process| process.args().clone())
+            .unwrap_or_default();
+        if args != expected_args {
+            bail!(
+                "the argv {:?} of pause image {} is not the expected {:?}, set pause_args of the node pool for the pause images with another entrypoint",
+                args,
+                image_ref,
+                expected_args
+            );
+        }
 
-        Self::get_process(&mut oci_spec, &container, &image_config, &empty_spec)?;
+        Ok(policy)
+    }
 
-        Self::get_mounts(&mut oci_spec, None, &container, &image_config, &empty_spec)?;
+    // The agent of the peer pods runs the pause binary of the pause bundle of the pod VM image,
+    // so nothing is pulled and the policy has no layers
+    pub fn create_peer_pod_sandbox_policy(node_pool: Option<&NodePool>) -> Result<ContainerPolicy> {
+        let (mut oci_spec, _) = Self::get_sandbox_rules(node_pool)?;
+        let args = Self::get_pause_args(node_pool)?;
+
+        Self::set_process(&mut oci_spec, |process| {
+            process.set_args(Some(args));
+            Ok(())
+        })?;
+
+        Ok(ContainerPolicy {
+            oci_spec,
+            custom: None,
+            image_digest: None,
+        })
+    }
 
-        let custom = Some(Custom { layers });
+    // Check the regex rules of env, args, annotations, and mount sources
+    fn validate(&self) -> Result<()> {
+        let mut rules = Vec::new();
 
-        Ok(ContainerPolicy { oci_spec, custom })
+        if let Some(process) = self.oci_spec.process() {
+            if let Some(env) = process.env() {
+                rules.extend(env.iter().cloned());
+            }
+
+            if let (Some(args), Some(custom)) = (process.args(), &self.custom) {
+                for (arg, args_match) in args.iter().zip(&custom.args_match) {
+                    if args_match == ARGS_MATCH_REGEX {
+                        rules.push(arg.clone());
+                    }
+                }
+            }
+        }
+
+        if let Some(annotations) = self.oci_spec.annotations() {
+            rules.extend(annotations.values().cloned());
+        }
+
+        if let Some(mounts) = self.oci_spec.mounts() {
+            for mount in mounts {
+                if let Some(source) = mount.source() {
+                    rules.push(source.to_string_lossy().to_string());
+                }
+            }
+        }
+
+        for rule in rules.iter().filter(|rule| pattern::is_regex(rule)) {
+            pattern::validate(rule)?;
+        }
+
+        Ok(())
     }
 
-    fn get_env(
-        spec: &Spec,
-        container: &serde_yaml::Value,
-        image_config: &ImageConfiguration,
-        kube_rules: &Spec,
-    ) -> Result<Vec<String>> {
-        // Override rule: the latter variables will override the former ones with the same name
-        // Order based on the CRI:
-        // - CRI default variables
-        // - HOSTNAME
-        // - Variables from Image Config
-        // - Variables from Kubernetes
-        // - Variables from Pod YAML
-        let mut results = Vec::new();
+    fn apply_overlay(&mut self, rules: &OverlayRules) -> Result<()> {
+        let spec = &mut self.oci_spec;
+
+        if !rules.env.is_empty() {
+            let mut process = if let Some(process) = spec.process() {
+                process.clone()
+            } else {
+                empty_process()?
+            };
+
+            let mut env = process.env().clone().unwrap_or_default();
+            merge_process_env(&mut env, &rules.env)?;
+
+            process.set_env(Some(env));
+            spec.set_process(Some(process));
+        }
+
+        if !rules.mounts.is_empty() {
+            let mounts = spec.mounts().clone().unwrap_or_default();
+
+            spec.set_mounts(Some(merge_mounts(&rules.mounts, &mounts)?));
+        }
+
+        Ok(())
+    }
+
+    // The argv of the sandbox is known (see create_sandbox_policy), it stays an exact rule
+    fn relax(&mut self, options: &PolicyOptions, sandbox: bool) {
+        let spec = &mut self.oci_spec;
+
+        if let Some(mut process) = spec.process().clone() {
+            if options.allow_any_env {
+                process.set_env(Some(vec![ANY_ENV.to_string()]));
+            }
+
+            if options.allow_any_args && !sandbox {
+                process.set_args(Some(vec![ANY_ARGS.to_string()]));
 
+                if let Some(custom) = &mut self.custom {
+                    custom.args_match = vec![ARGS_MATCH_REGEX.to_string()];
+                }
+            }
+
+            spec.set_process(Some(process));
+        }
+
+        if options.allow_any_mount_source {
+            if let Some(mut mounts) = spec.mounts().clone() {
+                for mount in &mut mounts {
+                    mount.set_source(Some(PathBuf::from(ANY_MOUNT_SOURCE)));
+                }
+
+                spec.set_mounts(Some(mounts));
+            }
+        }
+    }
+
+    // process.args is an ordered array of exact strings, i.e., the argv passed to execve
+    // without any shell quoting or splitting. Given that some consumers normalize whitespace
+    // and control characters (e.g., newlines), args containing control characters are
+    // additionally encoded in base64 so that they can be compared byte for byte.
+    fn get_args_base64(spec: &Spec) -> Vec<String> {
         if let Some(process) = spec.process() {
-            if let Some(envs) = process.env() {
-                results = envs.clone();
+            if let Some(args) = process.args() {
+                if args.iter().any(|arg| arg.chars().any(char::is_control)) {
+                    return args.iter().map(base64::encode).collect();
+                }
             }
         }
 
-        let mut kube_envs = Vec::new();
+        Vec::new()
+    }
 
-        if let Some(process) = kube_rules.process() {
-            if let Some(envs) = process.env() {
-                kube_envs = envs.clone();
+    // Given the args that are regex rules, return the matching of each of process.args if any
+    // of them is a regex. The args, e.g., the ones modified by the plugins, are exact strings
+    // otherwise.
+    fn get_args_match(spec: &Spec, regex_args: &[String]) -> Vec<String> {
+        if let Some(process) = spec.process() {
+            if let Some(args) = process.args() {
+                if args.iter().any(|arg| regex_args.contains(arg)) {
+                    return args
+                        .iter()
+                        .map(|arg| match regex_args.contains(arg) {
+                            true => ARGS_MATCH_REGEX.to_string(),
+                            false => ARGS_MATCH_STRING.to_string(),
+                        })
+                        .collect();
+                }
             }
         }
 
-        merge_process_env(&mut results, &kube_envs)?;
+        Vec::new()
+    }
 
-        let image_envs = image::get_env(image_config)?;
+    // The kubelet never pulls the images of the containers with imagePullPolicy: Never, which
+    // are then read from the local image stores unless the catalog has them
+    // Reference: https://kubernetes.io/docs/concepts/containers/images/#image-pull-policy
+    fn get_image_source(
+        container: &serde_yaml::Value,
+        image_name: &str,
+        options: &PolicyOptions,
+    ) -> Result<String> {
+        if PodYaml::get_image_pull_policy(container)? != Some("Never") {
+            return Ok(image_name.to_string());
+        }
 
-        merge_process_env(&mut results, &image_envs)?;
+        if let Some(catalog) = &options.catalog {
+            if catalog.get_image_config(image_name)?.is_some() {
+                return Ok(image_name.to_string());
+            }
+        }
 
-        let yaml_envs = PodYaml::get_env(container)?;
+        image::find_local_image(image_name, &options.oci_layouts)
+    }
 
-        merge_process_env(&mut results, &yaml_envs)?;
+    // Return the ref of the image in the catalog, if any. The tags missing from its index are
+    // resolved with a HEAD request of the manifest, which Docker Hub does not count, so that an
+    // image of the catalog is not pulled under another tag.
+    fn get_catalog_ref(image_ref: &str, catalog: &Catalog) -> Option<String> {
+        if catalog.contains(image_ref) {
+            return Some(image_ref.to_string());
+        }
 
-        Ok(results)
+        let digest = image::head_image_digest(image_ref)?;
+        let image_ref = image::get_image_ref_by_digest(image_ref, &digest);
+
+        catalog.contains(&image_ref).then_some(image_ref)
     }
 
-    fn get_process(
-        spec: &mut Spec,
-        container: &serde_yaml::Value,
+    fn pull_image_config(
+        image_ref: &str,
+        options: &PolicyOptions,
+        auth: Option<&str>,
+    ) -> Result<ImageConfiguration> {
+        if let Some(catalog) = &options.catalog {
+            if let Some(catalog_ref) = Self::get_catalog_ref(image_ref, catalog) {
+                if let Some(image_config) = catalog.get_image_config(&catalog_ref)? {
+                    return Ok(image_config);
+                }
+            }
+        }
+
+        image::pull_image_config(image_ref, auth)
+    }
+
+    fn pull_image_manifest(
+        image_ref: &str,
+        options: &PolicyOptions,
+        auth: Option<&str>,
+    ) -> Result<(ImageManifest, String)> {
+        if let Some(catalog) = &options.catalog {
+            if let Some(catalog_ref) = Self::get_catalog_ref(image_ref, catalog) {
+                if let Some(manifest) = catalog.get_image_manifest(&catalog_ref)? {
+                    return Ok(manifest);
+                }
+            }
+        }
+
+        image::pull_image_manifest(image_ref, auth)
+    }
+
+    fn get_custom(
+        image_ref: &str,
         image_config: &ImageConfiguration,
-        kube_rules: &Spec,
-    ) -> Result<()> {
-        let (working_dir, command, args) = PodYaml::get_entry_point(container)?;
+        manifest: Option<(ImageManifest, String)>,
+        options: &PolicyOptions,
+        auth: Option<&str>,
+    ) -> Result<(Custom, String)> {
+        let (manifest, digest) = match manifest {
+            Some(manifest) => manifest,
+            None => Self::pull_image_manifest(image_ref, options, auth)?,
+        };
 
+        options
+            .images
+            .lock()
+            .unwrap()
+            .insert(image_ref.to_string(), digest.clone());
+
+        let layers = layer::get_layers(image_config, &manifest)?;
+        let encrypted_layers = layer::get_encrypted_layers(image_config, &manifest)?;
+
+        if !encrypted_layers.is_empty() {
+            if let Some(key_provider) = &options.key_provider {
+                layer::check_key_provider(key_provider)?;
+            }
+        }
+
+        let image_config_digest = options
+            .embed_image_config
+            .as_ref()
+            .map(|_| manifest.config().digest().to_string());
+
+        let image_config = match options.embed_image_config {
+            Some(ImageConfigEmbedding::Full) => Some(image_config.clone()),
+            _ => None,
+        };
+
+        Ok((
+            Custom {
+                layers,
+                encrypted_layers,
+                args_base64: Vec::new(),
+                args_match: Vec::new(),
+                env_rule_match: Vec::new(),
+                image_config_digest,
+                image_config,
+            },
+            digest,
+        ))
+    }
+
+    fn set_process<F>(spec: &mut Spec, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Process) -> Result<()>,
+    {
         // Make a copy given that Spec does not support mutable getter
         let mut process = if let Some(process) = spec.process() {
             process.clone()
@@ -258,63 +1454,401 @@ impl ContainerPolicy {
             empty_process()?
         };
 
-        let args = merge_process_args(&command, &args, image_config)?;
+        f(&mut process)?;
 
-        let cwd = merge_process_cwd(&working_dir, image_config)?;
+        spec.set_process(Some(process));
+
+        Ok(())
+    }
 
-        // Overwrite the default cwd if the working_dir from either pod yaml or image config is not empty.
-        // Reference
-        // https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L144
-        // https://github.com/containerd/containerd/blob/main/oci/spec_opts.go#L234
-        if !cwd.as_os_str().is_empty() {
-            process.set_cwd(cwd);
+    // Override rule of env: the latter variables will override the former ones with the same
+    // name, in the order of the stages:
+    // - CRI default variables
+    // - Variables from Kubernetes
+    // - Variables from Image Config
+    // - Variables from Pod YAML
+    // Override rule of mounts: volumes from pod yaml take precedence over the mounts inserted
+    // by Kubelet, which take precedence over the image volumes, then the default mounts
+    fn apply_kubelet_rules(spec: &mut Spec, kube_rules: &Spec) -> Result<()> {
+        if let Some(process) = kube_rules.process() {
+            if let Some(kube_envs) = process.env() {
+                Self::set_process(spec, |process| {
+                    let mut env = process.env().clone().unwrap_or_default();
+                    merge_process_env(&mut env, kube_envs)?;
+                    process.set_env(Some(env));
+                    Ok(())
+                })?;
+            }
         }
 
-        let env = Self::get_env(spec, container, image_config, kube_rules)?;
+        if let Some(kube_mounts) = kube_rules.mounts() {
+            let mounts = spec.mounts().clone().unwrap_or_default();
+            spec.set_mounts(Some(merge_mounts(kube_mounts, &mounts)?));
+        }
 
-        process.set_args(Some(args));
-        process.set_env(Some(env));
+        Ok(())
+    }
 
-        spec.set_process(Some(process));
+    fn apply_image_rules(
+        spec: &mut Spec,
+        image_config: &ImageConfiguration,
+        kube_rules: &Spec,
+        layout: &GuestLayout,
+    ) -> Result<()> {
+        Self::set_process(spec, |process| {
+            process.set_args(Some(merge_process_args(&[], &[], image_config)?));
+
+            // Overwrite the default cwd if the working_dir of the image config is not empty.
+            // Reference
+            // https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L144
+            // https://github.com/containerd/containerd/blob/main/oci/spec_opts.go#L234
+            let cwd = merge_process_cwd("", image_config)?;
+            if !cwd.as_os_str().is_empty() {
+                process.set_cwd(cwd);
+            }
+
+            // containerd applies the env of the image config before the env of the CRI
+            // container config, i.e., the ones inserted by the kubelet, which then take
+            // precedence. The default PATH is only kept if the image config has no PATH.
+            // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L141
+            let kube_env = kube_rules
+                .process()
+                .as_ref()
+                .and_then(|process| process.env().clone())
+                .unwrap_or_default();
+
+            let mut env = process.env().clone().unwrap_or_default();
+            merge_process_env(&mut env, &image::get_env(image_config)?)?;
+            merge_process_env(&mut env, &kube_env)?;
+            process.set_env(Some(env));
+
+            Ok(())
+        })?;
+
+        let kube_mounts = kube_rules.mounts().clone().unwrap_or_default();
+        let image_volumes: Vec<Mount> = get_image_volume_mounts(image_config, layout)?
+            .into_iter()
+            .filter(|volume| {
+                !kube_mounts
+                    .iter()
+                    .any(|mount| mount.destination() == volume.destination())
+            })
+            .collect();
+
+        let mounts = spec.mounts().clone().unwrap_or_default();
+        spec.set_mounts(Some(merge_mounts(&image_volumes, &mounts)?));
 
         Ok(())
     }
 
-    fn get_mounts(
+    fn apply_yaml_rules(
         spec: &mut Spec,
-        pod_yaml: Option<&PodYaml>,
         container: &serde_yaml::Value,
+        pod_yaml: &PodYaml,
+        security_context: &SecurityContext,
         image_config: &ImageConfiguration,
         kube_rules: &Spec,
+        options: &PolicyOptions,
+    ) -> Result<Vec<String>> {
+        let (working_dir, command, args) = PodYaml::get_entry_point(container)?;
+        PodYaml::check_env_from(container, options.allow_any_env)?;
+
+        // The kubelet expands the command and args of the container, but not the entry point of
+        // the image. The args referring to runtime values become regex rules.
+        let env_values = PodYaml::get_env_values(container, pod_yaml.enable_service_links)?;
+        let mut regex_args = Vec::new();
+        let mut expand = |args: &[String]| -> Vec<String> {
+            args.iter()
+                .map(|arg| {
+                    let (arg, is_regex) = kubernetes::expand(arg, &env_values);
+                    if is_regex {
+                        regex_args.push(arg.clone());
+                    }
+                    arg
+                })
+                .collect()
+        };
+        let command = expand(&command);
+        let args = expand(&args);
+
+        check_shell_form(
+            &PodYaml::get_name(container)?,
+            &command,
+            &args,
+            image_config,
+        );
+
+        Self::set_process(spec, |process| {
+            // The command and args of the container replace the entry point of the image
+            process.set_args(Some(merge_process_args(&command, &args, image_config)?));
+
+            if !working_dir.is_empty() {
+                process.set_cwd(PathBuf::from(&working_dir));
+            }
+
+            let mut container_env = PodYaml::get_env(container, pod_yaml.enable_service_links)?;
+            if !options.keep_duplicate_env {
+                let duplicates;
+                (container_env, duplicates) = dedup_env(&container_env)?;
+
+                for name in duplicates {
+                    warn!(
+                        "container {}: env {} is given more than once, only the last one is kept",
+                        PodYaml::get_name(container)?,
+                        name
+                    );
+                }
+            }
+
+            let mut env = process.env().clone().unwrap_or_default();
+            merge_process_env(&mut env, &container_env)?;
+            if options.env_match == EnvMatch::Ordered {
+                env = Self::get_kubelet_env_order(env, kube_rules, image_config)?;
+            }
+            process.set_env(Some(env));
+
+            // containerd sets noNewPrivileges based on the NoNewPrivs of the CRI security context
+            // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L257
+            if security_context.no_new_privileges {
+                process.set_no_new_privileges(Some(true));
+            }
+
+            Ok(())
+        })?;
+
+        let user_context = pod_yaml.get_user_context(container)?;
+        Self::get_user(spec, &user_context, image_config).context("invalid user")?;
+
+        Ok(regex_args)
+    }
+
+    // The sources of some volumes (e.g., csi) are the files that Kata shares with the guest, and
+    // the recursive read-only mounts depend on the runtime of the node pool
+    fn apply_mount_rules(
+        spec: &mut Spec,
+        container: &serde_yaml::Value,
+        pod_yaml: &PodYaml,
+        security_context: &SecurityContext,
+        options: &PolicyOptions,
+        node_pool: Option<&NodePool>,
+    ) -> Result<()> {
+        let pod_mounts = pod_yaml.get_mounts(container, options, node_pool)?;
+        let mounts = spec.mounts().clone().unwrap_or_default();
+        spec.set_mounts(Some(merge_mounts(&pod_mounts, &mounts)?));
+
+        // containerd mounts the resolv.conf of the sandbox read-only with readOnlyRootFilesystem
+        // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L85
+        if security_context.read_only_root_filesystem {
+            Self::set_mount_read_only(spec, RESOLV_CONF_PATH);
+        }
+
+        Ok(())
+    }
+
+    // The kubelet appends the service variables after the env of the container, and containerd
+    // then appends the variables of the CRI that the defaults and the image config do not have.
+    // The layering puts the service variables before the ones of the image config and of the
+    // container, so they are moved to the end unless they replaced a variable of the image
+    // config in place.
+    // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kubelet_pods.go
+    fn get_kubelet_env_order(
+        env: Vec<String>,
+        kube_rules: &Spec,
+        image_config: &ImageConfiguration,
+    ) -> Result<Vec<String>> {
+        let kube_env = kube_rules
+            .process()
+            .as_ref()
+            .and_then(|process| process.env().clone())
+            .unwrap_or_default();
+
+        let mut image_names = HashSet::new();
+        for rule in image::get_env(image_config)? {
+            image_names.insert(split_env(&rule)?.0);
+        }
+
+        let mut results = Vec::new();
+        let mut services = Vec::new();
+        for rule in env {
+            if kube_env.contains(&rule) && !image_names.contains(&split_env(&rule)?.0) {
+                services.push(rule);
+            } else {
+                results.push(rule);
+            }
+        }
+
+        results.extend(services);
+
+        Ok(results)
+    }
+
+    // Check that the merged env rules match the values known at generation time, i.e., the env
+    // of the image config and the values of the env of the container overriding them as the
+    // kubelet expands them, so that escaping and merge bugs fail the generation
+    fn check_env(
+        spec: &Spec,
+        image_config: &ImageConfiguration,
+        container: &serde_yaml::Value,
+        enable_service_links: bool,
+    ) -> Result<()> {
+        let mut examples = BTreeMap::new();
+
+        if let Some(config) = image_config.config() {
+            for env in config.env().iter().flatten() {
+                if let Some((name, value)) = env.split_once('=') {
+                    examples.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+
+        // The env of the container overrides the variables of envFrom
+        for env_from in PodYaml::get_env_from(container)? {
+            match env_from {
+                EnvFrom::Value(name, value) => {
+                    examples.insert(name, value);
+                }
+                EnvFrom::Secret(prefix) => examples.retain(|name, _| !name.starts_with(&prefix)),
+            }
+        }
+
+        let (_, expanded) = PodYaml::expand_env(container, enable_service_links)?;
+        let env = container["env"].as_sequence().into_iter().flatten();
+
+        for (env, expanded) in env.zip(expanded) {
+            let name = env["name"].as_str().unwrap_or_default();
+
+            match expanded {
+                Some((value, false)) => examples.insert(name.to_string(), value),
+                // The values from references, or referring to them, are only known at runtime
+                _ => examples.remove(name),
+            };
+        }
+
+        let rules = spec
+            .process()
+            .as_ref()
+            .and_then(|process| process.env().clone())
+            .unwrap_or_default();
+
+        for (name, value) in examples {
+            let example = [&name, "=", &value].concat();
+
+            let mut matched = false;
+            for rule in &rules {
+                if pattern::matches(rule, &example)? {
+                    matched = true;
+                    break;
+                }
+            }
+
+            if !matched {
+                bail!("no env rule matches {} known at generation time", example);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_mount_read_only(spec: &mut Spec, destination: &str) {
+        if let Some(mut mounts) = spec.mounts().clone() {
+            for mount in &mut mounts {
+                if mount.destination() != Path::new(destination) {
+                    continue;
+                }
+
+                if let Some(mut options) = mount.options().clone() {
+                    options.iter_mut().for_each(|option| {
+                        if option == "rw" {
+                            *option = "ro".to_string()
+                        }
+                    });
+                    mount.set_options(Some(options));
+                }
+            }
+
+            spec.set_mounts(Some(mounts));
+        }
+    }
+
+    // The user is based on WithUser and WithUserID, i.e., runAsUser or the USER of the image
+    // Reference: https://github.com/containerd/containerd/blob/release/1.6/pkg/cri/server/container_create_linux.go#L373
+    fn get_user(
+        spec: &mut Spec,
+        user_context: &UserContext,
+        image_config: &ImageConfiguration,
     ) -> Result<()> {
-        let pod_mounts = if let Some(pod_yaml) = pod_yaml {
-            pod_yaml.get_mounts(container)?
+        let image_user = image_config
+            .config()
+            .as_ref()
+            .and_then(|config| config.user().clone())
+            .unwrap_or_default();
+
+        let (name, group) = match image_user.split_once(':') {
+            Some((name, group)) => (name, Some(group)),
+            None => (image_user.as_str(), None),
+        };
+
+        // An empty USER runs as root
+        let image_uid = if name.is_empty() {
+            Some(0)
         } else {
-            Vec::new()
+            name.parse::<u32>().ok()
         };
 
-        // Order based on OCI:
-        // - Volumes from pod yaml
-        // - Mounts inserted by Kubelet
-        // - Image volumes
-        // - Default mounts
-        let image_volumes = get_image_volume_mounts(image_config)?;
+        let uid = user_context.run_as_user.or(image_uid);
+
+        // The kubelet refuses to start containers that would run as root with runAsNonRoot
+        // Reference: https://github.com/kubernetes/kubernetes/blob/release-1.26/pkg/kubelet/kuberuntime/security_context_others.go#L30
+        if user_context.run_as_non_root {
+            match uid {
+                Some(0) => bail!("runAsNonRoot is set but the container would run as root"),
+                None => bail!(
+                    "runAsNonRoot is set but the image has the non-numeric user {}, which cannot be verified to be non-root",
+                    name
+                ),
+                _ => {}
+            }
+        }
 
-        let results = if let Some(kube_mounts) = kube_rules.mounts() {
-            merge_mounts(&pod_mounts, kube_mounts)?
-        } else {
-            pod_mounts
+        let uid = match uid {
+            Some(uid) => uid,
+            None => {
+                warn!(
+                    "user {} of the image is resolved from the image at runtime, the user rule is not generated",
+                    name
+                );
+                return Ok(());
+            }
         };
 
-        let results = merge_mounts(&results, &image_volumes)?;
+        let image_gid = match group {
+            Some(group) => match group.parse::<u32>() {
+                Ok(gid) => Some(gid),
+                Err(_) => {
+                    warn!(
+                        "group {} of the image is resolved from the image at runtime, the gid rule may not match",
+                        group
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let gid = user_context.run_as_group.or(image_gid).unwrap_or(0);
 
-        let results = if let Some(default_mounts) = spec.mounts() {
-            merge_mounts(&results, default_mounts)?
+        let mut process = if let Some(process) = spec.process() {
+            process.clone()
         } else {
-            results
+            empty_process()?
         };
 
-        spec.set_mounts(Some(results));
+        let mut user = process.user().clone();
+        user.set_uid(uid);
+        user.set_gid(gid);
+        process.set_user(user);
+
+        spec.set_process(Some(process));
 
         Ok(())
     }
@@ -0,0 +1,312 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::cluster;
+use crate::kubernetes::{KUBERNETES_MASTER_SERVICE_NAME, KUBERNETES_MASTER_SERVICE_NAMESPACE};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const ROLE_NAME: &str = "cc-policy";
+
+// Resources submitted by --from-dry-run, by API group
+const WORKLOAD_RESOURCES: &[(&str, &str)] = &[
+    ("", "pods"),
+    ("", "replicationcontrollers"),
+    ("apps", "deployments"),
+    ("apps", "replicasets"),
+    ("apps", "statefulsets"),
+    ("apps", "daemonsets"),
+    ("batch", "jobs"),
+    ("batch", "cronjobs"),
+];
+
+// Flags of the generation that access the cluster
+pub struct ClusterAccess {
+    pub services_from_cluster: bool,
+    pub pull_secrets_from_cluster: bool,
+    pub volumes_from_cluster: bool,
+    pub check_webhooks: bool,
+    pub from_dry_run: bool,
+    pub annotate_replica_sets: bool,
+}
+
+struct Permission {
+    verb: &'static str,
+    group: &'static str,
+    resource: &'static str,
+    // Restricts the permission to the object
+    name: Option<&'static str>,
+    // None for the cluster-scoped resources
+    namespace: Option<String>,
+    reason: &'static str,
+}
+
+impl Permission {
+    // <resource>[.<group>][/<name>], as given to kubectl auth can-i
+    fn resource_name(&self) -> String {
+        let mut result = self.resource.to_string();
+        if !self.group.is_empty() {
+            result = format!("{}.{}", result, self.group);
+        }
+        if let Some(name) = self.name {
+            result = format!("{}/{}", result, name);
+        }
+        result
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PolicyRule {
+    api_groups: Vec<String>,
+    resources: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    resource_names: Vec<String>,
+    verbs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Role {
+    api_version: String,
+    kind: String,
+    metadata: Metadata,
+    rules: Vec<PolicyRule>,
+}
+
+// Reference: the kubectl calls of cluster.rs and pod_yaml.rs. Note that secretKeyRef env
+// values are never read, their rules match any value, and the Secrets are only read for the
+// imagePullSecrets.
+fn get_permissions(namespace: &str, access: &ClusterAccess) -> Vec<Permission> {
+    let permission = |verb, group, resource, namespace: Option<&str>, reason| Permission {
+        verb,
+        group,
+        resource,
+        name: None,
+        namespace: namespace.map(String::from),
+        reason,
+    };
+
+    let mut results = vec![permission(
+        "get",
+        "",
+        "configmaps",
+        Some(namespace),
+        "configMapKeyRef and envFrom env values",
+    )];
+
+    if access.services_from_cluster {
+        results.push(permission(
+            "list",
+            "",
+            "services",
+            Some(namespace),
+            "--services-from-cluster",
+        ));
+        if namespace != KUBERNETES_MASTER_SERVICE_NAMESPACE {
+            results.push(Permission {
+                name: Some(KUBERNETES_MASTER_SERVICE_NAME),
+                ..permission(
+                    "get",
+                    "",
+                    "services",
+                    Some(KUBERNETES_MASTER_SERVICE_NAMESPACE),
+                    "--services-from-cluster",
+                )
+            });
+        }
+    }
+
+    if access.pull_secrets_from_cluster {
+        results.push(permission(
+            "get",
+            "",
+            "secrets",
+            Some(namespace),
+            "--pull-secrets-from-cluster",
+        ));
+    }
+
+    if access.volumes_from_cluster {
+        results.push(permission(
+            "get",
+            "",
+            "persistentvolumeclaims",
+            Some(namespace),
+            "--volumes-from-cluster",
+        ));
+        results.push(permission(
+            "get",
+            "",
+            "persistentvolumes",
+            None,
+            "--volumes-from-cluster",
+        ));
+    }
+
+    if access.check_webhooks {
+        results.push(permission(
+            "get",
+            "",
+            "namespaces",
+            None,
+            "--check-webhooks",
+        ));
+        results.push(permission(
+            "list",
+            "admissionregistration.k8s.io",
+            "mutatingwebhookconfigurations",
+            None,
+            "--check-webhooks",
+        ));
+    }
+
+    if access.annotate_replica_sets {
+        for verb in ["list", "patch"] {
+            results.push(permission(
+                verb,
+                "apps",
+                "replicasets",
+                Some(namespace),
+                "--annotate-replicasets",
+            ));
+        }
+    }
+
+    // kubectl apply gets the object, then creates or patches it
+    if access.from_dry_run {
+        for (group, resource) in WORKLOAD_RESOURCES {
+            for verb in ["get", "create", "patch"] {
+                results.push(permission(
+                    verb,
+                    group,
+                    resource,
+                    Some(namespace),
+                    "--from-dry-run",
+                ));
+            }
+        }
+    }
+
+    results
+}
+
+// One rule per resource (and object), with the verbs in order
+fn get_rules(permissions: &[&Permission]) -> Vec<PolicyRule> {
+    type Key<'a> = (&'a str, &'a str, Option<&'a str>);
+    let mut verbs: BTreeMap<Key, Vec<String>> = BTreeMap::new();
+    for permission in permissions {
+        let entry = verbs
+            .entry((permission.group, permission.resource, permission.name))
+            .or_default();
+        if !entry.iter().any(|verb| verb == permission.verb) {
+            entry.push(permission.verb.to_string());
+        }
+    }
+
+    verbs
+        .into_iter()
+        .map(|((group, resource, name), verbs)| PolicyRule {
+            api_groups: vec![group.to_string()],
+            resources: vec![resource.to_string()],
+            resource_names: name.map(String::from).into_iter().collect(),
+            verbs,
+        })
+        .collect()
+}
+
+// Return the Role of each namespace and the ClusterRole granting the permissions
+fn get_roles(permissions: &[Permission]) -> Vec<Role> {
+    let mut by_namespace: BTreeMap<Option<&str>, Vec<&Permission>> = BTreeMap::new();
+    for permission in permissions {
+        by_namespace
+            .entry(permission.namespace.as_deref())
+            .or_default()
+            .push(permission);
+    }
+
+    by_namespace
+        .into_iter()
+        .map(|(namespace, permissions)| Role {
+            api_version: "rbac.authorization.k8s.io/v1".to_string(),
+            kind: if namespace.is_some() {
+                "Role".to_string()
+            } else {
+                "ClusterRole".to_string()
+            },
+            metadata: Metadata {
+                name: ROLE_NAME.to_string(),
+                namespace: namespace.map(String::from),
+            },
+            rules: get_rules(&permissions),
+        })
+        .collect()
+}
+
+// Check that the current context has the permissions needed by the generation with the flags,
+// and print the roles granting them. The namespace defaults to the one of the context.
+pub fn preflight(namespace: Option<&str>, access: &ClusterAccess) -> Result<()> {
+    let namespace = match namespace {
+        Some(namespace) => namespace.to_string(),
+        None => cluster::get_context_namespace()?,
+    };
+
+    let permissions = get_permissions(&namespace, access);
+
+    let mut missing = 0;
+    for permission in &permissions {
+        let scope = match &permission.namespace {
+            Some(namespace) => format!("in namespace {}", namespace),
+            None => "cluster-wide".to_string(),
+        };
+
+        if cluster::can_i(
+            permission.verb,
+            &permission.resource_name(),
+            permission.namespace.as_deref(),
+        )? {
+            // stdout is the roles
+            eprintln!(
+                "ok: {} {} {} ({})",
+                permission.verb,
+                permission.resource_name(),
+                scope,
+                permission.reason
+            );
+        } else {
+            warn!(
+                "missing: {} {} {} ({})",
+                permission.verb,
+                permission.resource_name(),
+                scope,
+                permission.reason
+            );
+            missing += 1;
+        }
+    }
+
+    let documents = get_roles(&permissions)
+        .iter()
+        .map(serde_yaml::to_string)
+        .collect::<Result<Vec<String>, _>>()?;
+    print!("{}", documents.join("---\n"));
+
+    if missing > 0 {
+        bail!(
+            "{} of the {} permissions are missing, grant them with the roles above",
+            missing,
+            permissions.len()
+        );
+    }
+
+    Ok(())
+}
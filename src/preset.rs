@@ -0,0 +1,175 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::config::Config;
+use crate::delivery::Delivery;
+use crate::guest::GO_SHIM;
+use crate::node_pool::NodePool;
+use crate::policy::{Base64Alphabet, Base64Encoding};
+use crate::profile::CONTAINERD_1_6;
+
+use anyhow::{bail, Result};
+use clap::ArgEnum;
+
+// Settings of a platform selected by --preset, the options given on the command line and the
+// node pools of the config taking precedence
+//
+// The policy key is left as is: the io.katacontainers.config.agent.policy annotation of AKS holds
+// a Rego policy, which the JSON policy is not.
+#[derive(ArgEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    // Confidential containers on AKS, i.e., the kata-cc runtime class of the AKS node pools
+    Aks,
+}
+
+impl Preset {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Preset::Aks => "aks",
+        }
+    }
+
+    fn get_node_pool(&self) -> NodePool {
+        match self {
+            Preset::Aks => NodePool {
+                pause_image: Some("mcr.microsoft.com/oss/kubernetes/pause:3.6".to_string()),
+                guest_layout: Some(GO_SHIM.to_string()),
+                runtime_profile: Some(CONTAINERD_1_6.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    // Add the default rules of the containerd profile and the node pool of the preset named
+    // after it, unless the config defines one with that name
+    pub fn apply(&self, config: &mut Config) {
+        config.with_default_rules = true;
+        config
+            .node_pools
+            .entry(self.get_name().to_string())
+            .or_insert_with(|| self.get_node_pool());
+    }
+
+    // Node pool used if neither --node-pool nor the config selects one
+    pub fn get_default_node_pool(&self, config: &Config) -> Option<String> {
+        match config.node_pools.len() {
+            1 => Some(self.get_name().to_string()),
+            _ => None,
+        }
+    }
+
+    // The agent of AKS only reads the policy inline from the annotation, which it decodes with
+    // the padded standard alphabet, unwrapped
+    pub fn check_encoding(&self, encoding: &Base64Encoding, delivery: &Delivery) -> Result<()> {
+        match self {
+            Preset::Aks => {
+                if *delivery != Delivery::Inline {
+                    bail!("--preset {} requires --delivery inline", self.get_name());
+                }
+                if !matches!(encoding.alphabet, Base64Alphabet::Standard)
+                    || encoding.no_pad
+                    || encoding.wrap != 0
+                {
+                    bail!(
+                        "--preset {} requires the padded standard base64 alphabet without wrapping",
+                        self.get_name()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::kubernetes::KUBERNETES_PAUSE_NAME;
+    use crate::pod_yaml::PodYaml;
+    use crate::policy::{CcPolicy, PolicyOptions};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn get_testdata_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/aks")
+    }
+
+    fn get_options() -> PolicyOptions {
+        let mut config = Config::default();
+        Preset::Aks.apply(&mut config);
+
+        PolicyOptions {
+            with_default_rules: config.with_default_rules,
+            node_pool: Preset::Aks.get_default_node_pool(&config),
+            node_pools: config.node_pools,
+            catalog: Some(Catalog::open(&get_testdata_dir().join("catalog")).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_aks_pod() {
+        let content = fs::read_to_string(get_testdata_dir().join("pod.yaml")).unwrap();
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&content).unwrap();
+        let pod_yaml = PodYaml::from(&yaml).unwrap();
+
+        let policy = CcPolicy::from_pod_yaml(&pod_yaml, &get_options()).unwrap();
+        let policy: serde_json::Value = serde_json::from_str(&policy.to_string()).unwrap();
+
+        // The pause image of AKS, with the argv of /pause
+        let sandbox = &policy["containers"][KUBERNETES_PAUSE_NAME];
+        assert_eq!(
+            sandbox["oci_spec"]["process"]["args"],
+            serde_json::json!(["/pause"])
+        );
+
+        let container = &policy["containers"]["nginx"];
+        assert_eq!(
+            container["oci_spec"]["process"]["args"],
+            serde_json::json!(["/docker-entrypoint.sh", "nginx", "-g", "daemon off;"])
+        );
+
+        // The shared files are under the directory of the go-shim guest layout
+        let sources: Vec<&str> = container["oci_spec"]["mounts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|mount| mount["source"].as_str())
+            .collect();
+        assert!(sources
+            .iter()
+            .any(|source| source.starts_with("^/run/kata-containers/shared/containers/")));
+
+        assert_eq!(policy["metadata"]["node_pool"], "aks");
+        assert_eq!(policy["metadata"]["runtime_profile"], CONTAINERD_1_6);
+    }
+
+    #[test]
+    fn test_aks_encoding() {
+        let encoding = Base64Encoding::default();
+        assert!(Preset::Aks
+            .check_encoding(&encoding, &Delivery::Inline)
+            .is_ok());
+        assert!(Preset::Aks
+            .check_encoding(&encoding, &Delivery::Configmap)
+            .is_err());
+
+        let no_pad = Base64Encoding {
+            no_pad: true,
+            ..Default::default()
+        };
+        assert!(Preset::Aks
+            .check_encoding(&no_pad, &Delivery::Inline)
+            .is_err());
+
+        let url_safe = Base64Encoding {
+            alphabet: Base64Alphabet::UrlSafe,
+            ..Default::default()
+        };
+        assert!(Preset::Aks
+            .check_encoding(&url_safe, &Delivery::Inline)
+            .is_err());
+    }
+}
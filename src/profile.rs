@@ -0,0 +1,265 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::config::Config;
+use crate::cri;
+use crate::guest::{GuestLayout, GO_SHIM, PEER_PODS};
+use crate::kubernetes;
+use crate::node_pool;
+use crate::node_pool::NodePool;
+use crate::overlay::{Overlay, OVERLAY_WILDCARD};
+use crate::policy::SandboxStrategy;
+
+use anyhow::{bail, Result};
+use oci_spec::runtime::{Mount, Spec};
+
+// Runtime profiles whose defaults are added by --with_default_rules. The peer pods run
+// containerd 1.6 on the worker node with the pod VM on another host, i.e., the containerd
+// defaults with the peer-pods guest layout and the pause bundle of the pod VM image.
+pub const CONTAINERD_1_6: &str = "containerd-1.6";
+pub const PEER_PODS_PROFILE: &str = PEER_PODS;
+pub const PROFILES: &[&str] = &[CONTAINERD_1_6, PEER_PODS_PROFILE];
+
+fn get_env(spec: &Spec) -> Vec<String> {
+    spec.process()
+        .as_ref()
+        .and_then(|process| process.env().clone())
+        .unwrap_or_default()
+}
+
+fn get_mounts(spec: &Spec) -> Vec<Mount> {
+    spec.mounts().clone().unwrap_or_default()
+}
+
+fn print_env(title: &str, env: &[String]) {
+    println!("{}:", title);
+    for rule in env {
+        println!("  {}", rule);
+    }
+    println!();
+}
+
+fn print_mounts(title: &str, mounts: &[Mount]) {
+    println!("{}:", title);
+    for mount in mounts {
+        println!("  {}", mount.destination().display());
+        println!("    type: {}", mount.typ().as_deref().unwrap_or_default());
+        println!(
+            "    source: {}",
+            mount
+                .source()
+                .as_ref()
+                .map(|source| source.display().to_string())
+                .unwrap_or_default()
+        );
+        println!(
+            "    options: {}",
+            mount.options().clone().unwrap_or_default().join(",")
+        );
+    }
+    println!();
+}
+
+// Rules the profile contributes to every container and to the sandbox
+struct ProfileRules {
+    layout: GuestLayout,
+    sandbox: SandboxStrategy,
+    container_env: Vec<String>,
+    container_mounts: Vec<Mount>,
+    sandbox_env: Vec<String>,
+    sandbox_mounts: Vec<Mount>,
+    kube_env: Vec<String>,
+    kube_mounts: Vec<Mount>,
+}
+
+// Return the rules of the profile, after applying the wildcard rules of the overlay and the
+// node pool, if any
+fn get_profile_rules(
+    name: &str,
+    node_pool: Option<&NodePool>,
+    overlay: Option<&Overlay>,
+) -> Result<ProfileRules> {
+    // The guest layout of the node pool takes precedence over the one of the profile
+    let layout = match node_pool {
+        Some(node_pool) => node_pool.get_guest_layout()?,
+        None if name == PEER_PODS_PROFILE => GuestLayout::from_name(PEER_PODS)?,
+        None => node_pool::get_guest_layout(None)?,
+    };
+    let sandbox = match node_pool {
+        Some(node_pool) => node_pool.get_sandbox_strategy(),
+        None if name == PEER_PODS_PROFILE => SandboxStrategy::PeerPod,
+        None => SandboxStrategy::Pause,
+    };
+
+    let container_rules = cri::get_rules(false, false, false, &layout)?;
+    let mut container_env = get_env(&container_rules);
+    let mut container_mounts = get_mounts(&container_rules);
+    let sandbox_rules = cri::get_rules(true, false, false, &layout)?;
+
+    let kube_rules = kubernetes::get_rules(false, true, None, &layout)?;
+
+    if let Some(rules) = overlay.and_then(|overlay| overlay.containers.get(OVERLAY_WILDCARD)) {
+        cri::merge_process_env(&mut container_env, &rules.env)?;
+        container_mounts = cri::merge_mounts(&rules.mounts, &container_mounts)?;
+    }
+
+    Ok(ProfileRules {
+        layout,
+        sandbox,
+        container_env,
+        container_mounts,
+        sandbox_env: get_env(&sandbox_rules),
+        sandbox_mounts: get_mounts(&sandbox_rules),
+        kube_env: get_env(&kube_rules),
+        kube_mounts: get_mounts(&kube_rules),
+    })
+}
+
+// Print the rules the profile contributes to every container and to the sandbox, after
+// applying the wildcard rules of the overlay and the node pool of the config
+pub fn show(name: &str, config: &Config, node_pool: Option<&str>) -> Result<()> {
+    if !PROFILES.contains(&name) {
+        bail!(
+            "unknown profile {}, the supported profiles are: {}",
+            name,
+            PROFILES.join(", ")
+        );
+    }
+
+    let node_pool = node_pool::select(&config.node_pools, node_pool, None)?;
+
+    let overlay = match &config.overlay {
+        Some(path) => Some(Overlay::from_file(path)?),
+        None => None,
+    };
+
+    let rules = get_profile_rules(
+        name,
+        node_pool.map(|(_, node_pool)| node_pool),
+        overlay.as_ref(),
+    )?;
+
+    let mut pause_image = kubernetes::get_pause_image_ref();
+
+    if let Some((_, node_pool)) = node_pool {
+        if let Some(image) = &node_pool.pause_image {
+            pause_image = image.clone();
+        }
+    }
+
+    println!("Profile: {}", name);
+    if let Some((name, node_pool)) = node_pool {
+        println!("Node pool: {}", name);
+        if let Some(runtime_profile) = &node_pool.runtime_profile {
+            println!("Runtime profile: {}", runtime_profile);
+        }
+        println!(
+            "Guest layout: {}",
+            node_pool.guest_layout.as_deref().unwrap_or(GO_SHIM)
+        );
+    }
+    match rules.sandbox {
+        SandboxStrategy::Pause => println!("Pause image: {}", pause_image),
+        SandboxStrategy::PeerPod => println!("Sandbox: pause bundle of the pod VM image"),
+        SandboxStrategy::None => println!("Sandbox: none"),
+    }
+    if rules.layout.copies_volumes {
+        println!("Volumes: copied into the guest when the containers are created");
+    }
+    println!();
+
+    print_env("Container env rules", &rules.container_env);
+    print_mounts("Container mounts", &rules.container_mounts);
+    print_env("Sandbox env rules", &rules.sandbox_env);
+    print_mounts("Sandbox mounts", &rules.sandbox_mounts);
+    // Replaced by the exact rules of the services with --services-from-cluster
+    print_env("Kubernetes env rules (services)", &rules.kube_env);
+    print_mounts("Kubernetes mounts", &rules.kube_mounts);
+
+    println!("Templates:");
+    println!(
+        "  image volume source: {}",
+        rules.layout.get_shared_source("<volume name>")
+    );
+    println!(
+        "  env from the image config: ^<NAME>=<value>$, overriding the rules with the same name"
+    );
+    println!("  ^TERM=xterm$: added with tty");
+    println!(
+        "  sandbox env from the pause image: ^<NAME>=<value>$, \
+         overriding the rules with the same name"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cri::{DEFAULT_HOSTNAME_ENV, DEFAULT_PATH_ENV};
+
+    #[test]
+    fn test_default_path_env() {
+        for name in PROFILES {
+            let rules = get_profile_rules(name, None, None).unwrap();
+
+            // The containers get the HOSTNAME of the pod, the sandbox only the default PATH,
+            // which the env of the images then overrides
+            assert_eq!(
+                rules.container_env,
+                [DEFAULT_HOSTNAME_ENV, DEFAULT_PATH_ENV],
+                "{}",
+                name
+            );
+            assert_eq!(rules.sandbox_env, [DEFAULT_PATH_ENV], "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_default_path_env_overlay() {
+        let overlay = Overlay::from_content(
+            "containers:\n  \"*\":\n    env:\n    - PATH=/opt/bin\n    - ^ORG=[a-z]+$\n",
+            "overlay.yaml",
+        )
+        .unwrap();
+
+        for name in PROFILES {
+            let rules = get_profile_rules(name, None, Some(&overlay)).unwrap();
+
+            // Replaced at its position, the sandbox being left as is
+            assert_eq!(
+                rules.container_env,
+                [DEFAULT_HOSTNAME_ENV, "PATH=/opt/bin", "^ORG=[a-z]+$"],
+                "{}",
+                name
+            );
+            assert_eq!(rules.sandbox_env, [DEFAULT_PATH_ENV], "{}", name);
+        }
+
+        // Unset by an entry without '='
+        let overlay = Overlay::from_content(
+            "containers:\n  \"*\":\n    env:\n    - PATH\n",
+            "overlay.yaml",
+        )
+        .unwrap();
+        let rules = get_profile_rules(CONTAINERD_1_6, None, Some(&overlay)).unwrap();
+        assert_eq!(rules.container_env, [DEFAULT_HOSTNAME_ENV]);
+    }
+
+    #[test]
+    fn test_profile_layouts() {
+        let rules = get_profile_rules(CONTAINERD_1_6, None, None).unwrap();
+        assert!(matches!(rules.sandbox, SandboxStrategy::Pause));
+        assert!(!rules.layout.copies_volumes);
+
+        let rules = get_profile_rules(PEER_PODS_PROFILE, None, None).unwrap();
+        assert!(matches!(rules.sandbox, SandboxStrategy::PeerPod));
+        assert!(rules.layout.copies_volumes);
+
+        // The node pool takes precedence over the profile
+        let node_pool = NodePool::default();
+        let rules = get_profile_rules(PEER_PODS_PROFILE, Some(&node_pool), None).unwrap();
+        assert!(matches!(rules.sandbox, SandboxStrategy::Pause));
+        assert!(!rules.layout.copies_volumes);
+    }
+}
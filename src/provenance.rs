@@ -0,0 +1,220 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::image;
+use crate::subprocess;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::time::SystemTime;
+
+// in-toto statement with a SLSA provenance predicate
+// Reference: https://github.com/in-toto/attestation/blob/main/spec/v1/statement.md
+// https://slsa.dev/spec/v1.0/provenance
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://slsa.dev/provenance/v1";
+const BUILD_TYPE: &str = "https://github.com/mingweishih/cc-policy/generate/v1";
+const BUILDER_ID: &str = "https://github.com/mingweishih/cc-policy";
+// Reference: https://github.com/secure-systems-lab/dsse/blob/master/envelope.md
+const PAYLOAD_TYPE: &str = "application/vnd.in-toto+json";
+
+const COSIGN: &str = "cosign";
+
+#[derive(Serialize)]
+struct ResourceDescriptor {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+    // Algorithm to hex digest
+    digest: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildDefinition {
+    build_type: String,
+    external_parameters: BTreeMap<String, String>,
+    resolved_dependencies: Vec<ResourceDescriptor>,
+}
+
+#[derive(Serialize)]
+struct Builder {
+    id: String,
+    version: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuildMetadata {
+    started_on: String,
+}
+
+#[derive(Serialize)]
+struct RunDetails {
+    builder: Builder,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<BuildMetadata>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Provenance {
+    build_definition: BuildDefinition,
+    run_details: RunDetails,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Statement {
+    #[serde(rename = "_type")]
+    r#type: String,
+    subject: Vec<ResourceDescriptor>,
+    predicate_type: String,
+    predicate: Provenance,
+}
+
+#[derive(Serialize)]
+struct Signature {
+    sig: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    payload_type: String,
+    payload: String,
+    signatures: Vec<Signature>,
+}
+
+// Digests of the form <algorithm>:<hex>, e.g., the manifest digests of the images
+fn parse_digest(digest: &str) -> BTreeMap<String, String> {
+    match digest.split_once(':') {
+        Some((algorithm, hex)) => BTreeMap::from([(algorithm.to_string(), hex.to_string())]),
+        None => BTreeMap::new(),
+    }
+}
+
+impl Statement {
+    // The subject is the generated policy, and the resolved dependencies are the input and the
+    // images (by manifest digest) the policy was generated from. The start time is omitted
+    // with reproducible.
+    pub fn new(
+        policy_name: &str,
+        policy: &str,
+        external_parameters: BTreeMap<String, String>,
+        input: Option<(&str, &[u8])>,
+        images: &BTreeMap<String, String>,
+        reproducible: bool,
+    ) -> Statement {
+        let mut resolved_dependencies = Vec::new();
+
+        if let Some((name, content)) = input {
+            resolved_dependencies.push(ResourceDescriptor {
+                name: Some(name.to_string()),
+                uri: None,
+                digest: parse_digest(&image::get_sha256(content)),
+            });
+        }
+
+        for (image_ref, digest) in images {
+            resolved_dependencies.push(ResourceDescriptor {
+                name: None,
+                uri: Some(image_ref.clone()),
+                digest: parse_digest(digest),
+            });
+        }
+
+        let metadata = if reproducible {
+            None
+        } else {
+            Some(BuildMetadata {
+                started_on: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            })
+        };
+
+        Statement {
+            r#type: STATEMENT_TYPE.to_string(),
+            subject: vec![ResourceDescriptor {
+                name: Some(policy_name.to_string()),
+                uri: None,
+                digest: parse_digest(&image::get_sha256(policy.as_bytes())),
+            }],
+            predicate_type: PREDICATE_TYPE.to_string(),
+            predicate: Provenance {
+                build_definition: BuildDefinition {
+                    build_type: BUILD_TYPE.to_string(),
+                    external_parameters,
+                    resolved_dependencies,
+                },
+                run_details: RunDetails {
+                    builder: Builder {
+                        id: BUILDER_ID.to_string(),
+                        version: BTreeMap::from([(
+                            "cc-policy".to_string(),
+                            env!("CARGO_PKG_VERSION").to_string(),
+                        )]),
+                    },
+                    metadata,
+                },
+            },
+        }
+    }
+
+    // Return the statement, or a DSSE envelope of the statement signed by cosign with the key
+    pub fn to_json(&self, key: Option<&str>) -> Result<String> {
+        let payload = serde_json::to_string(self)?;
+
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(serde_json::to_string_pretty(self)? + "\n"),
+        };
+
+        let envelope = Envelope {
+            payload_type: PAYLOAD_TYPE.to_string(),
+            payload: base64::encode(&payload),
+            signatures: vec![Signature {
+                sig: sign(&pae(PAYLOAD_TYPE, &payload), key)?,
+            }],
+        };
+
+        Ok(serde_json::to_string_pretty(&envelope)? + "\n")
+    }
+}
+
+// Pre-authentication encoding signed by DSSE
+fn pae(payload_type: &str, payload: &str) -> String {
+    format!(
+        "DSSEv1 {} {} {} {}",
+        payload_type.len(),
+        payload_type,
+        payload.len(),
+        payload
+    )
+}
+
+// Return the base64 signature of the data
+fn sign(data: &str, key: &str) -> Result<String> {
+    let path = env::temp_dir().join(format!("cc-policy-provenance-{}.pae", std::process::id()));
+
+    fs::write(&path, data).context(loc!())?;
+
+    let args = [
+        OsStr::new("sign-blob"),
+        OsStr::new("--yes"),
+        OsStr::new("--tlog-upload=false"),
+        OsStr::new("--key"),
+        OsStr::new(key),
+        path.as_os_str(),
+    ];
+
+    let result = subprocess::run_text(COSIGN, &args);
+
+    fs::remove_file(&path).context(loc!())?;
+
+    Ok(result?.trim().to_string())
+}
@@ -0,0 +1,183 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::recording;
+use crate::recording::Mode;
+use crate::subprocess;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CURL: &str = "curl";
+
+// Retries of a request rejected by the rate limit of the registry, set by --registry-retries.
+// The delay doubles from one second for each retry.
+const DEFAULT_RETRIES: u32 = 3;
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+static RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_RETRIES);
+
+// Docker Hub counts the GET requests of the manifests, which skopeo sends for every inspection,
+// but not the HEAD requests, whose headers have the digest of the manifest, and the quota for
+// the ones of ratelimitpreview/test
+// Reference: https://docs.docker.com/docker-hub/download-rate-limit/
+const DOCKER_HUB_TOKEN_URL: &str =
+    "https://auth.docker.io/token?service=registry.docker.io&scope=repository:";
+const DOCKER_HUB_REGISTRY_URL: &str = "https://registry-1.docker.io/v2/";
+const DOCKER_HUB_QUOTA_REPOSITORY: &str = "ratelimitpreview/test";
+
+// The manifest types accepted by skopeo inspect --raw, so that the digest is the one of the
+// manifest list (index) of the multi-platform images as well
+// Reference: https://distribution.github.io/distribution/spec/api/#digest-header
+const MANIFEST_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+// Anonymous tokens by repository, reused until they expire
+static DOCKER_HUB_TOKENS: Mutex<BTreeMap<String, (String, Instant)>> = Mutex::new(BTreeMap::new());
+
+// Digests of the manifests by repository and tag, None if the HEAD request failed
+static DOCKER_HUB_DIGESTS: Mutex<BTreeMap<(String, String), Option<String>>> =
+    Mutex::new(BTreeMap::new());
+
+#[derive(Deserialize)]
+struct Token {
+    token: String,
+    // Seconds, 60 if not given
+    #[serde(default)]
+    expires_in: Option<u64>,
+}
+
+pub fn set_retries(retries: u32) {
+    RETRIES.store(retries, Ordering::Relaxed);
+}
+
+pub fn get_retries() -> u32 {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+// Whether the stderr of skopeo reports a 429 of the registry, e.g., toomanyrequests of Docker Hub
+pub fn is_rate_limited(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr).to_lowercase();
+
+    stderr.contains("toomanyrequests") || stderr.contains("429 too many requests")
+}
+
+// Wait before the given retry, from 0, except when the outputs are replayed
+pub fn backoff(retry: u32) {
+    if recording::get_mode() == Some(Mode::Replay) {
+        return;
+    }
+
+    let delay = INITIAL_DELAY * 2u32.saturating_pow(retry);
+
+    warn!(
+        "the registry rate-limits the requests, retrying in {}s",
+        delay.as_secs()
+    );
+
+    thread::sleep(delay);
+}
+
+fn get_docker_hub_token(repository: &str) -> Option<String> {
+    let mut tokens = DOCKER_HUB_TOKENS.lock().unwrap();
+
+    if let Some((token, expiry)) = tokens.get(repository) {
+        if Instant::now() < *expiry {
+            return Some(token.clone());
+        }
+    }
+
+    let url = [DOCKER_HUB_TOKEN_URL, repository, ":pull"].concat();
+    let body = subprocess::run_text(CURL, &["--silent", "--fail", &url]).ok()?;
+    let token: Token = serde_json::from_str(&body).ok()?;
+
+    let expiry = Instant::now() + Duration::from_secs(token.expires_in.unwrap_or(60));
+    tokens.insert(repository.to_string(), (token.token.clone(), expiry));
+
+    Some(token.token)
+}
+
+// Return the headers of the HEAD request of the manifest, None if it failed
+fn head_docker_hub_manifest(repository: &str, reference: &str) -> Option<String> {
+    let token = get_docker_hub_token(repository)?;
+    let mut headers = ["Authorization: Bearer ", &token, "\n"].concat();
+    for manifest_type in MANIFEST_TYPES {
+        headers.push_str(&["Accept: ", manifest_type, "\n"].concat());
+    }
+
+    let url = [
+        DOCKER_HUB_REGISTRY_URL,
+        repository,
+        "/manifests/",
+        reference,
+    ]
+    .concat();
+
+    // The token is read from stdin rather than shown in the arguments of curl
+    subprocess::output_with_input(
+        CURL,
+        &["--silent", "--fail", "--head", "--header", "@-", &url],
+        Some(headers.as_bytes()),
+    )
+    .and_then(|output| output.check_text())
+    .ok()
+}
+
+fn get_header<'a>(headers: &'a str, name: &str) -> Option<&'a str> {
+    headers.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+// Return the digest of the manifest of the public image of Docker Hub by tag, e.g., of
+// library/nginx and latest, without counting a pull. Each tag is requested once per run.
+pub fn get_docker_hub_digest(repository: &str, tag: &str) -> Option<String> {
+    let key = (repository.to_string(), tag.to_string());
+    if let Some(digest) = DOCKER_HUB_DIGESTS.lock().unwrap().get(&key) {
+        return digest.clone();
+    }
+
+    let digest = head_docker_hub_manifest(repository, tag)
+        .as_deref()
+        .and_then(|headers| get_header(headers, "docker-content-digest"))
+        .filter(|digest| digest.starts_with("sha256:"))
+        .map(String::from);
+
+    DOCKER_HUB_DIGESTS
+        .lock()
+        .unwrap()
+        .insert(key, digest.clone());
+
+    digest
+}
+
+// Return the quota of the anonymous pulls from the IP address, e.g., 0/100 per 21600s, None if
+// it is not known. The pulls of the logged-in users have another quota.
+pub fn get_docker_hub_quota() -> Option<String> {
+    let headers = head_docker_hub_manifest(DOCKER_HUB_QUOTA_REPOSITORY, "latest")?;
+
+    // e.g., ratelimit-remaining: 76;w=21600
+    let limit = get_header(&headers, "ratelimit-limit")?;
+    let (limit, window) = limit.split_once(";w=").unwrap_or((limit, ""));
+    let remaining = get_header(&headers, "ratelimit-remaining")?;
+    let remaining = remaining.split(';').next().unwrap_or_default();
+
+    if window.is_empty() {
+        Some(format!("{}/{}", remaining, limit))
+    } else {
+        Some(format!("{}/{} per {}s", remaining, limit, window))
+    }
+}
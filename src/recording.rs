@@ -0,0 +1,131 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::image;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Every external interaction (kubectl, skopeo, oras, cosign, az, and the exec plugins) is run
+// through the subprocess module, whose results are recorded with --record <dir> and read back
+// instead of running the tools with --replay <dir>, e.g., to reproduce a reported generation
+// bug without access to the cluster or the registries
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+static RECORDING: Mutex<Option<(Mode, PathBuf)>> = Mutex::new(None);
+
+// One file per command, named by the digest of the command
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    program: String,
+    args: Vec<String>,
+    // None if killed by a signal
+    code: Option<i32>,
+    // base64, the outputs are not necessarily UTF-8
+    stdout: String,
+    stderr: String,
+}
+
+pub fn set(mode: Mode, dir: &Path) -> Result<()> {
+    match mode {
+        Mode::Record => fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create the recording {}", dir.display()))?,
+        Mode::Replay => {
+            if !dir.is_dir() {
+                bail!("the recording {} is not a directory", dir.display());
+            }
+        }
+    }
+
+    *RECORDING.lock().unwrap() = Some((mode, dir.to_path_buf()));
+
+    Ok(())
+}
+
+pub fn get_mode() -> Option<Mode> {
+    RECORDING.lock().unwrap().as_ref().map(|(mode, _)| *mode)
+}
+
+// The temporary files passed to the tools (e.g., the input of a plugin, or the manifest of a
+// dry-run) are named by the process id, so they are identified by their content instead
+fn get_arg(arg: &OsStr) -> String {
+    let path = Path::new(arg);
+    if path.starts_with(env::temp_dir()) {
+        if let Ok(content) = fs::read(path) {
+            return format!("<temporary file {}>", image::get_sha256(&content));
+        }
+    }
+
+    arg.to_string_lossy().to_string()
+}
+
+fn get_path<S: AsRef<OsStr>>(program: &OsStr, args: &[S]) -> Result<(PathBuf, Vec<String>)> {
+    let dir = match RECORDING.lock().unwrap().as_ref() {
+        Some((_, dir)) => dir.clone(),
+        None => bail!("{}: no recording", loc!()),
+    };
+
+    let args: Vec<String> = args.iter().map(|arg| get_arg(arg.as_ref())).collect();
+    let key = serde_json::to_string(&(program.to_string_lossy(), &args))?;
+    let digest = image::get_sha256(key.as_bytes()).replace("sha256:", "");
+
+    Ok((dir.join(digest + ".json"), args))
+}
+
+// Write the result of the command, a command run several times keeps its last result
+pub fn record<S: AsRef<OsStr>>(
+    program: &OsStr,
+    args: &[S],
+    code: Option<i32>,
+    stdout: &[u8],
+    stderr: &[u8],
+) -> Result<()> {
+    let (path, args) = get_path(program, args)?;
+
+    let interaction = Interaction {
+        program: program.to_string_lossy().to_string(),
+        args,
+        code,
+        stdout: base64::encode(stdout),
+        stderr: base64::encode(stderr),
+    };
+
+    fs::write(&path, serde_json::to_string_pretty(&interaction)?)
+        .with_context(|| format!("failed to record {}", path.display()))
+}
+
+// Return the recorded exit code, stdout, and stderr of the command
+pub fn replay<S: AsRef<OsStr>>(
+    program: &OsStr,
+    args: &[S],
+    command: &str,
+) -> Result<(Option<i32>, Vec<u8>, Vec<u8>)> {
+    let (path, _) = get_path(program, args)?;
+
+    if !path.exists() {
+        bail!(
+            "{} is not in the recording ({} is missing), it was recorded with other inputs or options",
+            command,
+            path.display()
+        );
+    }
+
+    let content = fs::read_to_string(&path).context(loc!())?;
+    let interaction: Interaction = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    Ok((
+        interaction.code,
+        base64::decode(&interaction.stdout).context(loc!())?,
+        base64::decode(&interaction.stderr).context(loc!())?,
+    ))
+}
@@ -0,0 +1,173 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::image;
+use crate::policy::CcPolicy;
+use crate::subprocess;
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::fs;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const ORAS: &str = "oras";
+
+// Artifact type of the policy pushed with the referrers API
+// Reference: https://github.com/opencontainers/image-spec/blob/main/manifest.md#guidelines-for-artifact-usage
+pub const POLICY_ARTIFACT_TYPE: &str = "application/vnd.cc-policy.policy.v1+json";
+
+fn oras(args: &[&str]) -> Result<String> {
+    subprocess::run_text(ORAS, args)
+}
+
+// Attach the policy to the subject image as an OCI artifact, which registries supporting
+// the referrers API list under the digest of the image
+pub fn push_policy(policy: &Path, subject: &str) -> Result<()> {
+    let content = read_to_string(policy).context(loc!())?;
+
+    // Only push files that are valid policies
+    let _: CcPolicy = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse policy {}", policy.display()))?;
+
+    let subject = image::get_image_reference(subject);
+
+    let policy_str = policy.to_string_lossy().to_string();
+    let file = [policy_str.as_str(), ":", POLICY_ARTIFACT_TYPE].concat();
+
+    let output = oras(&[
+        "attach",
+        "--artifact-type",
+        POLICY_ARTIFACT_TYPE,
+        subprocess::operand(&subject)?,
+        subprocess::operand(&file)?,
+    ])?;
+
+    print!("{}", output);
+
+    Ok(())
+}
+
+// Unique files given that the documents are processed in parallel
+static PUSH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Push the policy as a standalone artifact and return the digest of its manifest
+pub fn push_artifact(reference: &str, policy: &str) -> Result<String> {
+    let path = env::temp_dir().join(format!(
+        "cc-policy-push-{}-{}.json",
+        std::process::id(),
+        PUSH_COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    fs::write(&path, policy).context(loc!())?;
+
+    let path_str = path.to_string_lossy().to_string();
+    let file = [path_str.as_str(), ":", POLICY_ARTIFACT_TYPE].concat();
+
+    let result = oras(&[
+        "push",
+        "--artifact-type",
+        POLICY_ARTIFACT_TYPE,
+        "--disable-path-validation",
+        subprocess::operand(reference)?,
+        file.as_str(),
+    ]);
+
+    fs::remove_file(&path).context(loc!())?;
+
+    // oras push prints the digest of the manifest, e.g., Digest: sha256:...
+    result?
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Digest:"))
+        .map(|digest| digest.trim().to_string())
+        .ok_or_else(|| {
+            anyhow!(
+                "{}: oras push did not print the digest of {}",
+                loc!(),
+                reference
+            )
+        })
+}
+
+// Strip the tag or digest of the reference
+fn get_repository(reference: &str) -> &str {
+    if let Some(index) = reference.find('@') {
+        return &reference[..index];
+    }
+
+    match (reference.rfind(':'), reference.rfind('/')) {
+        (Some(colon), Some(slash)) if colon > slash => &reference[..colon],
+        (Some(colon), None) => &reference[..colon],
+        _ => reference,
+    }
+}
+
+// Return the digests of the policy artifacts referring to the image. Both the output of
+// oras discover -o json (manifests) and --format json (referrers) are accepted.
+fn discover(reference: &str) -> Result<Vec<String>> {
+    let output = oras(&[
+        "discover",
+        "--artifact-type",
+        POLICY_ARTIFACT_TYPE,
+        "-o",
+        "json",
+        subprocess::operand(reference)?,
+    ])?;
+
+    let value: serde_json::Value = serde_json::from_str(&output).context(loc!())?;
+
+    let referrers = value["manifests"]
+        .as_array()
+        .or_else(|| value["referrers"].as_array())
+        .cloned()
+        .unwrap_or_default();
+
+    Ok(referrers
+        .iter()
+        .filter(|r| r["artifactType"] == POLICY_ARTIFACT_TYPE)
+        .filter_map(|r| r["digest"].as_str())
+        .map(String::from)
+        .collect())
+}
+
+// Fetch the policy attached to the image by the push command
+pub fn pull_policy(image_ref: &str) -> Result<CcPolicy> {
+    let reference = image::get_image_reference(image_ref);
+    let repository = get_repository(&reference);
+
+    let digests = discover(&reference)?;
+
+    let digest = match digests.as_slice() {
+        [] => bail!("{}: image {} has no attached policy", loc!(), reference),
+        [digest] => digest,
+        [digest, ..] => {
+            warn!(
+                "image {} has {} attached policies, using {}",
+                reference,
+                digests.len(),
+                digest
+            );
+            digest
+        }
+    };
+
+    let artifact = [repository, "@", digest.as_str()].concat();
+    let manifest = oras(&["manifest", "fetch", artifact.as_str()])?;
+    let manifest: serde_json::Value = serde_json::from_str(&manifest).context(loc!())?;
+
+    let layer = manifest["layers"][0]["digest"].as_str().ok_or_else(|| {
+        anyhow!(
+            "{}: policy artifact {} of {} has no layer",
+            loc!(),
+            digest,
+            reference
+        )
+    })?;
+
+    let blob = [repository, "@", layer].concat();
+    let policy = oras(&["blob", "fetch", "--output", "-", blob.as_str()])?;
+
+    serde_json::from_str(&policy)
+        .with_context(|| format!("failed to parse the policy attached to {}", reference))
+}
@@ -0,0 +1,145 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use clap::ArgEnum;
+use serde::Serialize;
+use similar::TextDiff;
+use std::env;
+use std::io::{stdout, IsTerminal};
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+const CYAN: &str = "36";
+
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+// Differences of the rules of a container between two policies
+#[derive(Serialize)]
+pub struct ContainerSummary {
+    pub name: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_added: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env_removed: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounts_added: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounts_removed: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounts_changed: Vec<String>,
+    pub args_changed: bool,
+}
+
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    // Colors are only used when writing to a terminal and NO_COLOR is not set
+    pub fn new(no_color: bool) -> Renderer {
+        let color = !no_color && env::var_os("NO_COLOR").is_none() && stdout().is_terminal();
+
+        Renderer { color }
+    }
+
+    pub fn paint(&self, text: &str, color: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", color, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    pub fn unified_diff(&self, old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+        let diff = TextDiff::from_lines(old, new)
+            .unified_diff()
+            .header(old_name, new_name)
+            .to_string();
+
+        diff.lines()
+            .map(|line| {
+                if line.starts_with("+++") || line.starts_with("---") {
+                    self.paint(line, YELLOW)
+                } else if line.starts_with('+') {
+                    self.paint(line, GREEN)
+                } else if line.starts_with('-') {
+                    self.paint(line, RED)
+                } else if line.starts_with("@@") {
+                    self.paint(line, CYAN)
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn render_list(&self, lines: &mut Vec<String>, title: &str, items: &[String], prefix: &str) {
+        if items.is_empty() {
+            return;
+        }
+
+        let color = if prefix == "+" { GREEN } else { RED };
+
+        lines.push(format!("  {}:", title));
+        for item in items {
+            lines.push(self.paint(&format!("    {} {}", prefix, item), color));
+        }
+    }
+
+    pub fn status(&self, status: &Status) -> String {
+        let (status, color) = match status {
+            Status::Added => ("added", GREEN),
+            Status::Removed => ("removed", RED),
+            Status::Changed => ("changed", YELLOW),
+            Status::Unchanged => ("unchanged", CYAN),
+        };
+
+        self.paint(status, color)
+    }
+
+    pub fn container_summaries(&self, summaries: &[ContainerSummary]) -> String {
+        let mut lines = Vec::new();
+
+        for summary in summaries {
+            lines.push(format!(
+                "{}: {}",
+                summary.name,
+                self.status(&summary.status)
+            ));
+
+            self.render_list(&mut lines, "env", &summary.env_added, "+");
+            self.render_list(&mut lines, "env", &summary.env_removed, "-");
+            self.render_list(&mut lines, "mounts", &summary.mounts_added, "+");
+            self.render_list(&mut lines, "mounts", &summary.mounts_removed, "-");
+
+            if !summary.mounts_changed.is_empty() {
+                lines.push("  mounts changed:".to_string());
+                for destination in &summary.mounts_changed {
+                    lines.push(self.paint(&format!("    ~ {}", destination), YELLOW));
+                }
+            }
+
+            if summary.args_changed {
+                lines.push(self.paint("  args changed", YELLOW));
+            }
+        }
+
+        lines.join("\n")
+    }
+}
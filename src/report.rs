@@ -0,0 +1,137 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::cri;
+use crate::pod_yaml::UserContext;
+
+use anyhow::Result;
+use oci_spec::image::ImageConfiguration;
+use oci_spec::runtime::Spec;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+// Where a value of the policy comes from, following the layering of containerd, i.e., the
+// manifest takes precedence over the image config, which takes precedence over the defaults
+// of the runtime profile and the kubelet
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Manifest,
+    Image,
+    Default,
+}
+
+#[derive(Serialize)]
+pub struct Assumption {
+    pub field: String,
+    pub value: String,
+    pub source: Source,
+}
+
+// Report of the generation written by --report
+#[derive(Default, Serialize)]
+pub struct Report {
+    // The cwd, the user, and the env of each container (<kind>/<name>/<container>) by source.
+    // Setting the ones from the image config or the defaults in the manifest pins them.
+    pub defaulting_delta: BTreeMap<String, Vec<Assumption>>,
+}
+
+fn get_image_env_names(image_config: &ImageConfiguration) -> HashSet<String> {
+    image_config
+        .config()
+        .as_ref()
+        .and_then(|config| config.env().clone())
+        .unwrap_or_default()
+        .iter()
+        .map(|env| env.split_once('=').map_or(env.as_str(), |(name, _)| name))
+        .map(String::from)
+        .collect()
+}
+
+pub fn get_defaulting_delta(
+    spec: &Spec,
+    container: &serde_yaml::Value,
+    user_context: &UserContext,
+    image_config: &ImageConfiguration,
+) -> Result<Vec<Assumption>> {
+    let mut results = Vec::new();
+
+    let process = match spec.process() {
+        Some(process) => process,
+        None => return Ok(results),
+    };
+
+    let config = image_config.config().as_ref();
+    let image_working_dir = config
+        .and_then(|config| config.working_dir().clone())
+        .unwrap_or_default();
+    let image_user = config
+        .and_then(|config| config.user().clone())
+        .unwrap_or_default();
+
+    let image_or_default = |from_image: bool| {
+        if from_image {
+            Source::Image
+        } else {
+            Source::Default
+        }
+    };
+
+    let cwd_source = if container["workingDir"]
+        .as_str()
+        .is_some_and(|working_dir| !working_dir.is_empty())
+    {
+        Source::Manifest
+    } else {
+        image_or_default(!image_working_dir.is_empty())
+    };
+
+    results.push(Assumption {
+        field: "cwd".to_string(),
+        value: process.cwd().display().to_string(),
+        source: cwd_source,
+    });
+
+    // An image USER without a group gives the primary group of the user
+    let user = process.user();
+    for (field, value, explicit) in [
+        ("user.uid", user.uid(), user_context.run_as_user.is_some()),
+        ("user.gid", user.gid(), user_context.run_as_group.is_some()),
+    ] {
+        results.push(Assumption {
+            field: field.to_string(),
+            value: value.to_string(),
+            source: if explicit {
+                Source::Manifest
+            } else {
+                image_or_default(!image_user.is_empty())
+            },
+        });
+    }
+
+    let manifest_env_names: HashSet<&str> = container["env"]
+        .as_sequence()
+        .into_iter()
+        .flatten()
+        .filter_map(|env| env["name"].as_str())
+        .collect();
+    let image_env_names = get_image_env_names(image_config);
+
+    for rule in process.env().iter().flatten() {
+        let (name, _) = cri::split_env(rule)?;
+
+        let source = if manifest_env_names.contains(name.as_str()) {
+            Source::Manifest
+        } else {
+            image_or_default(image_env_names.contains(&name))
+        };
+
+        results.push(Assumption {
+            field: ["env.", &name].concat(),
+            value: rule.to_string(),
+            source,
+        });
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,187 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::pod_yaml;
+use crate::render::Renderer;
+use crate::span::Spans;
+
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::Value;
+use std::fmt;
+
+// Scalar as resolved by a YAML parser
+#[derive(PartialEq, Eq)]
+enum Scalar {
+    Null,
+    Bool(bool),
+    Int(i128),
+    // Bits of the f64, so that NaN equals NaN
+    Float(u64),
+    String,
+}
+
+impl fmt::Display for Scalar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scalar::Null => write!(f, "null"),
+            Scalar::Bool(value) => write!(f, "the bool {}", value),
+            Scalar::Int(value) => write!(f, "the integer {}", value),
+            Scalar::Float(bits) => write!(f, "the float {}", f64::from_bits(*bits)),
+            Scalar::String => write!(f, "a string"),
+        }
+    }
+}
+
+fn parse_int(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.strip_prefix('+').unwrap_or(text)),
+    };
+    let digits = digits.replace('_', "");
+
+    let value = if let Some(binary) = digits.strip_prefix("0b") {
+        i128::from_str_radix(binary, 2).ok()?
+    } else if let Some(hex) = digits.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i128::from_str_radix(&digits[1..], 8).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+fn parse_float(text: &str) -> Option<f64> {
+    match text {
+        ".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" => return Some(f64::INFINITY),
+        "-.inf" | "-.Inf" | "-.INF" => return Some(f64::NEG_INFINITY),
+        ".nan" | ".NaN" | ".NAN" => return Some(f64::NAN),
+        _ => {}
+    }
+
+    // The parser of Rust also accepts inf and nan
+    let text = text.replace('_', "");
+    if !text.chars().any(|c| c.is_ascii_digit())
+        || !text
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+    {
+        return None;
+    }
+
+    text.parse().ok()
+}
+
+// Resolve the source text of a scalar like go-yaml v2 (YAML 1.1), which kubectl and the API
+// server use through sigs.k8s.io/yaml, e.g., yes and on are bools and 0755 is octal
+// Reference: https://github.com/go-yaml/yaml/blob/v2/resolve.go
+fn resolve_yaml11(text: &str) -> Scalar {
+    if text.starts_with('"') || text.starts_with('\'') {
+        return Scalar::String;
+    }
+
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => return Scalar::Null,
+        "y" | "Y" | "yes" | "Yes" | "YES" | "true" | "True" | "TRUE" | "on" | "On" | "ON" => {
+            return Scalar::Bool(true)
+        }
+        "n" | "N" | "no" | "No" | "NO" | "false" | "False" | "FALSE" | "off" | "Off" | "OFF" => {
+            return Scalar::Bool(false)
+        }
+        _ => {}
+    }
+
+    if let Some(value) = parse_int(text) {
+        return Scalar::Int(value);
+    }
+
+    match parse_float(text) {
+        Some(value) => Scalar::Float(value.to_bits()),
+        None => Scalar::String,
+    }
+}
+
+// Check that the only difference between the document and its annotated version is the
+// policy annotation, both as read by this tool and as read by kubectl. The documents whose
+// annotations cannot be patched in place (see pod_yaml::patch_body) are re-serialized, so the
+// plain scalars that YAML 1.1 and YAML 1.2 resolve differently (e.g., on, or 0755) may change
+// their meaning for kubectl, and thereby the pod template and its hash.
+pub fn check_injection(
+    kind: &str,
+    name: &str,
+    original: &Value,
+    original_spans: &Spans,
+    body: &str,
+) -> Result<()> {
+    let annotated: Value = serde_yaml::from_str(body).map_err(|e| {
+        anyhow!(
+            "{} {}: the annotated document is invalid: {}",
+            kind,
+            name,
+            e
+        )
+    })?;
+
+    let mut expected = original.clone();
+    let mut actual = annotated.clone();
+    pod_yaml::remove_policy_annotation(&mut expected, kind);
+    pod_yaml::remove_policy_annotation(&mut actual, kind);
+
+    if expected != actual {
+        let diff = Renderer::new(true).unified_diff(
+            "input",
+            "output",
+            &serde_yaml::to_string(&expected)?,
+            &serde_yaml::to_string(&actual)?,
+        );
+        bail!(
+            "{} {}: the injection changes more than the policy annotation:\n{}",
+            kind,
+            name,
+            diff
+        );
+    }
+
+    let annotated_spans = Spans::new(original_spans.file(), body, 1);
+
+    let mut changes = Vec::new();
+    for path in original_spans.scalar_paths() {
+        let (before, after) = match (
+            original_spans.scalar_text(path),
+            annotated_spans.scalar_text(path),
+        ) {
+            (Some(before), Some(after)) => (resolve_yaml11(before), resolve_yaml11(after)),
+            _ => continue,
+        };
+
+        if before != after {
+            let (line, column) = original_spans.position(path).unwrap_or_default();
+            let message = format!(
+                "{}:{}:{}: {}: kubectl reads {} in the input but {} in the output",
+                original_spans.file(),
+                line,
+                column,
+                path,
+                before,
+                after
+            );
+            changes.push((line, column, message));
+        }
+    }
+
+    if !changes.is_empty() {
+        changes.sort();
+        let changes: Vec<String> = changes.into_iter().map(|(_, _, message)| message).collect();
+        bail!(
+            "{} {}: the injection changes the meaning of scalars for kubectl, write them unambiguously in the input (e.g., quote the strings):\n{}",
+            kind,
+            name,
+            changes.join("\n")
+        );
+    }
+
+    Ok(())
+}
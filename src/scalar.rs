@@ -0,0 +1,61 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::span;
+
+use anyhow::Result;
+use serde_yaml::Value;
+
+// Manifests often quote booleans and numbers, e.g., privileged: "true", which are coerced
+// with a warning given that the API server may reject them
+fn warn_coerced(field: &str, value: &str, r#type: &str) {
+    warn!(
+        "{}: coerced the string \"{}\" into {}, which the API server may reject",
+        field, value, r#type
+    );
+}
+
+pub fn get_bool(value: &Value, field: &str) -> Result<bool> {
+    if let Some(v) = value.as_bool() {
+        return Ok(v);
+    }
+
+    let s = value
+        .as_str()
+        .ok_or_else(|| span::error(value, format!("failed to parse {} into bool", field)))?;
+
+    let result = match s.to_lowercase().as_str() {
+        "true" | "yes" | "on" => true,
+        "false" | "no" | "off" => false,
+        _ => {
+            return Err(span::error(
+                value,
+                format!("failed to parse {} into bool", field),
+            ))
+        }
+    };
+
+    warn_coerced(field, s, "a bool");
+
+    Ok(result)
+}
+
+pub fn get_u32(value: &Value, field: &str) -> Result<u32> {
+    if let Some(v) = value.as_u64() {
+        return u32::try_from(v)
+            .map_err(|_| span::error(value, format!("failed to parse {} into u32", field)));
+    }
+
+    let s = value
+        .as_str()
+        .ok_or_else(|| span::error(value, format!("failed to parse {} into u32", field)))?;
+
+    let result = s
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| span::error(value, format!("failed to parse {} into u32", field)))?;
+
+    warn_coerced(field, s, "a number");
+
+    Ok(result)
+}
@@ -0,0 +1,93 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::read_to_string;
+use std::path::Path;
+
+// Pod spec fields that a sidecar profile can contribute to
+const PROFILE_FIELDS: [&str; 3] = ["initContainers", "containers", "volumes"];
+
+// A sidecar profile describes the containers (and the volumes they use) that a
+// mutating admission webhook such as Istio or Linkerd injects into the pod.
+// Example (istio-1.20.yaml):
+//
+// name: istio-1.20
+// spec:
+//   initContainers:
+//     - name: istio-init
+//       image: docker.io/istio/proxyv2:1.20.0
+//   containers:
+//     - name: istio-proxy
+//       image: docker.io/istio/proxyv2:1.20.0
+//   volumes:
+//     - name: istio-envoy
+//       emptyDir: {}
+pub struct SidecarProfile {
+    pub name: String,
+    spec: serde_yaml::Value,
+}
+
+impl SidecarProfile {
+    pub fn from_file(path: &Path) -> Result<SidecarProfile> {
+        let yaml = read_to_string(path).context(loc!())?;
+        let profile: serde_yaml::Value = serde_yaml::from_str(&yaml).context(loc!())?;
+
+        let name = match profile.get("name") {
+            Some(name) => name
+                .as_str()
+                .ok_or_else(|| anyhow!("failed to parse name into str"))?
+                .to_string(),
+            None => path.display().to_string(),
+        };
+
+        let spec = profile
+            .get("spec")
+            .ok_or_else(|| anyhow!("sidecar profile {} does not have spec", name))?
+            .clone();
+
+        Ok(SidecarProfile { name, spec })
+    }
+
+    // Append the containers and volumes of the profile to the pod spec. Entries whose
+    // name already exists in the pod spec are skipped so that the manifest takes precedence.
+    pub fn inject(&self, spec: &mut serde_yaml::Value) -> Result<()> {
+        for field in PROFILE_FIELDS {
+            let extras = match self.spec.get(field) {
+                Some(v) => v.as_sequence().ok_or_else(|| {
+                    anyhow!(
+                        "failed to parse {} of profile {} into sequence",
+                        field,
+                        self.name
+                    )
+                })?,
+                None => continue,
+            };
+
+            if spec.get(field).is_none() {
+                spec[field] = serde_yaml::Value::Sequence(serde_yaml::Sequence::new());
+            }
+
+            let entries = spec[field]
+                .as_sequence_mut()
+                .ok_or_else(|| anyhow!("failed to parse {} into sequence", field))?;
+
+            for extra in extras {
+                let name = extra["name"]
+                    .as_str()
+                    .ok_or_else(|| anyhow!("failed to parse name into str"))?;
+
+                if entries
+                    .iter()
+                    .any(|entry| entry["name"].as_str() == Some(name))
+                {
+                    continue;
+                }
+
+                entries.push(extra.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
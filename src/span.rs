@@ -0,0 +1,565 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::Error;
+use serde_yaml::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+// Error about a node of a manifest. The node is identified by its address, which is resolved
+// into its path from the root of the document (e.g., spec.containers[0].name), and then into
+// its line and column in the source of the document.
+#[derive(Debug)]
+pub struct NodeError {
+    address: usize,
+    path: Option<String>,
+    // File, line, and column
+    position: Option<(String, usize, usize)>,
+    message: String,
+}
+
+impl NodeError {
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.position
+            .as_ref()
+            .map(|(_, line, column)| (*line, *column))
+    }
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.position, self.path.as_deref()) {
+            (_, None | Some("")) => write!(f, "{}", self.message),
+            (Some((file, line, column)), Some(path)) => {
+                write!(
+                    f,
+                    "{}:{}:{}: {}: {}",
+                    file, line, column, path, self.message
+                )
+            }
+            (None, Some(path)) => write!(f, "{}: {}", path, self.message),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+pub fn error(node: &Value, message: impl fmt::Display) -> Error {
+    Error::new(NodeError {
+        address: node as *const Value as usize,
+        path: None,
+        position: None,
+        message: message.to_string(),
+    })
+}
+
+fn join_key(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn find_path(value: &Value, address: usize, path: &str) -> Option<String> {
+    if value as *const Value as usize == address {
+        return Some(path.to_string());
+    }
+
+    match value {
+        Value::Mapping(mapping) => mapping.iter().find_map(|(key, value)| {
+            let key = key.as_str()?;
+            find_path(value, address, &join_key(path, key))
+        }),
+        Value::Sequence(sequence) => sequence.iter().enumerate().find_map(|(index, value)| {
+            let path = format!("{}[{}]", path, index);
+            find_path(value, address, &path)
+        }),
+        Value::Tagged(tagged) => find_path(&tagged.value, address, path),
+        _ => None,
+    }
+}
+
+// Resolve the path of the node of the error, if any, given the root of the document the node
+// belongs to. Nodes that are not in the document (e.g., the fallback Null of a missing key)
+// are left without a path.
+pub fn resolve(mut error: Error, root: &Value) -> Error {
+    if let Some(node) = error.downcast_mut::<NodeError>() {
+        if node.path.is_none() {
+            node.path = find_path(root, node.address, "");
+        }
+    }
+
+    error
+}
+
+fn parent(path: &str) -> Option<&str> {
+    path.rfind(['.', '[']).map(|index| &path[..index])
+}
+
+fn is_item(content: &str) -> bool {
+    content == "-" || content.starts_with("- ")
+}
+
+fn is_block_scalar(value: &str) -> bool {
+    value.starts_with('|') || value.starts_with('>')
+}
+
+// Anchors and tags without the value, which follows on the next lines
+fn is_properties(value: &str) -> bool {
+    value
+        .split_whitespace()
+        .all(|token| token.starts_with('&') || token.starts_with('!'))
+}
+
+// Return the key and the rest of the line of a block mapping entry
+fn split_key(content: &str) -> Option<(String, &str)> {
+    if is_item(content) {
+        return None;
+    }
+
+    let (key, rest) = match content.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let end = content[1..].find(quote)? + 1;
+            (
+                content[1..end].to_string(),
+                content[end + 1..].trim_start_matches(' '),
+            )
+        }
+        '{' | '[' | '#' | '?' | '*' => return None,
+        _ => {
+            let end = content.match_indices(':').find_map(|(index, _)| {
+                let after = &content[index + 1..];
+                (after.is_empty() || after.starts_with(' ')).then_some(index)
+            })?;
+            (content[..end].trim_end().to_string(), &content[end..])
+        }
+    };
+
+    let value = rest.strip_prefix(':')?;
+    if !value.is_empty() && !value.starts_with(' ') {
+        return None;
+    }
+
+    Some((key, value.trim_start_matches(' ')))
+}
+
+struct Collection {
+    // Column of the keys or the dashes of the entries
+    column: usize,
+    path: String,
+    sequence: bool,
+    next: usize,
+}
+
+// Source text of a scalar on the line of its node, without the trailing comment. Flow
+// collections, aliases, and the scalars with properties or continued on the next lines are
+// left out.
+fn get_scalar_text(value: &str) -> Option<&str> {
+    match value.chars().next()? {
+        '[' | '{' | '&' | '!' | '*' | '|' | '>' => None,
+        '"' => {
+            let mut escaped = false;
+            let end = value.char_indices().skip(1).find_map(|(index, c)| {
+                match c {
+                    '\\' if !escaped => escaped = true,
+                    '"' if !escaped => return Some(index),
+                    _ => escaped = false,
+                }
+                None
+            })?;
+            Some(&value[..=end])
+        }
+        // '' is an escaped quote
+        '\'' => {
+            let mut index = 1;
+            loop {
+                index += value[index..].find('\'')?;
+                if value[index + 1..].starts_with('\'') {
+                    index += 2;
+                } else {
+                    return Some(&value[..=index]);
+                }
+            }
+        }
+        _ => Some(
+            value
+                .split_once(" #")
+                .map_or(value, |(text, _)| text)
+                .trim_end(),
+        ),
+    }
+}
+
+// Line and column of the nodes of a YAML document by path, indexed from the block structure
+// of the source. The entries of flow collections and the content of block scalars are not
+// indexed, and the errors about them are located at the closest indexed ancestor.
+pub struct Spans {
+    file: String,
+    positions: HashMap<String, (usize, usize)>,
+    // Source text of the scalars by path, see get_scalar_text
+    scalars: HashMap<String, String>,
+}
+
+impl Spans {
+    // The body starts at first_line of the file
+    pub fn new(file: &str, body: &str, first_line: usize) -> Spans {
+        let mut positions = HashMap::new();
+        let mut scalars = HashMap::new();
+        let mut stack: Vec<Collection> = Vec::new();
+        // Path and column of the node whose value follows on the next lines, and whether the
+        // value may be a sequence at the same column (e.g., containers: followed by - name:)
+        let mut pending: Option<(String, usize, bool)> = None;
+        // Column of the node of a block scalar, whose content is skipped
+        let mut scalar: Option<usize> = None;
+        // Path and column of the node whose value starts on its line, which may continue on the
+        // next lines, e.g., multi-line plain scalars or flow collections
+        let mut inline: Option<(String, usize)> = None;
+
+        for (index, line) in body.lines().enumerate() {
+            let number = first_line + index;
+            let line = line.trim_end();
+            let mut content = line.trim_start_matches(' ');
+            let mut column = line.len() - content.len();
+
+            if content.is_empty() || content.starts_with('#') {
+                continue;
+            }
+
+            if let Some(indent) = scalar {
+                if column > indent {
+                    continue;
+                }
+                scalar = None;
+            }
+
+            if let Some((path, indent)) = &inline {
+                // The text of the scalar is not on the line of its node only
+                if column > *indent {
+                    scalars.remove(path);
+                    continue;
+                }
+                inline = None;
+            }
+
+            if let Some((path, indent, same_column)) = pending.take() {
+                let sequence = is_item(content);
+                if column > indent || (same_column && column == indent && sequence) {
+                    stack.push(Collection {
+                        column,
+                        path,
+                        sequence,
+                        next: 0,
+                    });
+                }
+            }
+
+            while stack.last().is_some_and(|top| {
+                top.column > column || (top.column == column && top.sequence && !is_item(content))
+            }) {
+                stack.pop();
+            }
+
+            if stack.is_empty() {
+                stack.push(Collection {
+                    column,
+                    path: String::new(),
+                    sequence: is_item(content),
+                    next: 0,
+                });
+            }
+
+            // The entries starting on the same line, e.g., - name: a
+            loop {
+                let top = match stack.last_mut() {
+                    Some(top) if top.column == column => top,
+                    _ => break,
+                };
+
+                if top.sequence {
+                    if !is_item(content) {
+                        break;
+                    }
+
+                    let path = format!("{}[{}]", top.path, top.next);
+                    top.next += 1;
+                    positions.insert(path.clone(), (number, column + 1));
+
+                    let rest = content[1..].trim_start_matches(' ');
+                    if rest.is_empty() || rest.starts_with('#') || is_properties(rest) {
+                        pending = Some((path, column, false));
+                    } else if is_block_scalar(rest) {
+                        scalar = Some(column);
+                    } else if is_item(rest) || split_key(rest).is_some() {
+                        column += content.len() - rest.len();
+                        content = rest;
+                        stack.push(Collection {
+                            column,
+                            path,
+                            sequence: is_item(rest),
+                            next: 0,
+                        });
+                        continue;
+                    } else {
+                        if let Some(text) = get_scalar_text(rest) {
+                            scalars.insert(path.clone(), text.to_string());
+                        }
+                        inline = Some((path, column));
+                    }
+                } else if let Some((key, value)) = split_key(content) {
+                    let path = join_key(&top.path, &key);
+                    positions.insert(path.clone(), (number, column + 1));
+
+                    if value.is_empty() || value.starts_with('#') || is_properties(value) {
+                        pending = Some((path, column, true));
+                    } else if is_block_scalar(value) {
+                        scalar = Some(column);
+                    } else {
+                        if let Some(text) = get_scalar_text(value) {
+                            scalars.insert(path.clone(), text.to_string());
+                        }
+                        inline = Some((path, column));
+                    }
+                }
+
+                break;
+            }
+        }
+
+        Spans {
+            file: file.to_string(),
+            positions,
+            scalars,
+        }
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn position(&self, path: &str) -> Option<(usize, usize)> {
+        self.positions.get(path).copied()
+    }
+
+    // Column of the shallowest nodes under the node, i.e., of its children if it is a block
+    // collection
+    pub fn child_column(&self, path: &str) -> Option<usize> {
+        self.positions
+            .iter()
+            .filter(|(other, _)| {
+                other
+                    .strip_prefix(path)
+                    .is_some_and(|rest| rest.starts_with(['.', '[']))
+            })
+            .map(|(_, (_, column))| *column)
+            .min()
+    }
+
+    pub fn scalar_text(&self, path: &str) -> Option<&str> {
+        self.scalars.get(path).map(String::as_str)
+    }
+
+    pub fn scalar_paths(&self) -> impl Iterator<Item = &str> {
+        self.scalars.keys().map(String::as_str)
+    }
+
+    // Locate the node of the error, if any, at its position or the one of its closest ancestor
+    pub fn locate(&self, mut error: Error) -> Error {
+        if let Some(node) = error.downcast_mut::<NodeError>() {
+            let mut path = node.path.as_deref();
+
+            while let Some(current) = path {
+                if let Some((line, column)) = self.positions.get(current) {
+                    node.position = Some((self.file.clone(), *line, *column));
+                    break;
+                }
+
+                path = parent(current);
+            }
+        }
+
+        error
+    }
+}
+
+// Locate the node of the error in the document, if its source is known
+pub fn locate(error: Error, spans: Option<&Spans>) -> Error {
+    match spans {
+        Some(spans) => spans.locate(error),
+        None => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Node of the value at the path of Spans, e.g., spec.containers[0].name
+    fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+        let mut value = value;
+
+        for part in path.split('.') {
+            let (key, indexes) = match part.find('[') {
+                Some(index) => (&part[..index], &part[index..]),
+                None => (part, ""),
+            };
+
+            if !key.is_empty() {
+                value = value.get(key)?;
+            }
+
+            for index in indexes.split(['[', ']']).filter(|index| !index.is_empty()) {
+                value = value.get(index.parse::<usize>().ok()?)?;
+            }
+        }
+
+        Some(value)
+    }
+
+    // Every indexed path is a node of the document, and every scalar text is the one of a
+    // string, number, or boolean on a single line
+    fn check(body: &str) -> Spans {
+        let root: Value = serde_yaml::from_str(body).unwrap();
+        let spans = Spans::new("test.yaml", body, 1);
+
+        for path in spans.positions.keys() {
+            assert!(lookup(&root, path).is_some(), "{}", path);
+        }
+
+        for (path, text) in &spans.scalars {
+            let node = lookup(&root, path).unwrap();
+            let parsed: Value = serde_yaml::from_str(text).unwrap();
+            assert_eq!(&parsed, node, "{}", path);
+        }
+
+        spans
+    }
+
+    #[test]
+    fn test_block_collections() {
+        let spans = check(
+            "kind: Pod\n\
+             spec:\n  \
+               containers:\n  \
+               - name: app\n    \
+                 args:\n    \
+                 - a\n    \
+                 - \"b # c\"\n  \
+               - name: sidecar # comment\n",
+        );
+
+        assert_eq!(spans.position("kind"), Some((1, 1)));
+        assert_eq!(spans.position("spec.containers"), Some((3, 3)));
+        assert_eq!(spans.position("spec.containers[0]"), Some((4, 3)));
+        assert_eq!(spans.position("spec.containers[0].name"), Some((4, 5)));
+        assert_eq!(spans.position("spec.containers[0].args[1]"), Some((7, 5)));
+        assert_eq!(spans.position("spec.containers[1].name"), Some((8, 5)));
+        assert_eq!(
+            spans.scalar_text("spec.containers[0].args[1]"),
+            Some("\"b # c\"")
+        );
+        assert_eq!(
+            spans.scalar_text("spec.containers[1].name"),
+            Some("sidecar")
+        );
+    }
+
+    #[test]
+    fn test_flow_collections() {
+        let spans = check(
+            "metadata: {name: a, labels: {app: a}}\n\
+             args: [a,\n  b]\n\
+             spec:\n  \
+               nodeSelector: {}\n  \
+               x: 1\n",
+        );
+
+        assert_eq!(spans.position("metadata"), Some((1, 1)));
+        assert_eq!(spans.position("metadata.name"), None);
+        assert_eq!(spans.position("args"), Some((2, 1)));
+        assert_eq!(spans.position("args[0]"), None);
+        assert_eq!(spans.position("spec.x"), Some((6, 3)));
+        assert_eq!(spans.scalar_text("metadata"), None);
+
+        // Located at the closest indexed ancestor
+        let root: Value = serde_yaml::from_str("metadata: {name: a}").unwrap();
+        let e = resolve(error(&root["metadata"]["name"], "bad"), &root);
+        let spans = Spans::new("test.yaml", "metadata: {name: a}", 1);
+        let e = spans.locate(e);
+        assert_eq!(
+            e.downcast_ref::<NodeError>().unwrap().position(),
+            Some((1, 1))
+        );
+    }
+
+    #[test]
+    fn test_block_scalars() {
+        let spans = check(
+            "data:\n  \
+               script: |\n    \
+                 key: value\n    \
+                 - item\n\n    \
+                 # not a comment\n  \
+               folded: >-\n    \
+                 a\n  \
+               next: x\n",
+        );
+
+        assert_eq!(spans.position("data.script"), Some((2, 3)));
+        assert_eq!(spans.position("data.key"), None);
+        assert_eq!(spans.position("data.folded"), Some((7, 3)));
+        assert_eq!(spans.position("data.next"), Some((9, 3)));
+        assert_eq!(spans.scalar_text("data.script"), None);
+        assert_eq!(spans.scalar_text("data.next"), Some("x"));
+    }
+
+    #[test]
+    fn test_multi_line_scalars() {
+        let spans = check(
+            "a: plain\n  continued\n\
+             b: \"double\n  quoted\"\n\
+             c:\n  - one\n    two\n  - three\n\
+             d: 1\n",
+        );
+
+        assert_eq!(spans.scalar_text("a"), None);
+        assert_eq!(spans.scalar_text("b"), None);
+        assert_eq!(spans.scalar_text("c[0]"), None);
+        assert_eq!(spans.scalar_text("c[1]"), Some("three"));
+        assert_eq!(spans.position("c[1]"), Some((8, 3)));
+        assert_eq!(spans.position("d"), Some((9, 1)));
+        assert_eq!(spans.scalar_text("d"), Some("1"));
+    }
+
+    #[test]
+    fn test_anchors_and_aliases() {
+        let spans = check(
+            "base: &base\n  \
+               cpu: \"1\"\n\
+             other: *base\n\
+             value: &value 2\n\
+             list:\n\
+             - &item\n  \
+               name: a\n\
+             - *item\n",
+        );
+
+        assert_eq!(spans.position("base.cpu"), Some((2, 3)));
+        assert_eq!(spans.position("other"), Some((3, 1)));
+        assert_eq!(spans.scalar_text("other"), None);
+        assert_eq!(spans.scalar_text("value"), None);
+        assert_eq!(spans.position("list[0].name"), Some((7, 3)));
+        assert_eq!(spans.position("list[1]"), Some((8, 1)));
+    }
+
+    #[test]
+    fn test_documents() {
+        // Each document of a stream is indexed from its own first line
+        let body = "kind: Service\nmetadata:\n  name: b\n";
+        let spans = Spans::new("test.yaml", body, 5);
+
+        assert_eq!(spans.position("kind"), Some((5, 1)));
+        assert_eq!(spans.position("metadata.name"), Some((7, 3)));
+        assert_eq!(spans.file(), "test.yaml");
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::recording;
+use crate::recording::Mode;
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// External tools (kubectl, skopeo, oras, cosign, az, and the exec plugins) are run without a
+// shell, so the arguments are passed as is. They are killed after the timeout.
+const DEFAULT_TIMEOUT_SECONDS: u64 = 300;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Bytes around the invalid UTF-8 of an output shown in the errors
+const CONTEXT_BYTES: usize = 32;
+
+// Set by --command-timeout
+static TIMEOUT_SECONDS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_SECONDS);
+
+// Paths of the tools overriding the lookup in PATH, set by --skopeo-path and --kubectl-path,
+// e.g., for a wrapper running skopeo in a toolbox container
+static PATHS: Mutex<BTreeMap<OsString, PathBuf>> = Mutex::new(BTreeMap::new());
+
+pub fn set_timeout(seconds: u64) {
+    TIMEOUT_SECONDS.store(seconds, Ordering::Relaxed);
+}
+
+pub fn set_path(program: &str, path: &Path) {
+    PATHS
+        .lock()
+        .unwrap()
+        .insert(program.into(), path.to_path_buf());
+}
+
+fn resolve(program: &OsStr) -> PathBuf {
+    match PATHS.lock().unwrap().get(program) {
+        Some(path) => path.clone(),
+        None => PathBuf::from(program),
+    }
+}
+
+pub struct Output {
+    // The program and its arguments, for the errors
+    command: String,
+    // None if killed by a signal
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl Output {
+    // Return stdout, or an error with stderr unless the program exited successfully
+    pub fn check(self) -> Result<Vec<u8>> {
+        let status = match self.code {
+            Some(0) => return Ok(self.stdout),
+            Some(code) => format!("failed with exit code {}", code),
+            None => "was killed by a signal".to_string(),
+        };
+
+        bail!(
+            "{} {}: {}",
+            self.command,
+            status,
+            escape(&self.stderr).trim()
+        );
+    }
+
+    // Same as check, with stdout decoded as UTF-8
+    pub fn check_text(self) -> Result<String> {
+        let command = self.command.clone();
+        decode(&command, "stdout", self.check()?)
+    }
+
+    pub fn stdout_text(&self) -> Result<String> {
+        decode(&self.command, "stdout", self.stdout.clone())
+    }
+}
+
+// Bytes that are not valid UTF-8 are printed as \xNN, so that nothing is lost nor replaced
+fn escape(bytes: &[u8]) -> String {
+    let mut result = String::new();
+
+    for chunk in bytes.utf8_chunks() {
+        result.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            result.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+
+    result
+}
+
+// The outputs of the tools are parsed as UTF-8 (e.g., JSON or YAML), anything else is an error
+// showing where the invalid bytes are
+fn decode(command: &str, stream: &str, bytes: Vec<u8>) -> Result<String> {
+    match String::from_utf8(bytes) {
+        Ok(result) => Ok(result),
+        Err(e) => {
+            let offset = e.utf8_error().valid_up_to();
+            let bytes = e.as_bytes();
+            let start = offset.saturating_sub(CONTEXT_BYTES);
+            let end = bytes.len().min(offset + CONTEXT_BYTES);
+
+            bail!(
+                "{} printed invalid UTF-8 on {} at byte {}: \"{}\", check the locale (LANG, LC_ALL) of the program",
+                command,
+                stream,
+                offset,
+                escape(&bytes[start..end])
+            );
+        }
+    }
+}
+
+// Check a value taken from the input (e.g., an image ref, or the name of a ConfigMap) before
+// passing it as an operand, which the tool would parse as an option if it started with a dash
+pub fn operand(value: &str) -> Result<&str> {
+    if value.is_empty() || value.starts_with('-') || value.chars().any(char::is_control) {
+        bail!("invalid command argument {:?}", value);
+    }
+
+    Ok(value)
+}
+
+fn describe<S: AsRef<OsStr>>(program: &OsStr, args: &[S]) -> String {
+    let mut result = program.to_string_lossy().to_string();
+    for arg in args {
+        result.push(' ');
+        result.push_str(&arg.as_ref().to_string_lossy());
+    }
+    result
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut result = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut result);
+        }
+        result
+    })
+}
+
+// Run the program and return its output whatever the exit code
+pub fn output<P, S>(program: P, args: &[S]) -> Result<Output>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+{
+    output_with_input(program, args, None)
+}
+
+// Same as output, with the input written to the stdin of the program, e.g., the credentials
+// that would otherwise be visible in the arguments of the process. The input is not recorded.
+pub fn output_with_input<P, S>(program: P, args: &[S], input: Option<&[u8]>) -> Result<Output>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+{
+    // The recordings are keyed by the name of the tool, whatever its path
+    let name = program.as_ref();
+    let program = resolve(name);
+    let program = program.as_os_str();
+    let command = describe(program, args);
+
+    let mode = recording::get_mode();
+    if mode == Some(Mode::Replay) {
+        let (code, stdout, stderr) = recording::replay(name, args, &command)?;
+        return Ok(Output {
+            command,
+            code,
+            stdout,
+            stderr,
+        });
+    }
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(match input {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run {}", command))?;
+
+    // Read both pipes while waiting so that the program does not block on a full pipe, and
+    // close stdin once written
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        let input = input.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    let timeout = Duration::from_secs(TIMEOUT_SECONDS.load(Ordering::Relaxed));
+    let start = Instant::now();
+
+    let status = loop {
+        if let Some(status) = child.try_wait().context(loc!())? {
+            break status;
+        }
+
+        // The pipes may be held open by the children of the program, they are not waited for
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!(
+                "{} timed out after {}s, see --command-timeout",
+                command,
+                timeout.as_secs()
+            );
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let output = Output {
+        command,
+        code: status.code(),
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+
+    if mode == Some(Mode::Record) {
+        recording::record(name, args, output.code, &output.stdout, &output.stderr)?;
+    }
+
+    Ok(output)
+}
+
+// Run the program and return its stdout, failing with its stderr unless it exits successfully
+pub fn run<P, S>(program: P, args: &[S]) -> Result<Vec<u8>>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+{
+    output(program, args)?.check()
+}
+
+// Run the program and return its stdout decoded as UTF-8
+pub fn run_text<P, S>(program: P, args: &[S]) -> Result<String>
+where
+    P: AsRef<OsStr>,
+    S: AsRef<OsStr>,
+{
+    output(program, args)?.check_text()
+}
@@ -0,0 +1,321 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::cluster;
+use crate::lint::{self, LooseRule};
+use crate::policy::ARGS_MATCH_REGEX;
+
+use anyhow::{Context, Result};
+use clap::ArgEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+use std::fs::read_to_string;
+use std::path::Path;
+
+#[derive(ArgEnum, Clone, PartialEq, Eq)]
+pub enum SummaryFormat {
+    Markdown,
+    Json,
+}
+
+#[derive(Serialize)]
+struct MountSummary {
+    destination: String,
+    source: String,
+    read_only: bool,
+}
+
+// What a reviewer of the policy of a container looks at, i.e., what the container may run,
+// write, and see of the host
+#[derive(Serialize)]
+struct ContainerSummary {
+    name: String,
+    // process.args, with the regex rules marked, see Custom::args_match
+    entrypoint: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    regex_args: Vec<usize>,
+    cwd: String,
+    uid: Option<u64>,
+    gid: Option<u64>,
+    // sysfs and the cgroups are only writable by the privileged containers, see cri::get_rules
+    privileged: bool,
+    no_new_privileges: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    writable_mounts: Vec<MountSummary>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    host_paths: Vec<MountSummary>,
+    // The env wildcards and the writable host paths among others, see lint::get_loose_rules
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    loose_rules: Vec<LooseRule>,
+}
+
+#[derive(Serialize)]
+struct PolicySummary {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    relaxed_rules: Vec<String>,
+    containers: Vec<ContainerSummary>,
+}
+
+fn get_strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|value| value.as_str())
+        .map(String::from)
+        .collect()
+}
+
+fn get_mount(mount: &Value) -> MountSummary {
+    MountSummary {
+        destination: mount["destination"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        source: mount["source"].as_str().unwrap_or_default().to_string(),
+        read_only: get_strings(&mount["options"]).iter().any(|o| o == "ro"),
+    }
+}
+
+fn summarize_container(
+    name: &str,
+    container: &Value,
+    loose_rules: Vec<LooseRule>,
+) -> ContainerSummary {
+    let spec = &container["oci_spec"];
+    let process = &spec["process"];
+
+    let regex_args = get_strings(&container["custom"]["args_match"])
+        .iter()
+        .enumerate()
+        .filter(|(_, args_match)| *args_match == ARGS_MATCH_REGEX)
+        .map(|(index, _)| index)
+        .collect();
+
+    let mounts = spec["mounts"].as_array().into_iter().flatten();
+
+    let privileged = mounts.clone().any(|mount| {
+        matches!(mount["type"].as_str(), Some("sysfs" | "cgroup"))
+            && get_strings(&mount["options"]).iter().any(|o| o == "rw")
+    });
+
+    // The volumes and the shared files, the other mounts being the ones of the runtime
+    let bind_mounts = mounts.filter(|mount| matches!(mount["type"].as_str(), Some("bind")));
+
+    let writable_mounts = bind_mounts
+        .clone()
+        .map(get_mount)
+        .filter(|mount| !mount.read_only)
+        .collect();
+
+    // The shared files of the guest and the unknown sources are regexes or empty, the paths of
+    // the node are exact except the ones of the local PersistentVolumes
+    let host_paths = bind_mounts
+        .map(get_mount)
+        .filter(|mount| {
+            mount.source.starts_with('/') || cluster::is_local_volume_source(&mount.source)
+        })
+        .collect();
+
+    ContainerSummary {
+        name: name.to_string(),
+        entrypoint: get_strings(&process["args"]),
+        regex_args,
+        cwd: process["cwd"].as_str().unwrap_or_default().to_string(),
+        uid: process["user"]["uid"].as_u64(),
+        gid: process["user"]["gid"].as_u64(),
+        privileged,
+        no_new_privileges: process["noNewPrivileges"].as_bool().unwrap_or_default(),
+        capabilities: get_strings(&process["capabilities"]["bounding"]),
+        writable_mounts,
+        host_paths,
+        loose_rules,
+    }
+}
+
+fn summarize(policy: &Value) -> PolicySummary {
+    let loose_rules = lint::get_loose_rules(policy);
+
+    // The rules of the policy, the other ones are under the containers
+    let relaxed_rules = loose_rules
+        .iter()
+        .filter(|loose_rule| loose_rule.path.starts_with("metadata."))
+        .map(|loose_rule| loose_rule.rule.clone())
+        .collect();
+
+    let containers = policy["containers"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, container)| {
+            let prefix = format!("containers.{}.", name);
+            let container_rules = loose_rules
+                .iter()
+                .filter(|loose_rule| loose_rule.path.starts_with(&prefix))
+                .cloned()
+                .collect();
+
+            summarize_container(name, container, container_rules)
+        })
+        .collect();
+
+    PolicySummary {
+        relaxed_rules,
+        containers,
+    }
+}
+
+// Code span of the value, delimited by more backticks than it contains
+fn code(value: &str) -> String {
+    let mut delimiter = String::from("`");
+    while value.contains(&delimiter) {
+        delimiter.push('`');
+    }
+
+    if value.starts_with('`') || value.ends_with('`') {
+        format!("{} {} {}", delimiter, value, delimiter)
+    } else {
+        format!("{}{}{}", delimiter, value, delimiter)
+    }
+}
+
+fn render_mounts(output: &mut String, title: &str, mounts: &[MountSummary]) -> Result<()> {
+    if mounts.is_empty() {
+        writeln!(output, "- {}: none", title)?;
+        return Ok(());
+    }
+
+    writeln!(output, "- {}:", title)?;
+    for mount in mounts {
+        let source = if mount.source.is_empty() {
+            "an unknown source".to_string()
+        } else {
+            code(&mount.source)
+        };
+        let access = if mount.read_only { "ro" } else { "rw" };
+
+        writeln!(
+            output,
+            "  - {} from {} ({})",
+            code(&mount.destination),
+            source,
+            access
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_markdown(name: &str, summary: &PolicySummary) -> Result<String> {
+    let mut output = String::new();
+
+    writeln!(output, "# Policy summary of {}", code(name))?;
+    writeln!(output)?;
+
+    if !summary.relaxed_rules.is_empty() {
+        let relaxed_rules: Vec<String> = summary.relaxed_rules.iter().map(|r| code(r)).collect();
+        writeln!(
+            output,
+            "**Relaxed rules:** {}, which are not enforced.",
+            relaxed_rules.join(", ")
+        )?;
+        writeln!(output)?;
+    }
+
+    for container in &summary.containers {
+        writeln!(output, "## Container {}", code(&container.name))?;
+        writeln!(output)?;
+
+        let entrypoint: Vec<String> = container
+            .entrypoint
+            .iter()
+            .enumerate()
+            .map(|(index, arg)| match container.regex_args.contains(&index) {
+                true => format!("{} (regex)", code(arg)),
+                false => code(arg),
+            })
+            .collect();
+        if entrypoint.is_empty() {
+            writeln!(output, "- Entrypoint: none")?;
+        } else {
+            writeln!(output, "- Entrypoint: {}", entrypoint.join(" "))?;
+        }
+
+        if !container.cwd.is_empty() {
+            writeln!(output, "- Working directory: {}", code(&container.cwd))?;
+        }
+
+        let id = |id: Option<u64>| id.map_or("unset".to_string(), |id| id.to_string());
+        let root = if container.uid == Some(0) {
+            " (root)"
+        } else {
+            ""
+        };
+        writeln!(
+            output,
+            "- User: uid {}{}, gid {}",
+            id(container.uid),
+            root,
+            id(container.gid)
+        )?;
+
+        let mut privileges = Vec::new();
+        if container.privileged {
+            privileges.push("**privileged**".to_string());
+        }
+        privileges.push(match container.no_new_privileges {
+            true => "no new privileges".to_string(),
+            false => "privilege escalation allowed".to_string(),
+        });
+        if !container.capabilities.is_empty() {
+            let capabilities: Vec<String> =
+                container.capabilities.iter().map(|c| code(c)).collect();
+            privileges.push(format!("capabilities {}", capabilities.join(", ")));
+        }
+        writeln!(output, "- Privileges: {}", privileges.join(", "))?;
+
+        render_mounts(&mut output, "Writable mounts", &container.writable_mounts)?;
+        render_mounts(&mut output, "Host paths", &container.host_paths)?;
+
+        if container.loose_rules.is_empty() {
+            writeln!(output, "- Loose rules: none")?;
+        } else {
+            writeln!(output, "- Loose rules:")?;
+            for loose_rule in &container.loose_rules {
+                let rule = code(&loose_rule.rule);
+                let parts: [&str; 3] = [loose_rule.kind, &rule, loose_rule.reason];
+                let parts: Vec<&str> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+                writeln!(output, "  - {}", parts.join(" "))?;
+            }
+        }
+
+        writeln!(output)?;
+    }
+
+    Ok(output)
+}
+
+// Summarize a policy for the reviewers, e.g., in the description of a change
+pub fn summarize_policy(path: &Path, format: &SummaryFormat) -> Result<()> {
+    let content = read_to_string(path)
+        .with_context(|| format!("failed to read policy {}", path.display()))?;
+    let policy: Value = serde_json::from_str(&content)
+        .with_context(|| format!("failed to parse policy {}", path.display()))?;
+
+    let summary = summarize(&policy);
+
+    match format {
+        SummaryFormat::Json => println!("{}", serde_json::to_string_pretty(&summary)?),
+        SummaryFormat::Markdown => {
+            print!(
+                "{}",
+                render_markdown(&path.display().to_string(), &summary)?
+            )
+        }
+    }
+
+    Ok(())
+}
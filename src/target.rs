@@ -0,0 +1,128 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use crate::affinity;
+use crate::PodYaml;
+
+use anyhow::Result;
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Rules deciding whether a document targets the confidential node pools, defined under
+// [target] in config.toml. A document is annotated if it matches any of the rules, and all
+// documents are annotated if no rule is defined.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Target {
+    pub runtime_class_names: Vec<String>,
+    // Labels that the nodeSelector of the pod must all contain
+    pub node_selector: BTreeMap<String, String>,
+    // Labels of the confidential nodes that the nodeSelector and the required node affinity of
+    // the pod must match
+    pub node_labels: BTreeMap<String, String>,
+    // Tolerations of which the pod must have at least one
+    pub tolerations: Vec<Toleration>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Toleration {
+    pub key: String,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub effect: Option<String>,
+}
+
+impl Toleration {
+    // Follow ToleratesTaint, i.e., an empty key with Exists tolerates every taint and
+    // an empty effect tolerates every effect
+    // Reference: https://github.com/kubernetes/api/blob/release-1.26/core/v1/toleration.go#L39
+    fn is_tolerated_by(&self, toleration: &serde_yaml::Value) -> bool {
+        let key = toleration["key"].as_str().unwrap_or_default();
+        let operator = toleration["operator"].as_str().unwrap_or("Equal");
+        let value = toleration["value"].as_str().unwrap_or_default();
+        let effect = toleration["effect"].as_str().unwrap_or_default();
+
+        if !effect.is_empty() {
+            if let Some(expected) = &self.effect {
+                if expected != effect {
+                    return false;
+                }
+            }
+        }
+
+        if key.is_empty() {
+            return operator == "Exists";
+        }
+
+        if key != self.key {
+            return false;
+        }
+
+        match operator {
+            "Exists" => true,
+            "Equal" => self.value.as_deref().unwrap_or_default() == value,
+            _ => false,
+        }
+    }
+}
+
+impl Target {
+    pub fn is_empty(&self) -> bool {
+        self.runtime_class_names.is_empty()
+            && self.node_selector.is_empty()
+            && self.node_labels.is_empty()
+            && self.tolerations.is_empty()
+    }
+
+    // Return the reason why the pod targets the confidential node pools, None if it does not
+    pub fn get_match(&self, pod_yaml: &PodYaml) -> Result<Option<String>> {
+        if self.is_empty() {
+            return Ok(Some("no target rules".to_string()));
+        }
+
+        if let Some(runtime_class_name) = pod_yaml.spec["runtimeClassName"].as_str() {
+            if self
+                .runtime_class_names
+                .iter()
+                .any(|name| name == runtime_class_name)
+            {
+                return Ok(Some(format!("runtimeClassName {}", runtime_class_name)));
+            }
+        }
+
+        if !self.node_selector.is_empty() {
+            let node_selector = pod_yaml.spec["nodeSelector"].as_mapping();
+
+            let matched = self.node_selector.iter().all(|(key, value)| {
+                node_selector
+                    .and_then(|m| m.get(&serde_yaml::Value::String(key.clone())))
+                    .and_then(|v| v.as_str())
+                    == Some(value.as_str())
+            });
+
+            if matched {
+                return Ok(Some("nodeSelector".to_string()));
+            }
+        }
+
+        if !self.node_labels.is_empty()
+            && affinity::has_node_constraints(pod_yaml.spec)
+            && affinity::matches_node_labels(pod_yaml.spec, &self.node_labels)?
+        {
+            return Ok(Some("node affinity".to_string()));
+        }
+
+        if let Some(tolerations) = pod_yaml.spec["tolerations"].as_sequence() {
+            for expected in &self.tolerations {
+                if tolerations.iter().any(|t| expected.is_tolerated_by(t)) {
+                    return Ok(Some(format!("toleration {}", expected.key)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,45 @@
+// Copyright (c) Cc-Policy Authors.
+// Licensed under the Apache 2.0 license.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::fs::read_to_string;
+use std::path::Path;
+
+#[derive(Serialize)]
+struct TemplatePolicy {
+    // The policy as a JSON value and as the serialized string
+    data: serde_json::Value,
+    json: String,
+    base64: String,
+}
+
+// Render the policies and their encodings through the Tera template
+// Reference: https://keats.github.io/tera/docs/#templates
+pub fn render(path: &Path, policies: &[(String, String)]) -> Result<String> {
+    let template = read_to_string(path)
+        .with_context(|| format!("failed to read the template {}", path.display()))?;
+
+    let policies = policies
+        .iter()
+        .map(|(policy, policy_base64)| -> Result<TemplatePolicy> {
+            Ok(TemplatePolicy {
+                data: serde_json::from_str(policy).context(loc!())?,
+                json: policy.clone(),
+                base64: policy_base64.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // policy and policy_base64 are the ones of the first workload, for inputs with a single
+    // workload
+    let mut context = tera::Context::new();
+    if let Some(policy) = policies.first() {
+        context.insert("policy", policy);
+        context.insert("policy_base64", &policy.base64);
+    }
+    context.insert("policies", &policies);
+
+    tera::Tera::one_off(&template, &context, false)
+        .map_err(|e| anyhow!("failed to render the template {}: {:?}", path.display(), e))
+}